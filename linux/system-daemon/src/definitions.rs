@@ -1,4 +1,5 @@
 use slog;
+use u2fhid_protocol::ReportFormat;
 
 #[derive(Serialize, Deserialize)]
 pub enum SocketInput {
@@ -49,7 +50,9 @@ impl slog::Value for CreateDeviceRequest {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DeviceDescription {
-    pub id: String
+    pub id: String,
+    // Framing of the packets sent over the socket
+    pub report_format: ReportFormat,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -16,47 +16,57 @@ use users::get_user_by_uid;
 use bidirectional_pipe::BidirectionalPipe;
 use softu2f_system_daemon::*;
 use tokio_linux_uhid::{Bus, CreateParams, InputEvent, OutputEvent, StreamError, UHIDDevice};
+use u2fhid_protocol::ReportFormat;
 
-const INPUT_REPORT_LEN: u8 = 64;
-const OUTPUT_REPORT_LEN: u8 = 64;
+// HID Report Descriptor from http://www.usb.org/developers/hidpage/HUTRR48.pdf,
+// with the report size and ID of the format
+fn report_descriptor(format: &ReportFormat) -> Vec<u8> {
+    let mut descriptor = vec![
+        0x06, 0xd0, 0xf1, // USAGE_PAGE (FIDO Alliance)
+        0x09, 0x01, // USAGE (U2F Authenticator Device)
+        0xa1, 0x01, // COLLECTION (Application)
+    ];
+    if let Some(report_id) = format.report_id() {
+        descriptor.extend_from_slice(&[0x85, report_id]); //   REPORT_ID
+    }
+    descriptor.extend_from_slice(&[
+        0x09, 0x20, //   USAGE (Input Report Data)
+        0x15, 0x00, //   LOGICAL_MINIMUM (0)
+        0x26, 0xff, 0x00, //   LOGICAL_MAXIMUM (255)
+        0x75, 0x08, //   REPORT_SIZE (8)
+    ]);
+    push_report_count(&mut descriptor, format.report_len());
+    descriptor.extend_from_slice(&[
+        0x81, 0x02, //   INPUT (Data,Var,Abs)
+        0x09, 0x21, //   USAGE (Output Report Data)
+        0x15, 0x00, //   LOGICAL_MINIMUM (0)
+        0x26, 0xff, 0x00, //   LOGICAL_MAXIMUM (255)
+        0x75, 0x08, //   REPORT_SIZE (8)
+    ]);
+    push_report_count(&mut descriptor, format.report_len());
+    descriptor.extend_from_slice(&[
+        0x91, 0x02, //   OUTPUT (Data,Var,Abs)
+        0xc0, // END_COLLECTION
+    ]);
+    descriptor
+}
 
-// HID Report Descriptor from http://www.usb.org/developers/hidpage/HUTRR48.pdf
-const REPORT_DESCRIPTOR: [u8; 34] = [
-    0x06,
-    0xd0,
-    0xf1, // USAGE_PAGE (FIDO Alliance)
-    0x09,
-    0x01, // USAGE (Keyboard)
-    0xa1,
-    0x01, // COLLECTION (Application)
-    0x09,
-    0x20, //   USAGE (Input Report Data)
-    0x15,
-    0x00, //   LOGICAL_MINIMUM (0)
-    0x26,
-    0xff,
-    0x00, //   LOGICAL_MAXIMUM (255)
-    0x75,
-    0x08, //   REPORT_SIZE (8)
-    0x95,
-    INPUT_REPORT_LEN, //   REPORT_COUNT (64)
-    0x81,
-    0x02, //   INPUT (Data,Var,Abs)
-    0x09,
-    0x21, //   USAGE(Output Report Data)
-    0x15,
-    0x00, //   LOGICAL_MINIMUM (0)
-    0x26,
-    0xff,
-    0x00, //   LOGICAL_MAXIMUM (255)
-    0x75,
-    0x08, //   REPORT_SIZE (8)
-    0x95,
-    OUTPUT_REPORT_LEN, //   REPORT_COUNT (64)
-    0x91,
-    0x02, //   OUTPUT (Data,Var,Abs)
-    0xc0, // END_COLLECTION
-];
+// REPORT_COUNT, with the shortest item that holds the count
+fn push_report_count(descriptor: &mut Vec<u8>, count: usize) {
+    if count <= 0xff {
+        descriptor.extend_from_slice(&[0x95, count as u8]);
+    } else if count <= 0xffff {
+        descriptor.extend_from_slice(&[0x96, count as u8, (count >> 8) as u8]);
+    } else {
+        descriptor.extend_from_slice(&[
+            0x97,
+            count as u8,
+            (count >> 8) as u8,
+            (count >> 16) as u8,
+            (count >> 24) as u8,
+        ]);
+    }
+}
 
 type PacketPipe =
     Box<dyn Pipe<Item = Packet, Error = Error, SinkItem = Packet, SinkError = Error> + Send>;
@@ -111,18 +121,23 @@ enum DeviceState {
 pub struct Device {
     id: String,
     logger: Logger,
+    report_format: ReportFormat,
     state: DeviceState,
     user: UCred,
 }
 
 impl Device {
-    pub fn new(stream: UnixStream, logger: &Logger) -> io::Result<Device>
-    {
+    pub fn new(
+        stream: UnixStream,
+        report_format: ReportFormat,
+        logger: &Logger,
+    ) -> io::Result<Device> {
         let user = stream.peer_cred()?;
         let id = nanoid::simple();
         Ok(Device {
             id: id.clone(),
             logger: logger.new(o!("device_id" => id)),
+            report_format,
             state: DeviceState::Uninitialized(bind_transport(stream)),
             user,
         })
@@ -141,6 +156,7 @@ fn bind_transport(stream: UnixStream) -> SocketPipe {
 
 fn initialize(
     device_id: &str,
+    report_format: ReportFormat,
     socket_transport: SocketPipe,
     logger: &Logger,
     _request: CreateDeviceRequest,
@@ -158,16 +174,19 @@ fn initialize(
         product: 0xffff,
         version: 0,
         country: 0,
-        data: REPORT_DESCRIPTOR.to_vec(),
+        data: report_descriptor(&report_format),
     };
 
     info!(logger, "Creating virtual U2F device"; "name" => &create_params.name);
     let uhid_device = UHIDDevice::create(create_params, logger.clone()).unwrap();
     // TODO chown device to self.user creds
-    let uhid_transport = into_transport(uhid_device);
+    let uhid_transport = into_transport(uhid_device, report_format);
 
     let socket_future = socket_transport.send(SocketOutput::CreateDeviceResponse(
-        Ok(DeviceDescription { id: device_id.to_string() }),
+        Ok(DeviceDescription {
+            id: device_id.to_string(),
+            report_format,
+        }),
     )).from_err();
 
     (Box::new(socket_future), uhid_transport)
@@ -204,11 +223,25 @@ fn run(
     BidirectionalPipe::new(mapped_socket_transport, uhid_transport)
 }
 
-fn into_transport<T: AsyncRead + Write + Send + 'static>(device: UHIDDevice<T>) -> PacketPipe {
+// uhid prefixes output reports with their report number, which is zero
+// and not part of the report when the descriptor declares no report IDs
+fn output_report(data: &[u8], report_format: &ReportFormat) -> Vec<u8> {
+    match report_format.report_id() {
+        Some(_) => data.to_vec(),
+        None => data.iter().skip(1).cloned().collect(),
+    }
+}
+
+fn into_transport<T: AsyncRead + Write + Send + 'static>(
+    device: UHIDDevice<T>,
+    report_format: ReportFormat,
+) -> PacketPipe {
     Box::new(
         device
-            .filter_map(|event| match event {
-                OutputEvent::Output { data } => Some(Packet::from_bytes(&data)),
+            .filter_map(move |event| match event {
+                OutputEvent::Output { data } => {
+                    Some(Packet::from_bytes(&output_report(&data, &report_format)))
+                }
                 _ => None,
             })
             .with(|packet: Packet| {
@@ -247,6 +280,7 @@ impl Future for Device {
             let state = &mut self.state;
             let user = &self.user;
             let device_id = &self.id;
+            let report_format = self.report_format;
             take(state, |state| match state {
                 DeviceState::Uninitialized(mut socket_transport) => {
                     debug!(logger, "Future::poll"; "state" => "uninitialized");
@@ -274,7 +308,14 @@ impl Future for Device {
                         SocketInput::CreateDeviceRequest(request) => {
                             res = Ok(AsyncLoop::Continue);
                             let (socket_future, uhid_transport) =
-                                initialize(device_id, socket_transport, logger, request, user);
+                                initialize(
+                                    device_id,
+                                    report_format,
+                                    socket_transport,
+                                    logger,
+                                    request,
+                                    user,
+                                );
 
                             DeviceState::Initialized {
                                 socket_future,
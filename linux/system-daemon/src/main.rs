@@ -1,3 +1,4 @@
+#[macro_use]
 extern crate clap;
 extern crate futures;
 extern crate hostname;
@@ -25,7 +26,7 @@ extern crate users;
 use std::io;
 use std::os::unix::io::FromRawFd;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 use futures::future;
 use futures::prelude::*;
 use slog::{Drain, Logger};
@@ -34,6 +35,7 @@ use tokio::reactor::Handle;
 
 use device::Device;
 use softu2f_system_daemon::DEFAULT_SOCKET_PATH;
+use u2fhid_protocol::{PacketError, ReportFormat};
 
 mod bidirectional_pipe;
 mod device;
@@ -42,6 +44,8 @@ const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const PATH_ARG: &str = "path";
+const REPORT_ID_ARG: &str = "report_id";
+const REPORT_SIZE_ARG: &str = "report_size";
 
 quick_error! {
     #[derive(Debug)]
@@ -56,6 +60,11 @@ quick_error! {
             cause(err)
             display("I/O error: {}", err)
         }
+        ReportFormat(err: PacketError) {
+            from()
+            cause(err)
+            display("Invalid report format: {}", err)
+        }
         WrongSocket(message: String) {
             display("{}", message)
         }
@@ -75,6 +84,15 @@ fn main() {
             .long("socket")
             .takes_value(true)
             .help("Bind to specified socket path instead of file-descriptor from systemd"))
+        .arg(Arg::with_name(REPORT_SIZE_ARG)
+            .long("report-size")
+            .takes_value(true)
+            .default_value("64")
+            .help("Size in bytes of the HID reports of created devices"))
+        .arg(Arg::with_name(REPORT_ID_ARG)
+            .long("report-id")
+            .takes_value(true)
+            .help("Number the HID reports of created devices with this report ID"))
         .after_help("By default expects to be run via systemd as root and passed a socket file-descriptor to listen on.")
         .get_matches();
 
@@ -86,19 +104,32 @@ fn main() {
     info!(log, "starting SoftU2F system daemon"; "version" => VERSION);
 
     tokio::run(
-        listener(socket_path, &log)
+        listener(socket_path, &args, &log)
             .map_err(|err| error!(log, "failed to start"; "error" => %err))
             .unwrap(),
     );
 }
 
+fn report_format(args: &ArgMatches) -> Result<ReportFormat, Error> {
+    let report_len = value_t!(args, REPORT_SIZE_ARG, usize).unwrap_or_else(|e| e.exit());
+    let report_id = if args.is_present(REPORT_ID_ARG) {
+        Some(value_t!(args, REPORT_ID_ARG, u8).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
+    };
+    ReportFormat::new(report_len, report_id).map_err(Error::ReportFormat)
+}
+
 fn listener(
     socket_path: Option<&str>,
+    args: &ArgMatches,
     log: &Logger,
 ) -> Result<impl Future<Item = (), Error = ()>, Error> {
+    let report_format = report_format(args)?;
+    info!(log, "creating devices"; "report_size" => report_format.report_len(), "report_id" => ?report_format.report_id());
     let accept_log = log.clone();
     let incoming_log = log.clone();
-    let accept = move |stream| accept(stream, &accept_log);
+    let accept = move |stream| accept(stream, report_format, &accept_log);
     let listener = socket_listener(socket_path)?;
     let incoming = listener.incoming().map_err(
         move |err| error!(incoming_log, "failed to poll for incoming connections"; "error" => %err),
@@ -137,20 +168,26 @@ fn systemd_socket_listener() -> Result<std::os::unix::net::UnixListener, Error>
     Ok(unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) })
 }
 
-fn accept(stream: tokio_uds::UnixStream, log: &Logger) -> impl Future<Item = (), Error = ()> {
+fn accept(
+    stream: tokio_uds::UnixStream,
+    report_format: ReportFormat,
+    log: &Logger,
+) -> impl Future<Item = (), Error = ()> {
     debug!(log, "accepting connection";
         "local_addr" => ?stream.local_addr(),
         "peer_addr" => ?stream.peer_addr(),
         "peer_cred" => ?stream.peer_cred());
-    tokio::spawn(handle_connection(stream, log)).into_future()
+    tokio::spawn(handle_connection(stream, report_format, log)).into_future()
 }
 
 fn handle_connection(
     stream: tokio_uds::UnixStream,
+    report_format: ReportFormat,
     log: &Logger,
 ) -> impl Future<Item = (), Error = ()> {
     let log = log.clone();
-    let device_created = future::result(Device::new(stream, &log).map_err(Error::Io));
+    let device_created =
+        future::result(Device::new(stream, report_format, &log).map_err(Error::Io));
     let device_completed = device_created.and_then(|device| device.from_err());
     device_completed.map_err(move |err| error!(log, "device failure"; "error" => %err))
 }
@@ -1,7 +1,7 @@
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 
@@ -32,6 +32,21 @@ where
     Ok(())
 }
 
+// Zeroes a file before removing it, for files that held keys
+pub(crate) fn wipe(path: &Path) -> io::Result<()> {
+    {
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        let len = file.metadata()?.len();
+        io::copy(&mut io::repeat(0).take(len), &mut file)?;
+        file.sync_all()?;
+    }
+    fs::remove_file(path)?;
+    if let Some(directory) = path.parent() {
+        fsync_dir(directory)?;
+    }
+    Ok(())
+}
+
 fn fsync_dir(dir: &Path) -> io::Result<()> {
     let f = File::open(dir)?;
     f.sync_all()
@@ -6,6 +6,7 @@ extern crate directories;
 extern crate dirs;
 #[macro_use]
 extern crate failure;
+#[macro_use]
 extern crate futures;
 extern crate futures_cpupool;
 #[macro_use]
@@ -30,8 +31,10 @@ extern crate u2f_core;
 extern crate u2fhid_protocol;
 
 use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
 use directories::{ProjectDirs, UserDirs};
 use failure::{Compat, Error};
 use futures::future;
@@ -41,20 +44,28 @@ use tokio_core::reactor::{Core, Handle};
 use tokio_io::codec::length_delimited;
 use tokio_serde_bincode::{ReadBincode, WriteBincode};
 use tokio_uds::{UCred, UnixStream};
-use u2f_core::{SecretStore, SecureCryptoOperations, U2F};
-use u2fhid_protocol::{Packet, U2FHID};
+use u2f_core::{
+    CosignerCredential, DevCosigner, KeyPool, SecureCryptoOperations, DEFAULT_COSIGNER_ENDPOINT,
+    U2F,
+};
+use u2fhid_protocol::{Dispatcher, Packet, PacketError, ReportFormat, U2FHID};
 
 use softu2f_system_daemon::{
     CreateDeviceError, CreateDeviceRequest, DeviceDescription, SocketInput, SocketOutput,
 };
-use storage::AppDirs;
+use storage::{AppDirs, Storage};
+use trace::replay::{ApproveAll, MemoryStore};
+use trace::{Recorder, Trace, TraceWriter};
 use user_presence::NotificationUserPresence;
+use vendor::DaemonInfo;
 
 mod atomic_file;
 mod config;
 mod storage;
 mod stores;
+mod trace;
 mod user_presence;
+mod vendor;
 
 quick_error! {
     #[derive(Debug)]
@@ -75,11 +86,19 @@ quick_error! {
         DeviceCreateFailed(err: CreateDeviceError) {
             display("{:?}", err)
         }
+        Packet(err: PacketError) {
+            from()
+            cause(err)
+            display("Packet error: {}", err)
+        }
         Failure(err: Compat<Error>) {
             from()
             cause(err)
             display("{}", err)
         }
+        ReplayDiffers(count: usize) {
+            display("{} responses differ from the trace", count)
+        }
     }
 }
 
@@ -111,10 +130,27 @@ trait Pipe: Stream + Sink {}
 
 impl<'a, T> Pipe for T where T: Stream + Sink + 'a {}
 
+#[derive(Clone)]
+struct Options {
+    key_pool_size: usize,
+    cosigner: String,
+    dev_cosigner: bool,
+    record: Option<PathBuf>,
+}
+
 const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const PATH_ARG: &str = "path";
+const KEY_POOL_SIZE_ARG: &str = "key-pool-size";
+const COSIGNER_ARG: &str = "cosigner";
+const DEV_COSIGNER_ARG: &str = "dev-cosigner";
+const RECORD_ARG: &str = "record";
+const TRACE_FILE_ARG: &str = "file";
+const ENROLL_COMMAND: &str = "enroll";
+const TRACE_COMMAND: &str = "trace";
+const DECODE_COMMAND: &str = "decode";
+const REPLAY_COMMAND: &str = "replay";
 
 fn main() -> Result<(), TransportError> {
     let args = App::new("SoftU2F System Daemon")
@@ -126,24 +162,86 @@ fn main() -> Result<(), TransportError> {
             .long("socket")
             .takes_value(true)
             .help("Bind to specified socket path instead of file-descriptor from systemd"))
+        .arg(Arg::with_name(KEY_POOL_SIZE_ARG)
+            .long("key-pool-size")
+            .value_name("N")
+            .takes_value(true)
+            .default_value("0")
+            .validator(|size| {
+                size.parse::<usize>()
+                    .map(|_| ())
+                    .map_err(|_| String::from("N must be a number"))
+            })
+            .help("Keys to generate ahead of time so registration does not wait on keygen"))
+        .arg(Arg::with_name(COSIGNER_ARG)
+            .long("cosigner")
+            .value_name("ENDPOINT")
+            .takes_value(true)
+            .default_value(DEFAULT_COSIGNER_ENDPOINT)
+            .help("Co-signer URL, or unix:PATH for a co-signer listening on a Unix domain socket"))
+        .arg(Arg::with_name(DEV_COSIGNER_ARG)
+            .long("dev-cosigner")
+            .help("Runs an unprotected co-signer inside the daemon, for development only"))
+        .arg(Arg::with_name(RECORD_ARG)
+            .long("record")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Records every packet to and from the host in a trace file, replacing any already there"))
+        .subcommand(SubCommand::with_name(ENROLL_COMMAND)
+            .about("Stores the co-signer credential from `local-server enroll`, read from standard input"))
+        .subcommand(SubCommand::with_name(TRACE_COMMAND)
+            .about("Reads a trace file recorded with --record")
+            .subcommand(SubCommand::with_name(DECODE_COMMAND)
+                .about("Prints the U2FHID messages and U2F APDUs in the trace")
+                .arg(Arg::with_name(TRACE_FILE_ARG).required(true)))
+            .subcommand(SubCommand::with_name(REPLAY_COMMAND)
+                .about("Sends the recorded requests to a fresh device and compares its responses with the recorded ones")
+                .arg(Arg::with_name(TRACE_FILE_ARG).required(true))
+                .after_help("Replays use a development co-signer and keys kept in memory, so keys registered before the trace was recorded are not known to the replay.")))
         .after_help("By default expects to be run via systemd as root and passed a socket file-descriptor to listen on.")
         .get_matches();
 
     let socket_path = args.value_of(PATH_ARG);
+    let options = Options {
+        key_pool_size: args.value_of(KEY_POOL_SIZE_ARG).unwrap().parse().unwrap(),
+        cosigner: args.value_of(COSIGNER_ARG).unwrap().to_string(),
+        dev_cosigner: args.is_present(DEV_COSIGNER_ARG),
+        record: args.value_of(RECORD_ARG).map(PathBuf::from),
+    };
     let decorator = slog_term::PlainSyncDecorator::new(std::io::stdout());
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
     let logger = Logger::root(drain, o!());
 
+    if args.subcommand_matches(ENROLL_COMMAND).is_some() {
+        return enroll(&logger);
+    }
+    if let Some(args) = args.subcommand_matches(TRACE_COMMAND) {
+        if let Some(args) = args.subcommand_matches(DECODE_COMMAND) {
+            let trace = Trace::read(Path::new(args.value_of(TRACE_FILE_ARG).unwrap()))?;
+            return Ok(trace::decode::write_transactions(
+                &trace,
+                &mut io::stdout(),
+            )?);
+        }
+        if let Some(args) = args.subcommand_matches(REPLAY_COMMAND) {
+            return replay_trace(Path::new(args.value_of(TRACE_FILE_ARG).unwrap()), &logger);
+        }
+        return Err(TransportError::InvalidState(
+            "Expected a trace command, decode or replay",
+        ));
+    }
+
     info!(logger, "Starting software Universal 2nd Factor device user daemon"; "version" => VERSION);
 
     let socket_path = socket_path.unwrap_or(softu2f_system_daemon::DEFAULT_SOCKET_PATH);
     let mut core = Core::new()?;
     let handle = core.handle();
-    core.run(connect(socket_path, handle, &logger))
+    core.run(connect(socket_path, options, handle, &logger))
 }
 
 fn connect(
     socket_path: &str,
+    options: Options,
     handle: Handle,
     logger: &Logger,
 ) -> Box<dyn Future<Item = (), Error = TransportError>> {
@@ -152,12 +250,13 @@ fn connect(
     Box::new(
         UnixStream::connect(socket_path)
             .map_err(TransportError::Io)
-            .and_then(|stream| connected(stream, handle, logger)),
+            .and_then(move |stream| connected(stream, options, handle, logger)),
     )
 }
 
 fn connected(
     stream: UnixStream,
+    options: Options,
     handle: Handle,
     logger: Logger,
 ) -> Box<dyn Future<Item = (), Error = TransportError>> {
@@ -174,7 +273,7 @@ fn connected(
     let created_device = create_device(transport, logger.clone());
 
     Box::new(created_device.and_then(move |(device, transport)| {
-        bind_service(device, transport, handle, &logger.clone())
+        bind_service(device, transport, options, handle, &logger.clone())
     }))
 }
 
@@ -238,6 +337,7 @@ where
 fn bind_service<T>(
     device: DeviceDescription,
     transport: T,
+    options: Options,
     handle: Handle,
     log: &Logger,
 ) -> Box<dyn Future<Item = (), Error = TransportError>>
@@ -247,65 +347,160 @@ where
         + 'static,
 {
     let packet_logger = log.new(o!());
+    let report_format = device.report_format;
     let transport = transport
-        .filter_map(move |output| socket_output_to_packet(&packet_logger, output))
-        .with(|packet| future::ok(packet_to_socket_input(packet)));
+        .filter_map(move |output| socket_output_to_packet(&packet_logger, &report_format, output))
+        .with(move |packet| future::result(packet_to_socket_input(packet, &report_format)));
+    let trace = match options.record {
+        Some(ref path) => match TraceWriter::create(path, report_format) {
+            Ok(trace) => {
+                info!(log, "Recording packets"; "path" => %path.display());
+                Some(trace)
+            }
+            Err(err) => return Box::new(future::err(TransportError::Io(err))),
+        },
+        None => None,
+    };
+    let transport = Recorder::new(transport, trace, log.new(o!()));
 
     let attestation = u2f_core::self_signed_attestation();
     let user_presence = Box::new(NotificationUserPresence::new(&handle, log.new(o!())));
-    let operations = Box::new(SecureCryptoOperations::new(attestation));
     let storage = match build_storage(log) {
-        Ok(store) => store,
+        Ok(storage) => storage,
         Err(err) => return Box::new(future::err(TransportError::Failure(err.compat()))),
     };
-    let service = match U2F::new(user_presence, operations, storage, log.new(o!())) {
+    let operations = if options.dev_cosigner {
+        let cosigner = match DevCosigner::new() {
+            Ok(cosigner) => cosigner,
+            Err(err) => return Box::new(future::err(TransportError::Io(err))),
+        };
+        warn!(log, "Using a development co-signer, keys are not protected");
+        SecureCryptoOperations::with_transport(attestation, cosigner.transport(), None)
+    } else {
+        if storage.cosigner_credential.is_none() {
+            warn!(log, "Not enrolled with the co-signer, it will refuse to generate or sign with keys until you run the enroll command");
+        }
+        SecureCryptoOperations::with_cosigner(
+            attestation,
+            &options.cosigner,
+            storage.cosigner_credential,
+        )
+    };
+    let mut operations = operations.with_logger(log.new(o!()));
+    // Pooled keys belong to the enrolled co-signer
+    if options.key_pool_size > 0 && !options.dev_cosigner {
+        info!(log, "Generating keys ahead of time"; "pool_size" => options.key_pool_size);
+        operations =
+            operations.with_key_pool(KeyPool::new(storage.key_pool, options.key_pool_size));
+    }
+    let operations = Box::new(operations);
+    let daemon_info = Rc::new(DaemonInfo);
+    let service = match U2F::with_vendor_handler(
+        user_presence,
+        operations,
+        storage.secret_store,
+        daemon_info.clone(),
+        log.new(o!()),
+    ) {
         Ok(service) => service,
         Err(err) => return Box::new(future::err(TransportError::Io(err))),
     };
 
     info!(log, "Virtual U2F device created"; "device_id" => device.id);
 
-    Box::new(U2FHID::bind_service(
-        handle,
-        transport,
-        service,
-        log.new(o!()),
-    ))
+    Box::new(
+        U2FHID::bind_service(handle, transport, service, log.new(o!()))
+            .with_vendor_handler(daemon_info)
+            .with_report_format(report_format),
+    )
 }
 
-fn socket_output_to_packet(logger: &Logger, event: SocketOutput) -> Option<Packet> {
+fn socket_output_to_packet(
+    logger: &Logger,
+    report_format: &ReportFormat,
+    event: SocketOutput,
+) -> Option<Packet> {
     match event {
-        SocketOutput::Packet(raw_packet) => match Packet::from_bytes(&raw_packet.to_bytes()) {
-            Ok(packet) => Some(packet),
-            Err(error) => {
-                info!(logger, "Bad packet"; "parse_error" => error);
-                debug!(logger, "Packet"; "raw_packet" => raw_packet);
-                None
+        SocketOutput::Packet(raw_packet) => {
+            match Packet::from_report(&raw_packet.to_bytes(), report_format) {
+                Ok(packet) => Some(packet),
+                Err(error) => {
+                    info!(logger, "Bad packet"; "parse_error" => error);
+                    debug!(logger, "Packet"; "raw_packet" => raw_packet);
+                    None
+                }
             }
-        },
+        }
         _ => None,
     }
 }
 
-fn packet_to_socket_input(packet: Packet) -> SocketInput {
-    SocketInput::Packet(softu2f_system_daemon::Packet::from_bytes(
-        &packet.into_bytes(),
+fn packet_to_socket_input(
+    packet: Packet,
+    report_format: &ReportFormat,
+) -> Result<SocketInput, TransportError> {
+    Ok(SocketInput::Packet(
+        softu2f_system_daemon::Packet::from_bytes(&packet.into_report(report_format)?),
     ))
 }
 
-fn build_storage(log: &Logger) -> Result<Box<dyn SecretStore>, Error> {
+fn app_dirs() -> Result<AppDirs, Error> {
     let user_dirs = UserDirs::new().ok_or(HomeDirectoryNotFound)?;
     let project_dirs =
         ProjectDirs::from("com.github", "danstiner", "Rust U2F").ok_or(HomeDirectoryNotFound)?;
 
-    storage::build(
-        &AppDirs {
-            user_home_dir: user_dirs.home_dir().to_owned(),
-            config_dir: project_dirs.config_dir().to_owned(),
-            data_local_dir: project_dirs.data_local_dir().to_owned(),
-        },
-        log,
-    )
+    Ok(AppDirs {
+        user_home_dir: user_dirs.home_dir().to_owned(),
+        config_dir: project_dirs.config_dir().to_owned(),
+        data_local_dir: project_dirs.data_local_dir().to_owned(),
+    })
+}
+
+fn build_storage(log: &Logger) -> Result<Storage, Error> {
+    storage::build(&app_dirs()?, log)
+}
+
+// Reads the credential printed by `local-server enroll` from standard input
+// and keeps it in the user's secret store
+fn enroll(log: &Logger) -> Result<(), TransportError> {
+    let mut token = String::new();
+    io::stdin().read_line(&mut token)?;
+    let credential = CosignerCredential::from_token(&token)
+        .ok_or(TransportError::InvalidState("Invalid co-signer credential"))?;
+    app_dirs()
+        .and_then(|dirs| storage::enroll(&dirs, &credential, log))
+        .map_err(|err| TransportError::Failure(err.compat()))
+}
+
+fn replay_trace(path: &Path, log: &Logger) -> Result<(), TransportError> {
+    let trace = Trace::read(path)?;
+    let cosigner = DevCosigner::new()?;
+    let operations = SecureCryptoOperations::with_transport(
+        u2f_core::self_signed_attestation(),
+        cosigner.transport(),
+        None,
+    );
+    let daemon_info = Rc::new(DaemonInfo);
+    let service = U2F::with_vendor_handler(
+        Box::new(ApproveAll),
+        Box::new(operations),
+        Box::new(MemoryStore::default()),
+        daemon_info.clone(),
+        log.new(o!()),
+    )?;
+    let mut dispatcher = Dispatcher::new(service);
+    dispatcher.set_vendor_handler(daemon_info);
+
+    let differences = trace::replay::replay(&trace, dispatcher, log.new(o!()));
+    for difference in &differences {
+        println!("{}", difference);
+    }
+    if differences.is_empty() {
+        info!(log, "Replay matches the trace"; "packets" => trace.packets.len());
+        Ok(())
+    } else {
+        Err(TransportError::ReplayDiffers(differences.len()))
+    }
 }
 
 fn require_root(cred: UCred) -> Result<(), TransportError> {
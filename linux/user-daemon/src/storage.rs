@@ -1,14 +1,17 @@
-use std::borrow::Borrow;
 use std::io;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use slog::Logger;
-use u2f_core::SecretStore;
+use u2f_core::{
+    try_reverse_app_id, CosignerCredential, KeyPoolStore, LegacyApplicationKey, SecretStore,
+};
 
 use config::{Config, ConfigFile, ConfigFilePath, SecretStoreType};
 use stores::file_store::FileStore;
 use stores::file_store_v2::FileStoreV2;
-use stores::secret_service_store::SecretServiceStore;
+use stores::key_pool::FileKeyPool;
+use stores::secret_service_store::{SecretServiceKeyPool, SecretServiceStore};
 use stores::UserSecretStore;
 
 pub struct AppDirs {
@@ -17,11 +20,48 @@ pub struct AppDirs {
     pub data_local_dir: PathBuf,
 }
 
-pub(crate) fn build(dirs: &AppDirs, log: &Logger) -> Result<Box<dyn SecretStore>, failure::Error> {
+#[derive(Debug, Fail)]
+#[fail(
+    display = "{} holds {} single-party keys from rust-u2f, which cannot be split with the co-signer, move it aside to start",
+    path, keys
+)]
+struct LegacySecretStore {
+    path: String,
+    keys: usize,
+}
+
+pub(crate) struct Storage {
+    pub secret_store: Box<dyn SecretStore>,
+    pub cosigner_credential: Option<CosignerCredential>,
+    pub key_pool: Arc<dyn KeyPoolStore>,
+}
+
+pub(crate) fn build(dirs: &AppDirs, log: &Logger) -> Result<Storage, failure::Error> {
     let config = determine_config(dirs, log)?;
+    check_legacy_stores(dirs, &config, log)?;
     let secret_store = build_secret_store(dirs, &config, log)?;
-    migrate_legacy_file_store(dirs, secret_store.borrow(), log)?;
-    Ok(secret_store.into_u2f_store())
+    let cosigner_credential = secret_store.cosigner_credential()?;
+    Ok(Storage {
+        secret_store: secret_store.into_u2f_store(),
+        cosigner_credential,
+        key_pool: build_key_pool(dirs, &config)?,
+    })
+}
+
+pub(crate) fn enroll(
+    dirs: &AppDirs,
+    credential: &CosignerCredential,
+    log: &Logger,
+) -> Result<(), failure::Error> {
+    let config = determine_config(dirs, log)?;
+    let secret_store = build_secret_store(dirs, &config, log)?;
+    secret_store.set_cosigner_credential(credential)?;
+    info!(log, "Stored co-signer credential"; "client_id" => &credential.client_id);
+    if let SecretStoreType::SecretService = config.secret_store_type {
+        SecretServiceKeyPool::clear()?;
+    }
+    FileKeyPool::clear(&dirs.data_local_dir)?;
+    Ok(())
 }
 
 fn determine_config(dirs: &AppDirs, log: &Logger) -> io::Result<Config> {
@@ -67,23 +107,51 @@ fn build_secret_store(
     }
 }
 
-fn migrate_legacy_file_store(
+// Pooled shares are kept in the same store as registrations
+fn build_key_pool(dirs: &AppDirs, config: &Config) -> io::Result<Arc<dyn KeyPoolStore>> {
+    match &config.secret_store_type {
+        SecretStoreType::SecretService => {
+            // Left in a file by an earlier version
+            FileKeyPool::clear(&dirs.data_local_dir)?;
+            Ok(Arc::new(SecretServiceKeyPool::new()))
+        }
+        SecretStoreType::File => Ok(Arc::new(FileKeyPool::new(&dirs.data_local_dir))),
+    }
+}
+
+// Registrations from upstream rust-u2f hold whole keys, which the co-signer
+// has no way to take a share of, so they are left where they are
+fn check_legacy_stores(
     dirs: &AppDirs,
-    secret_store: &dyn UserSecretStore,
+    config: &Config,
     log: &Logger,
-) -> io::Result<()> {
+) -> Result<(), failure::Error> {
     let legacy_file_store = FileStore::new(dirs.user_home_dir.join(".softu2f-secrets.json"))?;
     if legacy_file_store.exists() {
-        info!(
-            log,
-            "copying secrets from legacy secret store to newer format"
-        );
-        for secret in legacy_file_store.iter()? {
-            secret_store.add_secret(secret)?;
+        let keys = legacy_file_store.application_keys()?;
+        warn!(log, "Ignoring single-party keys from rust-u2f, they cannot be split with the co-signer";
+            "path" => legacy_file_store.path().display(), "keys" => keys.len());
+        log_legacy_keys(&keys, log);
+    }
+    if let SecretStoreType::File = config.secret_store_type {
+        let file_store = FileStoreV2::new(&dirs.data_local_dir)?;
+        if let Some(keys) = file_store.legacy_application_keys()? {
+            log_legacy_keys(&keys, log);
+            return Err(LegacySecretStore {
+                path: file_store.path().display().to_string(),
+                keys: keys.len(),
+            }
+            .into());
         }
-        info!(log, "finished copying secrets");
-        legacy_file_store.delete()?;
-        info!(log, "deleted legacy secret store");
     }
     Ok(())
 }
+
+fn log_legacy_keys(keys: &[LegacyApplicationKey], log: &Logger) {
+    for key in keys {
+        match try_reverse_app_id(&key.application) {
+            Some(site) => info!(log, "Single-party key"; "site" => site),
+            None => info!(log, "Single-party key"; "app_id" => key.application),
+        }
+    }
+}
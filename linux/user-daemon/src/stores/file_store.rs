@@ -1,19 +1,16 @@
-use alloc::vec::IntoIter;
 use std::collections::HashMap;
-use std::fs;
 use std::fs::File;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use serde_json;
-use u2f_core::{AppId, ApplicationKey, Counter};
+use u2f_core::{AppId, LegacyApplicationKey};
 
-use stores::Secret;
-
-#[derive(Serialize, Deserialize)]
+// The oldest rust-u2f store, written before keys were split with the
+// co-signer, so its keys are whole single-party keys
+#[derive(Deserialize)]
 struct Data {
-    application_keys: HashMap<AppId, ApplicationKey>,
-    counters: HashMap<AppId, Counter>,
+    application_keys: HashMap<AppId, LegacyApplicationKey>,
 }
 
 pub struct FileStore {
@@ -25,40 +22,25 @@ impl FileStore {
         Ok(FileStore { path })
     }
 
-    pub fn delete(self) -> io::Result<()> {
-        fs::remove_file(self.path)
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
     pub fn exists(&self) -> bool {
         self.path.exists()
     }
 
-    pub fn iter(&self) -> io::Result<IntoIter<Secret>> {
-        let data = self.load()?;
-        let secrets: Vec<Secret> = data
-            .application_keys
-            .values()
-            .map(|application_key| {
-                let counter = data
-                    .counters
-                    .get(&application_key.application)
-                    .unwrap_or(&0);
-                Secret {
-                    application_key: application_key.clone(),
-                    counter: *counter,
-                }
-            })
-            .collect();
-        Ok(secrets.into_iter())
-    }
-
-    fn load(&self) -> io::Result<Data> {
+    pub fn application_keys(&self) -> io::Result<Vec<LegacyApplicationKey>> {
         match File::open(&self.path) {
-            Ok(file) => serde_json::from_reader(file).map_err(|e| e.into()),
-            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Data {
-                application_keys: HashMap::new(),
-                counters: HashMap::new(),
-            }),
+            Ok(file) => {
+                let data: Data = serde_json::from_reader(file)?;
+                Ok(data
+                    .application_keys
+                    .into_iter()
+                    .map(|(_, key)| key)
+                    .collect())
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
             Err(err) => Err(err),
         }
     }
@@ -3,7 +3,10 @@ use std::io;
 use std::path::{Path, PathBuf};
 
 use serde_json;
-use u2f_core::{AppId, ApplicationKey, Counter, KeyHandle, SecretStore};
+use u2f_core::{
+    AppId, ApplicationKey, CosignerCredential, Counter, KeyHandle, LegacyApplicationKey,
+    SecretStore,
+};
 
 use atomic_file;
 use stores::{Secret, UserSecretStore};
@@ -11,6 +14,8 @@ use stores::{Secret, UserSecretStore};
 #[derive(Serialize, Deserialize)]
 struct Data {
     secrets: Vec<Secret>,
+    #[serde(default)]
+    cosigner_credential: Option<CosignerCredential>,
 }
 
 impl Data {
@@ -31,6 +36,17 @@ impl Data {
     }
 }
 
+// The same file as written by upstream rust-u2f, with whole single-party keys
+#[derive(Deserialize)]
+struct LegacyData {
+    secrets: Vec<LegacySecret>,
+}
+
+#[derive(Deserialize)]
+struct LegacySecret {
+    application_key: LegacyApplicationKey,
+}
+
 pub struct FileStoreV2 {
     path: PathBuf,
 }
@@ -41,11 +57,37 @@ impl FileStoreV2 {
         Ok(FileStoreV2 { path })
     }
 
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // The keys of a store left by upstream rust-u2f, which this store
+    // cannot read
+    pub fn legacy_application_keys(&self) -> io::Result<Option<Vec<LegacyApplicationKey>>> {
+        if self.read().is_ok() {
+            return Ok(None);
+        }
+        let data: LegacyData = match File::open(&self.path) {
+            Ok(file) => match serde_json::from_reader(file) {
+                Ok(data) => data,
+                Err(_) => return Ok(None),
+            },
+            Err(err) => return Err(err),
+        };
+        Ok(Some(
+            data.secrets
+                .into_iter()
+                .map(|secret| secret.application_key)
+                .collect(),
+        ))
+    }
+
     fn read(&self) -> io::Result<Data> {
         match File::open(&self.path) {
             Ok(file) => serde_json::from_reader(file).map_err(|e| e.into()),
             Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Data {
                 secrets: Vec::new(),
+                cosigner_credential: None,
             }),
             Err(err) => Err(err),
         }
@@ -65,6 +107,16 @@ impl UserSecretStore for FileStoreV2 {
         self.write(&data)
     }
 
+    fn cosigner_credential(&self) -> io::Result<Option<CosignerCredential>> {
+        Ok(self.read()?.cosigner_credential)
+    }
+
+    fn set_cosigner_credential(&self, credential: &CosignerCredential) -> io::Result<()> {
+        let mut data = self.read()?;
+        data.cosigner_credential = Some(credential.clone());
+        self.write(&data)
+    }
+
     fn into_u2f_store(self: Box<Self>) -> Box<dyn SecretStore> {
         self
     }
@@ -177,6 +229,20 @@ i2L2wGDHkWWIJJSthmgwkZovXHyMXMpDhw==
         // Skip key field, it is not easily comparable
     }
 
+    #[test]
+    fn set_cosigner_credential() {
+        let dir = TempDir::new("file_store_tests").unwrap();
+        let path = dir.path().join("store");
+        let store = FileStoreV2 { path };
+        let credential = CosignerCredential::new(String::from("client"), vec![1, 2, 3]);
+
+        assert!(store.cosigner_credential().unwrap().is_none());
+        store.set_cosigner_credential(&credential).unwrap();
+
+        let stored = store.cosigner_credential().unwrap().unwrap();
+        assert_eq!(stored.to_token(), credential.to_token());
+    }
+
     #[test]
     fn retrieve_nonexistent_key_is_none() {
         let dir = TempDir::new("file_store_tests").unwrap();
@@ -189,4 +255,32 @@ i2L2wGDHkWWIJJSthmgwkZovXHyMXMpDhw==
 
         assert!(key.is_none());
     }
+
+    #[test]
+    fn legacy_application_keys() {
+        let dir = TempDir::new("file_store_tests").unwrap();
+        let path = dir.path().join("store");
+        let legacy = format!(
+            r#"{{"secrets":[{{"application_key":{{"application":{},"handle":{},"key":{}}},"counter":3}}]}}"#,
+            serde_json::to_string(&fake_app_id()).unwrap(),
+            serde_json::to_string(&fake_key_handle()).unwrap(),
+            serde_json::to_string(&fake_key()).unwrap()
+        );
+        ::std::fs::write(&path, legacy).unwrap();
+        let store = FileStoreV2 { path };
+
+        let keys = store.legacy_application_keys().unwrap().unwrap();
+
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].application, fake_app_id());
+    }
+
+    #[test]
+    fn store_without_legacy_keys() {
+        let dir = TempDir::new("file_store_tests").unwrap();
+        let path = dir.path().join("store");
+        let store = FileStoreV2 { path };
+
+        assert!(store.legacy_application_keys().unwrap().is_none());
+    }
 }
@@ -0,0 +1,123 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde_json;
+use u2f_core::{KeyPoolStore, PrivateShare};
+
+use atomic_file;
+
+// Key shares not yet assigned to an application, kept in a file only the
+// user can read, beside the file store
+pub struct FileKeyPool {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileKeyPool {
+    pub fn new(dir: &Path) -> FileKeyPool {
+        FileKeyPool {
+            path: FileKeyPool::path(dir),
+            lock: Mutex::new(()),
+        }
+    }
+
+    // Drops pooled shares, which belong to the client that generated them
+    pub fn clear(dir: &Path) -> io::Result<()> {
+        match atomic_file::wipe(&FileKeyPool::path(dir)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn path(dir: &Path) -> PathBuf {
+        dir.join("key_pool.json")
+    }
+
+    fn read(&self) -> io::Result<Vec<PrivateShare>> {
+        match File::open(&self.path) {
+            Ok(file) => serde_json::from_reader(file).map_err(|e| e.into()),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn write(&self, shares: &[PrivateShare]) -> io::Result<()> {
+        atomic_file::overwrite(&self.path, move |writer| {
+            serde_json::to_writer(writer, &shares).map_err(|e| e.into())
+        })
+    }
+}
+
+impl KeyPoolStore for FileKeyPool {
+    fn add_pooled_share(&self, share: PrivateShare) -> io::Result<()> {
+        let _lock = self.lock.lock().unwrap();
+        let mut shares = self.read()?;
+        shares.push(share);
+        self.write(&shares)
+    }
+
+    fn take_pooled_share(&self) -> io::Result<Option<PrivateShare>> {
+        let _lock = self.lock.lock().unwrap();
+        let mut shares = self.read()?;
+        let share = shares.pop();
+        if share.is_some() {
+            self.write(&shares)?;
+        }
+        Ok(share)
+    }
+
+    fn pooled_share_count(&self) -> io::Result<usize> {
+        let _lock = self.lock.lock().unwrap();
+        Ok(self.read()?.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use u2f_core::DevCosigner;
+
+    use super::*;
+
+    use self::tempdir::TempDir;
+
+    #[test]
+    fn empty_pool_has_no_shares() {
+        let dir = TempDir::new("key_pool_tests").unwrap();
+        let pool = FileKeyPool::new(dir.path());
+
+        assert_eq!(pool.pooled_share_count().unwrap(), 0);
+        assert!(pool.take_pooled_share().unwrap().is_none());
+    }
+
+    #[test]
+    fn shares_round_trip_through_pool_file() {
+        let dir = TempDir::new("key_pool_tests").unwrap();
+        let cosigner = DevCosigner::new().unwrap();
+        let share = cosigner.generate_share().unwrap();
+        let id = share.id.clone();
+
+        FileKeyPool::new(dir.path())
+            .add_pooled_share(share)
+            .unwrap();
+
+        // As read back by the next run of the daemon
+        let pool = FileKeyPool::new(dir.path());
+        assert_eq!(pool.pooled_share_count().unwrap(), 1);
+        assert_eq!(pool.take_pooled_share().unwrap().unwrap().id, id);
+        assert!(pool.take_pooled_share().unwrap().is_none());
+
+        FileKeyPool::clear(dir.path()).unwrap();
+        assert!(!FileKeyPool::path(dir.path()).exists());
+    }
+
+    #[test]
+    fn clear_without_pool_file() {
+        let dir = TempDir::new("key_pool_tests").unwrap();
+
+        FileKeyPool::clear(dir.path()).unwrap();
+    }
+}
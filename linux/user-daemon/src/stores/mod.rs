@@ -1,9 +1,10 @@
 use std::io;
 
-use u2f_core::{ApplicationKey, Counter, SecretStore};
+use u2f_core::{ApplicationKey, CosignerCredential, Counter, SecretStore};
 
 pub(crate) mod file_store;
 pub(crate) mod file_store_v2;
+pub(crate) mod key_pool;
 pub(crate) mod secret_service_store;
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -14,5 +15,7 @@ pub struct Secret {
 
 pub trait UserSecretStore: SecretStore {
     fn add_secret(&self, secret: Secret) -> io::Result<()>;
+    fn cosigner_credential(&self) -> io::Result<Option<CosignerCredential>>;
+    fn set_cosigner_credential(&self, credential: &CosignerCredential) -> io::Result<()>;
     fn into_u2f_store(self: Box<Self>) -> Box<dyn SecretStore>;
 }
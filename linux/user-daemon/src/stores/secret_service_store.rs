@@ -1,12 +1,16 @@
 use std::collections::HashMap;
 use std::io;
 use std::io::ErrorKind;
+use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use failure::Error;
 use secret_service::{Collection, EncryptionType, Item, SecretService, SsError};
 use serde_json;
-use u2f_core::{try_reverse_app_id, AppId, ApplicationKey, Counter, KeyHandle, SecretStore};
+use u2f_core::{
+    try_reverse_app_id, AppId, ApplicationKey, CosignerCredential, Counter, KeyHandle,
+    KeyPoolStore, PrivateShare, SecretStore,
+};
 
 use stores::{Secret, UserSecretStore};
 
@@ -89,6 +93,53 @@ impl UserSecretStore for SecretServiceStore {
         Ok(())
     }
 
+    fn cosigner_credential(&self) -> io::Result<Option<CosignerCredential>> {
+        let collection = self
+            .service
+            .get_default_collection()
+            .map_err(|_error| io::Error::new(ErrorKind::Other, "get_default_collection"))?;
+        unlock_if_locked(&collection)?;
+        let attributes = cosigner_credential_attributes();
+        let attributes = attributes.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        let mut result = collection
+            .search_items(attributes)
+            .map_err(|_error| io::Error::new(ErrorKind::Other, "search_items"))?;
+        match result.pop() {
+            Some(item) => {
+                let secret_bytes = item
+                    .get_secret()
+                    .map_err(|_error| io::Error::new(ErrorKind::Other, "get_secret"))?;
+                let credential = serde_json::from_slice(&secret_bytes)
+                    .map_err(|error| io::Error::new(ErrorKind::Other, error))?;
+                Ok(Some(credential))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn set_cosigner_credential(&self, credential: &CosignerCredential) -> io::Result<()> {
+        let collection = self
+            .service
+            .get_default_collection()
+            .map_err(|_error| io::Error::new(ErrorKind::Other, "get_default_collection"))?;
+        unlock_if_locked(&collection)?;
+        let attributes = cosigner_credential_attributes();
+        let attributes = attributes.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        let secret = serde_json::to_string(credential)
+            .map_err(|error| io::Error::new(ErrorKind::Other, error))?;
+        let content_type = "application/json";
+        let _item = collection
+            .create_item(
+                "Universal 2nd Factor co-signer credential",
+                attributes,
+                secret.as_bytes(),
+                true,
+                content_type,
+            )
+            .map_err(|_error| io::Error::new(ErrorKind::Other, "create_item"))?;
+        Ok(())
+    }
+
     fn into_u2f_store(self: Box<Self>) -> Box<dyn SecretStore> {
         self
     }
@@ -183,6 +234,100 @@ impl SecretStore for SecretServiceStore {
     }
 }
 
+// Key shares not yet assigned to an application, kept in the keychain
+// beside the registrations. Refills run on their own thread, so each call
+// opens its own connection to the Secret Service.
+pub struct SecretServiceKeyPool {
+    lock: Mutex<()>,
+}
+
+impl SecretServiceKeyPool {
+    pub fn new() -> SecretServiceKeyPool {
+        SecretServiceKeyPool {
+            lock: Mutex::new(()),
+        }
+    }
+
+    // Drops pooled shares, which belong to the client that generated them
+    pub fn clear() -> io::Result<()> {
+        let store = connect()?;
+        let collection = default_collection(&store)?;
+        for item in pooled_share_items(&collection)? {
+            item.delete()
+                .map_err(|_error| io::Error::new(ErrorKind::Other, "delete"))?;
+        }
+        Ok(())
+    }
+}
+
+impl KeyPoolStore for SecretServiceKeyPool {
+    fn add_pooled_share(&self, share: PrivateShare) -> io::Result<()> {
+        let _lock = self.lock.lock().unwrap();
+        let store = connect()?;
+        let collection = default_collection(&store)?;
+        let attributes = pooled_share_attributes();
+        let attributes = attributes.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        let secret = serde_json::to_string(&share)
+            .map_err(|error| io::Error::new(ErrorKind::Other, error))?;
+        let _item = collection
+            .create_item(
+                "Universal 2nd Factor unassigned key share",
+                attributes,
+                secret.as_bytes(),
+                false,
+                "application/json",
+            )
+            .map_err(|_error| io::Error::new(ErrorKind::Other, "create_item"))?;
+        Ok(())
+    }
+
+    fn take_pooled_share(&self) -> io::Result<Option<PrivateShare>> {
+        let _lock = self.lock.lock().unwrap();
+        let store = connect()?;
+        let collection = default_collection(&store)?;
+        let item = match pooled_share_items(&collection)?.pop() {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        let secret_bytes = item
+            .get_secret()
+            .map_err(|_error| io::Error::new(ErrorKind::Other, "get_secret"))?;
+        let share = serde_json::from_slice(&secret_bytes)
+            .map_err(|error| io::Error::new(ErrorKind::Other, error))?;
+        item.delete()
+            .map_err(|_error| io::Error::new(ErrorKind::Other, "delete"))?;
+        Ok(Some(share))
+    }
+
+    fn pooled_share_count(&self) -> io::Result<usize> {
+        let _lock = self.lock.lock().unwrap();
+        let store = connect()?;
+        let collection = default_collection(&store)?;
+        Ok(pooled_share_items(&collection)?.len())
+    }
+}
+
+fn connect() -> io::Result<SecretServiceStore> {
+    SecretServiceStore::new().map_err(|error| io::Error::new(ErrorKind::Other, error.to_string()))
+}
+
+fn default_collection(store: &SecretServiceStore) -> io::Result<Collection> {
+    let collection = store
+        .service
+        .get_default_collection()
+        .map_err(|_error| io::Error::new(ErrorKind::Other, "get_default_collection"))?;
+    unlock_if_locked(&collection)?;
+    Ok(collection)
+}
+
+fn pooled_share_items<'a>(collection: &'a Collection<'a>) -> io::Result<Vec<Item<'a>>> {
+    let attributes = pooled_share_attributes();
+    let attributes = attributes.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    collection
+        .search_items(attributes)
+        .map_err(|_error| io::Error::new(ErrorKind::Other, "search_items"))
+}
+
 fn search_attributes(app_id: &AppId, handle: &KeyHandle) -> Vec<(&'static str, String)> {
     vec![
         ("application", "com.github.danstiner.rust-u2f".to_string()),
@@ -192,6 +337,22 @@ fn search_attributes(app_id: &AppId, handle: &KeyHandle) -> Vec<(&'static str, S
     ]
 }
 
+fn cosigner_credential_attributes() -> Vec<(&'static str, String)> {
+    vec![
+        ("application", "com.github.danstiner.rust-u2f".to_string()),
+        ("u2f_cosigner_credential", "true".to_string()),
+        ("xdg:schema", "com.github.danstiner.rust-u2f".to_string()),
+    ]
+}
+
+fn pooled_share_attributes() -> Vec<(&'static str, String)> {
+    vec![
+        ("application", "com.github.danstiner.rust-u2f".to_string()),
+        ("u2f_pooled_share", "true".to_string()),
+        ("xdg:schema", "com.github.danstiner.rust-u2f".to_string()),
+    ]
+}
+
 fn registration_attributes(app_id: &AppId, handle: &KeyHandle) -> Vec<(&'static str, String)> {
     let mut attributes = search_attributes(app_id, handle);
     attributes.push(("times_used", 0.to_string()));
@@ -0,0 +1,194 @@
+use std::io::{self, Write};
+
+use u2fhid_protocol::Command;
+
+use super::{Direction, Message, Reassembler, Trace};
+
+// Writes a line per U2FHID message in the trace, with U2F APDUs broken
+// down into their header and status word
+pub fn write_transactions<W: Write>(trace: &Trace, out: &mut W) -> io::Result<()> {
+    let mut inbound = Reassembler::default();
+    let mut outbound = Reassembler::default();
+    for traced in &trace.packets {
+        let reassembler = match traced.direction {
+            Direction::Inbound => &mut inbound,
+            Direction::Outbound => &mut outbound,
+        };
+        if let Some(message) = reassembler.push(traced.elapsed_ms, traced.packet.clone()) {
+            writeln!(out, "{}", describe(traced.direction, &message))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn describe(direction: Direction, message: &Message) -> String {
+    let arrow = match direction {
+        Direction::Inbound => "->",
+        Direction::Outbound => "<-",
+    };
+    let data = &message.data;
+    let description = match (message.command, direction) {
+        (Command::Msg, Direction::Inbound) => format!("MSG {}", describe_request_apdu(data)),
+        (Command::Msg, Direction::Outbound) => format!("MSG {}", describe_response_apdu(data)),
+        (Command::Init, Direction::Outbound) if data.len() >= 17 => format!(
+            "INIT nonce {} channel {} protocol {} version {}.{}.{} capabilities {:02x}",
+            hex(&data[..8]),
+            hex(&data[8..12]),
+            data[12],
+            data[13],
+            data[14],
+            data[15],
+            data[16]
+        ),
+        (Command::Init, _) => format!("INIT nonce {}", hex(data)),
+        (Command::Ping, _) => format!("PING {} bytes", data.len()),
+        (Command::Wink, _) => "WINK".to_string(),
+        (Command::Lock, Direction::Inbound) if !data.is_empty() => {
+            format!("LOCK {} seconds", data[0])
+        }
+        (Command::Lock, _) => "LOCK".to_string(),
+        (Command::Sync, _) => format!("SYNC {}", hex(data)),
+        (Command::Error, _) if !data.is_empty() => format!("ERROR {}", error_name(data[0])),
+        (Command::Error, _) => "ERROR".to_string(),
+        (Command::Vendor { identifier }, _) => {
+            format!("VENDOR {:02x} {} bytes", identifier, data.len())
+        }
+        (Command::Unknown { identifier }, _) => {
+            format!("UNKNOWN {:02x} {} bytes", identifier, data.len())
+        }
+    };
+    format!(
+        "{:6}.{:03} {} {:08x} {}",
+        message.elapsed_ms / 1000,
+        message.elapsed_ms % 1000,
+        arrow,
+        message.channel_id.0,
+        description
+    )
+}
+
+// Parsed here rather than with u2f-core, so a malformed request in a trace
+// is shown instead of stopping the decode
+fn describe_request_apdu(data: &[u8]) -> String {
+    if data.len() < 4 {
+        return format!("truncated APDU {}", hex(data));
+    }
+    let instruction = data[1];
+    let name = match instruction {
+        0x01 => "REGISTER",
+        0x02 => "AUTHENTICATE",
+        0x03 => "VERSION",
+        0x40..=0xbf => "VENDOR",
+        _ => "UNKNOWN",
+    };
+    let mut description = format!(
+        "{} cla {:02x} ins {:02x} p1 {:02x} p2 {:02x}",
+        name, data[0], instruction, data[2], data[3]
+    );
+    if instruction == 0x02 {
+        let control = match data[2] {
+            0x03 => " (enforce user presence and sign)",
+            0x07 => " (check only)",
+            0x08 => " (sign without user presence)",
+            _ => "",
+        };
+        description.push_str(control);
+    }
+    let body = &data[4..];
+    // Extended length encoding, Lc omitted when there is no request data
+    let request_data_len = match body.len() {
+        0..=3 => 0,
+        _ if body[0] == 0 => (usize::from(body[1]) << 8) | usize::from(body[2]),
+        _ => usize::from(body[0]),
+    };
+    description.push_str(&format!(", {} bytes of request data", request_data_len));
+    description
+}
+
+fn describe_response_apdu(data: &[u8]) -> String {
+    if data.len() < 2 {
+        return format!("truncated APDU {}", hex(data));
+    }
+    let (body, status) = data.split_at(data.len() - 2);
+    let status_word = (u16::from(status[0]) << 8) | u16::from(status[1]);
+    let name = match status_word {
+        0x9000 => "no error",
+        0x6985 => "conditions not satisfied",
+        0x6a80 => "wrong data",
+        0x6700 => "wrong length",
+        0x6e00 => "class not supported",
+        0x6d00 => "instruction not supported",
+        _ => "unknown",
+    };
+    format!(
+        "status {:04x} ({}), {} bytes of response data",
+        status_word,
+        name,
+        body.len()
+    )
+}
+
+fn error_name(code: u8) -> &'static str {
+    match code {
+        0x01 => "invalid command",
+        0x02 => "invalid parameter",
+        0x03 => "invalid message length",
+        0x04 => "invalid message sequencing",
+        0x05 => "message timed out",
+        0x06 => "channel busy",
+        0x0a => "command requires channel lock",
+        0x0b => "invalid channel",
+        0x7f => "other",
+        _ => "unknown",
+    }
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use u2fhid_protocol::ChannelId;
+
+    use super::*;
+
+    fn message(command: Command, data: Vec<u8>) -> Message {
+        Message {
+            elapsed_ms: 1250,
+            channel_id: ChannelId(0x0102_0304),
+            command,
+            data,
+        }
+    }
+
+    #[test]
+    fn describes_register_request() {
+        let mut data = vec![0x00, 0x01, 0x03, 0x00, 0x00, 0x00, 0x40];
+        data.extend_from_slice(&[0; 64]);
+        data.extend_from_slice(&[0x00, 0x00]);
+        assert_eq!(
+            describe(Direction::Inbound, &message(Command::Msg, data)),
+            "     1.250 -> 01020304 MSG REGISTER cla 00 ins 01 p1 03 p2 00, 64 bytes of request data"
+        );
+    }
+
+    #[test]
+    fn describes_response_status() {
+        assert_eq!(
+            describe(
+                Direction::Outbound,
+                &message(Command::Msg, vec![0x6a, 0x80])
+            ),
+            "     1.250 <- 01020304 MSG status 6a80 (wrong data), 0 bytes of response data"
+        );
+    }
+
+    #[test]
+    fn describes_truncated_request() {
+        assert_eq!(
+            describe(Direction::Inbound, &message(Command::Msg, vec![0x00])),
+            "     1.250 -> 01020304 MSG truncated APDU 00"
+        );
+    }
+}
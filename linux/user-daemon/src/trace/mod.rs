@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::time::Instant;
+
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use serde_json;
+use slog::Logger;
+use time;
+use u2fhid_protocol::{ChannelId, Command, Packet, ReportFormat};
+
+pub mod decode;
+pub mod replay;
+
+// Relative to the device
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TracedPacket {
+    // Since the trace was started
+    pub elapsed_ms: u64,
+    pub direction: Direction,
+    pub packet: Packet,
+}
+
+// A trace file is one record per line, starting with a Start record
+#[derive(Serialize, Deserialize)]
+enum TraceRecord {
+    Start {
+        started: String,
+        report_format: ReportFormat,
+    },
+    Packet(TracedPacket),
+}
+
+pub struct Trace {
+    pub report_format: ReportFormat,
+    pub packets: Vec<TracedPacket>,
+}
+
+impl Trace {
+    pub fn read(path: &Path) -> io::Result<Trace> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let report_format = match lines.next() {
+            Some(line) => match serde_json::from_str(&line?)? {
+                TraceRecord::Start { report_format, .. } => report_format,
+                TraceRecord::Packet(_) => return Err(invalid_trace("missing start record")),
+            },
+            None => return Err(invalid_trace("empty trace")),
+        };
+        let mut packets = Vec::new();
+        let mut lines = lines.peekable();
+        while let Some(line) = lines.next() {
+            let record = match serde_json::from_str(&line?) {
+                Ok(record) => record,
+                // The daemon may have stopped part way through the last line
+                Err(_) if lines.peek().is_none() => break,
+                Err(err) => return Err(err.into()),
+            };
+            match record {
+                TraceRecord::Packet(packet) => packets.push(packet),
+                TraceRecord::Start { .. } => return Err(invalid_trace("repeated start record")),
+            }
+        }
+        Ok(Trace {
+            report_format,
+            packets,
+        })
+    }
+}
+
+fn invalid_trace(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub struct TraceWriter {
+    started: Instant,
+    writer: BufWriter<File>,
+}
+
+impl TraceWriter {
+    // Replaces any trace already at the path. Traces hold key handles and
+    // signatures, so are only readable by the user.
+    pub fn create(path: &Path, report_format: ReportFormat) -> io::Result<TraceWriter> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        // The mode only applies to a new file, not one being replaced
+        file.set_permissions(Permissions::from_mode(0o600))?;
+        let mut writer = TraceWriter {
+            started: Instant::now(),
+            writer: BufWriter::new(file),
+        };
+        writer.write_record(&TraceRecord::Start {
+            started: time::now_utc().rfc3339().to_string(),
+            report_format,
+        })?;
+        Ok(writer)
+    }
+
+    pub fn record(&mut self, direction: Direction, packet: Packet) -> io::Result<()> {
+        let elapsed = self.started.elapsed();
+        let elapsed_ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+        self.write_record(&TraceRecord::Packet(TracedPacket {
+            elapsed_ms,
+            direction,
+            packet,
+        }))
+    }
+
+    // Flushed per record, so the trace is complete up to a crash
+    fn write_record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, record)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()
+    }
+}
+
+// Passes packets through to the transport, writing each to the trace
+pub struct Recorder<T> {
+    logger: Logger,
+    trace: Option<TraceWriter>,
+    transport: T,
+}
+
+impl<T> Recorder<T> {
+    pub fn new(transport: T, trace: Option<TraceWriter>, logger: Logger) -> Recorder<T> {
+        Recorder {
+            logger,
+            trace,
+            transport,
+        }
+    }
+
+    // Recording stops at the first failed write, the device carries on
+    fn record(&mut self, direction: Direction, packet: Packet) {
+        let result = match self.trace {
+            Some(ref mut trace) => trace.record(direction, packet),
+            None => return,
+        };
+        if let Err(err) = result {
+            warn!(self.logger, "Stopped recording, unable to write to the trace"; "error" => %err);
+            self.trace = None;
+        }
+    }
+}
+
+impl<T> Stream for Recorder<T>
+where
+    T: Stream<Item = Packet>,
+{
+    type Item = Packet;
+    type Error = T::Error;
+
+    fn poll(&mut self) -> Poll<Option<Packet>, T::Error> {
+        let packet = try_ready!(self.transport.poll());
+        if let Some(ref packet) = packet {
+            self.record(Direction::Inbound, packet.clone());
+        }
+        Ok(Async::Ready(packet))
+    }
+}
+
+impl<T> Sink for Recorder<T>
+where
+    T: Sink<SinkItem = Packet>,
+{
+    type SinkItem = Packet;
+    type SinkError = T::SinkError;
+
+    fn start_send(&mut self, packet: Packet) -> StartSend<Packet, T::SinkError> {
+        let copy = self.trace.as_ref().map(|_| packet.clone());
+        let result = self.transport.start_send(packet)?;
+        if let (AsyncSink::Ready, Some(copy)) = (&result, copy) {
+            self.record(Direction::Outbound, copy);
+        }
+        Ok(result)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), T::SinkError> {
+        self.transport.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), T::SinkError> {
+        self.transport.close()
+    }
+}
+
+// A whole U2FHID request or response
+#[derive(Clone, Debug)]
+pub struct Message {
+    // Of its first packet
+    pub elapsed_ms: u64,
+    pub channel_id: ChannelId,
+    pub command: Command,
+    pub data: Vec<u8>,
+}
+
+// Joins packets going one way back into messages. Sequence numbers are not
+// checked, a trace shows what was sent rather than what was valid.
+#[derive(Default)]
+pub struct Reassembler {
+    partial: HashMap<ChannelId, (Message, usize)>,
+}
+
+impl Reassembler {
+    pub fn push(&mut self, elapsed_ms: u64, packet: Packet) -> Option<Message> {
+        match packet {
+            Packet::Initialization {
+                channel_id,
+                command,
+                data,
+                payload_len,
+            } => {
+                let message = Message {
+                    elapsed_ms,
+                    channel_id,
+                    command,
+                    data,
+                };
+                self.complete(message, payload_len)
+            }
+            Packet::Continuation {
+                channel_id, data, ..
+            } => {
+                let (mut message, payload_len) = self.partial.remove(&channel_id)?;
+                message.data.extend_from_slice(&data);
+                self.complete(message, payload_len)
+            }
+        }
+    }
+
+    fn complete(&mut self, mut message: Message, payload_len: usize) -> Option<Message> {
+        if message.data.len() >= payload_len {
+            // Reports are padded out to their full length
+            message.data.truncate(payload_len);
+            self.partial.remove(&message.channel_id);
+            Some(message)
+        } else {
+            self.partial
+                .insert(message.channel_id, (message, payload_len));
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs;
+
+    use self::tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn reads_back_written_trace() {
+        let dir = TempDir::new("trace_tests").unwrap();
+        let path = dir.path().join("trace");
+        let format = ReportFormat::new(64, Some(3)).unwrap();
+        let mut writer = TraceWriter::create(&path, format).unwrap();
+        writer
+            .record(
+                Direction::Outbound,
+                Packet::Continuation {
+                    channel_id: ChannelId(5),
+                    sequence_number: 2,
+                    data: vec![1, 2, 3],
+                },
+            )
+            .unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let trace = Trace::read(&path).unwrap();
+        assert_eq!(trace.report_format, format);
+        assert_eq!(trace.packets.len(), 1);
+        assert_eq!(trace.packets[0].direction, Direction::Outbound);
+        match trace.packets[0].packet {
+            Packet::Continuation {
+                channel_id,
+                sequence_number,
+                ref data,
+            } => {
+                assert_eq!(channel_id, ChannelId(5));
+                assert_eq!(sequence_number, 2);
+                assert_eq!(data, &vec![1, 2, 3]);
+            }
+            _ => panic!("Expected a continuation packet"),
+        }
+    }
+
+    #[test]
+    fn reassembles_segmented_message() {
+        let mut reassembler = Reassembler::default();
+        let channel_id = ChannelId(5);
+        assert!(reassembler
+            .push(
+                10,
+                Packet::Initialization {
+                    channel_id,
+                    command: Command::Ping,
+                    data: vec![1; 57],
+                    payload_len: 60,
+                },
+            )
+            .is_none());
+        let message = reassembler
+            .push(
+                12,
+                Packet::Continuation {
+                    channel_id,
+                    sequence_number: 0,
+                    data: vec![2; 59],
+                },
+            )
+            .unwrap();
+        assert_eq!(message.elapsed_ms, 10);
+        assert_eq!(message.command, Command::Ping);
+        assert_eq!(message.data.len(), 60);
+        assert_eq!(&message.data[55..], &[1, 1, 2, 2, 2]);
+    }
+
+    #[test]
+    fn ignores_continuation_without_initialization() {
+        let mut reassembler = Reassembler::default();
+        assert!(reassembler
+            .push(
+                0,
+                Packet::Continuation {
+                    channel_id: ChannelId(5),
+                    sequence_number: 0,
+                    data: vec![2; 59],
+                },
+            )
+            .is_none());
+    }
+}
@@ -0,0 +1,765 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use slog::Logger;
+use u2f_core::{
+    self, AppId, ApplicationKey, Counter, KeyHandle, SecretStore, Service, UserPresence,
+};
+use u2fhid_protocol::{
+    ChannelId, Clock, Command, DispatchId, DispatchResponse, Dispatcher, Output, Packet,
+    StateMachine,
+};
+
+use super::decode::describe;
+use super::{Direction, Message, Reassembler, Trace};
+
+// A response that differs from the recorded one
+#[derive(Debug)]
+pub enum Difference {
+    Response {
+        recorded: Message,
+        replayed: Message,
+    },
+    // Recorded, but not sent by the replay
+    Missing(Message),
+    // Sent by the replay, but not recorded
+    Unexpected(Message),
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Difference::Response {
+                ref recorded,
+                ref replayed,
+            } => write!(
+                f,
+                "differs\n  recorded {}\n  replayed {}",
+                describe(Direction::Outbound, recorded),
+                describe(Direction::Outbound, replayed)
+            ),
+            Difference::Missing(ref message) => {
+                write!(
+                    f,
+                    "missing\n  recorded {}",
+                    describe(Direction::Outbound, message)
+                )
+            }
+            Difference::Unexpected(ref message) => {
+                write!(
+                    f,
+                    "unexpected\n  replayed {}",
+                    describe(Direction::Outbound, message)
+                )
+            }
+        }
+    }
+}
+
+// Sends the recorded requests to the service, on a clock that follows the
+// trace so timeouts fire as they did, and compares the responses with the
+// recorded ones. Responses that depend on randomness or keys are compared
+// by shape only: the channel ids given out by INIT, and all but the status
+// word of U2F responses. Channel ids and key handles given out in the
+// replay are used in place of the recorded ones in later requests.
+pub fn replay<S>(trace: &Trace, dispatcher: Dispatcher<S>, logger: Logger) -> Vec<Difference>
+where
+    S: Service<
+        Request = u2f_core::Request,
+        Response = u2f_core::Response,
+        Error = io::Error,
+        Future = Box<dyn Future<Item = u2f_core::Response, Error = io::Error>>,
+    >,
+{
+    let clock = ReplayClock(Rc::new(Cell::new(Instant::now())));
+    let started = clock.now();
+    let mut state_machine = StateMachine::new(Box::new(clock.clone()), logger.new(o!()));
+    state_machine.set_report_format(trace.report_format);
+    let mut replay = Replay {
+        channels: HashMap::new(),
+        differences: Vec::new(),
+        dispatch: None,
+        dispatcher,
+        held: HashMap::new(),
+        key_handles: HashMap::new(),
+        logger,
+        recorded: Reassembler::default(),
+        recorded_messages: VecDeque::new(),
+        recorded_packets: 0,
+        replayed: Reassembler::default(),
+        replayed_messages: VecDeque::new(),
+        replayed_packets: 0,
+        requests: Reassembler::default(),
+        state_machine,
+        timer: None,
+    };
+
+    for traced in &trace.packets {
+        clock
+            .0
+            .set(started + Duration::from_millis(traced.elapsed_ms));
+        if replay.timer.map_or(false, |at| clock.now() >= at) {
+            replay.timer = None;
+            replay.state_machine.step();
+            replay.take_outputs(traced.elapsed_ms);
+        }
+        match traced.direction {
+            Direction::Inbound => {
+                let packet = replay.map_channel(traced.packet.clone());
+                replay.accept_packet(traced.elapsed_ms, packet);
+            }
+            Direction::Outbound => {
+                replay.recorded_packets += 1;
+                // The device sent something not yet replayed, which can
+                // only be the response from the service
+                if replay.replayed_packets < replay.recorded_packets {
+                    replay.complete_dispatch(traced.elapsed_ms);
+                }
+                if let Some(message) = replay
+                    .recorded
+                    .push(traced.elapsed_ms, traced.packet.clone())
+                {
+                    replay.recorded_messages.push_back(message);
+                    replay.compare();
+                }
+            }
+        }
+    }
+    if let Some(traced) = trace.packets.last() {
+        let channel_ids: Vec<ChannelId> = replay.held.keys().cloned().collect();
+        for channel_id in channel_ids {
+            replay.release(traced.elapsed_ms, channel_id, None);
+        }
+        replay.complete_dispatch(traced.elapsed_ms);
+    }
+
+    let mut differences = replay.differences;
+    differences.extend(
+        replay
+            .recorded_messages
+            .into_iter()
+            .map(Difference::Missing),
+    );
+    differences.extend(
+        replay
+            .replayed_messages
+            .into_iter()
+            .map(Difference::Unexpected),
+    );
+    differences
+}
+
+#[derive(Clone)]
+struct ReplayClock(Rc<Cell<Instant>>);
+
+impl Clock for ReplayClock {
+    fn now(&self) -> Instant {
+        self.0.get()
+    }
+}
+
+struct Replay<S> {
+    // Recorded channel ids to the ones given out in the replay
+    channels: HashMap<ChannelId, ChannelId>,
+    differences: Vec<Difference>,
+    dispatch: Option<(
+        DispatchId,
+        Box<dyn Future<Item = DispatchResponse, Error = io::Error>>,
+    )>,
+    dispatcher: Dispatcher<S>,
+    // Packets of authenticate requests, held until the whole request is
+    // in so its key handle can be replaced
+    held: HashMap<ChannelId, Vec<Packet>>,
+    // Recorded key handles to the ones registered in the replay
+    key_handles: HashMap<Vec<u8>, Vec<u8>>,
+    logger: Logger,
+    recorded: Reassembler,
+    recorded_messages: VecDeque<Message>,
+    recorded_packets: usize,
+    replayed: Reassembler,
+    replayed_messages: VecDeque<Message>,
+    replayed_packets: usize,
+    requests: Reassembler,
+    state_machine: StateMachine,
+    timer: Option<Instant>,
+}
+
+impl<S> Replay<S>
+where
+    S: Service<
+        Request = u2f_core::Request,
+        Response = u2f_core::Response,
+        Error = io::Error,
+        Future = Box<dyn Future<Item = u2f_core::Response, Error = io::Error>>,
+    >,
+{
+    fn take_outputs(&mut self, elapsed_ms: u64) {
+        while let Some(output) = self.state_machine.poll_output() {
+            match output {
+                Output::Packet(packet) => {
+                    self.replayed_packets += 1;
+                    if let Some(message) = self.replayed.push(elapsed_ms, packet) {
+                        self.replayed_messages.push_back(message);
+                    }
+                }
+                Output::Dispatch(dispatch) => {
+                    self.dispatch = Some((dispatch.id, self.dispatcher.call(dispatch.request)));
+                }
+                Output::CancelDispatch(id) => {
+                    if self.dispatch.as_ref().map(|&(dispatch_id, _)| dispatch_id) == Some(id) {
+                        self.dispatch = None;
+                    }
+                }
+                Output::Timer(at) => self.timer = at,
+            }
+        }
+        self.compare();
+    }
+
+    fn accept_packet(&mut self, elapsed_ms: u64, packet: Packet) {
+        let channel_id = packet.channel_id();
+        let authenticate = match packet {
+            Packet::Initialization {
+                command: Command::Msg,
+                ref data,
+                ..
+            } => data.len() > 1 && data[1] == AUTHENTICATE_INSTRUCTION,
+            _ => false,
+        };
+        // A new request abandons any held one, which is sent as recorded
+        if let Packet::Initialization { .. } = packet {
+            self.release(elapsed_ms, channel_id, None);
+        }
+        let request = self.requests.push(elapsed_ms, packet.clone());
+        if !authenticate && !self.held.contains_key(&channel_id) {
+            self.state_machine.accept_packet(packet);
+            self.take_outputs(elapsed_ms);
+            return;
+        }
+        self.held.entry(channel_id).or_default().push(packet);
+        if let Some(request) = request {
+            self.release(elapsed_ms, channel_id, Some(request.data));
+        }
+    }
+
+    // Sends the packets held for a channel, with the recorded key handle
+    // replaced if the request is complete and the key was registered in
+    // the replay
+    fn release(&mut self, elapsed_ms: u64, channel_id: ChannelId, request: Option<Vec<u8>>) {
+        let mut packets = match self.held.remove(&channel_id) {
+            Some(packets) => packets,
+            None => return,
+        };
+        if let Some(mut request) = request {
+            if let Some(range) = key_handle_range(&request) {
+                if let Some(replayed) = self.key_handles.get(&request[range.clone()]) {
+                    request[range].copy_from_slice(replayed);
+                    replace_payload(&mut packets, &request);
+                }
+            }
+        }
+        for packet in packets {
+            self.state_machine.accept_packet(packet);
+        }
+        self.take_outputs(elapsed_ms);
+    }
+
+    fn complete_dispatch(&mut self, elapsed_ms: u64) {
+        if let Some((id, future)) = self.dispatch.take() {
+            match future.wait() {
+                Ok(response) => self.state_machine.complete_dispatch(id, response),
+                // Left to time out, as it would in the daemon
+                Err(err) => warn!(self.logger, "Service call failed"; "error" => %err),
+            }
+            self.take_outputs(elapsed_ms);
+        }
+    }
+
+    fn map_channel(&self, packet: Packet) -> Packet {
+        match packet {
+            Packet::Initialization {
+                channel_id,
+                command,
+                data,
+                payload_len,
+            } => Packet::Initialization {
+                channel_id: self.replayed_channel(channel_id),
+                command,
+                data,
+                payload_len,
+            },
+            Packet::Continuation {
+                channel_id,
+                sequence_number,
+                data,
+            } => Packet::Continuation {
+                channel_id: self.replayed_channel(channel_id),
+                sequence_number,
+                data,
+            },
+        }
+    }
+
+    fn replayed_channel(&self, recorded: ChannelId) -> ChannelId {
+        self.channels.get(&recorded).cloned().unwrap_or(recorded)
+    }
+
+    // Pairs up responses in the order they were sent
+    fn compare(&mut self) {
+        while !self.recorded_messages.is_empty() && !self.replayed_messages.is_empty() {
+            let recorded = self.recorded_messages.pop_front().unwrap();
+            let replayed = self.replayed_messages.pop_front().unwrap();
+            if recorded.command == Command::Init && replayed.command == Command::Init {
+                if let (Some(from), Some(to)) = (init_channel(&recorded), init_channel(&replayed)) {
+                    if recorded.data[..8] == replayed.data[..8] {
+                        self.channels.insert(from, to);
+                    }
+                }
+            }
+            if recorded.command == Command::Msg && replayed.command == Command::Msg {
+                if let (Some(from), Some(to)) = (
+                    registered_key_handle(&recorded.data),
+                    registered_key_handle(&replayed.data),
+                ) {
+                    // Lengths must match to replace one in place
+                    if from.len() == to.len() {
+                        self.key_handles.insert(from.to_vec(), to.to_vec());
+                    }
+                }
+            }
+            if !self.matches(&recorded, &replayed) {
+                self.differences
+                    .push(Difference::Response { recorded, replayed });
+            }
+        }
+    }
+
+    fn matches(&self, recorded: &Message, replayed: &Message) -> bool {
+        if self.replayed_channel(recorded.channel_id) != replayed.channel_id
+            || recorded.command != replayed.command
+        {
+            return false;
+        }
+        match recorded.command {
+            Command::Init => {
+                recorded.data.len() == replayed.data.len()
+                    && recorded.data.len() >= 12
+                    && recorded.data[..8] == replayed.data[..8]
+                    && recorded.data[12..] == replayed.data[12..]
+            }
+            Command::Msg => {
+                recorded.data.len() >= 2
+                    && replayed.data.len() >= 2
+                    && recorded.data[recorded.data.len() - 2..]
+                        == replayed.data[replayed.data.len() - 2..]
+            }
+            _ => recorded.data == replayed.data,
+        }
+    }
+}
+
+// The channel given out by an INIT response
+fn init_channel(message: &Message) -> Option<ChannelId> {
+    if message.data.len() < 12 {
+        return None;
+    }
+    let id = message.data[8..12]
+        .iter()
+        .fold(0u32, |id, &byte| (id << 8) | u32::from(byte));
+    Some(ChannelId(id))
+}
+
+const AUTHENTICATE_INSTRUCTION: u8 = 0x02;
+const REGISTER_RESPONSE_RESERVED_BYTE: u8 = 0x05;
+
+// Where the key handle is in an extended length authenticate APDU: after
+// the 7 byte header, the challenge and application parameters and the
+// key handle length
+fn key_handle_range(apdu: &[u8]) -> Option<Range<usize>> {
+    const KEY_HANDLE_OFFSET: usize = 7 + 32 + 32 + 1;
+    if apdu.len() < KEY_HANDLE_OFFSET || apdu[1] != AUTHENTICATE_INSTRUCTION {
+        return None;
+    }
+    let range = KEY_HANDLE_OFFSET..KEY_HANDLE_OFFSET + apdu[KEY_HANDLE_OFFSET - 1] as usize;
+    if range.end > apdu.len() {
+        return None;
+    }
+    Some(range)
+}
+
+// The key handle in a successful register response, after the reserved
+// byte, the public key and the key handle length
+fn registered_key_handle(response: &[u8]) -> Option<&[u8]> {
+    const KEY_HANDLE_OFFSET: usize = 1 + 65 + 1;
+    if response.len() < KEY_HANDLE_OFFSET
+        || response[0] != REGISTER_RESPONSE_RESERVED_BYTE
+        || response[response.len() - 2..] != [0x90, 0x00]
+    {
+        return None;
+    }
+    let end = KEY_HANDLE_OFFSET + response[KEY_HANDLE_OFFSET - 1] as usize;
+    if end + 2 > response.len() {
+        return None;
+    }
+    Some(&response[KEY_HANDLE_OFFSET..end])
+}
+
+// Writes the payload back into the packets it was reassembled from,
+// leaving any padding after it alone
+fn replace_payload(packets: &mut [Packet], payload: &[u8]) {
+    let mut remaining = payload;
+    for packet in packets {
+        let data = match *packet {
+            Packet::Initialization { ref mut data, .. } => data,
+            Packet::Continuation { ref mut data, .. } => data,
+        };
+        let len = data.len().min(remaining.len());
+        data[..len].copy_from_slice(&remaining[..len]);
+        remaining = &remaining[len..];
+    }
+}
+
+// Approves every request at once, there is no one to ask during a replay
+pub struct ApproveAll;
+
+impl UserPresence for ApproveAll {
+    fn approve_registration(&self, _: &AppId) -> Box<dyn Future<Item = bool, Error = io::Error>> {
+        Box::new(future::ok(true))
+    }
+
+    fn approve_authentication(&self, _: &AppId) -> Box<dyn Future<Item = bool, Error = io::Error>> {
+        Box::new(future::ok(true))
+    }
+
+    fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
+        Box::new(future::ok(()))
+    }
+
+    fn key_revoked(&self, _: &AppId) {}
+
+    fn waiting_for_approval(&self, _: &AppId, _: Duration) {}
+}
+
+// Keys registered during a replay, kept apart from the user's own keys
+#[derive(Default)]
+pub struct MemoryStore(RefCell<Vec<(ApplicationKey, Counter)>>);
+
+impl SecretStore for MemoryStore {
+    fn add_application_key(&self, key: &ApplicationKey) -> io::Result<()> {
+        self.0.borrow_mut().push((key.clone(), 0));
+        Ok(())
+    }
+
+    fn get_and_increment_counter(
+        &self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> io::Result<Counter> {
+        let mut keys = self.0.borrow_mut();
+        match keys
+            .iter_mut()
+            .find(|(key, _)| key.application == *application && key.handle == *handle)
+        {
+            Some((_, counter)) => {
+                *counter += 1;
+                Ok(*counter)
+            }
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no such key")),
+        }
+    }
+
+    fn retrieve_application_key(
+        &self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> io::Result<Option<ApplicationKey>> {
+        Ok(self
+            .0
+            .borrow()
+            .iter()
+            .find(|(key, _)| key.application == *application && key.handle == *handle)
+            .map(|(key, _)| key.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use slog::{self, Discard};
+    use u2fhid_protocol::{ReportFormat, BROADCAST_CHANNEL_ID};
+
+    use super::super::TracedPacket;
+    use super::*;
+
+    struct VersionService;
+
+    impl Service for VersionService {
+        type Request = u2f_core::Request;
+        type Response = u2f_core::Response;
+        type Error = io::Error;
+        type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+        fn call(&self, _req: Self::Request) -> Self::Future {
+            Box::new(future::ok(u2f_core::Response::Version {
+                version_string: String::from("U2F_V2"),
+            }))
+        }
+    }
+
+    const NONCE: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+    const RECORDED_CHANNEL_ID: ChannelId = ChannelId(0x0a0b_0c0d);
+
+    fn traced(
+        elapsed_ms: u64,
+        direction: Direction,
+        channel_id: ChannelId,
+        command: Command,
+        data: Vec<u8>,
+    ) -> TracedPacket {
+        TracedPacket {
+            elapsed_ms,
+            direction,
+            packet: Packet::Initialization {
+                channel_id,
+                command,
+                payload_len: data.len(),
+                data,
+            },
+        }
+    }
+
+    // An INIT, a PING and a U2F version request, with the device's
+    // responses as they were recorded
+    fn session(pong: Vec<u8>) -> Trace {
+        let mut init_response = NONCE.to_vec();
+        init_response.extend_from_slice(&[0x0a, 0x0b, 0x0c, 0x0d, 2, 0, 1, 0, 1]);
+        let mut version_response = b"U2F_V2".to_vec();
+        version_response.extend_from_slice(&[0x90, 0x00]);
+        Trace {
+            report_format: ReportFormat::default(),
+            packets: vec![
+                traced(
+                    0,
+                    Direction::Inbound,
+                    BROADCAST_CHANNEL_ID,
+                    Command::Init,
+                    NONCE.to_vec(),
+                ),
+                traced(
+                    1,
+                    Direction::Outbound,
+                    BROADCAST_CHANNEL_ID,
+                    Command::Init,
+                    init_response,
+                ),
+                traced(
+                    5,
+                    Direction::Inbound,
+                    RECORDED_CHANNEL_ID,
+                    Command::Ping,
+                    vec![1, 2, 3],
+                ),
+                traced(
+                    6,
+                    Direction::Outbound,
+                    RECORDED_CHANNEL_ID,
+                    Command::Ping,
+                    pong,
+                ),
+                traced(
+                    10,
+                    Direction::Inbound,
+                    RECORDED_CHANNEL_ID,
+                    Command::Msg,
+                    vec![0, 3, 0, 0, 0, 0, 0],
+                ),
+                traced(
+                    40,
+                    Direction::Outbound,
+                    RECORDED_CHANNEL_ID,
+                    Command::Msg,
+                    version_response,
+                ),
+            ],
+        }
+    }
+
+    fn logger() -> Logger {
+        slog::Logger::root(Discard, o!())
+    }
+
+    #[test]
+    fn replay_of_matching_session_has_no_differences() {
+        let differences = replay(
+            &session(vec![1, 2, 3]),
+            Dispatcher::new(VersionService),
+            logger(),
+        );
+        assert!(differences.is_empty(), "{:?}", differences);
+    }
+
+    #[test]
+    fn replay_reports_changed_response() {
+        let differences = replay(
+            &session(vec![1, 2]),
+            Dispatcher::new(VersionService),
+            logger(),
+        );
+        assert_eq!(differences.len(), 1);
+        match differences[0] {
+            Difference::Response {
+                ref recorded,
+                ref replayed,
+            } => {
+                assert_eq!(recorded.data, vec![1, 2]);
+                assert_eq!(replayed.data, vec![1, 2, 3]);
+            }
+            ref other => panic!("Unexpected difference {:?}", other),
+        }
+    }
+
+    // Registers a key handle that differs from any recorded one, and only
+    // signs with handles it registered. Responses are vendor responses laid
+    // out as register and authenticate responses, which is all the replay
+    // compares.
+    #[derive(Default)]
+    struct KeyService(RefCell<Vec<Vec<u8>>>);
+
+    impl Service for KeyService {
+        type Request = u2f_core::Request;
+        type Response = u2f_core::Response;
+        type Error = io::Error;
+        type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+        fn call(&self, req: Self::Request) -> Self::Future {
+            let response = match req {
+                u2f_core::Request::Register { .. } => {
+                    let key_handle = vec![0x40 + self.0.borrow().len() as u8; 255];
+                    self.0.borrow_mut().push(key_handle.clone());
+                    u2f_core::Response::Vendor {
+                        data: register_response(&key_handle),
+                    }
+                }
+                u2f_core::Request::Authenticate { key_handle, .. } => {
+                    if self
+                        .0
+                        .borrow()
+                        .iter()
+                        .any(|registered| registered[..] == *key_handle.as_ref())
+                    {
+                        u2f_core::Response::Vendor {
+                            data: vec![1, 0, 0, 0, 1],
+                        }
+                    } else {
+                        u2f_core::Response::InvalidKeyHandle
+                    }
+                }
+                _ => u2f_core::Response::UnknownError,
+            };
+            Box::new(future::ok(response))
+        }
+    }
+
+    // Without the status word
+    fn register_response(key_handle: &[u8]) -> Vec<u8> {
+        let mut data = vec![REGISTER_RESPONSE_RESERVED_BYTE];
+        data.extend_from_slice(&[4u8; 65]);
+        data.push(key_handle.len() as u8);
+        data.extend_from_slice(key_handle);
+        data
+    }
+
+    // Split into packets of the default report format
+    fn segmented(
+        elapsed_ms: u64,
+        direction: Direction,
+        command: Command,
+        data: Vec<u8>,
+    ) -> Vec<TracedPacket> {
+        let mut packets = vec![TracedPacket {
+            elapsed_ms,
+            direction,
+            packet: Packet::Initialization {
+                channel_id: RECORDED_CHANNEL_ID,
+                command,
+                payload_len: data.len(),
+                data: data[..57.min(data.len())].to_vec(),
+            },
+        }];
+        for (sequence_number, chunk) in data[57.min(data.len())..].chunks(59).enumerate() {
+            packets.push(TracedPacket {
+                elapsed_ms,
+                direction,
+                packet: Packet::Continuation {
+                    channel_id: RECORDED_CHANNEL_ID,
+                    sequence_number: sequence_number as u8,
+                    data: chunk.to_vec(),
+                },
+            });
+        }
+        packets
+    }
+
+    #[test]
+    fn replay_authenticates_with_key_handle_registered_in_replay() {
+        let recorded_key_handle = [7u8; 255];
+        let application = AppId::from_bytes(&[1u8; 32]);
+        let challenge = || u2f_core::Challenge::from_bytes(&[2u8; 32]).unwrap();
+        let register = u2f_core::Request::Register {
+            application,
+            challenge: challenge(),
+        };
+        let authenticate = u2f_core::Request::Authenticate {
+            application,
+            challenge: challenge(),
+            control_code: u2f_core::AuthenticateControlCode::EnforceUserPresenceAndSign,
+            key_handle: KeyHandle::from(&recorded_key_handle),
+        };
+        let mut registered = register_response(&recorded_key_handle);
+        registered.extend_from_slice(&[0x90, 0x00]);
+
+        let mut trace = session(vec![1, 2, 3]);
+        trace.packets.truncate(4);
+        trace.packets.extend(segmented(
+            10,
+            Direction::Inbound,
+            Command::Msg,
+            register.into_bytes().unwrap(),
+        ));
+        trace
+            .packets
+            .extend(segmented(20, Direction::Outbound, Command::Msg, registered));
+        trace.packets.extend(segmented(
+            30,
+            Direction::Inbound,
+            Command::Msg,
+            authenticate.into_bytes().unwrap(),
+        ));
+        trace.packets.extend(segmented(
+            40,
+            Direction::Outbound,
+            Command::Msg,
+            vec![1, 0, 0, 0, 1, 0x90, 0x00],
+        ));
+
+        let differences = replay(&trace, Dispatcher::new(KeyService::default()), logger());
+        assert!(differences.is_empty(), "{:?}", differences);
+    }
+
+    #[test]
+    fn replay_reports_response_missing_from_trace() {
+        let mut trace = session(vec![1, 2, 3]);
+        trace.packets.truncate(5);
+        let differences = replay(&trace, Dispatcher::new(VersionService), logger());
+        assert_eq!(differences.len(), 1);
+        match differences[0] {
+            Difference::Unexpected(ref message) => assert_eq!(message.command, Command::Msg),
+            ref other => panic!("Unexpected difference {:?}", other),
+        }
+    }
+}
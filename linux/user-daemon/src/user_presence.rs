@@ -1,4 +1,5 @@
 use std::io;
+use std::time::Duration as StdDuration;
 
 use futures::future;
 use futures::prelude::*;
@@ -83,6 +84,39 @@ impl NotificationUserPresence {
             Ok(user_present)
         }))
     }
+
+    // Shows a notification without waiting on the user
+    fn notify(
+        &self,
+        summary: &'static str,
+        message: String,
+        icon: &'static str,
+        timeout: Option<StdDuration>,
+    ) {
+        let logger = self.logger.clone();
+        self.executor
+            .spawn_fn(move || {
+                let mut notification = Notification::new();
+                notification
+                    .appname(APPNAME)
+                    .summary(summary)
+                    .body(&message)
+                    .icon(icon)
+                    .hint(NotificationHint::Category(String::from(HINT_CATEGORY)))
+                    .hint(NotificationHint::Urgency(URGENCY))
+                    .urgency(URGENCY);
+                if let Some(timeout) = timeout {
+                    notification
+                        .hint(NotificationHint::Transient(true))
+                        .timeout((timeout.as_secs() * 1000) as i32);
+                }
+                if let Err(err) = notification.show() {
+                    error!(logger, "Unable to show notification"; "error" => ?err);
+                }
+                Ok::<(), io::Error>(())
+            })
+            .forget();
+    }
 }
 
 impl UserPresence for NotificationUserPresence {
@@ -104,6 +138,31 @@ impl UserPresence for NotificationUserPresence {
         self.test_user_presence(&message)
     }
 
+    fn key_revoked(&self, application: &AppId) {
+        let site_name = try_reverse_app_id(application).unwrap_or(String::from("site"));
+        warn!(self.logger, "Key revoked at the co-signer"; "site" => &site_name);
+        let message = format!(
+            "Your key for {} has been revoked and can no longer be used",
+            site_name
+        );
+        self.notify(
+            "Security Key Revoked",
+            message,
+            "security-low-symbolic",
+            None,
+        );
+    }
+
+    fn waiting_for_approval(&self, application: &AppId, timeout: StdDuration) {
+        let site_name = try_reverse_app_id(application).unwrap_or(String::from("site"));
+        info!(self.logger, "Waiting for approval"; "site" => &site_name, "timeout_secs" => timeout.as_secs());
+        let message = format!(
+            "Waiting for approval to authenticate with {} on your approval device",
+            site_name
+        );
+        self.notify(SUMMARY, message, ICON, Some(timeout));
+    }
+
     fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
         let message = String::from("Ready to authenticate");
         Notification::new()
@@ -0,0 +1,31 @@
+use std::io;
+
+use futures::future;
+use futures::Future;
+use u2f_core::{VendorCommand, VendorHandler};
+
+use VERSION;
+
+// First command and instruction of the vendor ranges
+const VERSION_HID_COMMAND: u8 = 0xc0;
+const VERSION_INSTRUCTION: u8 = 0x40;
+
+// Tells tools talking to the device over HID which daemon they reached
+pub struct DaemonInfo;
+
+impl VendorHandler for DaemonInfo {
+    fn handle(
+        &self,
+        command: VendorCommand,
+        _data: &[u8],
+    ) -> Option<Box<dyn Future<Item = Vec<u8>, Error = io::Error>>> {
+        match command {
+            VendorCommand::Hid(VERSION_HID_COMMAND)
+            | VendorCommand::Apdu {
+                instruction: VERSION_INSTRUCTION,
+                ..
+            } => Some(Box::new(future::ok(VERSION.as_bytes().to_vec()))),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use reqwest::{Method, StatusCode};
+use serde_json;
+use u2f_core::{CosignerCredential, CosignerTransport, SigningKind, TransportResponse};
+
+use routes::APPROVALS_PATH;
+use sessions::PendingSession;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ApproverError {
+        Unreachable(err: io::Error) {
+            cause(err)
+            display("Unable to reach server: {}", err)
+        }
+        Malformed(err: serde_json::Error) {
+            from()
+            cause(err)
+            display("Server sent a malformed response: {}", err)
+        }
+        Status(status: StatusCode) {
+            display("Server responded with {}", status)
+        }
+        UnknownSession(id: u64) {
+            display("No signing context {} is waiting for approval", id)
+        }
+        Credential(err: io::Error) {
+            cause(err)
+            display("Unable to read approver credential: {}", err)
+        }
+        MalformedCredential {
+            display("Approver credential is malformed")
+        }
+        Unauthorized {
+            display("Server did not accept the approver credential")
+        }
+    }
+}
+
+// Talks to the approval routes of a running server, which only serves
+// them over loopback to an enrolled approver
+pub struct Approver {
+    transport: CosignerTransport,
+    credential: CosignerCredential,
+}
+
+impl Approver {
+    pub fn new(server: &str, credential: CosignerCredential) -> Approver {
+        Approver {
+            transport: CosignerTransport::new(server),
+            credential,
+        }
+    }
+
+    // Reads the credential printed by enroll-approver from a file
+    pub fn with_credential_file(server: &str, path: &Path) -> Result<Approver, ApproverError> {
+        let token = fs::read_to_string(path).map_err(ApproverError::Credential)?;
+        let credential =
+            CosignerCredential::from_token(&token).ok_or(ApproverError::MalformedCredential)?;
+        Ok(Approver::new(server, credential))
+    }
+
+    pub fn pending(&self) -> Result<Vec<PendingSession>, ApproverError> {
+        let response = self.send(Method::GET, APPROVALS_PATH)?;
+        match response.status {
+            status if status.is_success() => Ok(response.json()?),
+            StatusCode::UNAUTHORIZED => Err(ApproverError::Unauthorized),
+            status => Err(ApproverError::Status(status)),
+        }
+    }
+
+    pub fn decide(&self, id: u64, approve: bool) -> Result<(), ApproverError> {
+        let decision = if approve { "approve" } else { "deny" };
+        let path = format!("{}/{}/{}", APPROVALS_PATH, id, decision);
+        match self.send(Method::POST, &path)?.status {
+            status if status.is_success() => Ok(()),
+            StatusCode::NOT_FOUND => Err(ApproverError::UnknownSession(id)),
+            StatusCode::UNAUTHORIZED => Err(ApproverError::Unauthorized),
+            status => Err(ApproverError::Status(status)),
+        }
+    }
+
+    fn send(&self, method: Method, path: &str) -> Result<TransportResponse, ApproverError> {
+        self.transport
+            .send(method, path, Some(&self.credential.to_token()), None)
+            .map_err(ApproverError::Unreachable)
+    }
+}
+
+pub fn describe(session: &PendingSession) -> String {
+    let kind = match session.kind {
+        SigningKind::Registration => "register with",
+        SigningKind::Authentication => "authenticate with",
+    };
+    let site = session
+        .site
+        .clone()
+        .unwrap_or_else(|| session.app_id.to_base64());
+    format!(
+        "{}\t{} {}\tclient {}\tkey {}\twaiting {}s",
+        session.id, kind, site, session.client, session.key_id, session.waiting_secs
+    )
+}
@@ -0,0 +1,436 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use openssl::hash::{hash, MessageDigest};
+use serde_json;
+use u2f_core::{Counter, SigningContext, SigningKind};
+
+// Hash chained to by the first entry of a log
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    Keygen,
+    // A key claimed for a registration
+    Register,
+    Sign,
+    // A signature refused because its counter did not increase
+    CounterNotIncreasing,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditRecord {
+    pub event: AuditEvent,
+    pub key_id: String,
+    pub client: String,
+    // Base64 SHA-256 of a signing context's message, the digest gotham signs
+    // once the SignSecond route has checked it against the context
+    pub message_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counter: Option<Counter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<SigningKind>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site: Option<String>,
+}
+
+impl AuditRecord {
+    // Records an event about a signing session for its context
+    pub fn signing(
+        event: AuditEvent,
+        key_id: &str,
+        client: &str,
+        context: &SigningContext,
+    ) -> AuditRecord {
+        AuditRecord {
+            event,
+            key_id: key_id.to_string(),
+            client: client.to_string(),
+            message_hash: Some(context.message_hash()),
+            counter: context.counter,
+            kind: Some(context.kind),
+            site: context.site.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct EntryBody {
+    seq: u64,
+    timestamp: u64,
+    #[serde(flatten)]
+    record: AuditRecord,
+    prev_hash: String,
+}
+
+// One line of the log. The hash covers the serialized body, which itself
+// includes the previous entry's hash.
+#[derive(Serialize, Deserialize, Debug)]
+struct Entry {
+    #[serde(flatten)]
+    body: EntryBody,
+    hash: String,
+}
+
+// Kept alongside the log so truncating it can be detected
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Head {
+    entries: u64,
+    hash: String,
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum AuditError {
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            display("I/O error: {}", err)
+        }
+        Malformed(line: u64, err: serde_json::Error) {
+            cause(err)
+            display("Malformed entry on line {}: {}", line, err)
+        }
+        OutOfSequence(line: u64) {
+            display("Entry on line {} is out of sequence", line)
+        }
+        BrokenChain(line: u64) {
+            display("Entry on line {} does not match its hash chain", line)
+        }
+        MissingHead {
+            display("Log head file is missing")
+        }
+        Truncated(expected: u64, found: u64) {
+            display("Log has {} entries but its head records {}", found, expected)
+        }
+        HeadMismatch {
+            display("Last entry does not match the log head")
+        }
+        Torn(line: u64) {
+            display("Entry on line {} is incomplete", line)
+        }
+    }
+}
+
+fn head_path(path: &Path) -> PathBuf {
+    let mut head = path.as_os_str().to_owned();
+    head.push(".head");
+    PathBuf::from(head)
+}
+
+fn hash_body(body: &EntryBody) -> String {
+    let serialized = serde_json::to_vec(body).unwrap();
+    hash(MessageDigest::sha256(), &serialized)
+        .unwrap()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn genesis_head() -> Head {
+    Head {
+        entries: 0,
+        hash: String::from(GENESIS_HASH),
+    }
+}
+
+fn read_head(path: &Path) -> Result<Option<Head>, AuditError> {
+    match File::open(head_path(path)) {
+        Ok(file) => Ok(Some(
+            serde_json::from_reader(file).map_err(io::Error::from)?,
+        )),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn write_head(path: &Path, head: &Head) -> io::Result<()> {
+    let final_path = head_path(path);
+    let mut tmp_path = final_path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    serde_json::to_writer(&mut tmp, head)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, &final_path)
+}
+
+// Checks every entry's hash chain and that the log matches its head,
+// returning the number of entries
+pub fn verify(path: &Path) -> Result<u64, AuditError> {
+    verified_head(path).map(|head| head.entries)
+}
+
+fn verified_head(path: &Path) -> Result<Head, AuditError> {
+    let chain = verify_chain(path)?;
+    if chain.torn {
+        return Err(AuditError::Torn(chain.head.entries + 1));
+    }
+    check_head(path, &chain.head)?;
+    Ok(chain.head)
+}
+
+fn check_head(path: &Path, head: &Head) -> Result<(), AuditError> {
+    match read_head(path)? {
+        None if head.entries == 0 => Ok(()),
+        None => Err(AuditError::MissingHead),
+        Some(ref recorded) if recorded.entries != head.entries => {
+            Err(AuditError::Truncated(recorded.entries, head.entries))
+        }
+        Some(ref recorded) if recorded.hash != head.hash => Err(AuditError::HeadMismatch),
+        Some(_) => Ok(()),
+    }
+}
+
+// Recovers from an append that was cut short, which leaves either a torn
+// last line or a head one entry behind the log, then checks the log as
+// verify does
+fn recovered_head(path: &Path) -> Result<Head, AuditError> {
+    let chain = verify_chain(path)?;
+    let recorded = read_head(path)?.unwrap_or_else(genesis_head);
+    let lagging = chain.head.entries == recorded.entries + 1 && chain.prev == recorded;
+    if !lagging {
+        check_head(path, &chain.head)?;
+    }
+    if chain.torn {
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(chain.len)?;
+    }
+    if lagging {
+        write_head(path, &chain.head)?;
+    }
+    Ok(chain.head)
+}
+
+// The entries of a log whose hash chain verified
+struct Chain {
+    head: Head,
+    // The head before the last entry was appended
+    prev: Head,
+    // Length of the verified entries, which is short of the log's if torn
+    len: u64,
+    // Whether the last line is missing its newline, as the entry was not
+    // completely written
+    torn: bool,
+}
+
+fn verify_chain(path: &Path) -> Result<Chain, AuditError> {
+    let mut chain = Chain {
+        head: genesis_head(),
+        prev: genesis_head(),
+        len: 0,
+        torn: false,
+    };
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(chain),
+        Err(err) => return Err(err.into()),
+    };
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if line.last() != Some(&b'\n') {
+            chain.torn = true;
+            break;
+        }
+        let line_number = chain.head.entries + 1;
+        let entry: Entry =
+            serde_json::from_slice(&line).map_err(|err| AuditError::Malformed(line_number, err))?;
+        if entry.body.seq != chain.head.entries {
+            return Err(AuditError::OutOfSequence(line_number));
+        }
+        if entry.body.prev_hash != chain.head.hash || hash_body(&entry.body) != entry.hash {
+            return Err(AuditError::BrokenChain(line_number));
+        }
+        chain.len += line.len() as u64;
+        let head = Head {
+            entries: chain.head.entries + 1,
+            hash: entry.hash,
+        };
+        chain.prev = mem::replace(&mut chain.head, head);
+    }
+    Ok(chain)
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    state: Mutex<(File, Head)>,
+}
+
+impl AuditLog {
+    // Opens an existing log for appending, finishing any append that was
+    // cut short and refusing a log that otherwise fails verification
+    pub fn open(path: &Path) -> Result<AuditLog, AuditError> {
+        let head = recovered_head(path)?;
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            path: path.to_path_buf(),
+            state: Mutex::new((file, head)),
+        })
+    }
+
+    pub fn append(&self, record: AuditRecord) -> io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        self.append_at(record, timestamp)
+    }
+
+    fn append_at(&self, record: AuditRecord, timestamp: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let (ref mut file, ref mut head) = *state;
+        let body = EntryBody {
+            seq: head.entries,
+            timestamp,
+            record,
+            prev_hash: head.hash.clone(),
+        };
+        let entry = Entry {
+            hash: hash_body(&body),
+            body,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        file.sync_data()?;
+
+        *head = Head {
+            entries: head.entries + 1,
+            hash: entry.hash,
+        };
+        write_head(&self.path, head)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use super::*;
+
+    fn record(key_id: &str) -> AuditRecord {
+        AuditRecord {
+            event: AuditEvent::Sign,
+            key_id: key_id.to_string(),
+            client: String::from("127.0.0.1"),
+            message_hash: Some(String::from("aGFzaA==")),
+            counter: Some(1),
+            kind: Some(SigningKind::Authentication),
+            site: None,
+        }
+    }
+
+    fn write_log(path: &Path, entries: usize) {
+        let log = AuditLog::open(path).unwrap();
+        for i in 0..entries {
+            log.append_at(record(&i.to_string()), i as u64).unwrap();
+        }
+    }
+
+    #[test]
+    fn appended_log_verifies() {
+        let dir = TempDir::new("audit").unwrap();
+        let path = dir.path().join("audit.log");
+
+        write_log(&path, 3);
+        assert_eq!(verify(&path).unwrap(), 3);
+
+        write_log(&path, 2);
+        assert_eq!(verify(&path).unwrap(), 5);
+    }
+
+    #[test]
+    fn modified_entry_is_detected() {
+        let dir = TempDir::new("audit").unwrap();
+        let path = dir.path().join("audit.log");
+        write_log(&path, 3);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::write(
+            &path,
+            contents.replacen("\"key_id\":\"1\"", "\"key_id\":\"7\"", 1),
+        )
+        .unwrap();
+
+        match verify(&path) {
+            Err(AuditError::BrokenChain(2)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn truncation_is_detected() {
+        let dir = TempDir::new("audit").unwrap();
+        let path = dir.path().join("audit.log");
+        write_log(&path, 3);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let kept: Vec<&str> = contents.lines().take(2).collect();
+        fs::write(&path, kept.join("\n") + "\n").unwrap();
+
+        match verify(&path) {
+            Err(AuditError::Truncated(3, 2)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(AuditLog::open(&path).is_err());
+    }
+
+    #[test]
+    fn append_without_head_is_recovered() {
+        let dir = TempDir::new("audit").unwrap();
+        let path = dir.path().join("audit.log");
+        write_log(&path, 2);
+        let head = fs::read(head_path(&path)).unwrap();
+        write_log(&path, 1);
+        fs::write(head_path(&path), head).unwrap();
+
+        match verify(&path) {
+            Err(AuditError::Truncated(2, 3)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        write_log(&path, 1);
+        assert_eq!(verify(&path).unwrap(), 4);
+    }
+
+    #[test]
+    fn torn_entry_is_recovered() {
+        let dir = TempDir::new("audit").unwrap();
+        let path = dir.path().join("audit.log");
+        write_log(&path, 2);
+        let contents = fs::read_to_string(&path).unwrap();
+        let torn = contents.lines().next().unwrap();
+        fs::write(&path, contents.clone() + &torn[..torn.len() / 2]).unwrap();
+
+        match verify(&path) {
+            Err(AuditError::Torn(3)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        write_log(&path, 1);
+        assert_eq!(verify(&path).unwrap(), 3);
+        assert!(fs::read_to_string(&path).unwrap().starts_with(&contents));
+    }
+
+    #[test]
+    fn only_the_last_append_is_recovered() {
+        let dir = TempDir::new("audit").unwrap();
+        let path = dir.path().join("audit.log");
+        write_log(&path, 1);
+        let head = fs::read(head_path(&path)).unwrap();
+        write_log(&path, 2);
+        fs::write(head_path(&path), head).unwrap();
+
+        assert!(AuditLog::open(&path).is_err());
+    }
+}
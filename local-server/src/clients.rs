@@ -0,0 +1,915 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libc;
+use openssl::rand::rand_bytes;
+use serde_json;
+use u2f_core::{CosignerCredential, Counter};
+
+use atomic_file;
+
+const CLIENT_ID_BYTES: usize = 16;
+const SECRET_BYTES: usize = 32;
+
+#[derive(Serialize, Deserialize, Clone)]
+struct EnrolledClient {
+    name: String,
+    enrolled_at: u64,
+    credential: CosignerCredential,
+    #[serde(default)]
+    revoked: bool,
+    // Name of the account the client was enrolled into, if any
+    #[serde(default)]
+    account: Option<String>,
+}
+
+// May accept or deny signatures held for an approver, but not sign
+#[derive(Serialize, Deserialize, Clone)]
+struct EnrolledApprover {
+    name: String,
+    enrolled_at: u64,
+    credential: CosignerCredential,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Account {
+    created_at: u64,
+    // Most unrevoked keys the account's clients may own together
+    max_keys: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Data {
+    #[serde(default)]
+    accounts: HashMap<String, Account>,
+    #[serde(default)]
+    clients: HashMap<String, EnrolledClient>,
+    #[serde(default)]
+    approvers: HashMap<String, EnrolledApprover>,
+    // Key id to the id of the client that generated it
+    #[serde(default)]
+    keys: HashMap<String, String>,
+    // Keys generated ahead of time that no registration has claimed yet.
+    // They do not count towards the account's key limit and can not sign.
+    #[serde(default)]
+    unclaimed_keys: HashSet<String>,
+    #[serde(default)]
+    revoked_keys: HashSet<String>,
+    // Key id to the last counter the co-signer approved for it
+    #[serde(default)]
+    counters: HashMap<String, Counter>,
+}
+
+impl Data {
+    fn account_of(&self, client_id: &str) -> Option<&str> {
+        self.clients
+            .get(client_id)
+            .and_then(|client| client.account.as_ref())
+            .map(String::as_str)
+    }
+
+    fn keys_of_account<'a>(
+        &'a self,
+        account: &'a str,
+    ) -> impl Iterator<Item = (&'a String, &'a String)> {
+        self.keys
+            .iter()
+            .filter(move |&(_, owner)| self.account_of(owner) == Some(account))
+    }
+
+    // Revoked and unclaimed keys do not count towards the account's key
+    // limit
+    fn unrevoked_key_count(&self, account: &str) -> usize {
+        self.keys_of_account(account)
+            .filter(|&(key_id, _)| {
+                !self.revoked_keys.contains(key_id) && !self.unclaimed_keys.contains(key_id)
+            })
+            .count()
+    }
+
+    // The account's key limit, if its clients already own that many keys
+    fn quota_reached(&self, client_id: &str) -> Option<usize> {
+        let account = self.account_of(client_id)?;
+        let max_keys = self.accounts.get(account)?.max_keys?;
+        if self.unrevoked_key_count(account) >= max_keys {
+            Some(max_keys)
+        } else {
+            None
+        }
+    }
+}
+
+// Whether a client may use a key
+#[derive(Debug, PartialEq)]
+pub enum KeyAccess {
+    Allowed,
+    OwnedByOther,
+    // Generated ahead of time, the key must be claimed before it signs
+    Unclaimed,
+    // A new key would take the client's account over its limit
+    QuotaReached { max_keys: usize },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AccountSummary {
+    pub name: String,
+    pub max_keys: Option<usize>,
+    pub clients: usize,
+    pub keys: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct KeySummary {
+    pub key_id: String,
+    pub client_id: String,
+    pub client_name: String,
+    pub revoked: bool,
+    pub claimed: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CounterCheck {
+    Advanced,
+    // The counter did not increase, a sign the key share was copied
+    NotIncreasing { last: Counter },
+}
+
+// Enrolled clients and the keys they own, kept in a file that is re-read on
+// every operation so enrollments made while the server runs take effect.
+// The running server and the admin subcommands update it from separate
+// processes, so each operation holds an exclusive lock on a file beside it.
+pub struct ClientRegistry {
+    path: PathBuf,
+    lock_path: PathBuf,
+}
+
+impl ClientRegistry {
+    pub fn new(data_dir: &Path) -> ClientRegistry {
+        ClientRegistry {
+            path: data_dir.join("clients.json"),
+            lock_path: data_dir.join("clients.json.lock"),
+        }
+    }
+
+    // The lock is released when the returned file is closed. It is taken on
+    // a separate file because writes replace clients.json.
+    fn lock(&self) -> io::Result<File> {
+        if let Some(directory) = self.lock_path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(&self.lock_path)?;
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+                return Ok(file);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    fn read(&self) -> io::Result<Data> {
+        match File::open(&self.path) {
+            Ok(file) => serde_json::from_reader(file).map_err(|e| e.into()),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Data::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn write(&self, data: &Data) -> io::Result<()> {
+        atomic_file::overwrite(&self.path, move |writer| {
+            serde_json::to_writer_pretty(writer, &data).map_err(|e| e.into())
+        })
+    }
+
+    pub fn enroll(&self, name: &str) -> io::Result<CosignerCredential> {
+        let _lock = self.lock()?;
+        let mut data = self.read()?;
+        let credential = enroll_client(&mut data, name, None)?;
+        self.write(&data)?;
+        Ok(credential)
+    }
+
+    // Enrolls a client into an account, or returns None if there is no
+    // such account
+    pub fn enroll_into_account(
+        &self,
+        name: &str,
+        account: &str,
+    ) -> io::Result<Option<CosignerCredential>> {
+        let _lock = self.lock()?;
+        let mut data = self.read()?;
+        if !data.accounts.contains_key(account) {
+            return Ok(None);
+        }
+        let credential = enroll_client(&mut data, name, Some(account))?;
+        self.write(&data)?;
+        Ok(Some(credential))
+    }
+
+    // Returns false if the account already exists
+    pub fn create_account(&self, name: &str, max_keys: Option<usize>) -> io::Result<bool> {
+        let _lock = self.lock()?;
+        let mut data = self.read()?;
+        if data.accounts.contains_key(name) {
+            return Ok(false);
+        }
+        data.accounts.insert(
+            name.to_string(),
+            Account {
+                created_at: now(),
+                max_keys,
+            },
+        );
+        self.write(&data)?;
+        Ok(true)
+    }
+
+    // Returns false if there is no such account. Keys over a lowered limit
+    // are kept, but no more are generated.
+    pub fn set_max_keys(&self, name: &str, max_keys: Option<usize>) -> io::Result<bool> {
+        let _lock = self.lock()?;
+        let mut data = self.read()?;
+        match data.accounts.get_mut(name) {
+            Some(account) => account.max_keys = max_keys,
+            None => return Ok(false),
+        }
+        self.write(&data)?;
+        Ok(true)
+    }
+
+    pub fn accounts(&self) -> io::Result<Vec<AccountSummary>> {
+        let _lock = self.lock()?;
+        let data = self.read()?;
+        let mut accounts: Vec<AccountSummary> = data
+            .accounts
+            .iter()
+            .map(|(name, account)| AccountSummary {
+                name: name.clone(),
+                max_keys: account.max_keys,
+                clients: data
+                    .clients
+                    .values()
+                    .filter(|client| client.account.as_ref() == Some(name))
+                    .count(),
+                keys: data.unrevoked_key_count(name),
+            })
+            .collect();
+        accounts.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(accounts)
+    }
+
+    // The keys owned by an account's clients, or None if there is no such
+    // account
+    pub fn account_keys(&self, name: &str) -> io::Result<Option<Vec<KeySummary>>> {
+        let _lock = self.lock()?;
+        let data = self.read()?;
+        if !data.accounts.contains_key(name) {
+            return Ok(None);
+        }
+        let mut keys: Vec<KeySummary> = data
+            .keys_of_account(name)
+            .map(|(key_id, client_id)| KeySummary {
+                key_id: key_id.clone(),
+                client_id: client_id.clone(),
+                client_name: data.clients[client_id].name.clone(),
+                revoked: data.revoked_keys.contains(key_id),
+                claimed: !data.unclaimed_keys.contains(key_id),
+            })
+            .collect();
+        keys.sort_by(|a, b| a.key_id.cmp(&b.key_id));
+        Ok(Some(keys))
+    }
+
+    // Returns the full credential of the client presenting the bearer token
+    pub fn authenticate(&self, token: &str) -> io::Result<Option<CosignerCredential>> {
+        let presented = match CosignerCredential::from_token(token) {
+            Some(credential) => credential,
+            None => return Ok(None),
+        };
+        let _lock = self.lock()?;
+        Ok(self
+            .read()?
+            .clients
+            .get(&presented.client_id)
+            .map(|client| &client.credential)
+            .filter(|enrolled| enrolled.secret_matches(&presented))
+            .cloned())
+    }
+
+    // Approvers are kept apart from clients, so neither's credential is
+    // accepted in place of the other's
+    pub fn enroll_approver(&self, name: &str) -> io::Result<CosignerCredential> {
+        let _lock = self.lock()?;
+        let mut data = self.read()?;
+        let credential = new_credential()?;
+        data.approvers.insert(
+            credential.client_id.clone(),
+            EnrolledApprover {
+                name: name.to_string(),
+                enrolled_at: now(),
+                credential: credential.clone(),
+            },
+        );
+        self.write(&data)?;
+        Ok(credential)
+    }
+
+    // Returns the name of the approver presenting the bearer token
+    pub fn authenticate_approver(&self, token: &str) -> io::Result<Option<String>> {
+        let presented = match CosignerCredential::from_token(token) {
+            Some(credential) => credential,
+            None => return Ok(None),
+        };
+        let _lock = self.lock()?;
+        Ok(self
+            .read()?
+            .approvers
+            .get(&presented.client_id)
+            .filter(|approver| approver.credential.secret_matches(&presented))
+            .map(|approver| approver.name.clone()))
+    }
+
+    // Records the client as the owner of a key it is generating. The key
+    // is left unclaimed, as clients generate keys ahead of time.
+    pub fn record_generated_key(&self, client_id: &str, key_id: &str) -> io::Result<KeyAccess> {
+        let _lock = self.lock()?;
+        let mut data = self.read()?;
+        if let Some(owner) = data.keys.get(key_id) {
+            return Ok(if owner == client_id {
+                KeyAccess::Allowed
+            } else {
+                KeyAccess::OwnedByOther
+            });
+        }
+        data.keys.insert(key_id.to_string(), client_id.to_string());
+        data.unclaimed_keys.insert(key_id.to_string());
+        self.write(&data)?;
+        Ok(KeyAccess::Allowed)
+    }
+
+    // Claims a key for a registration, recording the client as its owner
+    // if it has none. The key then counts towards the account's limit, so
+    // it is refused if the account has reached it.
+    pub fn claim_key(&self, client_id: &str, key_id: &str) -> io::Result<KeyAccess> {
+        let _lock = self.lock()?;
+        let mut data = self.read()?;
+        match data.keys.get(key_id) {
+            Some(owner) if owner != client_id => return Ok(KeyAccess::OwnedByOther),
+            Some(_) if !data.unclaimed_keys.contains(key_id) => return Ok(KeyAccess::Allowed),
+            _ => {}
+        }
+        if let Some(max_keys) = data.quota_reached(client_id) {
+            return Ok(KeyAccess::QuotaReached { max_keys });
+        }
+        data.keys.insert(key_id.to_string(), client_id.to_string());
+        data.unclaimed_keys.remove(key_id);
+        self.write(&data)?;
+        Ok(KeyAccess::Allowed)
+    }
+
+    // Checked before a keygen starts, as its key id is not known yet
+    pub fn may_generate_key(&self, client_id: &str) -> io::Result<KeyAccess> {
+        let _lock = self.lock()?;
+        Ok(match self.read()?.quota_reached(client_id) {
+            Some(max_keys) => KeyAccess::QuotaReached { max_keys },
+            None => KeyAccess::Allowed,
+        })
+    }
+
+    // Signing needs a claimed key the client owns. A key with no owner yet
+    // is claimed by the client when adopting, otherwise it is refused.
+    pub fn may_sign(&self, client_id: &str, key_id: &str, adopt: bool) -> io::Result<KeyAccess> {
+        if adopt {
+            return self.claim_key(client_id, key_id);
+        }
+        let _lock = self.lock()?;
+        let data = self.read()?;
+        Ok(match data.keys.get(key_id) {
+            Some(owner) if owner != client_id => KeyAccess::OwnedByOther,
+            Some(_) if data.unclaimed_keys.contains(key_id) => KeyAccess::Unclaimed,
+            Some(_) => KeyAccess::Allowed,
+            None => KeyAccess::OwnedByOther,
+        })
+    }
+
+    pub fn owns_key(&self, client_id: &str, key_id: &str) -> io::Result<bool> {
+        let _lock = self.lock()?;
+        Ok(self
+            .read()?
+            .keys
+            .get(key_id)
+            .map_or(false, |owner| owner == client_id))
+    }
+
+    // Revokes a client and every key it owns, returning the revoked key
+    // ids, or None if no such client is enrolled
+    pub fn revoke_client(&self, client_id: &str) -> io::Result<Option<Vec<String>>> {
+        let _lock = self.lock()?;
+        let mut data = self.read()?;
+        match data.clients.get_mut(client_id) {
+            Some(client) => client.revoked = true,
+            None => return Ok(None),
+        }
+        let mut key_ids: Vec<String> = data
+            .keys
+            .iter()
+            .filter(|&(_, owner)| owner == client_id)
+            .map(|(key_id, _)| key_id.clone())
+            .collect();
+        key_ids.sort();
+        data.revoked_keys.extend(key_ids.iter().cloned());
+        self.write(&data)?;
+        Ok(Some(key_ids))
+    }
+
+    // Revokes a single key, returning false if no client owns it
+    pub fn revoke_key(&self, key_id: &str) -> io::Result<bool> {
+        let _lock = self.lock()?;
+        let mut data = self.read()?;
+        if !data.keys.contains_key(key_id) {
+            return Ok(false);
+        }
+        data.revoked_keys.insert(key_id.to_string());
+        self.write(&data)?;
+        Ok(true)
+    }
+
+    // Records the counter of a signature about to be co-signed, unless it
+    // is not greater than the last one recorded for the key
+    pub fn advance_counter(&self, key_id: &str, counter: Counter) -> io::Result<CounterCheck> {
+        let _lock = self.lock()?;
+        let mut data = self.read()?;
+        if let Some(&last) = data.counters.get(key_id) {
+            if counter <= last {
+                return Ok(CounterCheck::NotIncreasing { last });
+            }
+        }
+        data.counters.insert(key_id.to_string(), counter);
+        self.write(&data)?;
+        Ok(CounterCheck::Advanced)
+    }
+
+    // Whether the client, or the given key, has been revoked
+    pub fn is_revoked(&self, client_id: &str, key_id: Option<&str>) -> io::Result<bool> {
+        let _lock = self.lock()?;
+        let data = self.read()?;
+        let client_revoked = data
+            .clients
+            .get(client_id)
+            .map_or(false, |client| client.revoked);
+        let key_revoked = key_id.map_or(false, |key_id| data.revoked_keys.contains(key_id));
+        Ok(client_revoked || key_revoked)
+    }
+}
+
+fn enroll_client(
+    data: &mut Data,
+    name: &str,
+    account: Option<&str>,
+) -> io::Result<CosignerCredential> {
+    let credential = new_credential()?;
+    data.clients.insert(
+        credential.client_id.clone(),
+        EnrolledClient {
+            name: name.to_string(),
+            enrolled_at: now(),
+            credential: credential.clone(),
+            revoked: false,
+            account: account.map(str::to_string),
+        },
+    );
+    Ok(credential)
+}
+
+fn new_credential() -> io::Result<CosignerCredential> {
+    Ok(CosignerCredential::new(
+        hex(&random_bytes(CLIENT_ID_BYTES)?),
+        random_bytes(SECRET_BYTES)?,
+    ))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+fn random_bytes(len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; len];
+    rand_bytes(&mut bytes).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    Ok(bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn enrolled_client_authenticates() {
+        let dir = TempDir::new("clients").unwrap();
+        let registry = ClientRegistry::new(dir.path());
+
+        let credential = registry.enroll("laptop").unwrap();
+        let authenticated = registry
+            .authenticate(&credential.to_token())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(authenticated.client_id, credential.client_id);
+    }
+
+    #[test]
+    fn unenrolled_client_is_rejected() {
+        let dir = TempDir::new("clients").unwrap();
+        let registry = ClientRegistry::new(dir.path());
+        let enrolled = registry.enroll("laptop").unwrap();
+        let forged = CosignerCredential::new(enrolled.client_id, vec![0u8; SECRET_BYTES]);
+
+        assert!(registry.authenticate(&forged.to_token()).unwrap().is_none());
+        assert!(registry.authenticate("garbage").unwrap().is_none());
+    }
+
+    #[test]
+    fn enrollments_from_two_registries_are_kept() {
+        let dir = TempDir::new("clients").unwrap();
+        let server = ClientRegistry::new(dir.path());
+        let admin = ClientRegistry::new(dir.path());
+
+        let enrolling = thread::spawn(move || {
+            (0..10)
+                .map(|i| server.enroll(&format!("laptop{}", i)).unwrap())
+                .collect::<Vec<_>>()
+        });
+        let mut credentials: Vec<CosignerCredential> = (0..10)
+            .map(|i| admin.enroll(&format!("desktop{}", i)).unwrap())
+            .collect();
+        credentials.extend(enrolling.join().unwrap());
+
+        for credential in credentials {
+            assert!(admin
+                .authenticate(&credential.to_token())
+                .unwrap()
+                .is_some());
+        }
+    }
+
+    #[test]
+    fn approvers_and_clients_authenticate_apart() {
+        let dir = TempDir::new("clients").unwrap();
+        let registry = ClientRegistry::new(dir.path());
+        let client = registry.enroll("laptop").unwrap();
+        let approver = registry.enroll_approver("phone").unwrap();
+
+        assert_eq!(
+            registry
+                .authenticate_approver(&approver.to_token())
+                .unwrap(),
+            Some(String::from("phone"))
+        );
+        assert!(registry
+            .authenticate_approver(&client.to_token())
+            .unwrap()
+            .is_none());
+        assert!(registry
+            .authenticate(&approver.to_token())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn keys_belong_to_first_claimant() {
+        let dir = TempDir::new("clients").unwrap();
+        let registry = ClientRegistry::new(dir.path());
+
+        assert_eq!(registry.claim_key("a", "key").unwrap(), KeyAccess::Allowed);
+        assert_eq!(registry.claim_key("a", "key").unwrap(), KeyAccess::Allowed);
+        assert_eq!(
+            registry.claim_key("b", "key").unwrap(),
+            KeyAccess::OwnedByOther
+        );
+        assert!(registry.owns_key("a", "key").unwrap());
+        assert!(!registry.owns_key("b", "key").unwrap());
+    }
+
+    #[test]
+    fn unclaimed_keys_are_adopted_only_when_allowed() {
+        let dir = TempDir::new("clients").unwrap();
+        let registry = ClientRegistry::new(dir.path());
+
+        assert_eq!(
+            registry.may_sign("a", "old-key", false).unwrap(),
+            KeyAccess::OwnedByOther
+        );
+        assert!(!registry.owns_key("a", "old-key").unwrap());
+
+        assert_eq!(
+            registry.may_sign("a", "old-key", true).unwrap(),
+            KeyAccess::Allowed
+        );
+        assert_eq!(
+            registry.may_sign("b", "old-key", true).unwrap(),
+            KeyAccess::OwnedByOther
+        );
+        assert_eq!(
+            registry.may_sign("a", "old-key", false).unwrap(),
+            KeyAccess::Allowed
+        );
+    }
+
+    #[test]
+    fn counters_must_increase() {
+        let dir = TempDir::new("clients").unwrap();
+        let registry = ClientRegistry::new(dir.path());
+
+        assert_eq!(
+            registry.advance_counter("key", 5).unwrap(),
+            CounterCheck::Advanced
+        );
+        assert_eq!(
+            registry.advance_counter("key", 5).unwrap(),
+            CounterCheck::NotIncreasing { last: 5 }
+        );
+        assert_eq!(
+            registry.advance_counter("key", 3).unwrap(),
+            CounterCheck::NotIncreasing { last: 5 }
+        );
+        assert_eq!(
+            registry.advance_counter("key", 6).unwrap(),
+            CounterCheck::Advanced
+        );
+        assert_eq!(
+            registry.advance_counter("other", 1).unwrap(),
+            CounterCheck::Advanced
+        );
+    }
+
+    #[test]
+    fn revoking_a_client_revokes_its_keys() {
+        let dir = TempDir::new("clients").unwrap();
+        let registry = ClientRegistry::new(dir.path());
+        let lost = registry.enroll("lost laptop").unwrap();
+        let other = registry.enroll("desktop").unwrap();
+        registry.claim_key(&lost.client_id, "key1").unwrap();
+        registry.claim_key(&lost.client_id, "key2").unwrap();
+        registry.claim_key(&other.client_id, "key3").unwrap();
+
+        let revoked = registry.revoke_client(&lost.client_id).unwrap().unwrap();
+
+        assert_eq!(revoked, vec!["key1", "key2"]);
+        assert!(registry.is_revoked(&lost.client_id, None).unwrap());
+        assert!(registry.is_revoked(&lost.client_id, Some("key1")).unwrap());
+        assert!(!registry.is_revoked(&other.client_id, Some("key3")).unwrap());
+        assert!(registry.revoke_client("unknown").unwrap().is_none());
+    }
+
+    #[test]
+    fn revoking_a_key_leaves_other_keys() {
+        let dir = TempDir::new("clients").unwrap();
+        let registry = ClientRegistry::new(dir.path());
+        registry.claim_key("a", "key1").unwrap();
+        registry.claim_key("a", "key2").unwrap();
+
+        assert!(registry.revoke_key("key1").unwrap());
+
+        assert!(registry.is_revoked("a", Some("key1")).unwrap());
+        assert!(!registry.is_revoked("a", Some("key2")).unwrap());
+        assert!(!registry.is_revoked("a", None).unwrap());
+        assert!(!registry.revoke_key("unknown").unwrap());
+    }
+
+    #[test]
+    fn revocations_survive_concurrent_updates() {
+        let dir = TempDir::new("clients").unwrap();
+        let server = ClientRegistry::new(dir.path());
+        let admin = ClientRegistry::new(dir.path());
+        let key_ids: Vec<String> = (0..20).map(|i| format!("key{}", i)).collect();
+        for key_id in &key_ids {
+            server.claim_key("a", key_id).unwrap();
+        }
+
+        let signed = key_ids.clone();
+        let signing = thread::spawn(move || {
+            for key_id in &signed {
+                server.advance_counter(key_id, 1).unwrap();
+            }
+        });
+        for key_id in &key_ids {
+            assert!(admin.revoke_key(key_id).unwrap());
+        }
+        signing.join().unwrap();
+
+        for key_id in &key_ids {
+            assert!(admin.is_revoked("a", Some(key_id)).unwrap());
+            assert_eq!(
+                admin.advance_counter(key_id, 1).unwrap(),
+                CounterCheck::NotIncreasing { last: 1 }
+            );
+        }
+    }
+
+    #[test]
+    fn accounts_limit_keys_across_their_clients() {
+        let dir = TempDir::new("clients").unwrap();
+        let registry = ClientRegistry::new(dir.path());
+        assert!(registry.create_account("alice", Some(2)).unwrap());
+        assert!(!registry.create_account("alice", None).unwrap());
+        let laptop = registry
+            .enroll_into_account("laptop", "alice")
+            .unwrap()
+            .unwrap();
+        let phone = registry
+            .enroll_into_account("phone", "alice")
+            .unwrap()
+            .unwrap();
+        registry.claim_key(&laptop.client_id, "key1").unwrap();
+        registry.claim_key(&phone.client_id, "key2").unwrap();
+
+        let reached = KeyAccess::QuotaReached { max_keys: 2 };
+        assert_eq!(
+            registry.may_generate_key(&laptop.client_id).unwrap(),
+            reached
+        );
+        assert_eq!(
+            registry.claim_key(&phone.client_id, "key3").unwrap(),
+            reached
+        );
+        // Keys already owned stay usable
+        assert_eq!(
+            registry.claim_key(&laptop.client_id, "key1").unwrap(),
+            KeyAccess::Allowed
+        );
+
+        registry.revoke_key("key1").unwrap();
+        assert_eq!(
+            registry.claim_key(&phone.client_id, "key3").unwrap(),
+            KeyAccess::Allowed
+        );
+    }
+
+    #[test]
+    fn generated_keys_count_once_claimed() {
+        let dir = TempDir::new("clients").unwrap();
+        let registry = ClientRegistry::new(dir.path());
+        registry.create_account("alice", Some(1)).unwrap();
+        let laptop = registry
+            .enroll_into_account("laptop", "alice")
+            .unwrap()
+            .unwrap();
+        for key_id in &["pooled1", "pooled2"] {
+            assert_eq!(
+                registry
+                    .record_generated_key(&laptop.client_id, key_id)
+                    .unwrap(),
+                KeyAccess::Allowed
+            );
+        }
+        assert_eq!(
+            registry.record_generated_key("other", "pooled1").unwrap(),
+            KeyAccess::OwnedByOther
+        );
+
+        // Pooled keys neither count nor sign until claimed
+        assert_eq!(registry.accounts().unwrap()[0].keys, 0);
+        assert_eq!(
+            registry
+                .may_sign(&laptop.client_id, "pooled1", false)
+                .unwrap(),
+            KeyAccess::Unclaimed
+        );
+
+        assert_eq!(
+            registry.claim_key(&laptop.client_id, "pooled1").unwrap(),
+            KeyAccess::Allowed
+        );
+        assert_eq!(
+            registry
+                .may_sign(&laptop.client_id, "pooled1", false)
+                .unwrap(),
+            KeyAccess::Allowed
+        );
+        assert_eq!(
+            registry.claim_key(&laptop.client_id, "pooled2").unwrap(),
+            KeyAccess::QuotaReached { max_keys: 1 }
+        );
+        let keys = registry.account_keys("alice").unwrap().unwrap();
+        assert!(keys[0].claimed);
+        assert!(!keys[1].claimed);
+    }
+
+    #[test]
+    fn quota_holds_across_registries() {
+        let dir = TempDir::new("clients").unwrap();
+        let first = ClientRegistry::new(dir.path());
+        let second = ClientRegistry::new(dir.path());
+        first.create_account("alice", Some(5)).unwrap();
+        let laptop = first
+            .enroll_into_account("laptop", "alice")
+            .unwrap()
+            .unwrap();
+        let phone = first
+            .enroll_into_account("phone", "alice")
+            .unwrap()
+            .unwrap();
+
+        let claiming = thread::spawn(move || {
+            (0..10)
+                .filter(|i| {
+                    first
+                        .claim_key(&laptop.client_id, &format!("laptop-key{}", i))
+                        .unwrap()
+                        == KeyAccess::Allowed
+                })
+                .count()
+        });
+        let claimed = (0..10)
+            .filter(|i| {
+                second
+                    .claim_key(&phone.client_id, &format!("phone-key{}", i))
+                    .unwrap()
+                    == KeyAccess::Allowed
+            })
+            .count();
+
+        assert_eq!(claimed + claiming.join().unwrap(), 5);
+        assert_eq!(second.accounts().unwrap()[0].keys, 5);
+    }
+
+    #[test]
+    fn accounts_list_only_their_keys() {
+        let dir = TempDir::new("clients").unwrap();
+        let registry = ClientRegistry::new(dir.path());
+        registry.create_account("alice", None).unwrap();
+        registry.create_account("bob", Some(1)).unwrap();
+        let alice = registry
+            .enroll_into_account("laptop", "alice")
+            .unwrap()
+            .unwrap();
+        let bob = registry
+            .enroll_into_account("desktop", "bob")
+            .unwrap()
+            .unwrap();
+        registry.claim_key(&alice.client_id, "key1").unwrap();
+        registry.claim_key(&bob.client_id, "key2").unwrap();
+        registry.claim_key(&alice.client_id, "key3").unwrap();
+        registry.revoke_key("key3").unwrap();
+
+        let keys = registry.account_keys("alice").unwrap().unwrap();
+        assert_eq!(
+            keys,
+            vec![
+                KeySummary {
+                    key_id: String::from("key1"),
+                    client_id: alice.client_id.clone(),
+                    client_name: String::from("laptop"),
+                    revoked: false,
+                    claimed: true,
+                },
+                KeySummary {
+                    key_id: String::from("key3"),
+                    client_id: alice.client_id,
+                    client_name: String::from("laptop"),
+                    revoked: true,
+                    claimed: true,
+                },
+            ]
+        );
+        // Revoked keys are listed but not counted
+        assert_eq!(registry.accounts().unwrap()[0].keys, 1);
+        assert_eq!(
+            registry.accounts().unwrap()[1],
+            AccountSummary {
+                name: String::from("bob"),
+                max_keys: Some(1),
+                clients: 1,
+                keys: 1,
+            }
+        );
+        assert!(registry.account_keys("carol").unwrap().is_none());
+        assert!(registry
+            .enroll_into_account("laptop", "carol")
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,429 @@
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde_json;
+
+pub const DEFAULT_ADDRESS: &str = "localhost";
+pub const DEFAULT_PORT: u16 = 8000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Off,
+    Critical,
+    Normal,
+    Debug,
+}
+
+impl LogLevel {
+    fn as_rocket_value(&self) -> &'static str {
+        match *self {
+            LogLevel::Off => "off",
+            LogLevel::Critical => "critical",
+            LogLevel::Normal => "normal",
+            LogLevel::Debug => "debug",
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<LogLevel, ConfigError> {
+        match s {
+            "off" => Ok(LogLevel::Off),
+            "critical" => Ok(LogLevel::Critical),
+            "normal" => Ok(LogLevel::Normal),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(ConfigError::InvalidLogLevel(s.to_string())),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TlsConfig {
+    pub certs: PathBuf,
+    pub key: PathBuf,
+}
+
+// Serves the routes on a Unix domain socket instead of TCP
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct UnixSocketConfig {
+    pub path: PathBuf,
+    // Users whose daemons may connect, besides the server's own
+    #[serde(default)]
+    pub allowed_uids: Vec<u32>,
+}
+
+// Relative paths are resolved against the working directory local-server
+// was started from, except audit_log and the socket which are relative to
+// data_dir
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub address: String,
+    pub port: u16,
+    pub tls: Option<TlsConfig>,
+    pub unix_socket: Option<UnixSocketConfig>,
+    // Where the gotham key share database is kept
+    pub data_dir: PathBuf,
+    pub log_level: LogLevel,
+    pub policy: Option<PathBuf>,
+    pub audit_log: PathBuf,
+    // Lets an enrolled client claim a key no client owns yet, one generated
+    // before client credentials were required, by signing with it
+    pub adopt_unclaimed_keys: bool,
+    // The master key the key shares are encrypted under, from a file or a
+    // passphrase read at startup, see shares::MasterKey
+    pub master_key_file: Option<PathBuf>,
+    pub master_passphrase: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            address: String::from(DEFAULT_ADDRESS),
+            port: DEFAULT_PORT,
+            tls: None,
+            unix_socket: None,
+            data_dir: PathBuf::from("."),
+            log_level: LogLevel::Normal,
+            policy: None,
+            audit_log: PathBuf::from("audit.log"),
+            adopt_unclaimed_keys: false,
+            master_key_file: None,
+            master_passphrase: false,
+        }
+    }
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ConfigError {
+        Io(path: PathBuf, err: io::Error) {
+            cause(err)
+            display("Unable to read {}: {}", path.display(), err)
+        }
+        Json(path: PathBuf, err: serde_json::Error) {
+            cause(err)
+            display("Invalid config file {}: {}", path.display(), err)
+        }
+        InvalidPort(port: String) {
+            display("Invalid port {:?}, expected a number from 1 to 65535", port)
+        }
+        InvalidAddress(address: String) {
+            display("Invalid listen address {:?}", address)
+        }
+        InvalidLogLevel(level: String) {
+            display("Invalid log level {:?}, expected off, critical, normal or debug", level)
+        }
+        InvalidUid(uid: String) {
+            display("Invalid user id {:?}", uid)
+        }
+        UidsWithoutSocket {
+            display("Allowed user ids need a Unix socket to listen on")
+        }
+        IncompleteTls {
+            display("TLS needs both a certificate chain and a private key")
+        }
+        MissingFile(description: &'static str, path: PathBuf) {
+            display("{} {} does not exist", description, path.display())
+        }
+        InvalidDataDir(path: PathBuf, err: io::Error) {
+            cause(err)
+            display("Unable to use data directory {}: {}", path.display(), err)
+        }
+        ConflictingMasterKeys {
+            display("Give either a master key file or a master passphrase, not both")
+        }
+    }
+}
+
+// Command line values that override the config file
+#[derive(Default)]
+pub struct Overrides<'a> {
+    pub address: Option<&'a str>,
+    pub port: Option<&'a str>,
+    pub tls_certs: Option<&'a str>,
+    pub tls_key: Option<&'a str>,
+    pub unix_socket: Option<&'a str>,
+    // Comma separated
+    pub allowed_uids: Option<&'a str>,
+    pub data_dir: Option<&'a str>,
+    pub log_level: Option<&'a str>,
+    pub policy: Option<&'a str>,
+    pub audit_log: Option<&'a str>,
+    pub adopt_unclaimed_keys: bool,
+    pub master_key_file: Option<&'a str>,
+    pub master_passphrase: bool,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let file = File::open(path).map_err(|err| ConfigError::Io(path.to_path_buf(), err))?;
+        serde_json::from_reader(file).map_err(|err| ConfigError::Json(path.to_path_buf(), err))
+    }
+
+    pub fn apply(mut self, overrides: &Overrides) -> Result<Config, ConfigError> {
+        if let Some(address) = overrides.address {
+            self.address = address.to_string();
+        }
+        if let Some(port) = overrides.port {
+            self.port = match port.parse() {
+                Ok(port) if port != 0 => port,
+                _ => return Err(ConfigError::InvalidPort(port.to_string())),
+            };
+        }
+        match (overrides.tls_certs, overrides.tls_key) {
+            (Some(certs), Some(key)) => {
+                self.tls = Some(TlsConfig {
+                    certs: PathBuf::from(certs),
+                    key: PathBuf::from(key),
+                })
+            }
+            (None, None) => {}
+            _ => return Err(ConfigError::IncompleteTls),
+        }
+        if let Some(path) = overrides.unix_socket {
+            let allowed_uids = self
+                .unix_socket
+                .take()
+                .map(|socket| socket.allowed_uids)
+                .unwrap_or_default();
+            self.unix_socket = Some(UnixSocketConfig {
+                path: PathBuf::from(path),
+                allowed_uids,
+            });
+        }
+        if let Some(uids) = overrides.allowed_uids {
+            let uids = uids
+                .split(',')
+                .map(|uid| {
+                    uid.trim()
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidUid(uid.to_string()))
+                })
+                .collect::<Result<Vec<u32>, ConfigError>>()?;
+            match self.unix_socket {
+                Some(ref mut socket) => socket.allowed_uids = uids,
+                None => return Err(ConfigError::UidsWithoutSocket),
+            }
+        }
+        if let Some(data_dir) = overrides.data_dir {
+            self.data_dir = PathBuf::from(data_dir);
+        }
+        if let Some(log_level) = overrides.log_level {
+            self.log_level = log_level.parse()?;
+        }
+        if let Some(policy) = overrides.policy {
+            self.policy = Some(PathBuf::from(policy));
+        }
+        if let Some(audit_log) = overrides.audit_log {
+            self.audit_log = PathBuf::from(audit_log);
+        }
+        if overrides.adopt_unclaimed_keys {
+            self.adopt_unclaimed_keys = true;
+        }
+        // Either master key on the command line replaces the config file's
+        if let Some(master_key_file) = overrides.master_key_file {
+            self.master_key_file = Some(PathBuf::from(master_key_file));
+            self.master_passphrase = false;
+        }
+        if overrides.master_passphrase {
+            self.master_key_file = None;
+            self.master_passphrase = true;
+        }
+        Ok(self)
+    }
+
+    // Checks the settings and makes every path absolute, creating the data
+    // directory if needed, so the server can run from within data_dir
+    pub fn resolve(mut self) -> Result<Config, ConfigError> {
+        if self.port == 0 {
+            return Err(ConfigError::InvalidPort(self.port.to_string()));
+        }
+        let resolves = (self.address.as_str(), self.port)
+            .to_socket_addrs()
+            .map(|mut addrs| addrs.next().is_some())
+            .unwrap_or(false);
+        if !resolves {
+            return Err(ConfigError::InvalidAddress(self.address.clone()));
+        }
+
+        fs::create_dir_all(&self.data_dir)
+            .and_then(|_| fs::canonicalize(&self.data_dir))
+            .map(|data_dir| self.data_dir = data_dir)
+            .map_err(|err| ConfigError::InvalidDataDir(self.data_dir.clone(), err))?;
+        self.audit_log = self.data_dir.join(&self.audit_log);
+        if let Some(ref mut socket) = self.unix_socket {
+            socket.path = self.data_dir.join(&socket.path);
+        }
+
+        if let Some(ref mut tls) = self.tls {
+            tls.certs = existing_file("TLS certificate chain", &tls.certs)?;
+            tls.key = existing_file("TLS private key", &tls.key)?;
+        }
+        if let Some(ref policy) = self.policy {
+            self.policy = Some(existing_file("Policy file", policy)?);
+        }
+        if let Some(ref master_key_file) = self.master_key_file {
+            if self.master_passphrase {
+                return Err(ConfigError::ConflictingMasterKeys);
+            }
+            self.master_key_file = Some(existing_file("Master key file", master_key_file)?);
+        }
+        Ok(self)
+    }
+
+    // get_server() ignites Rocket itself, so its settings are passed on
+    // through Rocket's environment variables
+    pub fn export_to_rocket(&self) {
+        env::set_var("ROCKET_ADDRESS", &self.address);
+        env::set_var("ROCKET_PORT", self.port.to_string());
+        env::set_var("ROCKET_LOG", self.log_level.as_rocket_value());
+        match self.tls {
+            Some(ref tls) => env::set_var(
+                "ROCKET_TLS",
+                format!(
+                    "{{certs={:?},key={:?}}}",
+                    tls.certs.to_string_lossy(),
+                    tls.key.to_string_lossy()
+                ),
+            ),
+            None => env::remove_var("ROCKET_TLS"),
+        }
+    }
+}
+
+fn existing_file(description: &'static str, path: &Path) -> Result<PathBuf, ConfigError> {
+    fs::canonicalize(path).map_err(|_| ConfigError::MissingFile(description, path.to_path_buf()))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use super::*;
+
+    #[test]
+    fn overrides_replace_file_values() {
+        let overrides = Overrides {
+            port: Some("9000"),
+            log_level: Some("debug"),
+            ..Overrides::default()
+        };
+
+        let config = Config::default().apply(&overrides).unwrap();
+
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.log_level, LogLevel::Debug);
+        assert_eq!(config.address, DEFAULT_ADDRESS);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let bad_port = Overrides {
+            port: Some("0"),
+            ..Overrides::default()
+        };
+        let bad_level = Overrides {
+            log_level: Some("verbose"),
+            ..Overrides::default()
+        };
+        let half_tls = Overrides {
+            tls_certs: Some("cert.pem"),
+            ..Overrides::default()
+        };
+
+        assert!(Config::default().apply(&bad_port).is_err());
+        assert!(Config::default().apply(&bad_level).is_err());
+        assert!(Config::default().apply(&half_tls).is_err());
+    }
+
+    #[test]
+    fn unix_socket_takes_allowed_uids() {
+        let overrides = Overrides {
+            unix_socket: Some("cosigner.sock"),
+            allowed_uids: Some("1000, 1001"),
+            ..Overrides::default()
+        };
+        let uids_only = Overrides {
+            allowed_uids: Some("1000"),
+            ..Overrides::default()
+        };
+        let bad_uid = Overrides {
+            unix_socket: Some("cosigner.sock"),
+            allowed_uids: Some("alice"),
+            ..Overrides::default()
+        };
+
+        let config = Config::default().apply(&overrides).unwrap();
+
+        assert_eq!(
+            config.unix_socket,
+            Some(UnixSocketConfig {
+                path: PathBuf::from("cosigner.sock"),
+                allowed_uids: vec![1000, 1001],
+            })
+        );
+        assert!(Config::default().apply(&uids_only).is_err());
+        assert!(Config::default().apply(&bad_uid).is_err());
+    }
+
+    #[test]
+    fn resolve_creates_data_dir_and_checks_tls_files() {
+        let temp_dir = TempDir::new("config_tests").unwrap();
+        let data_dir = temp_dir.path().join("data");
+        let mut config = Config::default();
+        config.data_dir = data_dir.clone();
+
+        let resolved = config.clone().resolve().unwrap();
+        assert!(data_dir.is_dir());
+        assert_eq!(
+            resolved.audit_log,
+            fs::canonicalize(&data_dir).unwrap().join("audit.log")
+        );
+
+        config.tls = Some(TlsConfig {
+            certs: temp_dir.path().join("missing.pem"),
+            key: temp_dir.path().join("missing.key"),
+        });
+        assert!(config.resolve().is_err());
+    }
+
+    #[test]
+    fn one_master_key_is_taken() {
+        let temp_dir = TempDir::new("config_tests").unwrap();
+        let key_file = temp_dir.path().join("master.key");
+        fs::write(&key_file, [0u8; 32]).unwrap();
+        let mut config = Config::default();
+        config.data_dir = temp_dir.path().join("data");
+        config.master_passphrase = true;
+
+        let overridden = config
+            .clone()
+            .apply(&Overrides {
+                master_key_file: key_file.to_str(),
+                ..Overrides::default()
+            })
+            .unwrap()
+            .resolve()
+            .unwrap();
+        assert_eq!(
+            overridden.master_key_file,
+            Some(fs::canonicalize(&key_file).unwrap())
+        );
+        assert!(!overridden.master_passphrase);
+
+        config.master_key_file = Some(key_file);
+        match config.resolve() {
+            Err(ConfigError::ConflictingMasterKeys) => {}
+            _ => panic!("both master keys accepted"),
+        }
+    }
+}
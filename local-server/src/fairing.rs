@@ -0,0 +1,269 @@
+use std::sync::Arc;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Method, Status};
+use rocket::{Data, Request, Response};
+use u2f_core::Refusal;
+
+use audit::{AuditEvent, AuditLog, AuditRecord};
+use clients::{ClientRegistry, KeyAccess};
+use routes::{
+    bearer_token, client_identity, key_access_refusal, registry_error, Authenticated, Refused,
+    SigningRound, REFUSED_PATH,
+};
+use sessions::SigningSessions;
+
+// The routes the co-signer needs to act on
+#[derive(Debug, PartialEq)]
+enum CosignerRoute<'a> {
+    // /ecdsa/keygen/first, or a later keygen round for a key id
+    Keygen(Option<&'a str>),
+    // /ecdsa/keygen/<id>/chaincode/second, the last keygen round
+    KeygenComplete(&'a str),
+    // /u2f/keys/<id>/claim
+    ClaimKey(&'a str),
+    // /u2f/sign/<id>/context
+    SigningContext(&'a str),
+    // /ecdsa/sign/<id>/first
+    SignFirst(&'a str),
+    // /ecdsa/sign/<id>/second
+    SignSecond(&'a str),
+    // Any other gotham route
+    Other,
+}
+
+fn parse_cosigner_path(path: &str) -> Option<CosignerRoute<'_>> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let route = match segments[..] {
+        ["ecdsa", "keygen", "first"] => CosignerRoute::Keygen(None),
+        ["ecdsa", "keygen", id, "chaincode", "second"] if !id.is_empty() => {
+            CosignerRoute::KeygenComplete(id)
+        }
+        ["ecdsa", "keygen", id, _, ..] if !id.is_empty() => CosignerRoute::Keygen(Some(id)),
+        ["u2f", "keys", id, "claim"] if !id.is_empty() => CosignerRoute::ClaimKey(id),
+        ["u2f", "sign", id, "context"] if !id.is_empty() => CosignerRoute::SigningContext(id),
+        ["ecdsa", "sign", id, "first"] if !id.is_empty() => CosignerRoute::SignFirst(id),
+        ["ecdsa", "sign", id, "second"] if !id.is_empty() => CosignerRoute::SignSecond(id),
+        ["ecdsa", ..] => CosignerRoute::Other,
+        _ => return None,
+    };
+    Some(route)
+}
+
+impl<'a> CosignerRoute<'a> {
+    fn key_id(&self) -> Option<&'a str> {
+        match *self {
+            CosignerRoute::Keygen(key_id) => key_id,
+            CosignerRoute::KeygenComplete(key_id)
+            | CosignerRoute::ClaimKey(key_id)
+            | CosignerRoute::SigningContext(key_id)
+            | CosignerRoute::SignFirst(key_id)
+            | CosignerRoute::SignSecond(key_id) => Some(key_id),
+            CosignerRoute::Other => None,
+        }
+    }
+}
+
+// Authenticates enrolled clients, refuses revoked clients and keys, and
+// keeps each key to the client that generated it. The final gotham
+// signing round is left to the SignSecond route, which checks it against
+// the approved signing context, see SigningSessions.
+pub struct SigningPolicyFairing {
+    registry: Arc<ClientRegistry>,
+    sessions: Arc<SigningSessions>,
+    adopt_unclaimed_keys: bool,
+}
+
+impl SigningPolicyFairing {
+    pub fn new(
+        registry: Arc<ClientRegistry>,
+        sessions: Arc<SigningSessions>,
+        adopt_unclaimed_keys: bool,
+    ) -> SigningPolicyFairing {
+        SigningPolicyFairing {
+            registry,
+            sessions,
+            adopt_unclaimed_keys,
+        }
+    }
+
+    fn authorize(&self, request: &Request, route: &CosignerRoute) -> Result<(), Refusal> {
+        let token = bearer_token(request);
+        let credential = match token.map(|token| self.registry.authenticate(token)) {
+            Some(Ok(Some(credential))) => credential,
+            Some(Err(err)) => return Err(registry_error(err)),
+            _ => return Err(Refusal::new(String::from("client is not enrolled"))),
+        };
+        let client_id = credential.client_id.clone();
+        request.local_cache(|| Authenticated(Some(credential)));
+
+        match self.registry.is_revoked(&client_id, route.key_id()) {
+            Ok(false) => {}
+            Ok(true) => return Err(Refusal::revoked(String::from("key has been revoked"))),
+            Err(err) => return Err(registry_error(err)),
+        }
+
+        let access = match *route {
+            CosignerRoute::Keygen(None) => self.registry.may_generate_key(&client_id),
+            CosignerRoute::Keygen(Some(key_id)) | CosignerRoute::KeygenComplete(key_id) => {
+                self.registry.record_generated_key(&client_id, key_id)
+            }
+            CosignerRoute::SigningContext(key_id)
+            | CosignerRoute::SignFirst(key_id)
+            | CosignerRoute::SignSecond(key_id) => {
+                self.registry
+                    .may_sign(&client_id, key_id, self.adopt_unclaimed_keys)
+            }
+            // Claimed by the ClaimKey route once the registration is checked
+            CosignerRoute::ClaimKey(_) | CosignerRoute::Other => Ok(KeyAccess::Allowed),
+        };
+        match access {
+            Ok(access) => {
+                if let Some(refusal) = key_access_refusal(access) {
+                    return Err(refusal);
+                }
+            }
+            Err(err) => return Err(registry_error(err)),
+        }
+
+        if let CosignerRoute::SignFirst(key_id) = *route {
+            self.sessions.start_presign(key_id);
+        }
+        Ok(())
+    }
+}
+
+impl Fairing for SigningPolicyFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Co-signer signing policy",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _data: &Data) {
+        let result = match parse_cosigner_path(request.uri().path()) {
+            Some(ref route) => self.authorize(request, route),
+            None => return,
+        };
+        if let Err(refusal) = result {
+            request.local_cache(|| Refused(refusal));
+            request.set_method(Method::Post);
+            request.set_uri(Origin::parse(REFUSED_PATH).unwrap());
+        }
+    }
+
+    // A first signing round only counts once gotham has served it, a
+    // refused request having been rerouted away from the round's path
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if let Some(CosignerRoute::SignFirst(key_id)) = parse_cosigner_path(request.uri().path()) {
+            if response.status().class().is_success() {
+                self.sessions.presign(key_id);
+            }
+        }
+    }
+}
+
+// Records completed keygen and signing sessions before their final
+// response is sent, see AuditLog
+pub struct AuditFairing {
+    log: Arc<AuditLog>,
+}
+
+impl AuditFairing {
+    pub fn new(log: Arc<AuditLog>) -> AuditFairing {
+        AuditFairing { log }
+    }
+}
+
+impl Fairing for AuditFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Co-signer audit log",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if response.status().class().is_success() {
+            let record = match parse_cosigner_path(request.uri().path()) {
+                Some(CosignerRoute::KeygenComplete(key_id)) => AuditRecord {
+                    event: AuditEvent::Keygen,
+                    key_id: key_id.to_string(),
+                    client: client_identity(request),
+                    message_hash: None,
+                    counter: None,
+                    kind: None,
+                    site: None,
+                },
+                Some(CosignerRoute::SignSecond(key_id)) => {
+                    match request.local_cache(|| SigningRound(None)).0 {
+                        Some(ref session) => AuditRecord::signing(
+                            AuditEvent::Sign,
+                            key_id,
+                            &session.client,
+                            &session.context,
+                        ),
+                        None => return,
+                    }
+                }
+                _ => return,
+            };
+            // Withhold the result of any session that could not be recorded
+            if let Err(err) = self.log.append(record) {
+                error!("Unable to append to the audit log: {}", err);
+                *response = Response::build()
+                    .status(Status::InternalServerError)
+                    .finalize();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cosigner_routes() {
+        assert_eq!(
+            parse_cosigner_path("/ecdsa/keygen/first"),
+            Some(CosignerRoute::Keygen(None))
+        );
+        assert_eq!(
+            parse_cosigner_path("/ecdsa/keygen/abc/fourth"),
+            Some(CosignerRoute::Keygen(Some("abc")))
+        );
+        assert_eq!(
+            parse_cosigner_path("/ecdsa/keygen/abc/chaincode/second"),
+            Some(CosignerRoute::KeygenComplete("abc"))
+        );
+        assert_eq!(
+            parse_cosigner_path("/u2f/keys/abc/claim"),
+            Some(CosignerRoute::ClaimKey("abc"))
+        );
+        assert_eq!(
+            parse_cosigner_path("/u2f/sign/abc/context"),
+            Some(CosignerRoute::SigningContext("abc"))
+        );
+        assert_eq!(
+            parse_cosigner_path("/ecdsa/sign/abc/first"),
+            Some(CosignerRoute::SignFirst("abc"))
+        );
+        assert_eq!(
+            parse_cosigner_path("/ecdsa/sign/abc/second"),
+            Some(CosignerRoute::SignSecond("abc"))
+        );
+        assert_eq!(
+            parse_cosigner_path("/ecdsa/sign/abc/first/extra"),
+            Some(CosignerRoute::Other)
+        );
+    }
+
+    #[test]
+    fn ignores_other_routes() {
+        assert_eq!(parse_cosigner_path("/u2f/refused"), None);
+        assert_eq!(parse_cosigner_path("/"), None);
+    }
+}
@@ -1,9 +1,614 @@
+extern crate clap;
+extern crate curv;
+extern crate httparse;
+extern crate kms;
+extern crate libc;
+#[macro_use]
+extern crate log;
+extern crate multi_party_ecdsa;
+extern crate openssl;
+#[macro_use]
+extern crate quick_error;
+extern crate reqwest;
+extern crate rocket;
+extern crate rocket_contrib;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate server_lib;
+extern crate time;
+extern crate u2f_core;
+
+mod approver;
+mod atomic_file;
+mod audit;
+mod clients;
+mod config;
+mod fairing;
+mod party_one;
+mod policy;
+mod routes;
+mod sessions;
+mod shares;
+mod unix_socket;
+
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+use rocket::error::LaunchError;
+use rocket::local::Client;
 
 use self::server_lib::server::*;
+use approver::{Approver, ApproverError};
+use audit::{AuditError, AuditLog};
+use clients::ClientRegistry;
+use config::{Config, ConfigError, Overrides};
+use fairing::{AuditFairing, SigningPolicyFairing};
+use party_one::PartyOne;
+use policy::{Policy, PolicyConfig};
+use sessions::SigningSessions;
+use shares::{MasterKey, ShareError, ShareStore};
+use u2f_core::DEFAULT_COSIGNER_ENDPOINT;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Config(err: ConfigError) {
+            from()
+            cause(err)
+            display("{}", err)
+        }
+        Policy(err: io::Error) {
+            cause(err)
+            display("Unable to load signing policy: {}", err)
+        }
+        Registry(err: io::Error) {
+            cause(err)
+            display("Unable to update client registry: {}", err)
+        }
+        UnknownClient(client_id: String) {
+            display("No client is enrolled with id {}", client_id)
+        }
+        UnknownKey(key_id: String) {
+            display("No client owns a key with id {}", key_id)
+        }
+        UnknownAccount(name: String) {
+            display("No account is named {}", name)
+        }
+        AccountExists(name: String) {
+            display("An account is already named {}", name)
+        }
+        Audit(err: AuditError) {
+            from()
+            cause(err)
+            display("Audit log failed verification: {}", err)
+        }
+        Approver(err: ApproverError) {
+            from()
+            cause(err)
+            display("{}", err)
+        }
+        Launch(err: LaunchError) {
+            from()
+            display("Unable to start server: {}", err)
+        }
+        Socket(err: io::Error) {
+            cause(err)
+            display("Unable to serve on the Unix socket: {}", err)
+        }
+        Shares(err: ShareError) {
+            from()
+            cause(err)
+            display("{}", err)
+        }
+        NoMasterKey {
+            display("Key shares are encrypted under a master key, give one with --master-key-file or --master-passphrase")
+        }
+    }
+}
+
+fn id_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("ID")
+        .required(true)
+        .index(1)
+        .validator(|id| {
+            id.parse::<u64>()
+                .map(|_| ())
+                .map_err(|_| String::from("ID must be a number"))
+        })
+        .help("Id of the request, as listed by the approvals command")
+}
+
+fn account_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("ACCOUNT").required(true).index(1)
+}
+
+fn max_keys_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("max-keys")
+        .long("max-keys")
+        .value_name("N")
+        .takes_value(true)
+        .validator(|max_keys| {
+            max_keys
+                .parse::<usize>()
+                .map(|_| ())
+                .map_err(|_| String::from("N must be a number"))
+        })
+        .help("Most keys the account's clients may own together [default: unlimited]")
+}
+
+fn credential_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("credential")
+        .long("credential")
+        .value_name("FILE")
+        .takes_value(true)
+        .required(true)
+        .help("File holding the approver credential printed by enroll-approver")
+}
+
+fn server_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("server")
+        .long("server")
+        .value_name("URL")
+        .takes_value(true)
+        .default_value(DEFAULT_COSIGNER_ENDPOINT)
+        .help("Server to reach over loopback, or unix:PATH for its Unix socket")
+}
 
 pub fn main() {
-    get_server().launch();
+    if let Err(err) = run() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Error> {
+    let args = App::new("Threshold U2F co-signer")
+        .version(VERSION)
+        .author(AUTHORS)
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("JSON config file, overridden by any options given"),
+        )
+        .arg(
+            Arg::with_name("address")
+                .short("a")
+                .long("address")
+                .value_name("ADDRESS")
+                .takes_value(true)
+                .help("Address to listen on [default: localhost]"),
+        )
+        .arg(
+            Arg::with_name("port")
+                .long("port")
+                .value_name("PORT")
+                .takes_value(true)
+                .help("Port to listen on [default: 8000]"),
+        )
+        .arg(
+            Arg::with_name("tls-certs")
+                .long("tls-certs")
+                .value_name("FILE")
+                .takes_value(true)
+                .requires("tls-key")
+                .help("PEM certificate chain to serve TLS with"),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .value_name("FILE")
+                .takes_value(true)
+                .requires("tls-certs")
+                .help("PEM private key to serve TLS with"),
+        )
+        .arg(
+            Arg::with_name("unix-socket")
+                .long("unix-socket")
+                .value_name("PATH")
+                .takes_value(true)
+                .help("Listens on a Unix domain socket instead of TCP, relative to the data directory"),
+        )
+        .arg(
+            Arg::with_name("allow-uid")
+                .long("allow-uid")
+                .value_name("UIDS")
+                .takes_value(true)
+                .help("Comma separated ids of the users allowed to connect to the Unix socket, besides the server's own"),
+        )
+        .arg(
+            Arg::with_name("data-dir")
+                .short("d")
+                .long("data-dir")
+                .value_name("DIR")
+                .takes_value(true)
+                .help("Directory holding the key share database [default: .]"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .takes_value(true)
+                .possible_values(&["off", "critical", "normal", "debug"])
+                .help("Server log level [default: normal]"),
+        )
+        .arg(
+            Arg::with_name("policy")
+                .short("p")
+                .long("policy")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("JSON file of per-client signing policies"),
+        )
+        .arg(
+            Arg::with_name("audit-log")
+                .long("audit-log")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Hash chained log of keygen and signing sessions, relative to the data directory [default: audit.log]"),
+        )
+        .arg(
+            Arg::with_name("adopt-unclaimed-keys")
+                .long("adopt-unclaimed-keys")
+                .help("Gives keys generated before clients were enrolled to the first client signing with them"),
+        )
+        .arg(
+            Arg::with_name("master-key-file")
+                .long("master-key-file")
+                .value_name("FILE")
+                .takes_value(true)
+                .conflicts_with("master-passphrase")
+                .help("File of 32 random bytes the key shares are encrypted under"),
+        )
+        .arg(
+            Arg::with_name("master-passphrase")
+                .long("master-passphrase")
+                .help("Reads a passphrase to encrypt the key shares under from standard input"),
+        )
+        .subcommand(
+            SubCommand::with_name("enroll")
+                .about("Enrolls a client, printing the credential to give its user daemon")
+                .arg(
+                    Arg::with_name("NAME")
+                        .required(true)
+                        .index(1)
+                        .help("Name to remember the client by, e.g. its owner and device"),
+                )
+                .arg(
+                    Arg::with_name("account")
+                        .long("account")
+                        .value_name("ACCOUNT")
+                        .takes_value(true)
+                        .help("Account the client's keys count towards"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("enroll-approver")
+                .about("Enrolls an approver, printing the credential to approve or deny signatures with")
+                .arg(
+                    Arg::with_name("NAME")
+                        .required(true)
+                        .index(1)
+                        .help("Name to remember the approver by"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("account")
+                .about("Manages accounts, which group a user's clients and limit their keys")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Creates an account to enroll clients into")
+                        .arg(account_arg())
+                        .arg(max_keys_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("limit")
+                        .about("Changes an account's key limit, keeping any keys over it")
+                        .arg(account_arg())
+                        .arg(max_keys_arg()),
+                )
+                .subcommand(SubCommand::with_name("list").about("Lists accounts and their key counts"))
+                .subcommand(
+                    SubCommand::with_name("keys")
+                        .about("Lists the keys owned by an account's clients")
+                        .arg(account_arg()),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("revoke")
+                .about("Refuses any further signing with a client's keys, or a single key")
+                .arg(
+                    Arg::with_name("client")
+                        .long("client")
+                        .value_name("ID")
+                        .takes_value(true)
+                        .help("Revokes the client and every key it owns"),
+                )
+                .arg(
+                    Arg::with_name("key")
+                        .long("key")
+                        .value_name("ID")
+                        .takes_value(true)
+                        .help("Revokes a single key"),
+                )
+                .group(
+                    ArgGroup::with_name("target")
+                        .args(&["client", "key"])
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("approvals")
+                .about("Lists signing requests waiting for approval on a running server")
+                .arg(credential_arg())
+                .arg(server_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("approve")
+                .about("Approves a signing request waiting on a running server")
+                .arg(id_arg())
+                .arg(credential_arg())
+                .arg(server_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("deny")
+                .about("Denies a signing request waiting on a running server")
+                .arg(id_arg())
+                .arg(credential_arg())
+                .arg(server_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("rotate-master-key")
+                .about("Encrypts the key shares under a new master key, which a running server needs on its next start")
+                .arg(
+                    Arg::with_name("new-key-file")
+                        .long("new-key-file")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .help("File of 32 random bytes to use from now on"),
+                )
+                .arg(
+                    Arg::with_name("new-passphrase")
+                        .long("new-passphrase")
+                        .help("Reads the passphrase to use from now on from standard input, after the current one"),
+                )
+                .group(
+                    ArgGroup::with_name("new-master-key")
+                        .args(&["new-key-file", "new-passphrase"])
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-audit-log")
+                .about("Checks an audit log has not been modified or truncated")
+                .arg(Arg::with_name("FILE").required(true).index(1)),
+        )
+        .get_matches();
+
+    if let Some(verify_args) = args.subcommand_matches("verify-audit-log") {
+        let path = Path::new(verify_args.value_of("FILE").unwrap());
+        let entries = audit::verify(path)?;
+        println!("Audit log verified, {} entries", entries);
+        return Ok(());
+    }
+
+    match args.subcommand() {
+        ("approvals", Some(approvals_args)) => {
+            let approver = approver(approvals_args)?;
+            for session in approver.pending()? {
+                println!("{}", approver::describe(&session));
+            }
+            return Ok(());
+        }
+        (decision, Some(decision_args)) if decision == "approve" || decision == "deny" => {
+            let approver = approver(decision_args)?;
+            let id = decision_args.value_of("ID").unwrap().parse().unwrap();
+            approver.decide(id, decision == "approve")?;
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let config = match args.value_of("config") {
+        Some(path) => Config::load(Path::new(path))?,
+        None => Config::default(),
+    };
+    let config = config
+        .apply(&Overrides {
+            address: args.value_of("address"),
+            port: args.value_of("port"),
+            tls_certs: args.value_of("tls-certs"),
+            tls_key: args.value_of("tls-key"),
+            unix_socket: args.value_of("unix-socket"),
+            allowed_uids: args.value_of("allow-uid"),
+            data_dir: args.value_of("data-dir"),
+            log_level: args.value_of("log-level"),
+            policy: args.value_of("policy"),
+            audit_log: args.value_of("audit-log"),
+            adopt_unclaimed_keys: args.is_present("adopt-unclaimed-keys"),
+            master_key_file: args.value_of("master-key-file"),
+            master_passphrase: args.is_present("master-passphrase"),
+        })?
+        .resolve()?;
+
+    let registry = Arc::new(ClientRegistry::new(&config.data_dir));
+
+    if let Some(enroll_args) = args.subcommand_matches("enroll") {
+        let name = enroll_args.value_of("NAME").unwrap();
+        let credential = match enroll_args.value_of("account") {
+            Some(account) => registry
+                .enroll_into_account(name, account)
+                .map_err(Error::Registry)?
+                .ok_or_else(|| Error::UnknownAccount(account.to_string()))?,
+            None => registry.enroll(name).map_err(Error::Registry)?,
+        };
+        println!("{}", credential.to_token());
+        return Ok(());
+    }
+
+    if let Some(approver_args) = args.subcommand_matches("enroll-approver") {
+        let name = approver_args.value_of("NAME").unwrap();
+        let credential = registry.enroll_approver(name).map_err(Error::Registry)?;
+        println!("{}", credential.to_token());
+        return Ok(());
+    }
+
+    if let Some(account_args) = args.subcommand_matches("account") {
+        return manage_accounts(&registry, account_args);
+    }
+
+    if let Some(revoke_args) = args.subcommand_matches("revoke") {
+        if let Some(client_id) = revoke_args.value_of("client") {
+            let key_ids = registry
+                .revoke_client(client_id)
+                .map_err(Error::Registry)?
+                .ok_or_else(|| Error::UnknownClient(client_id.to_string()))?;
+            println!("Revoked client {} and {} keys", client_id, key_ids.len());
+            for key_id in key_ids {
+                println!("{}", key_id);
+            }
+        }
+        if let Some(key_id) = revoke_args.value_of("key") {
+            if !registry.revoke_key(key_id).map_err(Error::Registry)? {
+                return Err(Error::UnknownKey(key_id.to_string()));
+            }
+            println!("Revoked key {}", key_id);
+        }
+        return Ok(());
+    }
+
+    if let Some(rotate_args) = args.subcommand_matches("rotate-master-key") {
+        let current = master_key(&config, false)?;
+        let new = match rotate_args.value_of("new-key-file") {
+            Some(path) => MasterKey::File(PathBuf::from(path)),
+            None => {
+                MasterKey::Passphrase(shares::read_passphrase("New master passphrase: ", true)?)
+            }
+        };
+        shares::rotate_master_key(&config.data_dir, &current, &new)?;
+        println!("Key shares are now encrypted under the new master key");
+        return Ok(());
+    }
+
+    let policy_config = match config.policy {
+        Some(ref path) => PolicyConfig::load(path).map_err(Error::Policy)?,
+        None => PolicyConfig::default(),
+    };
+    let sessions = Arc::new(SigningSessions::new(Policy::new(policy_config)));
+    let audit_log = Arc::new(AuditLog::open(&config.audit_log)?);
+    // A passphrase is confirmed when it is first used
+    let master_key = master_key(&config, !shares::has_share_key(&config.data_dir))?;
+    let party_one = Arc::new(PartyOne::new(ShareStore::open(
+        &config.data_dir,
+        &master_key,
+    )?));
+
+    // gotham keeps its database relative to the working directory
+    env::set_current_dir(&config.data_dir)
+        .map_err(|err| ConfigError::InvalidDataDir(config.data_dir.clone(), err))?;
+    config.export_to_rocket();
+
+    let rocket = get_server()
+        .mount(
+            "/",
+            routes::routes(&sessions, &registry, &audit_log, &party_one),
+        )
+        .mount("/", party_one::routes(&party_one))
+        .attach(SigningPolicyFairing::new(
+            registry,
+            sessions,
+            config.adopt_unclaimed_keys,
+        ))
+        .attach(AuditFairing::new(audit_log));
+    match config.unix_socket {
+        Some(ref socket) => {
+            let client = Client::untracked(rocket)?;
+            unix_socket::serve(client, &socket.path, &socket.allowed_uids).map_err(Error::Socket)
+        }
+        None => Err(rocket.launch().into()),
+    }
 }
 
+fn master_key(config: &Config, confirm: bool) -> Result<MasterKey, Error> {
+    match config.master_key_file {
+        Some(ref path) => Ok(MasterKey::File(path.clone())),
+        None if config.master_passphrase => Ok(MasterKey::Passphrase(shares::read_passphrase(
+            "Master passphrase: ",
+            confirm,
+        )?)),
+        None => Err(Error::NoMasterKey),
+    }
+}
+
+fn approver(args: &ArgMatches) -> Result<Approver, ApproverError> {
+    Approver::with_credential_file(
+        args.value_of("server").unwrap(),
+        Path::new(args.value_of("credential").unwrap()),
+    )
+}
 
+fn manage_accounts(registry: &ClientRegistry, args: &ArgMatches) -> Result<(), Error> {
+    let max_keys = |args: &ArgMatches| args.value_of("max-keys").map(|n| n.parse().unwrap());
+    match args.subcommand() {
+        ("create", Some(create_args)) => {
+            let name = create_args.value_of("ACCOUNT").unwrap();
+            if !registry
+                .create_account(name, max_keys(create_args))
+                .map_err(Error::Registry)?
+            {
+                return Err(Error::AccountExists(name.to_string()));
+            }
+        }
+        ("limit", Some(limit_args)) => {
+            let name = limit_args.value_of("ACCOUNT").unwrap();
+            if !registry
+                .set_max_keys(name, max_keys(limit_args))
+                .map_err(Error::Registry)?
+            {
+                return Err(Error::UnknownAccount(name.to_string()));
+            }
+        }
+        ("list", Some(_)) => {
+            for account in registry.accounts().map_err(Error::Registry)? {
+                let max_keys = account
+                    .max_keys
+                    .map_or_else(|| String::from("unlimited"), |n| n.to_string());
+                println!(
+                    "{}\t{} clients\t{} of {} keys",
+                    account.name, account.clients, account.keys, max_keys
+                );
+            }
+        }
+        ("keys", Some(keys_args)) => {
+            let name = keys_args.value_of("ACCOUNT").unwrap();
+            let keys = registry
+                .account_keys(name)
+                .map_err(Error::Registry)?
+                .ok_or_else(|| Error::UnknownAccount(name.to_string()))?;
+            for key in keys {
+                println!(
+                    "{}\t{} ({}){}{}",
+                    key.key_id,
+                    key.client_name,
+                    key.client_id,
+                    if key.claimed { "" } else { "\tpooled" },
+                    if key.revoked { "\trevoked" } else { "" }
+                );
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
@@ -0,0 +1,450 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use curv::cryptographic_primitives::proofs::sigma_dlog::DLogProof;
+use curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm::{
+    CommWitness, EcKeyPair,
+};
+use curv::{BigInt, GE};
+use kms::chain_code::two_party::party1::ChainCode1;
+use kms::ecdsa::two_party::{party2, MasterKey1};
+use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::{party_one, party_two};
+use rocket::handler::{Handler, Outcome};
+use rocket::http::{Method, Status};
+use rocket::response::content;
+use rocket::{Data, Request, Route};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+
+use shares::{new_share_id, ShareError, ShareStore};
+
+// Ahead of gotham's own routes for the same rounds
+const PARTY_ONE_RANK: isize = -10;
+
+const ROUND_LIMIT: u64 = 16384;
+
+// A keygen not completed in this long is dropped
+const KEYGEN_TIMEOUT: Duration = Duration::from_secs(600);
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum RoundError {
+        Malformed {
+            display("Malformed round message")
+        }
+        UnknownKeygen {
+            display("No keygen in progress with this id")
+        }
+        OutOfOrder {
+            display("Keygen round out of order")
+        }
+        UnknownKey {
+            display("No key share with this id")
+        }
+        NoFirstRound {
+            display("No first signing round for the key")
+        }
+        Failed(what: &'static str) {
+            display("The other party failed the {}", what)
+        }
+        Store(err: ShareError) {
+            from()
+            cause(err)
+            display("{}", err)
+        }
+    }
+}
+
+impl RoundError {
+    fn status(&self) -> Status {
+        match *self {
+            RoundError::Malformed | RoundError::OutOfOrder | RoundError::Failed(_) => {
+                Status::BadRequest
+            }
+            RoundError::UnknownKeygen | RoundError::UnknownKey | RoundError::NoFirstRound => {
+                Status::NotFound
+            }
+            RoundError::Store(_) => Status::InternalServerError,
+        }
+    }
+}
+
+// gotham's body for the second signing round
+#[derive(Deserialize)]
+pub struct SignSecondRequest {
+    message: BigInt,
+    party_two_sign_message: party2::SignMessage,
+    x_pos_child_key: BigInt,
+    y_pos_child_key: BigInt,
+}
+
+// Party one's secrets once its share is fixed by the second keygen round
+struct KeyMaterial {
+    public_share: GE,
+    party_two_public: GE,
+    paillier_key_pair: party_one::PaillierKeyPair,
+    private: party_one::Party1Private,
+}
+
+// Where a keygen is, named for the last round served
+enum Stage {
+    First {
+        comm_witness: party_one::CommWitness,
+        ec_key_pair: party_one::EcKeyPair,
+    },
+    Second(KeyMaterial),
+    Third {
+        keys: KeyMaterial,
+        party_two_pdl_first_message: party_two::PDLFirstMessage,
+        pdl_decommit: party_one::PDLdecommit,
+        alpha: BigInt,
+    },
+    Fourth(KeyMaterial),
+    ChainCodeFirst {
+        keys: KeyMaterial,
+        comm_witness: CommWitness,
+        ec_key_pair: EcKeyPair,
+    },
+}
+
+struct Keygen {
+    started: Instant,
+    stage: Stage,
+}
+
+struct Signing {
+    party_two_first_message: party_two::EphKeyGenFirstMsg,
+    ec_key_pair: party_one::EphEcKeyPair,
+}
+
+// The co-signer's side of gotham's keygen and signing rounds, as gotham
+// serves them, with the finished shares kept encrypted in the ShareStore
+// rather than gotham's database. Keygens in progress and first signing
+// rounds are only held in memory, a client starts them again after a
+// restart.
+pub struct PartyOne {
+    store: ShareStore,
+    keygens: Mutex<HashMap<String, Keygen>>,
+    signings: Mutex<HashMap<String, Signing>>,
+}
+
+impl PartyOne {
+    pub fn new(store: ShareStore) -> PartyOne {
+        PartyOne {
+            store,
+            keygens: Mutex::new(HashMap::new()),
+            signings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Keys generated by gotham before shares were kept here are left to
+    // gotham's routes
+    pub fn holds(&self, key_id: &str) -> bool {
+        self.store.contains(key_id)
+    }
+
+    fn keygen_first(&self) -> Result<(String, party_one::KeyGenFirstMsg), RoundError> {
+        let key_id = new_share_id()?;
+        let (message, comm_witness, ec_key_pair) = MasterKey1::key_gen_first_message();
+        let mut keygens = self.keygens.lock().unwrap();
+        keygens.retain(|_, keygen| keygen.started.elapsed() < KEYGEN_TIMEOUT);
+        keygens.insert(
+            key_id.clone(),
+            Keygen {
+                started: Instant::now(),
+                stage: Stage::First {
+                    comm_witness,
+                    ec_key_pair,
+                },
+            },
+        );
+        Ok((key_id, message))
+    }
+
+    // Each round takes the keygen out while it runs, as the second round's
+    // Paillier key takes a while, and puts it back at its next stage. A
+    // round out of order ends the keygen.
+    fn take_keygen(&self, key_id: &str) -> Result<Keygen, RoundError> {
+        match self.keygens.lock().unwrap().remove(key_id) {
+            Some(ref keygen) if keygen.started.elapsed() >= KEYGEN_TIMEOUT => {
+                Err(RoundError::UnknownKeygen)
+            }
+            Some(keygen) => Ok(keygen),
+            None => Err(RoundError::UnknownKeygen),
+        }
+    }
+
+    fn put_keygen(&self, key_id: &str, started: Instant, stage: Stage) {
+        self.keygens
+            .lock()
+            .unwrap()
+            .insert(key_id.to_string(), Keygen { started, stage });
+    }
+
+    fn keygen_second(
+        &self,
+        key_id: &str,
+        d_log_proof: DLogProof,
+    ) -> Result<impl Serialize, RoundError> {
+        let keygen = self.take_keygen(key_id)?;
+        let (comm_witness, ec_key_pair) = match keygen.stage {
+            Stage::First {
+                comm_witness,
+                ec_key_pair,
+            } => (comm_witness, ec_key_pair),
+            _ => return Err(RoundError::OutOfOrder),
+        };
+        let public_share = comm_witness.public_share.clone();
+        let (message, paillier_key_pair, private) =
+            MasterKey1::key_gen_second_message(comm_witness, &ec_key_pair, &d_log_proof);
+        let keys = KeyMaterial {
+            public_share,
+            party_two_public: d_log_proof.pk,
+            paillier_key_pair,
+            private,
+        };
+        self.put_keygen(key_id, keygen.started, Stage::Second(keys));
+        Ok(message)
+    }
+
+    fn keygen_third(
+        &self,
+        key_id: &str,
+        party_two_pdl_first_message: party_two::PDLFirstMessage,
+    ) -> Result<impl Serialize, RoundError> {
+        let keygen = self.take_keygen(key_id)?;
+        let keys = match keygen.stage {
+            Stage::Second(keys) => keys,
+            _ => return Err(RoundError::OutOfOrder),
+        };
+        let (message, pdl_decommit, alpha) =
+            MasterKey1::key_gen_third_message(&party_two_pdl_first_message, &keys.private);
+        let stage = Stage::Third {
+            keys,
+            party_two_pdl_first_message,
+            pdl_decommit,
+            alpha,
+        };
+        self.put_keygen(key_id, keygen.started, stage);
+        Ok(message)
+    }
+
+    fn keygen_fourth(
+        &self,
+        key_id: &str,
+        party_two_pdl_second_message: party_two::PDLSecondMessage,
+    ) -> Result<impl Serialize, RoundError> {
+        let keygen = self.take_keygen(key_id)?;
+        let (keys, party_two_pdl_first_message, pdl_decommit, alpha) = match keygen.stage {
+            Stage::Third {
+                keys,
+                party_two_pdl_first_message,
+                pdl_decommit,
+                alpha,
+            } => (keys, party_two_pdl_first_message, pdl_decommit, alpha),
+            _ => return Err(RoundError::OutOfOrder),
+        };
+        let message = MasterKey1::key_gen_fourth_message(
+            &party_two_pdl_first_message,
+            &party_two_pdl_second_message,
+            keys.private.clone(),
+            pdl_decommit,
+            alpha,
+        )
+        .map_err(|_| RoundError::Failed("Paillier key proof"))?;
+        self.put_keygen(key_id, keygen.started, Stage::Fourth(keys));
+        Ok(message)
+    }
+
+    fn chain_code_first(&self, key_id: &str) -> Result<impl Serialize, RoundError> {
+        let keygen = self.take_keygen(key_id)?;
+        let keys = match keygen.stage {
+            Stage::Fourth(keys) => keys,
+            _ => return Err(RoundError::OutOfOrder),
+        };
+        let (message, comm_witness, ec_key_pair) = ChainCode1::chain_code_first_message();
+        let stage = Stage::ChainCodeFirst {
+            keys,
+            comm_witness,
+            ec_key_pair,
+        };
+        self.put_keygen(key_id, keygen.started, stage);
+        Ok(message)
+    }
+
+    // The last keygen round, which stores the finished share
+    fn chain_code_second(
+        &self,
+        key_id: &str,
+        d_log_proof: DLogProof,
+    ) -> Result<impl Serialize, RoundError> {
+        let keygen = self.take_keygen(key_id)?;
+        let (keys, comm_witness, ec_key_pair) = match keygen.stage {
+            Stage::ChainCodeFirst {
+                keys,
+                comm_witness,
+                ec_key_pair,
+            } => (keys, comm_witness, ec_key_pair),
+            _ => return Err(RoundError::OutOfOrder),
+        };
+        let message = ChainCode1::chain_code_second_message(comm_witness, &d_log_proof);
+        let chain_code = ChainCode1::compute_chain_code(&ec_key_pair, &d_log_proof.pk).chain_code;
+        let master_key = MasterKey1::set_master_key(
+            &chain_code,
+            keys.private,
+            &keys.public_share,
+            &keys.party_two_public,
+            keys.paillier_key_pair,
+        );
+        self.store.put(key_id, &master_key)?;
+        Ok(message)
+    }
+
+    // Replaces any earlier first round for the key
+    fn sign_first(
+        &self,
+        key_id: &str,
+        party_two_first_message: party_two::EphKeyGenFirstMsg,
+    ) -> Result<impl Serialize, RoundError> {
+        let (message, ec_key_pair) = MasterKey1::sign_first_message();
+        self.signings.lock().unwrap().insert(
+            key_id.to_string(),
+            Signing {
+                party_two_first_message,
+                ec_key_pair,
+            },
+        );
+        Ok(message)
+    }
+
+    // Consumes the key's first round, so each is used for one signature
+    pub fn sign_second(
+        &self,
+        key_id: &str,
+        request: SignSecondRequest,
+    ) -> Result<party_one::SignatureRecid, RoundError> {
+        let signing = self
+            .signings
+            .lock()
+            .unwrap()
+            .remove(key_id)
+            .ok_or(RoundError::NoFirstRound)?;
+        let master_key: MasterKey1 = self.store.get(key_id)?.ok_or(RoundError::UnknownKey)?;
+        master_key
+            .get_child(vec![request.x_pos_child_key, request.y_pos_child_key])
+            .sign_second_message(
+                &request.party_two_sign_message,
+                &signing.party_two_first_message,
+                &signing.ec_key_pair,
+                &request.message,
+            )
+            .map_err(|_| RoundError::Failed("signing round"))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Round {
+    KeygenFirst,
+    KeygenSecond,
+    KeygenThird,
+    KeygenFourth,
+    ChainCodeFirst,
+    ChainCodeSecond,
+    SignFirst,
+}
+
+#[derive(Clone)]
+struct ServeRound {
+    party_one: Arc<PartyOne>,
+    round: Round,
+}
+
+impl ServeRound {
+    fn serve(&self, key_id: &str, body: &[u8]) -> Result<String, RoundError> {
+        let party_one = &self.party_one;
+        match self.round {
+            Round::KeygenFirst => to_json(party_one.keygen_first()),
+            Round::KeygenSecond => to_json(party_one.keygen_second(key_id, parse(body)?)),
+            Round::KeygenThird => to_json(party_one.keygen_third(key_id, parse(body)?)),
+            Round::KeygenFourth => to_json(party_one.keygen_fourth(key_id, parse(body)?)),
+            Round::ChainCodeFirst => to_json(party_one.chain_code_first(key_id)),
+            Round::ChainCodeSecond => to_json(party_one.chain_code_second(key_id, parse(body)?)),
+            Round::SignFirst => to_json(party_one.sign_first(key_id, parse(body)?)),
+        }
+    }
+}
+
+impl Handler for ServeRound {
+    fn handle<'r>(&self, request: &'r Request, data: Data) -> Outcome<'r> {
+        let key_id = match self.round {
+            Round::KeygenFirst => String::new(),
+            _ => match request.get_param::<String>(0) {
+                Some(Ok(key_id)) => key_id,
+                _ => return Outcome::failure(Status::BadRequest),
+            },
+        };
+        if let Round::SignFirst = self.round {
+            if !self.party_one.holds(&key_id) {
+                return Outcome::forward(data);
+            }
+        }
+        let mut body = Vec::new();
+        if data
+            .open()
+            .take(ROUND_LIMIT)
+            .read_to_end(&mut body)
+            .is_err()
+        {
+            return Outcome::failure(Status::BadRequest);
+        }
+        match self.serve(&key_id, &body) {
+            Ok(body) => Outcome::from(request, content::Json(body)),
+            Err(err) => {
+                warn!("Round {} failed: {}", request.uri().path(), err);
+                Outcome::failure(err.status())
+            }
+        }
+    }
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, RoundError> {
+    serde_json::from_slice(body).map_err(|_| RoundError::Malformed)
+}
+
+fn to_json<T: Serialize>(message: Result<T, RoundError>) -> Result<String, RoundError> {
+    message.map(|message| serde_json::to_string(&message).unwrap())
+}
+
+// The second signing round is served by routes::SignSecond, once it has
+// checked the round against the approved signing context
+pub fn routes(party_one: &Arc<PartyOne>) -> Vec<Route> {
+    let rounds = [
+        ("/ecdsa/keygen/first", Round::KeygenFirst),
+        ("/ecdsa/keygen/<id>/second", Round::KeygenSecond),
+        ("/ecdsa/keygen/<id>/third", Round::KeygenThird),
+        ("/ecdsa/keygen/<id>/fourth", Round::KeygenFourth),
+        ("/ecdsa/keygen/<id>/chaincode/first", Round::ChainCodeFirst),
+        (
+            "/ecdsa/keygen/<id>/chaincode/second",
+            Round::ChainCodeSecond,
+        ),
+        ("/ecdsa/sign/<id>/first", Round::SignFirst),
+    ];
+    rounds
+        .iter()
+        .map(|&(path, round)| {
+            Route::ranked(
+                PARTY_ONE_RANK,
+                Method::Post,
+                path,
+                ServeRound {
+                    party_one: party_one.clone(),
+                    round,
+                },
+            )
+        })
+        .collect()
+}
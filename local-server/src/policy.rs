@@ -0,0 +1,324 @@
+use std::collections::vec_deque::VecDeque;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json;
+use u2f_core::AppId;
+
+// Policy file layout, AppId hashes are base64 encoded as elsewhere:
+// {
+//   "default": { "denied_app_ids": ["..."] },
+//   "clients": {
+//     "127.0.0.1": {
+//       "allowed_app_ids": ["..."],
+//       "rate_limit": { "max_signatures": 20, "period_secs": 3600 },
+//       "signing_hours": { "start": 8, "end": 20 },
+//       "approval_required_app_ids": ["..."]
+//     }
+//   }
+// }
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct PolicyConfig {
+    #[serde(default)]
+    pub default: ClientPolicy,
+    #[serde(default)]
+    pub clients: HashMap<String, ClientPolicy>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ClientPolicy {
+    // When present, only these applications may be signed for
+    #[serde(default)]
+    pub allowed_app_ids: Option<HashSet<AppId>>,
+    #[serde(default)]
+    pub denied_app_ids: HashSet<AppId>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    pub signing_hours: Option<SigningHours>,
+    // Applications that need an approver to accept each signature
+    #[serde(default)]
+    pub approval_required_app_ids: HashSet<AppId>,
+}
+
+// At most max_signatures for a single key in any period_secs window
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct RateLimit {
+    pub max_signatures: usize,
+    pub period_secs: u64,
+}
+
+// Local hours of the day, start inclusive and end exclusive.
+// A window with start after end wraps past midnight.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct SigningHours {
+    pub start: u8,
+    pub end: u8,
+}
+
+impl SigningHours {
+    fn contains(&self, hour: u8) -> bool {
+        if self.start <= self.end {
+            hour >= self.start && hour < self.end
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+impl PolicyConfig {
+    pub fn load(path: &Path) -> io::Result<PolicyConfig> {
+        let file = File::open(path)?;
+        let config: PolicyConfig = serde_json::from_reader(file)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        for policy in Some(&self.default).into_iter().chain(self.clients.values()) {
+            if let Some(hours) = policy.signing_hours {
+                if hours.start > 23 || hours.end > 24 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "signing_hours must be within 0..24",
+                    ));
+                }
+            }
+            if let Some(limit) = policy.rate_limit {
+                if limit.period_secs == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "rate_limit period_secs must be positive",
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn for_client(&self, client: &str) -> &ClientPolicy {
+        self.clients.get(client).unwrap_or(&self.default)
+    }
+}
+
+quick_error! {
+    #[derive(Debug, PartialEq)]
+    pub enum Violation {
+        AppIdDenied {
+            display("application is on the deny list")
+        }
+        AppIdNotAllowed {
+            display("application is not on the allow list")
+        }
+        RateLimited {
+            display("signing rate limit exceeded for key")
+        }
+        OutsideSigningHours {
+            display("signing is not permitted at this time of day")
+        }
+    }
+}
+
+pub struct Policy {
+    config: PolicyConfig,
+    signatures: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl Policy {
+    pub fn new(config: PolicyConfig) -> Policy {
+        Policy {
+            config,
+            signatures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Checks a signing request against the client's policy,
+    // counting it towards the key's rate limit if permitted
+    pub fn check(
+        &self,
+        client: &str,
+        key_id: &str,
+        application: &AppId,
+        now: Instant,
+        local_hour: u8,
+    ) -> Result<(), Violation> {
+        let policy = self.config.for_client(client);
+
+        if policy.denied_app_ids.contains(application) {
+            return Err(Violation::AppIdDenied);
+        }
+        if let Some(ref allowed) = policy.allowed_app_ids {
+            if !allowed.contains(application) {
+                return Err(Violation::AppIdNotAllowed);
+            }
+        }
+        if let Some(hours) = policy.signing_hours {
+            if !hours.contains(local_hour) {
+                return Err(Violation::OutsideSigningHours);
+            }
+        }
+
+        // Signatures are only remembered for keys with a limit, and only
+        // for as long as they count towards it
+        if let Some(limit) = policy.rate_limit {
+            let mut signatures = self.signatures.lock().unwrap();
+            let history = signatures
+                .entry(key_id.to_string())
+                .or_insert_with(VecDeque::new);
+            let period = Duration::from_secs(limit.period_secs);
+            while history
+                .front()
+                .map_or(false, |signed| now.duration_since(*signed) >= period)
+            {
+                history.pop_front();
+            }
+            if history.len() >= limit.max_signatures {
+                return Err(Violation::RateLimited);
+            }
+            history.push_back(now);
+        }
+        Ok(())
+    }
+
+    pub fn requires_approval(&self, client: &str, application: &AppId) -> bool {
+        self.config
+            .for_client(client)
+            .approval_required_app_ids
+            .contains(application)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: &str = "127.0.0.1";
+    const KEY_ID: &str = "key";
+
+    fn app_id(byte: u8) -> AppId {
+        AppId::from_bytes(&[byte; 32])
+    }
+
+    fn policy_for_client(client_policy: ClientPolicy) -> Policy {
+        let mut config = PolicyConfig::default();
+        config.clients.insert(CLIENT.to_string(), client_policy);
+        Policy::new(config)
+    }
+
+    #[test]
+    fn default_policy_allows_everything() {
+        let policy = Policy::new(PolicyConfig::default());
+
+        assert_eq!(
+            policy.check(CLIENT, KEY_ID, &app_id(1), Instant::now(), 3),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn denied_app_id_is_refused() {
+        let mut client_policy = ClientPolicy::default();
+        client_policy.denied_app_ids.insert(app_id(1));
+        let policy = policy_for_client(client_policy);
+
+        assert_eq!(
+            policy.check(CLIENT, KEY_ID, &app_id(1), Instant::now(), 12),
+            Err(Violation::AppIdDenied)
+        );
+        assert_eq!(
+            policy.check("10.0.0.1", KEY_ID, &app_id(1), Instant::now(), 12),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn app_id_missing_from_allow_list_is_refused() {
+        let mut client_policy = ClientPolicy::default();
+        client_policy.allowed_app_ids = Some(vec![app_id(1)].into_iter().collect());
+        let policy = policy_for_client(client_policy);
+
+        assert_eq!(
+            policy.check(CLIENT, KEY_ID, &app_id(1), Instant::now(), 12),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(CLIENT, KEY_ID, &app_id(2), Instant::now(), 12),
+            Err(Violation::AppIdNotAllowed)
+        );
+    }
+
+    #[test]
+    fn rate_limit_applies_per_key_within_period() {
+        let mut client_policy = ClientPolicy::default();
+        client_policy.rate_limit = Some(RateLimit {
+            max_signatures: 2,
+            period_secs: 60,
+        });
+        let policy = policy_for_client(client_policy);
+        let start = Instant::now();
+
+        assert_eq!(policy.check(CLIENT, KEY_ID, &app_id(1), start, 12), Ok(()));
+        assert_eq!(policy.check(CLIENT, KEY_ID, &app_id(1), start, 12), Ok(()));
+        assert_eq!(
+            policy.check(CLIENT, KEY_ID, &app_id(1), start, 12),
+            Err(Violation::RateLimited)
+        );
+        assert_eq!(policy.check(CLIENT, "other", &app_id(1), start, 12), Ok(()));
+        assert_eq!(
+            policy.check(
+                CLIENT,
+                KEY_ID,
+                &app_id(1),
+                start + Duration::from_secs(60),
+                12
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn signatures_are_only_remembered_within_rate_limit_period() {
+        let policy = Policy::new(PolicyConfig::default());
+        policy
+            .check(CLIENT, KEY_ID, &app_id(1), Instant::now(), 12)
+            .unwrap();
+        assert!(policy.signatures.lock().unwrap().is_empty());
+
+        let mut client_policy = ClientPolicy::default();
+        client_policy.rate_limit = Some(RateLimit {
+            max_signatures: 5,
+            period_secs: 60,
+        });
+        let policy = policy_for_client(client_policy);
+        let start = Instant::now();
+        for offset in 0..3 {
+            let now = start + Duration::from_secs(offset * 40);
+            policy.check(CLIENT, KEY_ID, &app_id(1), now, 12).unwrap();
+        }
+        assert_eq!(policy.signatures.lock().unwrap()[KEY_ID].len(), 2);
+    }
+
+    #[test]
+    fn signing_hours_wrap_past_midnight() {
+        let mut client_policy = ClientPolicy::default();
+        client_policy.signing_hours = Some(SigningHours { start: 22, end: 6 });
+        let policy = policy_for_client(client_policy);
+
+        assert_eq!(
+            policy.check(CLIENT, KEY_ID, &app_id(1), Instant::now(), 23),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(CLIENT, KEY_ID, &app_id(1), Instant::now(), 5),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(CLIENT, KEY_ID, &app_id(1), Instant::now(), 12),
+            Err(Violation::OutsideSigningHours)
+        );
+    }
+}
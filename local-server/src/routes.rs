@@ -0,0 +1,541 @@
+use std::io::{self, Read};
+use std::sync::Arc;
+
+use rocket::handler::{Handler, Outcome};
+use rocket::http::{Method, Status};
+use rocket::response::{content, status};
+use rocket::{self, Data, Request, Route};
+use rocket_contrib::json::Json;
+use serde_json;
+use server_lib::routes::ecdsa;
+use u2f_core::{
+    Approval, ApprovalPending, BigInt, CosignerCredential, Refusal, SigningContext, SigningKind,
+};
+
+use audit::{AuditEvent, AuditLog, AuditRecord};
+use clients::{ClientRegistry, CounterCheck, KeyAccess};
+use party_one::{PartyOne, RoundError};
+use sessions::{ApprovedSession, Decision, SigningSessions, PENDING_TIMEOUT};
+
+pub const REFUSED_PATH: &str = "/u2f/refused";
+pub const APPROVALS_PATH: &str = "/u2f/approvals";
+
+const CONTEXT_LIMIT: u64 = 4096;
+const SIGN_SECOND_LIMIT: u64 = 16384;
+
+// Ahead of gotham's own route for the second signing round, which the
+// SignSecond handler serves instead
+const SIGN_SECOND_RANK: isize = -10;
+
+// Why a request was refused, stashed by the fairing before it
+// reroutes the request to REFUSED_PATH
+pub struct Refused(pub Refusal);
+
+// The enrolled client a request was authenticated as, cached on the
+// request by the policy fairing
+pub struct Authenticated(pub Option<CosignerCredential>);
+
+// The approved session of a final signing round, cached on the request
+// for the audit fairing
+pub struct SigningRound(pub Option<ApprovedSession>);
+
+pub fn authenticated_client<'a>(request: &'a Request) -> Option<&'a CosignerCredential> {
+    request.local_cache(|| Authenticated(None)).0.as_ref()
+}
+
+pub fn client_identity(request: &Request) -> String {
+    authenticated_client(request)
+        .map(|credential| credential.client_id.clone())
+        .unwrap_or_else(|| String::from("unauthenticated"))
+}
+
+const BEARER_PREFIX: &str = "Bearer ";
+
+pub fn bearer_token<'a>(request: &'a Request) -> Option<&'a str> {
+    request
+        .headers()
+        .get_one("Authorization")
+        .filter(|value| value.starts_with(BEARER_PREFIX))
+        .map(|value| &value[BEARER_PREFIX.len()..])
+}
+
+pub fn refusal<'r>(request: &'r Request, refusal: &Refusal) -> Outcome<'r> {
+    let body = serde_json::to_string(refusal).unwrap();
+    Outcome::from(
+        request,
+        status::Custom(Status::Forbidden, content::Json(body)),
+    )
+}
+
+pub fn registry_error(err: io::Error) -> Refusal {
+    error!("Unable to read client registry: {}", err);
+    Refusal::new(String::from("client registry unavailable"))
+}
+
+// Why a client may not use a key, or None if it may
+pub fn key_access_refusal(access: KeyAccess) -> Option<Refusal> {
+    let reason = match access {
+        KeyAccess::Allowed => return None,
+        KeyAccess::OwnedByOther => String::from("key does not belong to this client"),
+        KeyAccess::Unclaimed => String::from("key has not been claimed for a registration"),
+        KeyAccess::QuotaReached { max_keys } => {
+            format!("account already has its limit of {} keys", max_keys)
+        }
+    };
+    Some(Refusal::new(reason))
+}
+
+#[derive(Clone)]
+struct SubmitContext {
+    sessions: Arc<SigningSessions>,
+    registry: Arc<ClientRegistry>,
+    audit_log: Arc<AuditLog>,
+}
+
+impl SubmitContext {
+    // Refuses, and records, a counter that did not increase since the
+    // last signature approved for the key
+    fn check_counter(
+        &self,
+        client_id: &str,
+        key_id: &str,
+        context: &SigningContext,
+    ) -> Result<(), Refusal> {
+        let counter = match context.counter {
+            Some(counter) => counter,
+            None => return Ok(()),
+        };
+        let last = match self.registry.advance_counter(key_id, counter) {
+            Ok(CounterCheck::Advanced) => return Ok(()),
+            Ok(CounterCheck::NotIncreasing { last }) => last,
+            Err(err) => return Err(registry_error(err)),
+        };
+        warn!(
+            "Counter {} for key {} is not greater than {}, its share may have been cloned",
+            counter, key_id, last
+        );
+        let record =
+            AuditRecord::signing(AuditEvent::CounterNotIncreasing, key_id, client_id, context);
+        if let Err(err) = self.audit_log.append(record) {
+            error!("Unable to append to the audit log: {}", err);
+        }
+        Err(Refusal::new(format!(
+            "counter {} is not greater than the last co-signed counter {}",
+            counter, last
+        )))
+    }
+}
+
+impl Handler for SubmitContext {
+    fn handle<'r>(&self, request: &'r Request, data: Data) -> Outcome<'r> {
+        let key_id = match request.get_param::<String>(0) {
+            Some(Ok(key_id)) => key_id,
+            _ => return Outcome::failure(Status::BadRequest),
+        };
+        let context: SigningContext = match serde_json::from_reader(data.open().take(CONTEXT_LIMIT))
+        {
+            Ok(context) => context,
+            Err(_) => return Outcome::failure(Status::BadRequest),
+        };
+        let credential = match authenticated_client(request) {
+            Some(credential) => credential,
+            None => {
+                return refusal(
+                    request,
+                    &Refusal::new(String::from("client is not enrolled")),
+                )
+            }
+        };
+        if context.kind != SigningKind::Authentication {
+            return refusal(
+                request,
+                &Refusal::new(String::from("only authentications are co-signed")),
+            );
+        }
+        if let Err(mismatch) = context.verify() {
+            return refusal(request, &Refusal::new(mismatch.to_string()));
+        }
+        // The counter only advances for a context the policy accepts
+        let decision =
+            self.sessions
+                .submit_context(&credential.client_id, &key_id, &context, || {
+                    self.check_counter(&credential.client_id, &key_id, &context)
+                        .map_err(|refused| refused.reason)
+                });
+        respond_to_decision(request, credential, &key_id, decision)
+    }
+}
+
+fn respond_to_decision<'r>(
+    request: &'r Request,
+    credential: &CosignerCredential,
+    key_id: &str,
+    decision: Result<Decision, String>,
+) -> Outcome<'r> {
+    match decision {
+        Ok(Decision::Approved(context)) => {
+            let approval = Approval {
+                proof: credential.approval_proof(key_id, &context),
+            };
+            let body = serde_json::to_string(&approval).unwrap();
+            Outcome::from(request, content::Json(body))
+        }
+        Ok(Decision::Pending) => {
+            let pending = ApprovalPending {
+                timeout_secs: PENDING_TIMEOUT.as_secs(),
+            };
+            let body = serde_json::to_string(&pending).unwrap();
+            Outcome::from(
+                request,
+                status::Custom(Status::Accepted, content::Json(body)),
+            )
+        }
+        Err(reason) => refusal(request, &Refusal::new(reason)),
+    }
+}
+
+// Polled by a client whose context is held for an approver
+#[derive(Clone)]
+struct PollContext(Arc<SigningSessions>);
+
+impl Handler for PollContext {
+    fn handle<'r>(&self, request: &'r Request, _data: Data) -> Outcome<'r> {
+        let key_id = match request.get_param::<String>(0) {
+            Some(Ok(key_id)) => key_id,
+            _ => return Outcome::failure(Status::BadRequest),
+        };
+        let credential = match authenticated_client(request) {
+            Some(credential) => credential,
+            None => {
+                return refusal(
+                    request,
+                    &Refusal::new(String::from("client is not enrolled")),
+                )
+            }
+        };
+        let decision = self.0.poll(&key_id);
+        respond_to_decision(request, credential, &key_id, decision)
+    }
+}
+
+// The digest a client asks gotham to sign in the second signing round
+#[derive(Deserialize)]
+struct SignSecondMessage {
+    message: BigInt,
+}
+
+// Serves gotham's second signing round, consuming the approval and first
+// round for the key, see SigningSessions::finish. The round is refused
+// unless the digest it signs is that of the approved context. Keys from
+// before shares were kept by PartyOne are still signed by gotham.
+#[derive(Clone)]
+struct SignSecond {
+    sessions: Arc<SigningSessions>,
+    party_one: Arc<PartyOne>,
+}
+
+impl SignSecond {
+    fn finish(&self, key_id: &str, message: &BigInt) -> Result<ApprovedSession, Refusal> {
+        let session = self.sessions.finish(key_id)?;
+        if *message != session.context.digest() {
+            return Err(Refusal::new(String::from(
+                "message does not match the approved signing context",
+            )));
+        }
+        Ok(session)
+    }
+
+    fn gotham_sign_second<'r>(
+        &self,
+        request: &'r Request,
+        key_id: String,
+        message: &BigInt,
+        body: &[u8],
+    ) -> Outcome<'r> {
+        let sign_request = match serde_json::from_slice(body) {
+            Ok(sign_request) => sign_request,
+            Err(_) => return Outcome::failure(Status::BadRequest),
+        };
+        let state = match request.guard() {
+            rocket::Outcome::Success(state) => state,
+            _ => return Outcome::failure(Status::InternalServerError),
+        };
+        let claim = match request.guard() {
+            rocket::Outcome::Success(claim) => claim,
+            _ => return Outcome::failure(Status::Unauthorized),
+        };
+
+        let session = match self.finish(&key_id, message) {
+            Ok(session) => session,
+            Err(refused) => return refusal(request, &refused),
+        };
+        match ecdsa::sign_second(state, claim, key_id.clone(), Json(sign_request)) {
+            Ok(signature) => {
+                request.local_cache(|| SigningRound(Some(session)));
+                Outcome::from(request, signature)
+            }
+            Err(err) => {
+                error!("Second signing round for key {} failed: {}", key_id, err);
+                Outcome::failure(Status::InternalServerError)
+            }
+        }
+    }
+}
+
+impl Handler for SignSecond {
+    fn handle<'r>(&self, request: &'r Request, data: Data) -> Outcome<'r> {
+        let key_id = match request.get_param::<String>(0) {
+            Some(Ok(key_id)) => key_id,
+            _ => return Outcome::failure(Status::BadRequest),
+        };
+        let mut body = Vec::new();
+        if data
+            .open()
+            .take(SIGN_SECOND_LIMIT)
+            .read_to_end(&mut body)
+            .is_err()
+        {
+            return Outcome::failure(Status::BadRequest);
+        }
+        let signed = match serde_json::from_slice::<SignSecondMessage>(&body) {
+            Ok(signed) => signed,
+            Err(_) => return Outcome::failure(Status::BadRequest),
+        };
+        if !self.party_one.holds(&key_id) {
+            return self.gotham_sign_second(request, key_id, &signed.message, &body);
+        }
+        let sign_request = match serde_json::from_slice(&body) {
+            Ok(sign_request) => sign_request,
+            Err(_) => return Outcome::failure(Status::BadRequest),
+        };
+
+        let session = match self.finish(&key_id, &signed.message) {
+            Ok(session) => session,
+            Err(refused) => return refusal(request, &refused),
+        };
+        match self.party_one.sign_second(&key_id, sign_request) {
+            Ok(signature) => {
+                request.local_cache(|| SigningRound(Some(session)));
+                Outcome::from(
+                    request,
+                    content::Json(serde_json::to_string(&signature).unwrap()),
+                )
+            }
+            Err(RoundError::NoFirstRound) => refusal(
+                request,
+                &Refusal::first_round_unknown(String::from("no first signing round for key")),
+            ),
+            Err(err) => {
+                error!("Second signing round for key {} failed: {}", key_id, err);
+                Outcome::failure(Status::InternalServerError)
+            }
+        }
+    }
+}
+
+// Approvers are only served over loopback, from the approvals command or
+// a companion app on the same machine, and must present a credential from
+// enroll-approver
+fn authorize_approver(registry: &ClientRegistry, request: &Request) -> Result<(), Status> {
+    let local = request
+        .remote()
+        .map_or(false, |address| address.ip().is_loopback());
+    if !local {
+        return Err(Status::Forbidden);
+    }
+    match bearer_token(request).map(|token| registry.authenticate_approver(token)) {
+        Some(Ok(Some(_))) => Ok(()),
+        Some(Err(err)) => {
+            error!("Unable to read client registry: {}", err);
+            Err(Status::ServiceUnavailable)
+        }
+        _ => Err(Status::Unauthorized),
+    }
+}
+
+#[derive(Clone)]
+struct ListApprovals {
+    sessions: Arc<SigningSessions>,
+    registry: Arc<ClientRegistry>,
+}
+
+impl Handler for ListApprovals {
+    fn handle<'r>(&self, request: &'r Request, _data: Data) -> Outcome<'r> {
+        if let Err(status) = authorize_approver(&self.registry, request) {
+            return Outcome::failure(status);
+        }
+        let body = serde_json::to_string(&self.sessions.pending()).unwrap();
+        Outcome::from(request, content::Json(body))
+    }
+}
+
+#[derive(Clone)]
+struct DecideApproval {
+    sessions: Arc<SigningSessions>,
+    registry: Arc<ClientRegistry>,
+    approve: bool,
+}
+
+impl Handler for DecideApproval {
+    fn handle<'r>(&self, request: &'r Request, _data: Data) -> Outcome<'r> {
+        if let Err(status) = authorize_approver(&self.registry, request) {
+            return Outcome::failure(status);
+        }
+        let id = match request.get_param::<u64>(0) {
+            Some(Ok(id)) => id,
+            _ => return Outcome::failure(Status::BadRequest),
+        };
+        if self.sessions.decide(id, self.approve) {
+            Outcome::from(request, Status::NoContent)
+        } else {
+            Outcome::failure(Status::NotFound)
+        }
+    }
+}
+
+// Claims a key for the registration described by the posted context,
+// see ClientRegistry::claim_key. The registration is held to the
+// client's policy and recorded, but not co-signed.
+#[derive(Clone)]
+struct ClaimKey {
+    sessions: Arc<SigningSessions>,
+    registry: Arc<ClientRegistry>,
+    audit_log: Arc<AuditLog>,
+}
+
+impl Handler for ClaimKey {
+    fn handle<'r>(&self, request: &'r Request, data: Data) -> Outcome<'r> {
+        let key_id = match request.get_param::<String>(0) {
+            Some(Ok(key_id)) => key_id,
+            _ => return Outcome::failure(Status::BadRequest),
+        };
+        let context: SigningContext = match serde_json::from_reader(data.open().take(CONTEXT_LIMIT))
+        {
+            Ok(context) => context,
+            Err(_) => return Outcome::failure(Status::BadRequest),
+        };
+        let credential = match authenticated_client(request) {
+            Some(credential) => credential,
+            None => {
+                return refusal(
+                    request,
+                    &Refusal::new(String::from("client is not enrolled")),
+                )
+            }
+        };
+        if context.kind != SigningKind::Registration {
+            return refusal(
+                request,
+                &Refusal::new(String::from("context is not for a registration")),
+            );
+        }
+        if let Err(mismatch) = context.verify() {
+            return refusal(request, &Refusal::new(mismatch.to_string()));
+        }
+        if let Err(reason) =
+            self.sessions
+                .check_registration(&credential.client_id, &key_id, &context)
+        {
+            return refusal(request, &Refusal::new(reason));
+        }
+        match self.registry.claim_key(&credential.client_id, &key_id) {
+            Ok(access) => {
+                if let Some(refused) = key_access_refusal(access) {
+                    return refusal(request, &refused);
+                }
+            }
+            Err(err) => return refusal(request, &registry_error(err)),
+        }
+        let record = AuditRecord::signing(
+            AuditEvent::Register,
+            &key_id,
+            &credential.client_id,
+            &context,
+        );
+        if let Err(err) = self.audit_log.append(record) {
+            error!("Unable to append to the audit log: {}", err);
+            return Outcome::failure(Status::InternalServerError);
+        }
+        Outcome::from(request, Status::NoContent)
+    }
+}
+
+#[derive(Clone)]
+struct RefuseRequest;
+
+impl Handler for RefuseRequest {
+    fn handle<'r>(&self, request: &'r Request, _data: Data) -> Outcome<'r> {
+        let refused =
+            request.local_cache(|| Refused(Refusal::new(String::from("request refused"))));
+        refusal(request, &refused.0)
+    }
+}
+
+pub fn routes(
+    sessions: &Arc<SigningSessions>,
+    registry: &Arc<ClientRegistry>,
+    audit_log: &Arc<AuditLog>,
+    party_one: &Arc<PartyOne>,
+) -> Vec<Route> {
+    vec![
+        Route::new(
+            Method::Post,
+            "/u2f/sign/<id>/context",
+            SubmitContext {
+                sessions: sessions.clone(),
+                registry: registry.clone(),
+                audit_log: audit_log.clone(),
+            },
+        ),
+        Route::new(
+            Method::Get,
+            "/u2f/sign/<id>/context",
+            PollContext(sessions.clone()),
+        ),
+        Route::new(
+            Method::Post,
+            "/u2f/keys/<id>/claim",
+            ClaimKey {
+                sessions: sessions.clone(),
+                registry: registry.clone(),
+                audit_log: audit_log.clone(),
+            },
+        ),
+        Route::ranked(
+            SIGN_SECOND_RANK,
+            Method::Post,
+            "/ecdsa/sign/<id>/second",
+            SignSecond {
+                sessions: sessions.clone(),
+                party_one: party_one.clone(),
+            },
+        ),
+        Route::new(
+            Method::Get,
+            APPROVALS_PATH,
+            ListApprovals {
+                sessions: sessions.clone(),
+                registry: registry.clone(),
+            },
+        ),
+        Route::new(
+            Method::Post,
+            "/u2f/approvals/<id>/approve",
+            DecideApproval {
+                sessions: sessions.clone(),
+                registry: registry.clone(),
+                approve: true,
+            },
+        ),
+        Route::new(
+            Method::Post,
+            "/u2f/approvals/<id>/deny",
+            DecideApproval {
+                sessions: sessions.clone(),
+                registry: registry.clone(),
+                approve: false,
+            },
+        ),
+        Route::new(Method::Post, REFUSED_PATH, RefuseRequest),
+    ]
+}
@@ -0,0 +1,493 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use time;
+use u2f_core::{AppId, Counter, Refusal, SigningContext, SigningKind};
+
+use policy::Policy;
+
+// How long an approved signing context may wait for the signing rounds
+pub const APPROVAL_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long a signing context may wait for an approver to accept it
+pub const PENDING_TIMEOUT: Duration = Duration::from_secs(120);
+
+struct Approval {
+    approved_at: Instant,
+    session: ApprovedSession,
+}
+
+struct PendingApproval {
+    id: u64,
+    submitted_at: Instant,
+    denied: bool,
+    session: ApprovedSession,
+}
+
+// The client and context a signature was approved for
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApprovedSession {
+    pub client: String,
+    pub context: SigningContext,
+}
+
+// Whether the signing rounds for a submitted context may start
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Approved(SigningContext),
+    // Waiting on an approver, see SigningSessions::decide
+    Pending,
+}
+
+// A signing context waiting on an approver, as listed to approvers
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PendingSession {
+    pub id: u64,
+    pub key_id: String,
+    pub client: String,
+    pub kind: SigningKind,
+    pub app_id: AppId,
+    pub site: Option<String>,
+    pub counter: Option<Counter>,
+    pub waiting_secs: u64,
+}
+
+#[derive(Default)]
+struct State {
+    approvals: HashMap<String, Approval>,
+    pending: HashMap<String, PendingApproval>,
+    last_pending_id: u64,
+    // Keys whose first signing round has not yet been followed by a second
+    presigned: HashSet<String>,
+}
+
+// Tracks which keys have an approved signing context. The first gotham
+// signing round does not depend on the message, so clients run it ahead
+// of time, but the second is only served for a key with an outstanding
+// approval and a first round it has not been served for. Each approval
+// and each first round is good for a single signature, so the gotham
+// server never signs twice with the same ephemeral key. Contexts the
+// policy holds for an approver wait as pending until accepted or denied.
+pub struct SigningSessions {
+    policy: Policy,
+    state: Mutex<State>,
+}
+
+impl SigningSessions {
+    pub fn new(policy: Policy) -> SigningSessions {
+        SigningSessions {
+            policy,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    // Checks the context against the policy, then gives accept a last
+    // chance to refuse it before it is approved or held for an approver
+    pub fn submit_context<F>(
+        &self,
+        client: &str,
+        key_id: &str,
+        context: &SigningContext,
+        accept: F,
+    ) -> Result<Decision, String>
+    where
+        F: FnOnce() -> Result<(), String>,
+    {
+        let local_hour = time::now().tm_hour as u8;
+        self.submit_context_at(client, key_id, context, Instant::now(), local_hour, accept)
+    }
+
+    // Registrations are not co-signed, but are held to the same policy
+    pub fn check_registration(
+        &self,
+        client: &str,
+        key_id: &str,
+        context: &SigningContext,
+    ) -> Result<(), String> {
+        let local_hour = time::now().tm_hour as u8;
+        self.policy
+            .check(client, key_id, &context.app_id, Instant::now(), local_hour)
+            .map_err(|violation| violation.to_string())
+    }
+
+    fn submit_context_at<F>(
+        &self,
+        client: &str,
+        key_id: &str,
+        context: &SigningContext,
+        now: Instant,
+        local_hour: u8,
+        accept: F,
+    ) -> Result<Decision, String>
+    where
+        F: FnOnce() -> Result<(), String>,
+    {
+        self.policy
+            .check(client, key_id, &context.app_id, now, local_hour)
+            .map_err(|violation| violation.to_string())?;
+        accept()?;
+        let session = ApprovedSession {
+            client: client.to_string(),
+            context: context.clone(),
+        };
+        let mut state = self.state.lock().unwrap();
+        state.approvals.remove(key_id);
+        if self.policy.requires_approval(client, &context.app_id) {
+            state.last_pending_id += 1;
+            let id = state.last_pending_id;
+            state.pending.insert(
+                key_id.to_string(),
+                PendingApproval {
+                    id,
+                    submitted_at: now,
+                    denied: false,
+                    session,
+                },
+            );
+            return Ok(Decision::Pending);
+        }
+        state.pending.remove(key_id);
+        state.approvals.insert(
+            key_id.to_string(),
+            Approval {
+                approved_at: now,
+                session,
+            },
+        );
+        Ok(Decision::Approved(context.clone()))
+    }
+
+    // Polled by a client waiting on an approver for its submitted context
+    pub fn poll(&self, key_id: &str) -> Result<Decision, String> {
+        self.poll_at(key_id, Instant::now())
+    }
+
+    fn poll_at(&self, key_id: &str, now: Instant) -> Result<Decision, String> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(approval) = state.approvals.get(key_id) {
+            if !expired(approval, now) {
+                return Ok(Decision::Approved(approval.session.context.clone()));
+            }
+        }
+        match state.pending.remove(key_id) {
+            Some(ref pending) if pending.denied => {
+                Err(String::from("approver denied the signature"))
+            }
+            Some(ref pending) if pending_expired(pending, now) => {
+                Err(String::from("no approver accepted the signature in time"))
+            }
+            Some(pending) => {
+                state.pending.insert(key_id.to_string(), pending);
+                Ok(Decision::Pending)
+            }
+            None => Err(String::from("no signing context submitted for key")),
+        }
+    }
+
+    // Contexts waiting on an approver, oldest first
+    pub fn pending(&self) -> Vec<PendingSession> {
+        self.pending_at(Instant::now())
+    }
+
+    fn pending_at(&self, now: Instant) -> Vec<PendingSession> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .pending
+            .retain(|_, pending| !pending_expired(pending, now));
+        let mut sessions: Vec<PendingSession> = state
+            .pending
+            .iter()
+            .filter(|&(_, pending)| !pending.denied)
+            .map(|(key_id, pending)| {
+                let context = &pending.session.context;
+                PendingSession {
+                    id: pending.id,
+                    key_id: key_id.clone(),
+                    client: pending.session.client.clone(),
+                    kind: context.kind,
+                    app_id: context.app_id,
+                    site: context.site.clone(),
+                    counter: context.counter,
+                    waiting_secs: now.duration_since(pending.submitted_at).as_secs(),
+                }
+            })
+            .collect();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
+    // Accepts or denies a pending context, returning false if no context
+    // with the id is waiting
+    pub fn decide(&self, id: u64, approve: bool) -> bool {
+        self.decide_at(id, approve, Instant::now())
+    }
+
+    fn decide_at(&self, id: u64, approve: bool, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let key_id = match state.pending.iter().find(|&(_, pending)| {
+            pending.id == id && !pending.denied && !pending_expired(pending, now)
+        }) {
+            Some((key_id, _)) => key_id.clone(),
+            None => return false,
+        };
+        if !approve {
+            state.pending.get_mut(&key_id).unwrap().denied = true;
+            return true;
+        }
+        let pending = state.pending.remove(&key_id).unwrap();
+        state.approvals.insert(
+            key_id,
+            Approval {
+                approved_at: now,
+                session: pending.session,
+            },
+        );
+        true
+    }
+
+    // Called before the first signing round, which replaces any earlier
+    // first round for the key whether or not it succeeds
+    pub fn start_presign(&self, key_id: &str) {
+        self.state.lock().unwrap().presigned.remove(key_id);
+    }
+
+    // Called once the first signing round has succeeded
+    pub fn presign(&self, key_id: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .presigned
+            .insert(key_id.to_string());
+    }
+
+    // Called before the second signing round, consuming the approval and
+    // the first round. The approval is kept if there was no first round,
+    // so the client can run both rounds instead.
+    pub fn finish(&self, key_id: &str) -> Result<ApprovedSession, Refusal> {
+        self.finish_at(key_id, Instant::now())
+    }
+
+    fn finish_at(&self, key_id: &str, now: Instant) -> Result<ApprovedSession, Refusal> {
+        let mut state = self.state.lock().unwrap();
+        match state.approvals.get(key_id) {
+            Some(approval) if !expired(approval, now) => {}
+            _ => {
+                state.approvals.remove(key_id);
+                return Err(Refusal::new(String::from(
+                    "no approved signing context for key",
+                )));
+            }
+        }
+        if !state.presigned.remove(key_id) {
+            return Err(Refusal::first_round_unknown(String::from(
+                "no first signing round for key",
+            )));
+        }
+        Ok(state.approvals.remove(key_id).unwrap().session)
+    }
+}
+
+fn expired(approval: &Approval, now: Instant) -> bool {
+    now.duration_since(approval.approved_at) > APPROVAL_TIMEOUT
+}
+
+fn pending_expired(pending: &PendingApproval, now: Instant) -> bool {
+    now.duration_since(pending.submitted_at) > PENDING_TIMEOUT
+}
+
+#[cfg(test)]
+mod tests {
+    use u2f_core::AppId;
+
+    use super::*;
+    use policy::PolicyConfig;
+
+    const KEY_ID: &str = "key";
+
+    fn sessions() -> SigningSessions {
+        SigningSessions::new(Policy::new(PolicyConfig::default()))
+    }
+
+    fn sessions_requiring_approval() -> SigningSessions {
+        let mut config = PolicyConfig::default();
+        config
+            .default
+            .approval_required_app_ids
+            .insert(context().app_id);
+        SigningSessions::new(Policy::new(config))
+    }
+
+    fn context() -> SigningContext {
+        SigningContext::authentication(AppId::from_bytes(&[1u8; 32]), 1, vec![0u8; 69])
+    }
+
+    fn accept() -> Result<(), String> {
+        Ok(())
+    }
+
+    #[test]
+    fn signing_requires_approved_context() {
+        let sessions = sessions();
+        sessions.presign(KEY_ID);
+
+        assert!(!sessions.finish(KEY_ID).unwrap_err().first_round_unknown);
+    }
+
+    #[test]
+    fn approval_is_good_for_one_signature() {
+        let sessions = sessions();
+        let now = Instant::now();
+        assert_eq!(
+            sessions.submit_context_at("client", KEY_ID, &context(), now, 12, accept),
+            Ok(Decision::Approved(context()))
+        );
+
+        sessions.presign(KEY_ID);
+        assert_eq!(
+            sessions.finish_at(KEY_ID, now),
+            Ok(ApprovedSession {
+                client: String::from("client"),
+                context: context(),
+            })
+        );
+        sessions.presign(KEY_ID);
+        assert!(sessions.finish_at(KEY_ID, now).is_err());
+    }
+
+    #[test]
+    fn context_is_only_accepted_after_policy_check() {
+        let mut config = PolicyConfig::default();
+        config.default.denied_app_ids.insert(context().app_id);
+        let sessions = SigningSessions::new(Policy::new(config));
+        let now = Instant::now();
+        assert!(sessions
+            .submit_context_at("client", KEY_ID, &context(), now, 12, || {
+                panic!("accepted a context the policy refused")
+            })
+            .is_err());
+
+        let sessions = self::sessions();
+        assert_eq!(
+            sessions.submit_context_at("client", KEY_ID, &context(), now, 12, || {
+                Err(String::from("refused"))
+            }),
+            Err(String::from("refused"))
+        );
+        sessions.presign(KEY_ID);
+        assert!(sessions.finish_at(KEY_ID, now).is_err());
+    }
+
+    #[test]
+    fn first_round_is_good_for_one_signature() {
+        let sessions = sessions();
+        let now = Instant::now();
+        sessions.presign(KEY_ID);
+        sessions
+            .submit_context_at("client", KEY_ID, &context(), now, 12, accept)
+            .unwrap();
+        assert!(sessions.finish_at(KEY_ID, now).is_ok());
+
+        sessions
+            .submit_context_at("client", KEY_ID, &context(), now, 12, accept)
+            .unwrap();
+        assert!(
+            sessions
+                .finish_at(KEY_ID, now)
+                .unwrap_err()
+                .first_round_unknown
+        );
+
+        // The approval waits for the client to run the first round
+        sessions.presign(KEY_ID);
+        assert!(sessions.finish_at(KEY_ID, now).is_ok());
+    }
+
+    #[test]
+    fn failed_first_round_replaces_earlier_one() {
+        let sessions = sessions();
+        let now = Instant::now();
+        sessions.presign(KEY_ID);
+        sessions
+            .submit_context_at("client", KEY_ID, &context(), now, 12, accept)
+            .unwrap();
+
+        sessions.start_presign(KEY_ID);
+        assert!(sessions.finish_at(KEY_ID, now).is_err());
+
+        sessions.start_presign(KEY_ID);
+        sessions.presign(KEY_ID);
+        assert!(sessions.finish_at(KEY_ID, now).is_ok());
+    }
+
+    #[test]
+    fn approval_expires() {
+        let sessions = sessions();
+        let now = Instant::now();
+        sessions
+            .submit_context_at("client", KEY_ID, &context(), now, 12, accept)
+            .unwrap();
+        sessions.presign(KEY_ID);
+
+        assert!(sessions
+            .finish_at(KEY_ID, now + APPROVAL_TIMEOUT + Duration::from_secs(1))
+            .is_err());
+    }
+
+    #[test]
+    fn held_context_waits_for_approver() {
+        let sessions = sessions_requiring_approval();
+        let now = Instant::now();
+        assert_eq!(
+            sessions.submit_context_at("client", KEY_ID, &context(), now, 12, accept),
+            Ok(Decision::Pending)
+        );
+        sessions.presign(KEY_ID);
+        assert!(sessions.finish_at(KEY_ID, now).is_err());
+        assert_eq!(sessions.poll_at(KEY_ID, now), Ok(Decision::Pending));
+
+        let pending = sessions.pending_at(now);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].key_id, KEY_ID);
+        assert!(sessions.decide_at(pending[0].id, true, now));
+
+        assert_eq!(
+            sessions.poll_at(KEY_ID, now),
+            Ok(Decision::Approved(context()))
+        );
+        assert!(sessions.pending_at(now).is_empty());
+        sessions.presign(KEY_ID);
+        assert!(sessions.finish_at(KEY_ID, now).is_ok());
+    }
+
+    #[test]
+    fn denied_context_is_refused() {
+        let sessions = sessions_requiring_approval();
+        let now = Instant::now();
+        sessions
+            .submit_context_at("client", KEY_ID, &context(), now, 12, accept)
+            .unwrap();
+        let id = sessions.pending_at(now)[0].id;
+
+        assert!(sessions.decide_at(id, false, now));
+
+        assert!(sessions.pending_at(now).is_empty());
+        assert!(!sessions.decide_at(id, true, now));
+        assert!(sessions.poll_at(KEY_ID, now).is_err());
+        sessions.presign(KEY_ID);
+        assert!(sessions.finish_at(KEY_ID, now).is_err());
+    }
+
+    #[test]
+    fn held_context_expires() {
+        let sessions = sessions_requiring_approval();
+        let now = Instant::now();
+        sessions
+            .submit_context_at("client", KEY_ID, &context(), now, 12, accept)
+            .unwrap();
+        let later = now + PENDING_TIMEOUT + Duration::from_secs(1);
+
+        assert!(sessions.poll_at(KEY_ID, later).is_err());
+        assert!(sessions.pending_at(later).is_empty());
+    }
+}
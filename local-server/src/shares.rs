@@ -0,0 +1,503 @@
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use libc;
+use openssl::base64;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+
+use atomic_file;
+
+const SHARE_KEY_FILE: &str = "share-key.json";
+const SHARES_DIR: &str = "shares";
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const SALT_LEN: usize = 16;
+const PBKDF2_ITERATIONS: usize = 200_000;
+// Associated data of the wrapped share key, each share's being its key id
+const SHARE_KEY_AAD: &[u8] = b"share key";
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ShareError {
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            display("Unable to access key shares: {}", err)
+        }
+        Json(err: serde_json::Error) {
+            from()
+            cause(err)
+            display("Invalid key share data: {}", err)
+        }
+        Crypto(err: ErrorStack) {
+            from()
+            cause(err)
+            display("Key share encryption failed: {}", err)
+        }
+        InvalidKeyFile(path: PathBuf) {
+            display("Master key file {} must hold exactly {} bytes", path.display(), KEY_LEN)
+        }
+        MasterKeyKind(expected: &'static str) {
+            display("The share key is wrapped with a master {}, give that instead", expected)
+        }
+        WrongMasterKey {
+            display("The master key does not unwrap the share key")
+        }
+        MissingShareKey(path: PathBuf) {
+            display("The share key {} is missing, so the key shares cannot be decrypted", path.display())
+        }
+        Undecryptable(key_id: String) {
+            display("Key share {} does not decrypt, it was modified or written under another share key", key_id)
+        }
+        InvalidKeyId(key_id: String) {
+            display("Invalid key id {:?}", key_id)
+        }
+        PassphraseMismatch {
+            display("The passphrases do not match")
+        }
+    }
+}
+
+// The master key wraps the share key, which encrypts every share, so the
+// master key can be replaced without touching the shares
+pub enum MasterKey {
+    // Exactly KEY_LEN random bytes
+    File(PathBuf),
+    // Stretched with PBKDF2 and a salt kept with the wrapped share key
+    Passphrase(String),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Kdf {
+    salt: String,
+    iterations: usize,
+}
+
+// share-key.json, the share key encrypted under the master key. Binary
+// fields are base64 and the key is followed by its GCM tag.
+#[derive(Serialize, Deserialize)]
+struct WrappedShareKey {
+    kdf: Option<Kdf>,
+    nonce: String,
+    key: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SealedShare {
+    nonce: String,
+    ciphertext: String,
+}
+
+impl MasterKey {
+    fn new_kdf(&self) -> Result<Option<Kdf>, ShareError> {
+        match *self {
+            MasterKey::File(_) => Ok(None),
+            MasterKey::Passphrase(_) => Ok(Some(Kdf {
+                salt: base64::encode_block(&random_bytes(SALT_LEN)?),
+                iterations: PBKDF2_ITERATIONS,
+            })),
+        }
+    }
+
+    fn derive(&self, kdf: Option<&Kdf>) -> Result<Vec<u8>, ShareError> {
+        match (self, kdf) {
+            (MasterKey::File(path), None) => {
+                let key = fs::read(path)?;
+                if key.len() != KEY_LEN {
+                    return Err(ShareError::InvalidKeyFile(path.clone()));
+                }
+                Ok(key)
+            }
+            (MasterKey::Passphrase(passphrase), Some(kdf)) => {
+                let mut key = vec![0u8; KEY_LEN];
+                pbkdf2_hmac(
+                    passphrase.as_bytes(),
+                    &decode(&kdf.salt)?,
+                    kdf.iterations,
+                    MessageDigest::sha256(),
+                    &mut key,
+                )?;
+                Ok(key)
+            }
+            (MasterKey::File(_), Some(_)) => Err(ShareError::MasterKeyKind("passphrase")),
+            (MasterKey::Passphrase(_), None) => Err(ShareError::MasterKeyKind("key file")),
+        }
+    }
+}
+
+// The co-signer's key shares, each encrypted with AES-256-GCM under the
+// share key and bound to its key id, in a file of its own in data_dir
+pub struct ShareStore {
+    dir: PathBuf,
+    share_key: Vec<u8>,
+}
+
+impl ShareStore {
+    // Unwraps the share key, generating one on first use
+    pub fn open(data_dir: &Path, master_key: &MasterKey) -> Result<ShareStore, ShareError> {
+        let path = data_dir.join(SHARE_KEY_FILE);
+        let dir = data_dir.join(SHARES_DIR);
+        let share_key = match read_share_key(&path)? {
+            Some(wrapped) => unwrap_share_key(&wrapped, master_key)?,
+            None => {
+                // A lost share key is not replaced, the shares would be
+                // unreadable under the new one
+                if has_shares(&dir)? {
+                    return Err(ShareError::MissingShareKey(path));
+                }
+                let share_key = random_bytes(KEY_LEN)?;
+                write_share_key(&path, &wrap_share_key(&share_key, master_key)?)?;
+                share_key
+            }
+        };
+        fs::create_dir_all(&dir)?;
+        Ok(ShareStore { dir, share_key })
+    }
+
+    pub fn contains(&self, key_id: &str) -> bool {
+        is_share_id(key_id) && self.dir.join(key_id).is_file()
+    }
+
+    pub fn put<T: Serialize>(&self, key_id: &str, share: &T) -> Result<(), ShareError> {
+        let path = self.share_path(key_id)?;
+        let nonce = random_bytes(NONCE_LEN)?;
+        let mut tag = [0u8; TAG_LEN];
+        let mut ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.share_key,
+            Some(&nonce),
+            key_id.as_bytes(),
+            &serde_json::to_vec(share)?,
+            &mut tag,
+        )?;
+        ciphertext.extend_from_slice(&tag);
+        let sealed = SealedShare {
+            nonce: base64::encode_block(&nonce),
+            ciphertext: base64::encode_block(&ciphertext),
+        };
+        atomic_file::overwrite(&path, |writer| {
+            serde_json::to_writer(writer, &sealed).map_err(|e| e.into())
+        })?;
+        Ok(())
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key_id: &str) -> Result<Option<T>, ShareError> {
+        let path = self.share_path(key_id)?;
+        let sealed: SealedShare = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let plaintext = open(
+            &self.share_key,
+            &decode(&sealed.nonce)?,
+            key_id.as_bytes(),
+            &decode(&sealed.ciphertext)?,
+        )
+        .ok_or_else(|| ShareError::Undecryptable(key_id.to_string()))?;
+        Ok(Some(serde_json::from_slice(&plaintext)?))
+    }
+
+    fn share_path(&self, key_id: &str) -> Result<PathBuf, ShareError> {
+        if !is_share_id(key_id) {
+            return Err(ShareError::InvalidKeyId(key_id.to_string()));
+        }
+        Ok(self.dir.join(key_id))
+    }
+}
+
+pub fn has_share_key(data_dir: &Path) -> bool {
+    data_dir.join(SHARE_KEY_FILE).exists()
+}
+
+// Rewraps the share key under a new master key. The shares are left as
+// they are, and a running server keeps the share key it unwrapped at
+// startup, so it carries on serving while the master key is replaced.
+pub fn rotate_master_key(
+    data_dir: &Path,
+    current: &MasterKey,
+    new: &MasterKey,
+) -> Result<(), ShareError> {
+    let path = data_dir.join(SHARE_KEY_FILE);
+    let wrapped =
+        read_share_key(&path)?.ok_or_else(|| ShareError::MissingShareKey(path.clone()))?;
+    let share_key = unwrap_share_key(&wrapped, current)?;
+    write_share_key(&path, &wrap_share_key(&share_key, new)?)
+}
+
+// A random version 4 UUID, the form of gotham's key ids
+pub fn new_share_id() -> Result<String, ShareError> {
+    let mut bytes = random_bytes(16)?;
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    ))
+}
+
+// Key ids name share files, so only the UUIDs new_share_id makes are taken
+fn is_share_id(key_id: &str) -> bool {
+    key_id.len() == 36
+        && key_id.chars().enumerate().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit() && !c.is_ascii_uppercase(),
+        })
+}
+
+// Reads a passphrase from standard input, without echoing it when that is
+// a terminal. With confirm, a new passphrase is asked for twice.
+pub fn read_passphrase(prompt: &str, confirm: bool) -> Result<String, ShareError> {
+    let passphrase = read_line_quietly(prompt)?;
+    if passphrase.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty passphrase").into());
+    }
+    if confirm && read_line_quietly("Repeat passphrase: ")? != passphrase {
+        return Err(ShareError::PassphraseMismatch);
+    }
+    Ok(passphrase)
+}
+
+fn read_line_quietly(prompt: &str) -> io::Result<String> {
+    let fd = libc::STDIN_FILENO;
+    let mut saved: libc::termios = unsafe { mem::zeroed() };
+    let quiet = unsafe { libc::isatty(fd) == 1 && libc::tcgetattr(fd, &mut saved) == 0 };
+    if quiet {
+        eprint!("{}", prompt);
+        io::stderr().flush()?;
+        let mut no_echo = saved;
+        no_echo.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &no_echo) };
+    }
+    let mut line = String::new();
+    let read = io::stdin().lock().read_line(&mut line);
+    if quiet {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+        eprintln!();
+    }
+    read?;
+    Ok(line.trim_end_matches(&['\n', '\r'][..]).to_string())
+}
+
+fn wrap_share_key(share_key: &[u8], master_key: &MasterKey) -> Result<WrappedShareKey, ShareError> {
+    let kdf = master_key.new_kdf()?;
+    let key = master_key.derive(kdf.as_ref())?;
+    let nonce = random_bytes(NONCE_LEN)?;
+    let mut tag = [0u8; TAG_LEN];
+    let mut wrapped = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key,
+        Some(&nonce),
+        SHARE_KEY_AAD,
+        share_key,
+        &mut tag,
+    )?;
+    wrapped.extend_from_slice(&tag);
+    Ok(WrappedShareKey {
+        kdf,
+        nonce: base64::encode_block(&nonce),
+        key: base64::encode_block(&wrapped),
+    })
+}
+
+fn unwrap_share_key(
+    wrapped: &WrappedShareKey,
+    master_key: &MasterKey,
+) -> Result<Vec<u8>, ShareError> {
+    let key = master_key.derive(wrapped.kdf.as_ref())?;
+    open(
+        &key,
+        &decode(&wrapped.nonce)?,
+        SHARE_KEY_AAD,
+        &decode(&wrapped.key)?,
+    )
+    .ok_or(ShareError::WrongMasterKey)
+}
+
+// Decrypts and authenticates ciphertext followed by its tag, None if it
+// was not encrypted under the key and associated data
+fn open(key: &[u8], nonce: &[u8], aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < TAG_LEN {
+        return None;
+    }
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        aad,
+        ciphertext,
+        tag,
+    )
+    .ok()
+}
+
+fn read_share_key(path: &Path) -> Result<Option<WrappedShareKey>, ShareError> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn write_share_key(path: &Path, wrapped: &WrappedShareKey) -> Result<(), ShareError> {
+    atomic_file::overwrite(path, |writer| {
+        serde_json::to_writer_pretty(writer, wrapped).map_err(|e| e.into())
+    })?;
+    Ok(())
+}
+
+fn has_shares(dir: &Path) -> io::Result<bool> {
+    match fs::read_dir(dir) {
+        Ok(mut entries) => Ok(entries.next().is_some()),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>, ShareError> {
+    let mut bytes = vec![0u8; len];
+    rand_bytes(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn decode(field: &str) -> Result<Vec<u8>, ShareError> {
+    base64::decode_block(field).map_err(ShareError::Crypto)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Share {
+        secret: String,
+    }
+
+    fn share() -> Share {
+        Share {
+            secret: String::from("party one secret"),
+        }
+    }
+
+    fn key_file(dir: &Path, name: &str) -> MasterKey {
+        let path = dir.join(name);
+        fs::write(&path, random_bytes(KEY_LEN).unwrap()).unwrap();
+        MasterKey::File(path)
+    }
+
+    #[test]
+    fn shares_are_encrypted_at_rest() {
+        let dir = TempDir::new("shares").unwrap();
+        let master_key = key_file(dir.path(), "master.key");
+        let id = new_share_id().unwrap();
+
+        ShareStore::open(dir.path(), &master_key)
+            .unwrap()
+            .put(&id, &share())
+            .unwrap();
+
+        let on_disk = fs::read(dir.path().join(SHARES_DIR).join(&id)).unwrap();
+        assert!(!String::from_utf8_lossy(&on_disk).contains("party one secret"));
+        // As read back by the next run of the server
+        let store = ShareStore::open(dir.path(), &master_key).unwrap();
+        assert!(store.contains(&id));
+        assert_eq!(store.get::<Share>(&id).unwrap(), Some(share()));
+        assert_eq!(store.get::<Share>(&new_share_id().unwrap()).unwrap(), None);
+    }
+
+    #[test]
+    fn other_master_keys_are_refused() {
+        let dir = TempDir::new("shares").unwrap();
+        ShareStore::open(dir.path(), &key_file(dir.path(), "master.key")).unwrap();
+
+        match ShareStore::open(dir.path(), &key_file(dir.path(), "other.key")) {
+            Err(ShareError::WrongMasterKey) => {}
+            _ => panic!("share key unwrapped with another master key"),
+        }
+        match ShareStore::open(dir.path(), &MasterKey::Passphrase(String::from("hunter2"))) {
+            Err(ShareError::MasterKeyKind("key file")) => {}
+            _ => panic!("share key unwrapped with a passphrase"),
+        }
+    }
+
+    #[test]
+    fn shares_are_bound_to_their_key_id() {
+        let dir = TempDir::new("shares").unwrap();
+        let store = ShareStore::open(dir.path(), &key_file(dir.path(), "master.key")).unwrap();
+        let id = new_share_id().unwrap();
+        let other_id = new_share_id().unwrap();
+        store.put(&id, &share()).unwrap();
+
+        let shares = dir.path().join(SHARES_DIR);
+        fs::copy(shares.join(&id), shares.join(&other_id)).unwrap();
+
+        assert!(store.get::<Share>(&other_id).is_err());
+        assert!(store.get::<Share>("../share-key.json").is_err());
+    }
+
+    #[test]
+    fn rotation_keeps_shares_readable() {
+        let dir = TempDir::new("shares").unwrap();
+        let old_key = key_file(dir.path(), "master.key");
+        let passphrase = MasterKey::Passphrase(String::from("correct horse battery staple"));
+        let id = new_share_id().unwrap();
+        ShareStore::open(dir.path(), &old_key)
+            .unwrap()
+            .put(&id, &share())
+            .unwrap();
+
+        rotate_master_key(dir.path(), &old_key, &passphrase).unwrap();
+
+        let store = ShareStore::open(dir.path(), &passphrase).unwrap();
+        assert_eq!(store.get::<Share>(&id).unwrap(), Some(share()));
+        assert!(ShareStore::open(dir.path(), &old_key).is_err());
+        assert!(rotate_master_key(dir.path(), &old_key, &passphrase).is_err());
+    }
+
+    #[test]
+    fn lost_share_key_is_not_replaced() {
+        let dir = TempDir::new("shares").unwrap();
+        let master_key = key_file(dir.path(), "master.key");
+        ShareStore::open(dir.path(), &master_key)
+            .unwrap()
+            .put(&new_share_id().unwrap(), &share())
+            .unwrap();
+
+        fs::remove_file(dir.path().join(SHARE_KEY_FILE)).unwrap();
+
+        match ShareStore::open(dir.path(), &master_key) {
+            Err(ShareError::MissingShareKey(_)) => {}
+            _ => panic!("share key replaced"),
+        }
+    }
+
+    #[test]
+    fn share_ids_are_uuids() {
+        let id = new_share_id().unwrap();
+
+        assert!(is_share_id(&id));
+        assert_eq!(&id[14..15], "4");
+        assert!(!is_share_id("../../etc/passwd"));
+        assert!(!is_share_id(&id.to_uppercase()));
+    }
+}
@@ -0,0 +1,304 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use httparse;
+use libc;
+use rocket::http::{Header, Method};
+use rocket::local::Client;
+
+// Keygen's Paillier proofs are the largest bodies the routes take
+const MAX_HEAD_LEN: usize = 16 * 1024;
+const MAX_BODY_LEN: usize = 4 * 1024 * 1024;
+const MAX_HEADERS: usize = 32;
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct ParsedRequest {
+    method: String,
+    uri: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+// Serves the routes on a Unix domain socket instead of TCP, for a
+// co-signer on the same host as its user daemons. Requests are answered
+// by Rocket in process, as if from loopback, once the peer's uid has been
+// checked against the server's own and allowed_uids.
+pub fn serve(client: Client, path: &Path, allowed_uids: &[u32]) -> io::Result<()> {
+    let client = Arc::new(client);
+    let listener = bind(path)?;
+    info!("Listening on {}", path.display());
+    for stream in listener.incoming() {
+        let stream = stream?;
+        match peer_uid(&stream) {
+            Ok(uid) if may_connect(uid, allowed_uids) => {}
+            Ok(uid) => {
+                warn!("Refused a connection from uid {}", uid);
+                continue;
+            }
+            Err(err) => {
+                warn!("Unable to read the credentials of a connection: {}", err);
+                continue;
+            }
+        }
+        let client = client.clone();
+        thread::spawn(move || {
+            if let Err(err) = handle(&client, stream) {
+                warn!("Failed to serve a request over the socket: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn may_connect(uid: u32, allowed_uids: &[u32]) -> bool {
+    uid == unsafe { libc::geteuid() } || allowed_uids.contains(&uid)
+}
+
+// Connecting needs write permission on the socket, so only the server's
+// user and group get it
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    // A socket left behind by an earlier run, anything else is kept
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+    Ok(listener)
+}
+
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut credentials = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(credentials.uid)
+}
+
+fn handle(client: &Client, mut stream: UnixStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let request = match read_request(&mut stream) {
+        Ok(request) => request,
+        Err(err) => {
+            write_response(&mut stream, 400, "Bad Request", &[], &[])?;
+            return Err(err);
+        }
+    };
+    let method = match Method::from_str(&request.method) {
+        Ok(method) => method,
+        Err(_) => return write_response(&mut stream, 405, "Method Not Allowed", &[], &[]),
+    };
+
+    let mut local = client
+        .req(method, request.uri)
+        .remote(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+        .body(request.body);
+    for (name, value) in request.headers {
+        local.add_header(Header::new(name, value));
+    }
+    let mut response = local.dispatch();
+    let status = response.status();
+    let headers: Vec<(String, String)> = response
+        .headers()
+        .iter()
+        .map(|header| (header.name().to_string(), header.value().to_string()))
+        .collect();
+    let body = response.body_bytes().unwrap_or_default();
+    write_response(&mut stream, status.code, status.reason, &headers, &body)
+}
+
+// Reads one request, its body delimited by Content-Length
+fn read_request<R: Read>(reader: &mut R) -> io::Result<ParsedRequest> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let read = reader.read(&mut chunk)?;
+        if read == 0 {
+            return Err(invalid_request("connection closed mid request"));
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut headers);
+        let head_len = match parsed.parse(&buffer) {
+            Ok(httparse::Status::Complete(head_len)) => head_len,
+            Ok(httparse::Status::Partial) if buffer.len() < MAX_HEAD_LEN => continue,
+            _ => return Err(invalid_request("malformed request head")),
+        };
+        let method = parsed.method.unwrap_or_default().to_string();
+        let uri = parsed.path.unwrap_or_default().to_string();
+
+        let mut body_len = 0;
+        let mut kept_headers = Vec::new();
+        for header in parsed.headers.iter() {
+            let value = String::from_utf8(header.value.to_vec())
+                .map_err(|_| invalid_request("header is not UTF-8"))?;
+            if header.name.eq_ignore_ascii_case("content-length") {
+                body_len = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid_request("invalid Content-Length"))?;
+            } else if header.name.eq_ignore_ascii_case("transfer-encoding") {
+                return Err(invalid_request("transfer encodings are not supported"));
+            }
+            kept_headers.push((header.name.to_string(), value));
+        }
+        if body_len > MAX_BODY_LEN {
+            return Err(invalid_request("body is too large"));
+        }
+
+        let mut body = buffer.split_off(head_len);
+        if body.len() > body_len {
+            return Err(invalid_request("more data than Content-Length"));
+        }
+        let already_read = body.len();
+        body.resize(body_len, 0);
+        reader.read_exact(&mut body[already_read..])?;
+        return Ok(ParsedRequest {
+            method,
+            uri,
+            headers: kept_headers,
+            body,
+        });
+    }
+}
+
+// Every response closes the connection, as the client expects
+fn write_response<W: Write>(
+    writer: &mut W,
+    code: u16,
+    reason: &str,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", code, reason);
+    for (name, value) in headers {
+        if !name.eq_ignore_ascii_case("content-length") && !name.eq_ignore_ascii_case("connection")
+        {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    head.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    ));
+    writer.write_all(head.as_bytes())?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+fn invalid_request(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::io::Cursor;
+
+    use self::tempdir::TempDir;
+    use super::*;
+
+    #[test]
+    fn request_is_read_with_its_body() {
+        let raw = b"POST /ecdsa/keygen/first HTTP/1.1\r\nAuthorization: Bearer t\r\nContent-Length: 4\r\n\r\nnull";
+
+        let request = read_request(&mut Cursor::new(raw.to_vec())).unwrap();
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.uri, "/ecdsa/keygen/first");
+        assert!(request
+            .headers
+            .contains(&(String::from("Authorization"), String::from("Bearer t"))));
+        assert_eq!(request.body, b"null".to_vec());
+    }
+
+    #[test]
+    fn oversized_or_truncated_requests_are_rejected() {
+        let too_large = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_LEN + 1
+        );
+        let truncated = b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nnull";
+        let chunked = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+        assert!(read_request(&mut Cursor::new(too_large.into_bytes())).is_err());
+        assert!(read_request(&mut Cursor::new(truncated.to_vec())).is_err());
+        assert!(read_request(&mut Cursor::new(chunked.to_vec())).is_err());
+    }
+
+    #[test]
+    fn response_replaces_framing_headers() {
+        let mut written = Vec::new();
+        let headers = vec![
+            (
+                String::from("Content-Type"),
+                String::from("application/json"),
+            ),
+            (String::from("Content-Length"), String::from("99")),
+        ];
+
+        write_response(&mut written, 403, "Forbidden", &headers, b"{}").unwrap();
+
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "HTTP/1.1 403 Forbidden\r\nContent-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}"
+        );
+    }
+
+    #[test]
+    fn socket_is_private_and_peers_are_checked() {
+        let dir = TempDir::new("unix_socket").unwrap();
+        let path = dir.path().join("cosigner.sock");
+        drop(UnixListener::bind(&path).unwrap());
+
+        // Replaces the stale socket
+        let listener = bind(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        let _client = UnixStream::connect(&path).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let uid = peer_uid(&stream).unwrap();
+        assert_eq!(uid, unsafe { libc::geteuid() });
+        assert!(may_connect(uid, &[]));
+        assert!(!may_connect(uid + 1, &[]));
+        assert!(may_connect(uid + 1, &[uid + 1]));
+    }
+
+    #[test]
+    fn other_files_are_not_replaced() {
+        let dir = TempDir::new("unix_socket").unwrap();
+        let path = dir.path().join("cosigner.sock");
+        fs::write(&path, b"not a socket").unwrap();
+
+        assert!(bind(&path).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"not a socket".to_vec());
+    }
+}
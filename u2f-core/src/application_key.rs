@@ -2,6 +2,7 @@ use client_lib::ecdsa::PrivateShare;
 
 use app_id::AppId;
 use key_handle::KeyHandle;
+use private_key::PrivateKey;
 
 // A private key is generated per application
 // This stores the AppID for indexing, and a private key for signing
@@ -38,3 +39,12 @@ impl Clone for ApplicationKey {
         app_key
     }
 }
+
+// A registration from upstream rust-u2f, which holds the whole private key
+// rather than a share of it
+#[derive(Deserialize)]
+pub struct LegacyApplicationKey {
+    pub application: AppId,
+    pub handle: KeyHandle,
+    pub key: PrivateKey,
+}
@@ -0,0 +1,506 @@
+use base64;
+use byteorder::{BigEndian, ByteOrder};
+use client_lib::BigInt;
+use openssl::hash::{hash, MessageDigest};
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use app_id::AppId;
+use known_app_ids::try_reverse_app_id;
+use serde_base64::{from_base64, to_base64};
+use transport::{CosignerTransport, TransportResponse};
+
+use super::{Counter, SignError};
+
+pub const DEFAULT_COSIGNER_ENDPOINT: &str = "http://localhost:8000";
+
+// Longest a client waits for an approver, whatever the co-signer allows
+pub const MAX_APPROVAL_WAIT: Duration = Duration::from_secs(300);
+const APPROVAL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const APP_ID_LEN: usize = 32;
+const CHALLENGE_LEN: usize = 32;
+const COUNTER_LEN: usize = 4;
+const PUBLIC_KEY_LEN: usize = 65;
+const REGISTRATION_RESERVED_BYTE: u8 = 0x00;
+const USER_PRESENT: u8 = 0b0000_0001;
+
+// Authentications are co-signed. Registrations are attested by the client
+// alone, but their context is sent when the new key is claimed, see
+// CosignerClient::claim_key.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SigningKind {
+    Registration,
+    Authentication,
+}
+
+// Sent to the co-signer ahead of each signing session, so it can
+// decide whether to take part before any signing rounds happen
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SigningContext {
+    pub kind: SigningKind,
+    pub app_id: AppId,
+    // Name of the site if the AppId is a known one, see try_reverse_app_id
+    pub site: Option<String>,
+    // Counter being signed for an authentication, which must increase with
+    // every signature so the co-signer can notice a cloned key share
+    pub counter: Option<Counter>,
+    // The data the co-signer signs, checked against the rest of the context
+    #[serde(serialize_with = "to_base64", deserialize_with = "from_base64")]
+    pub message: Vec<u8>,
+}
+
+quick_error! {
+    #[derive(Debug, PartialEq)]
+    pub enum ContextMismatch {
+        MalformedMessage {
+            display("signed message is not a U2F message of the context's kind")
+        }
+        UserPresence {
+            display("signed message does not assert the user was present")
+        }
+        AppId {
+            display("AppId does not match the signed message")
+        }
+        Site {
+            display("site name does not match the AppId")
+        }
+        Counter {
+            display("counter does not match the signed message")
+        }
+    }
+}
+
+impl SigningContext {
+    pub fn registration(app_id: AppId, message: Vec<u8>) -> SigningContext {
+        SigningContext {
+            kind: SigningKind::Registration,
+            app_id,
+            site: try_reverse_app_id(&app_id),
+            counter: None,
+            message,
+        }
+    }
+
+    pub fn authentication(app_id: AppId, counter: Counter, message: Vec<u8>) -> SigningContext {
+        SigningContext {
+            kind: SigningKind::Authentication,
+            app_id,
+            site: try_reverse_app_id(&app_id),
+            counter: Some(counter),
+            message,
+        }
+    }
+
+    // Base64 SHA-256 of the message, the digest actually signed
+    pub fn message_hash(&self) -> String {
+        base64::encode(&hash(MessageDigest::sha256(), &self.message).unwrap()[..])
+    }
+
+    // The digest as the message of the second gotham signing round
+    pub fn digest(&self) -> BigInt {
+        BigInt::from(&hash(MessageDigest::sha256(), &self.message).unwrap()[..])
+    }
+
+    // Checks the context describes the message being signed, see
+    // message_to_sign_for_register and message_to_sign_for_authenticate
+    pub fn verify(&self) -> Result<(), ContextMismatch> {
+        let (app_id, counter) = match self.kind {
+            SigningKind::Registration => {
+                // The key handle between challenge and public key is at
+                // least a byte long
+                let min_len = 1 + APP_ID_LEN + CHALLENGE_LEN + 1 + PUBLIC_KEY_LEN;
+                if self.message.len() < min_len || self.message[0] != REGISTRATION_RESERVED_BYTE {
+                    return Err(ContextMismatch::MalformedMessage);
+                }
+                (&self.message[1..=APP_ID_LEN], None)
+            }
+            SigningKind::Authentication => {
+                if self.message.len() != APP_ID_LEN + 1 + COUNTER_LEN + CHALLENGE_LEN {
+                    return Err(ContextMismatch::MalformedMessage);
+                }
+                // Authentications are only signed once the user is present
+                if self.message[APP_ID_LEN] != USER_PRESENT {
+                    return Err(ContextMismatch::UserPresence);
+                }
+                let counter_offset = APP_ID_LEN + 1;
+                let counter = BigEndian::read_u32(
+                    &self.message[counter_offset..counter_offset + COUNTER_LEN],
+                );
+                (&self.message[..APP_ID_LEN], Some(counter))
+            }
+        };
+        if app_id != self.app_id.as_ref() {
+            return Err(ContextMismatch::AppId);
+        }
+        if self.site != try_reverse_app_id(&self.app_id) {
+            return Err(ContextMismatch::Site);
+        }
+        if self.counter != counter {
+            return Err(ContextMismatch::Counter);
+        }
+        Ok(())
+    }
+}
+
+// Body of the response when the co-signer refuses to take part
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Refusal {
+    pub reason: String,
+    // Set when the key or the whole client was revoked by an administrator
+    #[serde(default)]
+    pub revoked: bool,
+    // Set when the co-signer has no first signing round for the key, which
+    // the client can run again
+    #[serde(default)]
+    pub first_round_unknown: bool,
+}
+
+impl Refusal {
+    pub fn new(reason: String) -> Refusal {
+        Refusal {
+            reason,
+            revoked: false,
+            first_round_unknown: false,
+        }
+    }
+
+    pub fn revoked(reason: String) -> Refusal {
+        Refusal {
+            reason,
+            revoked: true,
+            first_round_unknown: false,
+        }
+    }
+
+    pub fn first_round_unknown(reason: String) -> Refusal {
+        Refusal {
+            reason,
+            revoked: false,
+            first_round_unknown: true,
+        }
+    }
+}
+
+// Body of the response when the co-signer approves a signing context,
+// proving it holds the same credential as the client
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Approval {
+    pub proof: String,
+}
+
+// Body of the response when the co-signer holds a signing context until
+// an approver accepts it. The client polls the context until then.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApprovalPending {
+    pub timeout_secs: u64,
+}
+
+// Issued to a client when it is enrolled with the co-signer, and shared
+// by both so each side can authenticate the other
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CosignerCredential {
+    pub client_id: String,
+    #[serde(serialize_with = "to_base64", deserialize_with = "from_base64")]
+    secret: Vec<u8>,
+}
+
+impl CosignerCredential {
+    pub fn new(client_id: String, secret: Vec<u8>) -> CosignerCredential {
+        CosignerCredential { client_id, secret }
+    }
+
+    // Sent as the bearer token of every request, "<client id>:<base64 secret>"
+    pub fn to_token(&self) -> String {
+        format!("{}:{}", self.client_id, base64::encode(&self.secret))
+    }
+
+    pub fn from_token(token: &str) -> Option<CosignerCredential> {
+        let mut parts = token.trim().splitn(2, ':');
+        let client_id = parts.next().filter(|id| !id.is_empty())?;
+        let secret = base64::decode(parts.next()?).ok()?;
+        if secret.is_empty() {
+            return None;
+        }
+        Some(CosignerCredential::new(client_id.to_string(), secret))
+    }
+
+    // Constant time comparison of the secrets of two credentials
+    pub fn secret_matches(&self, other: &CosignerCredential) -> bool {
+        self.secret.len() == other.secret.len() && memcmp::eq(&self.secret, &other.secret)
+    }
+
+    pub fn approval_proof(&self, key_id: &str, context: &SigningContext) -> String {
+        let pkey = PKey::hmac(&self.secret).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &pkey).unwrap();
+        signer.update(key_id.as_bytes()).unwrap();
+        signer.update(&[0]).unwrap();
+        signer.update(&context.message).unwrap();
+        base64::encode(&signer.sign_to_vec().unwrap())
+    }
+
+    fn verify_approval(&self, key_id: &str, context: &SigningContext, approval: &Approval) -> bool {
+        let expected = self.approval_proof(key_id, context);
+        expected.len() == approval.proof.len()
+            && memcmp::eq(expected.as_bytes(), approval.proof.as_bytes())
+    }
+}
+
+pub fn signing_context_path(key_id: &str) -> String {
+    format!("u2f/sign/{}/context", key_id)
+}
+
+pub fn claim_key_path(key_id: &str) -> String {
+    format!("u2f/keys/{}/claim", key_id)
+}
+
+#[derive(Clone)]
+pub(crate) struct CosignerClient {
+    transport: CosignerTransport,
+    credential: Option<CosignerCredential>,
+}
+
+impl CosignerClient {
+    pub fn new(
+        transport: CosignerTransport,
+        credential: Option<CosignerCredential>,
+    ) -> CosignerClient {
+        CosignerClient {
+            transport,
+            credential,
+        }
+    }
+
+    // Submits the context, returning how long to wait for an approver if
+    // the co-signer holds it, see wait_for_approval
+    pub fn submit_signing_context(
+        &self,
+        key_id: &str,
+        context: &SigningContext,
+    ) -> Result<Option<Duration>, SignError> {
+        let path = signing_context_path(key_id);
+        let response = self.send(Method::POST, &path, Some(context))?;
+        Ok(self
+            .approval(key_id, context, response)?
+            .map(|pending| Duration::from_secs(pending.timeout_secs).min(MAX_APPROVAL_WAIT)))
+    }
+
+    // Claims a key for the registration described by the context, from
+    // which on it counts towards the account's key limit at the co-signer
+    pub fn claim_key(&self, key_id: &str, context: &SigningContext) -> Result<(), SignError> {
+        let response = self.send(Method::POST, &claim_key_path(key_id), Some(context))?;
+        match response.status {
+            status if status.is_success() => Ok(()),
+            StatusCode::FORBIDDEN => Err(refused(&response)),
+            status => Err(SignError::CosignerUnavailable(format!(
+                "unexpected status {}",
+                status
+            ))),
+        }
+    }
+
+    // Polls a held context until an approver accepts it. Blocks for up to
+    // the timeout, so is kept off the reactor.
+    pub fn wait_for_approval(
+        &self,
+        key_id: &str,
+        context: &SigningContext,
+        timeout: Duration,
+    ) -> Result<(), SignError> {
+        let path = signing_context_path(key_id);
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            thread::sleep(APPROVAL_POLL_INTERVAL);
+            let response = self.send::<()>(Method::GET, &path, None)?;
+            if self.approval(key_id, context, response)?.is_none() {
+                return Ok(());
+            }
+        }
+        Err(SignError::ApprovalTimedOut)
+    }
+
+    // Posts a gotham round to the co-signer, refusals included
+    pub fn post_round<T, R>(&self, path: &str, body: &T) -> Result<R, SignError>
+    where
+        T: Serialize,
+        R: DeserializeOwned,
+    {
+        let response = self.send(Method::POST, path, Some(body))?;
+        match response.status {
+            status if status.is_success() => response
+                .json()
+                .map_err(|err| SignError::CosignerUnavailable(err.to_string())),
+            StatusCode::FORBIDDEN => Err(refused(&response)),
+            status => Err(SignError::CosignerUnavailable(format!(
+                "unexpected status {}",
+                status
+            ))),
+        }
+    }
+
+    fn send<T: Serialize>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&T>,
+    ) -> Result<TransportResponse, SignError> {
+        let body = match body {
+            Some(body) => Some(
+                serde_json::to_vec(body)
+                    .map_err(|err| SignError::CosignerUnavailable(err.to_string()))?,
+            ),
+            None => None,
+        };
+        let token = self.credential.as_ref().map(CosignerCredential::to_token);
+        self.transport
+            .send(method, path, token.as_ref().map(String::as_str), body)
+            .map_err(|err| SignError::CosignerUnavailable(err.to_string()))
+    }
+
+    // Returns whether the context is still waiting for an approver
+    fn approval(
+        &self,
+        key_id: &str,
+        context: &SigningContext,
+        response: TransportResponse,
+    ) -> Result<Option<ApprovalPending>, SignError> {
+        match response.status {
+            StatusCode::ACCEPTED => {
+                Ok(Some(response.json().map_err(|err| {
+                    SignError::CosignerUnavailable(err.to_string())
+                })?))
+            }
+            status if status.is_success() => match self.credential {
+                Some(ref credential) => match response.json::<Approval>() {
+                    Ok(ref approval) if credential.verify_approval(key_id, context, approval) => {
+                        Ok(None)
+                    }
+                    _ => Err(SignError::CosignerUnauthenticated),
+                },
+                None => Ok(None),
+            },
+            StatusCode::FORBIDDEN => Err(refused(&response)),
+            status => Err(SignError::CosignerUnavailable(format!(
+                "unexpected status {}",
+                status
+            ))),
+        }
+    }
+}
+
+fn refused(response: &TransportResponse) -> SignError {
+    let refusal: Refusal = response
+        .json()
+        .unwrap_or_else(|_| Refusal::new(String::from("no reason given")));
+    if refusal.revoked {
+        SignError::KeyRevoked
+    } else if refusal.first_round_unknown {
+        SignError::PresignatureUnknown
+    } else {
+        SignError::Refused(refusal.reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{message_to_sign_for_authenticate, message_to_sign_for_register, Challenge};
+    use key_handle::KeyHandle;
+
+    fn credential(secret: &[u8]) -> CosignerCredential {
+        CosignerCredential::new(String::from("client"), secret.to_vec())
+    }
+
+    #[test]
+    fn token_round_trips() {
+        let original = credential(&[1, 2, 3]);
+
+        let parsed = CosignerCredential::from_token(&original.to_token()).unwrap();
+
+        assert_eq!(parsed.client_id, "client");
+        assert!(parsed.secret_matches(&original));
+        assert!(CosignerCredential::from_token("client").is_none());
+        assert!(CosignerCredential::from_token(":AQID").is_none());
+    }
+
+    fn authentication_context(counter: Counter) -> SigningContext {
+        let app_id = AppId::from_bytes(&[0u8; 32]);
+        let message = message_to_sign_for_authenticate(&app_id, &Challenge([1u8; 32]), 1, counter);
+        SigningContext::authentication(app_id, counter, message)
+    }
+
+    fn registration_context() -> SigningContext {
+        let app_id = AppId::from_bytes(&[0u8; 32]);
+        let message = message_to_sign_for_register(
+            &app_id,
+            &Challenge([1u8; 32]),
+            &[4u8; PUBLIC_KEY_LEN],
+            &KeyHandle::from(&[2u8; 64]),
+        );
+        SigningContext::registration(app_id, message)
+    }
+
+    #[test]
+    fn context_matches_signed_message() {
+        assert_eq!(authentication_context(7).verify(), Ok(()));
+
+        let mut context = authentication_context(7);
+        context.counter = Some(8);
+        assert_eq!(context.verify(), Err(ContextMismatch::Counter));
+
+        let mut context = authentication_context(7);
+        context.app_id = AppId::from_bytes(&[2u8; 32]);
+        assert_eq!(context.verify(), Err(ContextMismatch::AppId));
+
+        let mut context = authentication_context(7);
+        context.site = Some(String::from("github.com"));
+        assert_eq!(context.verify(), Err(ContextMismatch::Site));
+
+        let mut context = authentication_context(7);
+        context.message.pop();
+        assert_eq!(context.verify(), Err(ContextMismatch::MalformedMessage));
+
+        let mut context = authentication_context(7);
+        context.message[APP_ID_LEN] = 0;
+        assert_eq!(context.verify(), Err(ContextMismatch::UserPresence));
+    }
+
+    #[test]
+    fn registration_context_matches_registration_message() {
+        assert_eq!(registration_context().verify(), Ok(()));
+
+        let mut context = registration_context();
+        context.counter = Some(1);
+        assert_eq!(context.verify(), Err(ContextMismatch::Counter));
+
+        let mut context = registration_context();
+        context.app_id = AppId::from_bytes(&[2u8; 32]);
+        assert_eq!(context.verify(), Err(ContextMismatch::AppId));
+
+        // An authentication message is not accepted as a registration
+        let mut context = authentication_context(7);
+        context.kind = SigningKind::Registration;
+        context.counter = None;
+        assert_eq!(context.verify(), Err(ContextMismatch::MalformedMessage));
+    }
+
+    #[test]
+    fn approval_from_other_credential_is_rejected() {
+        let context = authentication_context(1);
+        let approval = Approval {
+            proof: credential(&[1, 2, 3]).approval_proof("key", &context),
+        };
+
+        assert!(credential(&[1, 2, 3]).verify_approval("key", &context, &approval));
+        assert!(!credential(&[1, 2, 4]).verify_approval("key", &context, &approval));
+        assert!(!credential(&[1, 2, 3]).verify_approval("other", &context, &approval));
+    }
+}
@@ -0,0 +1,76 @@
+use std::io;
+use std::sync::Arc;
+
+use client_lib::ecdsa::PrivateShare;
+use rocket::handler::{Handler, Outcome};
+use rocket::http::{Method, Status};
+use rocket::local::Client;
+use rocket::response::content;
+use rocket::{Data, Request, Route};
+use server_lib::server;
+
+use cosigner::{claim_key_path, signing_context_path, CosignerClient};
+use gotham_crypto::keygen_error;
+use keygen;
+use transport::CosignerTransport;
+
+// Gotham's server in this process, for development and tests, called
+// through Rocket's local client rather than a socket. It has none of
+// local-server's enrollment, policy or audit log: every signing context is
+// approved and any client may use any key, claimed or not.
+pub struct DevCosigner {
+    transport: CosignerTransport,
+}
+
+impl DevCosigner {
+    pub fn new() -> io::Result<DevCosigner> {
+        let rocket = server::get_server().mount(
+            "/",
+            vec![
+                Route::new(
+                    Method::Post,
+                    &format!("/{}", signing_context_path("<id>")),
+                    ApproveContext,
+                ),
+                Route::new(
+                    Method::Post,
+                    &format!("/{}", claim_key_path("<id>")),
+                    ClaimKey,
+                ),
+            ],
+        );
+        let client = Client::untracked(rocket)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+        Ok(DevCosigner {
+            transport: CosignerTransport::Local(Arc::new(client)),
+        })
+    }
+
+    pub fn transport(&self) -> CosignerTransport {
+        self.transport.clone()
+    }
+
+    // A key share not assigned to any application, for tests of what
+    // stores them
+    pub fn generate_share(&self) -> io::Result<PrivateShare> {
+        keygen::generate(&CosignerClient::new(self.transport(), None)).map_err(keygen_error)
+    }
+}
+
+#[derive(Clone)]
+struct ApproveContext;
+
+impl Handler for ApproveContext {
+    fn handle<'r>(&self, request: &'r Request, _data: Data) -> Outcome<'r> {
+        Outcome::from(request, content::Json("{}"))
+    }
+}
+
+#[derive(Clone)]
+struct ClaimKey;
+
+impl Handler for ClaimKey {
+    fn handle<'r>(&self, request: &'r Request, _data: Data) -> Outcome<'r> {
+        Outcome::from(request, Status::NoContent)
+    }
+}
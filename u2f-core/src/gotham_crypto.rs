@@ -1,35 +1,127 @@
 use app_id::AppId;
 use application_key::ApplicationKey;
 use attestation::{Attestation, AttestationCertificate};
+use cosigner::{CosignerClient, CosignerCredential, SigningContext, DEFAULT_COSIGNER_ENDPOINT};
+use futures::Future;
+use futures_cpupool::CpuPool;
 use key_handle::KeyHandle;
-use openssl::hash::{hash, MessageDigest};
+use key_pool::KeyPool;
+use keygen;
+use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::party_one;
+use openssl::hash::MessageDigest;
 use openssl::pkey::PKey;
 use openssl::sign::Signer;
+use presign::{self, Presignatures};
 
 use private_key::PrivateKey;
+use slog;
+use slog::Drain;
+use slog_stdlog;
 use std::io;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use transport::CosignerTransport;
 
 use super::CryptoOperations;
 use super::SignError;
 use super::Signature;
+use super::UserPresence;
 
 use client_lib::*;
 
+// Signatures waiting on an approver each hold a thread of the executor
+const SIGNING_THREADS: usize = 4;
+
 pub struct GothamCryptoOperations {
-    client_shim: ClientShim,
+    cosigning: Cosigning,
     attestation: Attestation,
+    key_pool: Option<KeyPool>,
+}
+
+// Signs with the co-signer, from the executor, and runs first signing
+// rounds ahead of time in the background
+#[derive(Clone)]
+struct Cosigning {
+    cosigner: CosignerClient,
+    presignatures: Arc<Presignatures>,
+    // Held for a signature or presignature, as the co-signer keeps one
+    // first signing round per key
+    signing: Arc<Mutex<()>>,
+    // Runs every blocking round with the co-signer off the reactor,
+    // presignatures included
+    executor: CpuPool,
+    logger: slog::Logger,
 }
 
 impl GothamCryptoOperations {
     pub fn new(attestation: Attestation) -> GothamCryptoOperations {
+        GothamCryptoOperations::with_credential(attestation, None)
+    }
+
+    // Authenticates to the co-signer with a credential from enrollment
+    pub fn with_credential(
+        attestation: Attestation,
+        credential: Option<CosignerCredential>,
+    ) -> GothamCryptoOperations {
+        GothamCryptoOperations::with_cosigner(attestation, DEFAULT_COSIGNER_ENDPOINT, credential)
+    }
+
+    pub fn with_cosigner(
+        attestation: Attestation,
+        endpoint: &str,
+        credential: Option<CosignerCredential>,
+    ) -> GothamCryptoOperations {
+        GothamCryptoOperations::with_transport(
+            attestation,
+            CosignerTransport::new(endpoint),
+            credential,
+        )
+    }
+
+    // Reaches the co-signer however the transport does, such as in this
+    // process for a DevCosigner
+    pub fn with_transport(
+        attestation: Attestation,
+        transport: CosignerTransport,
+        credential: Option<CosignerCredential>,
+    ) -> GothamCryptoOperations {
         GothamCryptoOperations {
-            client_shim: ClientShim::new("http://localhost:8000".to_string(), None),
+            cosigning: Cosigning {
+                cosigner: CosignerClient::new(transport, credential),
+                presignatures: Arc::new(Presignatures::default()),
+                signing: Arc::new(Mutex::new(())),
+                executor: CpuPool::new(SIGNING_THREADS),
+                logger: slog::Logger::root(slog_stdlog::StdLog.fuse(), o!()),
+            },
             attestation: attestation,
+            key_pool: None,
         }
     }
 
-    fn generate_key(&self) -> ecdsa::PrivateShare {
-        ecdsa::get_master_key(&self.client_shim)
+    pub fn with_logger(mut self, logger: slog::Logger) -> GothamCryptoOperations {
+        self.cosigning.logger = logger;
+        self
+    }
+
+    // Registers with key shares generated ahead of time, starting to
+    // fill the pool straight away
+    pub fn with_key_pool(mut self, key_pool: KeyPool) -> GothamCryptoOperations {
+        self.key_pool = Some(key_pool);
+        self.refill_key_pool();
+        self
+    }
+
+    fn generate_key(&self) -> io::Result<ecdsa::PrivateShare> {
+        keygen::generate(&self.cosigning.cosigner).map_err(keygen_error)
+    }
+
+    fn refill_key_pool(&self) {
+        if let Some(ref key_pool) = self.key_pool {
+            let cosigner = self.cosigning.cosigner.clone();
+            key_pool.refill(self.cosigning.logger.clone(), move || {
+                keygen::generate(&cosigner).map_err(keygen_error)
+            });
+        }
     }
 
     fn generate_key_handle() -> io::Result<KeyHandle> {
@@ -56,33 +148,128 @@ impl CryptoOperations for GothamCryptoOperations {
     }
 
     fn generate_application_key(&self, application: &AppId) -> io::Result<ApplicationKey> {
-        let key = self.generate_key();
+        let pooled = match self.key_pool {
+            Some(ref key_pool) => key_pool.take()?,
+            None => None,
+        };
+        let key = match pooled {
+            Some(key) => key,
+            None => self.generate_key()?,
+        };
+        self.refill_key_pool();
+        self.cosigning.presign_in_background(&key.id);
         let handle = Self::generate_key_handle()?;
         Ok(ApplicationKey::new(*application, handle, key))
     }
 
+    // The key only counts towards the account's limit once claimed
+    fn claim_application_key(
+        &self,
+        key: &ApplicationKey,
+        context: SigningContext,
+    ) -> Box<dyn Future<Item = (), Error = SignError>> {
+        let cosigner = self.cosigning.cosigner.clone();
+        let key_id = key.key().id.clone();
+        Box::new(
+            self.cosigning
+                .executor
+                .spawn_fn(move || cosigner.claim_key(&key_id, &context)),
+        )
+    }
+
     fn get_attestation_certificate(&self) -> AttestationCertificate {
         self.attestation.certificate.clone()
     }
 
-    fn sign(&self, ps: &ecdsa::PrivateShare, data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
-        let x_pos = BigInt::from(0);
-        let y_pos = BigInt::from(0);
+    fn sign(
+        &self,
+        key: ApplicationKey,
+        context: SigningContext,
+        presence: Rc<dyn UserPresence>,
+    ) -> Box<dyn Future<Item = Box<dyn Signature>, Error = SignError>> {
+        let cosigning = self.cosigning.clone();
+        let submitted = self.cosigning.executor.spawn_fn(move || {
+            // The co-signer refuses to take part unless it has approved a context first
+            let approval_wait = cosigning
+                .cosigner
+                .submit_signing_context(&key.key().id, &context)?;
+            Ok((approval_wait, key, context))
+        });
 
-        let child_master_key = ps.master_key.get_child(vec![x_pos.clone(), y_pos.clone()]);
+        let cosigning = self.cosigning.clone();
+        Box::new(submitted.and_then(move |(approval_wait, key, context)| {
+            if let Some(timeout) = approval_wait {
+                presence.waiting_for_approval(&context.app_id, timeout);
+            }
+            let executor = cosigning.executor.clone();
+            executor.spawn_fn(move || {
+                let ps = key.key();
+                if let Some(timeout) = approval_wait {
+                    cosigning
+                        .cosigner
+                        .wait_for_approval(&ps.id, &context, timeout)?;
+                }
+                cosigning.sign(ps, context.digest())
+            })
+        }))
+    }
+}
 
-        // TODO check result
-        let result = hash(MessageDigest::sha256(), data).unwrap();
+impl Cosigning {
+    // Runs the first signing round for the key's next signature while idle,
+    // leaving only one round-trip to sign
+    fn presign_in_background(&self, key_id: &str) {
+        let cosigning = self.clone();
+        let key_id = key_id.to_string();
+        self.executor
+            .spawn_fn(move || {
+                let _signing = cosigning.signing.lock().unwrap();
+                match presign::presign(&cosigning.cosigner, &key_id) {
+                    Ok(presignature) => cosigning.presignatures.insert(&key_id, presignature),
+                    // Without one the next signature runs both rounds
+                    Err(err) => {
+                        warn!(cosigning.logger, "Unable to run first signing round ahead of time"; "key_id" => &key_id, "error" => %err)
+                    }
+                }
+                Ok(()) as Result<(), ()>
+            })
+            .forget();
+    }
 
-        let signature = ecdsa::sign(
-            &self.client_shim,
-            BigInt::from(&result[..]),
-            &child_master_key,
-            x_pos,
-            y_pos,
-            &ps.id,
-        )
-        .expect("No signature generated. Is server running?");
+    // Signs the digest of an approved context, blocking on the co-signer
+    fn sign(
+        &self,
+        ps: &ecdsa::PrivateShare,
+        message: BigInt,
+    ) -> Result<Box<dyn Signature>, SignError> {
+        let x_pos = BigInt::from(0);
+        let y_pos = BigInt::from(0);
+
+        let signature = {
+            let _signing = self.signing.lock().unwrap();
+            match self.presignatures.take(&ps.id) {
+                Some(presignature) => {
+                    let signed = presign::sign_presigned(
+                        &self.cosigner,
+                        ps,
+                        presignature,
+                        message.clone(),
+                        x_pos.clone(),
+                        y_pos.clone(),
+                    );
+                    match signed {
+                        // The co-signer lost its first round, e.g. it restarted
+                        Err(SignError::PresignatureUnknown) => {
+                            self.sign_both_rounds(ps, message, x_pos, y_pos)
+                        }
+                        signed => signed,
+                    }
+                }
+                None => self.sign_both_rounds(ps, message, x_pos, y_pos),
+            }
+        };
+        self.presign_in_background(&ps.id);
+        let signature = signature?;
 
         let mut v = BigInt::to_vec(&signature.r);
         v.extend(BigInt::to_vec(&signature.s));
@@ -93,6 +280,29 @@ impl CryptoOperations for GothamCryptoOperations {
             .to_vec();
         Ok(Box::new(RawSignature(der_sig)))
     }
+
+    // For a key without a presignature, both rounds back to back
+    fn sign_both_rounds(
+        &self,
+        ps: &ecdsa::PrivateShare,
+        message: BigInt,
+        x_pos: BigInt,
+        y_pos: BigInt,
+    ) -> Result<party_one::SignatureRecid, SignError> {
+        let presignature = presign::presign(&self.cosigner, &ps.id)?;
+        presign::sign_presigned(&self.cosigner, ps, presignature, message, x_pos, y_pos)
+    }
+}
+
+// A co-signer refusing keygen, over its quota for example, is a denied
+// registration rather than a failure of the device
+pub(crate) fn keygen_error(err: SignError) -> io::Error {
+    match err {
+        SignError::Refused(_) | SignError::KeyRevoked => {
+            io::Error::new(io::ErrorKind::PermissionDenied, err.to_string())
+        }
+        _ => io::Error::new(io::ErrorKind::Other, err.to_string()),
+    }
 }
 
 #[derive(Debug)]
@@ -0,0 +1,185 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use client_lib::ecdsa::PrivateShare;
+use slog;
+
+// Keeps key shares generated ahead of time, not yet assigned to an
+// application. Refills write from a background thread.
+pub trait KeyPoolStore: Send + Sync {
+    fn add_pooled_share(&self, share: PrivateShare) -> io::Result<()>;
+    fn take_pooled_share(&self) -> io::Result<Option<PrivateShare>>;
+    fn pooled_share_count(&self) -> io::Result<usize>;
+}
+
+// A pool of key shares so registration does not wait on a full keygen
+pub struct KeyPool {
+    store: Arc<dyn KeyPoolStore>,
+    size: usize,
+    refilling: Arc<AtomicBool>,
+}
+
+impl KeyPool {
+    pub fn new(store: Arc<dyn KeyPoolStore>, size: usize) -> KeyPool {
+        KeyPool {
+            store,
+            size,
+            refilling: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub(crate) fn take(&self) -> io::Result<Option<PrivateShare>> {
+        self.store.take_pooled_share()
+    }
+
+    // Tops the pool up to its size on a background thread, unless a
+    // refill is already running. A failed keygen ends the refill, the
+    // next registration starts another.
+    pub(crate) fn refill<F>(&self, logger: slog::Logger, generate: F)
+    where
+        F: Fn() -> io::Result<PrivateShare> + Send + 'static,
+    {
+        if self.size == 0 || self.refilling.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let store = self.store.clone();
+        let size = self.size;
+        let refilling = RefillGuard(self.refilling.clone());
+        thread::spawn(move || {
+            let _refilling = refilling;
+            if let Err(err) = top_up(store.as_ref(), size, generate) {
+                warn!(logger, "Unable to refill key pool"; "error" => %err);
+            }
+        });
+    }
+}
+
+fn top_up<F>(store: &dyn KeyPoolStore, size: usize, generate: F) -> io::Result<()>
+where
+    F: Fn() -> io::Result<PrivateShare>,
+{
+    while store.pooled_share_count()? < size {
+        store.add_pooled_share(generate()?)?;
+    }
+    Ok(())
+}
+
+// Clears the refilling flag when a refill ends, even if keygen fails
+struct RefillGuard(Arc<AtomicBool>);
+
+impl Drop for RefillGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use tests::COSIGNER;
+
+    #[derive(Default)]
+    struct MemoryPool(Mutex<Vec<PrivateShare>>);
+
+    impl KeyPoolStore for MemoryPool {
+        fn add_pooled_share(&self, share: PrivateShare) -> io::Result<()> {
+            self.0.lock().unwrap().push(share);
+            Ok(())
+        }
+
+        fn take_pooled_share(&self) -> io::Result<Option<PrivateShare>> {
+            Ok(self.0.lock().unwrap().pop())
+        }
+
+        fn pooled_share_count(&self) -> io::Result<usize> {
+            Ok(self.0.lock().unwrap().len())
+        }
+    }
+
+    // Keeps the message of each record logged
+    #[derive(Clone, Default)]
+    struct Messages(Arc<Mutex<Vec<String>>>);
+
+    impl slog::Drain for Messages {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+            self.0.lock().unwrap().push(record.msg().to_string());
+            Ok(())
+        }
+    }
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, o!())
+    }
+
+    fn wait_for_refill(pool: &KeyPool) {
+        let deadline = Instant::now() + Duration::from_secs(60);
+        while pool.refilling.load(Ordering::SeqCst) {
+            assert!(Instant::now() < deadline, "refill did not end");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn refill_tops_pool_up_to_size() {
+        let store = Arc::new(MemoryPool::default());
+        store
+            .add_pooled_share(COSIGNER.generate_share().unwrap())
+            .unwrap();
+        let pool = KeyPool::new(store.clone(), 3);
+
+        pool.refill(logger(), || COSIGNER.generate_share());
+        wait_for_refill(&pool);
+
+        assert_eq!(store.pooled_share_count().unwrap(), 3);
+        assert!(pool.take().unwrap().is_some());
+        assert_eq!(store.pooled_share_count().unwrap(), 2);
+    }
+
+    #[test]
+    fn failed_keygen_ends_refill() {
+        let store = Arc::new(MemoryPool::default());
+        let pool = KeyPool::new(store.clone(), 2);
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let messages = Messages::default();
+
+        for _ in 0..2 {
+            let attempts = attempts.clone();
+            let logger = slog::Logger::root(messages.clone(), o!());
+            pool.refill(logger, move || {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "co-signer unavailable",
+                ))
+            });
+            wait_for_refill(&pool);
+        }
+
+        // Each refill gave up after its first failure, and logged it
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            *messages.0.lock().unwrap(),
+            vec!["Unable to refill key pool"; 2]
+        );
+        assert_eq!(store.pooled_share_count().unwrap(), 0);
+        assert!(pool.take().unwrap().is_none());
+    }
+
+    #[test]
+    fn pool_of_size_zero_is_not_refilled() {
+        let pool = KeyPool::new(Arc::new(MemoryPool::default()), 0);
+
+        pool.refill(logger(), || panic!("no share should be generated"));
+
+        assert!(!pool.refilling.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,73 @@
+use client_lib::ecdsa::PrivateShare;
+use curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm::{
+    Party1FirstMessage, Party1SecondMessage,
+};
+use kms::chain_code::two_party::party2::ChainCode2;
+use kms::ecdsa::two_party::{party1, MasterKey2};
+use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::party_one;
+
+use cosigner::CosignerClient;
+use SignError;
+
+// The gotham keygen rounds, as gotham-client runs them, except that a
+// co-signer refusing a round or failing its proofs is an error rather
+// than a panic
+pub(crate) fn generate(cosigner: &CosignerClient) -> Result<PrivateShare, SignError> {
+    let (id, party_one_first_message): (String, party_one::KeyGenFirstMsg) =
+        cosigner.post_round("ecdsa/keygen/first", &())?;
+    let (party_two_first_message, ec_key_pair) = MasterKey2::key_gen_first_message();
+
+    let party_one_second_message: party1::KeyGenParty1Message2 = cosigner.post_round(
+        &format!("ecdsa/keygen/{}/second", id),
+        &party_two_first_message.d_log_proof,
+    )?;
+    let (party_two_second_message, paillier, pdl_challenge) =
+        MasterKey2::key_gen_second_message(&party_one_first_message, &party_one_second_message)
+            .map_err(|_| keygen_failed("key exchange"))?;
+
+    let party_one_third_message: party_one::PDLFirstMessage = cosigner.post_round(
+        &format!("ecdsa/keygen/{}/third", id),
+        &party_two_second_message.pdl_first_message,
+    )?;
+    let party_one_fourth_message: party_one::PDLSecondMessage = cosigner.post_round(
+        &format!("ecdsa/keygen/{}/fourth", id),
+        &MasterKey2::key_gen_third_message(&pdl_challenge),
+    )?;
+    MasterKey2::key_gen_fourth_message(
+        &pdl_challenge,
+        &party_one_third_message,
+        &party_one_fourth_message,
+    )
+    .map_err(|_| keygen_failed("Paillier key proof"))?;
+
+    let chain_code_first_message: Party1FirstMessage =
+        cosigner.post_round(&format!("ecdsa/keygen/{}/chaincode/first", id), &())?;
+    let (party_two_chain_code_message, chain_code_key_pair) =
+        ChainCode2::chain_code_first_message();
+    let chain_code_second_message: Party1SecondMessage = cosigner.post_round(
+        &format!("ecdsa/keygen/{}/chaincode/second", id),
+        &party_two_chain_code_message.d_log_proof,
+    )?;
+    ChainCode2::chain_code_second_message(&chain_code_first_message, &chain_code_second_message)
+        .map_err(|_| keygen_failed("chain code exchange"))?;
+    let chain_code = ChainCode2::compute_chain_code(
+        &chain_code_key_pair,
+        &chain_code_second_message.comm_witness.public_share,
+    )
+    .chain_code;
+
+    let master_key = MasterKey2::set_master_key(
+        &chain_code,
+        &ec_key_pair,
+        &party_one_second_message
+            .ecdh_second_message
+            .comm_witness
+            .public_share,
+        &paillier,
+    );
+    Ok(PrivateShare { id, master_key })
+}
+
+fn keygen_failed(round: &str) -> SignError {
+    SignError::CosignerUnavailable(format!("co-signer failed the keygen {}", round))
+}
@@ -4,9 +4,13 @@ extern crate assert_matches;
 extern crate base64;
 extern crate byteorder;
 extern crate futures;
+extern crate futures_cpupool;
 extern crate hex;
+extern crate httparse;
+extern crate kms;
 #[macro_use]
 extern crate lazy_static;
+extern crate multi_party_ecdsa;
 extern crate openssl;
 #[macro_use]
 extern crate quick_error;
@@ -19,6 +23,8 @@ extern crate slog;
 extern crate client_lib;
 extern crate curv;
 extern crate rand_core;
+extern crate reqwest;
+extern crate rocket;
 extern crate secp256k1;
 extern crate serde_json;
 extern crate server_lib;
@@ -30,22 +36,31 @@ use std::fmt::Debug;
 use std::io;
 use std::rc::Rc;
 use std::result::Result;
-
-use client_lib::*;
+use std::time::Duration;
 
 pub use crate::app_id::AppId;
-pub use crate::application_key::ApplicationKey;
+pub use crate::application_key::{ApplicationKey, LegacyApplicationKey};
 use crate::attestation::AttestationCertificate;
 use crate::constants::*;
+pub use crate::cosigner::{
+    signing_context_path, Approval, ApprovalPending, ContextMismatch, CosignerCredential, Refusal,
+    SigningContext, SigningKind, DEFAULT_COSIGNER_ENDPOINT, MAX_APPROVAL_WAIT,
+};
+pub use crate::dev_cosigner::DevCosigner;
 pub use crate::gotham_crypto::GothamCryptoOperations as SecureCryptoOperations;
 pub use crate::key_handle::KeyHandle;
+pub use crate::key_pool::{KeyPool, KeyPoolStore};
 pub use crate::known_app_ids::try_reverse_app_id;
 use crate::known_app_ids::BOGUS_APP_ID_HASH;
 pub use crate::private_key::PrivateKey;
-pub use crate::request::{AuthenticateControlCode, Request};
+pub use crate::request::{AuthenticateControlCode, Request, RequestError};
 pub use crate::response::Response;
 pub use crate::self_signed_attestation::self_signed_attestation;
+pub use crate::transport::{CosignerTransport, TransportResponse, UNIX_ENDPOINT_PREFIX};
+pub use crate::vendor::{VendorCommand, VendorHandler};
 use byteorder::{BigEndian, WriteBytesExt};
+pub use client_lib::ecdsa::PrivateShare;
+pub use client_lib::BigInt;
 use futures::future;
 use futures::Future;
 use futures::IntoFuture;
@@ -58,15 +73,22 @@ mod app_id;
 mod application_key;
 mod attestation;
 mod constants;
+mod cosigner;
+mod dev_cosigner;
 mod gotham_crypto;
 mod key_handle;
+mod key_pool;
+mod keygen;
 mod known_app_ids;
+mod presign;
 mod private_key;
 mod public_key;
 mod request;
 mod response;
 mod self_signed_attestation;
 mod serde_base64;
+mod transport;
+mod vendor;
 
 #[derive(Debug)]
 pub enum StatusCode {
@@ -76,6 +98,7 @@ pub enum StatusCode {
     RequestLengthInvalid,
     RequestClassNotSupported,
     RequestInstructionNotSuppored,
+    InvalidRequestData,
     UnknownError,
 }
 
@@ -88,20 +111,54 @@ impl StatusCode {
             StatusCode::RequestLengthInvalid => SW_WRONG_LENGTH,
             StatusCode::RequestClassNotSupported => SW_CLA_NOT_SUPPORTED,
             StatusCode::RequestInstructionNotSuppored => SW_INS_NOT_SUPPORTED,
+            StatusCode::InvalidRequestData => SW_WRONG_DATA,
             StatusCode::UnknownError => SW_UNKNOWN,
         };
         write.write_u16::<BigEndian>(value).unwrap();
     }
 }
 
-#[derive(Debug)]
-pub enum SignError {}
+quick_error! {
+    #[derive(Debug)]
+    pub enum SignError {
+        CosignerUnavailable(message: String) {
+            display("co-signer unavailable: {}", message)
+        }
+        Refused(reason: String) {
+            display("co-signer refused to sign: {}", reason)
+        }
+        CosignerUnauthenticated {
+            display("co-signer failed to prove it holds this client's credential")
+        }
+        KeyRevoked {
+            display("key was revoked at the co-signer")
+        }
+        ApprovalTimedOut {
+            display("no approver accepted the signature in time")
+        }
+        PresignatureUnknown {
+            display("co-signer has no first signing round for the key")
+        }
+    }
+}
 
 pub type Counter = u32;
 
 #[derive(Clone, Debug)]
 pub struct Challenge([u8; 32]);
 
+impl Challenge {
+    // None unless the slice is exactly 32 bytes
+    pub fn from_bytes(slice: &[u8]) -> Option<Challenge> {
+        if slice.len() != 32 {
+            return None;
+        }
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(slice);
+        Some(Challenge(bytes))
+    }
+}
+
 impl AsRef<[u8]> for Challenge {
     fn as_ref(&self) -> &[u8] {
         self.0.as_ref()
@@ -120,14 +177,31 @@ pub trait UserPresence {
         application: &AppId,
     ) -> Box<dyn Future<Item = bool, Error = io::Error>>;
     fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>>;
+    // Tells the user a key can no longer be used, without waiting on them
+    fn key_revoked(&self, application: &AppId);
+    // Tells the user a signature is waiting on an approver, for at most timeout
+    fn waiting_for_approval(&self, application: &AppId, timeout: Duration);
 }
 
 pub trait CryptoOperations {
     fn attest(&self, data: &[u8]) -> Result<Box<dyn Signature>, SignError>;
     fn generate_application_key(&self, application: &AppId) -> io::Result<ApplicationKey>;
+    // Claims a new key at the co-signer for the registration described by
+    // the context, which the co-signer may refuse
+    fn claim_application_key(
+        &self,
+        key: &ApplicationKey,
+        context: SigningContext,
+    ) -> Box<dyn Future<Item = (), Error = SignError>>;
     fn get_attestation_certificate(&self) -> AttestationCertificate;
-    fn sign(&self, key: &ecdsa::PrivateShare, data: &[u8])
-        -> Result<Box<dyn Signature>, SignError>;
+    // Signs the message of the context, which the co-signer checks first,
+    // telling the user through presence if it is held for an approver
+    fn sign(
+        &self,
+        key: ApplicationKey,
+        context: SigningContext,
+        presence: Rc<dyn UserPresence>,
+    ) -> Box<dyn Future<Item = Box<dyn Signature>, Error = SignError>>;
 }
 
 pub trait SecretStore {
@@ -194,10 +268,11 @@ quick_error! {
 pub struct U2F(Rc<U2FInner>);
 
 struct U2FInner {
-    approval: Box<dyn UserPresence>,
+    approval: Rc<dyn UserPresence>,
     logger: slog::Logger,
     operations: Box<dyn CryptoOperations>,
     storage: Box<dyn SecretStore>,
+    vendor_handler: Option<Rc<dyn VendorHandler>>,
 }
 
 impl U2F {
@@ -207,14 +282,40 @@ impl U2F {
         storage: Box<dyn SecretStore>,
         logger: L,
     ) -> io::Result<Self> {
-        let logger = logger
-            .into()
-            .unwrap_or_else(|| slog::Logger::root(slog_stdlog::StdLog.fuse(), o!()));
-        let inner = U2FInner {
+        Self::build(approval, operations, storage, None, logger.into())
+    }
+
+    // Also answers APDUs with an instruction in the vendor range
+    pub fn with_vendor_handler<L: Into<Option<slog::Logger>>>(
+        approval: Box<dyn UserPresence>,
+        operations: Box<dyn CryptoOperations>,
+        storage: Box<dyn SecretStore>,
+        vendor_handler: Rc<dyn VendorHandler>,
+        logger: L,
+    ) -> io::Result<Self> {
+        Self::build(
             approval,
+            operations,
+            storage,
+            Some(vendor_handler),
+            logger.into(),
+        )
+    }
+
+    fn build(
+        approval: Box<dyn UserPresence>,
+        operations: Box<dyn CryptoOperations>,
+        storage: Box<dyn SecretStore>,
+        vendor_handler: Option<Rc<dyn VendorHandler>>,
+        logger: Option<slog::Logger>,
+    ) -> io::Result<Self> {
+        let logger = logger.unwrap_or_else(|| slog::Logger::root(slog_stdlog::StdLog.fuse(), o!()));
+        let inner = U2FInner {
+            approval: Rc::from(approval),
             logger,
             operations,
             storage,
+            vendor_handler,
         };
         Ok(U2F(Rc::new(inner)))
     }
@@ -307,26 +408,29 @@ impl U2F {
         application_key: ApplicationKey,
         user_present: bool,
         counter: Counter,
-    ) -> Result<Authentication, AuthenticateError> {
+    ) -> Box<dyn Future<Item = Authentication, Error = AuthenticateError>> {
         let user_presence_byte = user_presence_byte(user_present);
 
         // println!("Authentication Key {:?}", &application_key);
 
-        let signature = self_rc.operations.sign(
-            application_key.key(),
-            &message_to_sign_for_authenticate(
-                &application_key.application,
-                &challenge,
-                user_presence_byte,
-                counter,
-            ),
-        )?;
-
-        Ok(Authentication {
+        let message = message_to_sign_for_authenticate(
+            &application_key.application,
+            &challenge,
+            user_presence_byte,
             counter,
-            signature,
-            user_present,
-        })
+        );
+        let context = SigningContext::authentication(application_key.application, counter, message);
+        Box::new(
+            self_rc
+                .operations
+                .sign(application_key, context, self_rc.approval.clone())
+                .from_err()
+                .map(move |signature| Authentication {
+                    counter,
+                    signature,
+                    user_present,
+                }),
+        )
     }
 
     pub fn get_version_string(&self) -> String {
@@ -388,60 +492,37 @@ impl U2F {
         if !user_present {
             return Box::new(future::err(RegisterError::ApprovalRequired));
         }
-        // Generate the same key for testing
         let application_key = match self_rc.operations.generate_application_key(&application) {
             Ok(application_key) => application_key,
             Err(err) => return Box::new(future::err(err).from_err()),
         };
-        // println!("RegistrationKeyJson {:?}", &application_key);
-
-        // println!(
-        //     "RegistrationKeyHex {}",
-        //     BigInt::to_hex(
-        //         &application_key
-        //             .key()
-        //             .master_key
-        //             .public
-        //             .q
-        //             .bytes_compressed_to_big_int()
-        //     )
-        // );
-
-        // Application specific private key is stored
-        Box::new(
-            self_rc
-                .storage
-                .add_application_key(&application_key)
-                .into_future()
-                .from_err()
-                .and_then(move |_| Self::_register_step3(self_rc, challenge, application_key)),
-        )
+        let public_key_bytes = application_public_key(&application_key);
+        let message = message_to_sign_for_register(
+            &application_key.application,
+            &challenge,
+            &public_key_bytes,
+            &application_key.handle,
+        );
+        let context = SigningContext::registration(application, message.clone());
+
+        // The co-signer may refuse the registration, so the key is only
+        // stored once it is claimed
+        let claimed = self_rc
+            .operations
+            .claim_application_key(&application_key, context);
+        Box::new(claimed.from_err().and_then(move |_| {
+            self_rc.storage.add_application_key(&application_key)?;
+            Self::_register_step3(self_rc, application_key, public_key_bytes, &message)
+        }))
     }
 
     fn _register_step3(
         self_rc: Rc<U2FInner>,
-        challenge: Challenge,
         application_key: ApplicationKey,
+        public_key_bytes: Vec<u8>,
+        message: &[u8],
     ) -> Result<Registration, RegisterError> {
-        let x_pos = BigInt::from(0);
-        let y_pos = BigInt::from(0);
-
-        let child_master_key = application_key
-            .key()
-            .master_key
-            .get_child(vec![x_pos.clone(), y_pos.clone()]);
-
-        // Create public key from the application specific private key
-        let public_key_bytes = child_master_key.public.q.pk_to_key_slice();
-        // println!("PublicKey {:?}", base64::encode(&public_key_bytes));
-        // let public_key = PublicKey::from_key(application_key.key());
-        // let public_key_bytes: Vec<u8> = public_key.to_raw();
-        let signature = self_rc.operations.attest(&message_to_sign_for_register(
-            &application_key.application,
-            &challenge,
-            &public_key_bytes,
-            &application_key.handle,
-        ))?;
+        let signature = self_rc.operations.attest(message)?;
         let attestation_certificate = self_rc.operations.get_attestation_certificate();
 
         // Return a struct of the application handle, and the user public key
@@ -535,6 +616,7 @@ impl Service for U2F {
                     AuthenticateControlCode::EnforceUserPresenceAndSign => {
                         debug!(logger, "ControlCode::EnforceUserPresenceAndSign");
                         let logger_clone = logger.clone();
+                        let self_rc = self.0.clone();
                         Box::new(
                             self.authenticate(application, challenge, key_handle)
                                 .map(move |authentication| {
@@ -558,6 +640,15 @@ impl Service for U2F {
                                         error!(logger_clone, "I/O error"; "error" => ?err);
                                         Ok(Response::UnknownError)
                                     }
+                                    AuthenticateError::Signing(SignError::ApprovalTimedOut) => {
+                                        info!(logger_clone, "Signature was not approved in time");
+                                        Ok(Response::TestOfUserPresenceNotSatisfied)
+                                    }
+                                    AuthenticateError::Signing(SignError::KeyRevoked) => {
+                                        warn!(logger_clone, "Key revoked at the co-signer");
+                                        self_rc.approval.key_revoked(&application);
+                                        Ok(Response::InvalidKeyHandle)
+                                    }
                                     AuthenticateError::Signing(err) => {
                                         error!(logger_clone, "Signing error"; "error" => ?err);
                                         Ok(Response::UnknownError)
@@ -586,6 +677,35 @@ impl Service for U2F {
                 error!(logger, "I/O error"; "error" => ?err);
                 Ok(Response::UnknownError)
             })),
+            Request::Vendor {
+                instruction,
+                parameter1,
+                parameter2,
+                data,
+            } => {
+                debug!(logger, "Vendor request"; "instruction" => instruction);
+                let command = VendorCommand::Apdu {
+                    instruction,
+                    parameter1,
+                    parameter2,
+                };
+                let handled = self
+                    .0
+                    .vendor_handler
+                    .as_ref()
+                    .and_then(|handler| handler.handle(command, &data));
+                match handled {
+                    Some(response) => {
+                        Box::new(response.map(|data| Response::Vendor { data }).or_else(
+                            move |err| {
+                                error!(logger, "Vendor request failed"; "error" => ?err);
+                                Ok(Response::UnknownError)
+                            },
+                        ))
+                    }
+                    None => Box::new(future::ok(Response::InstructionNotSupported)),
+                }
+            }
         }
     }
 }
@@ -625,6 +745,19 @@ fn message_to_sign_for_authenticate(
     message
 }
 
+// Registrations are for the child key that is signed with, see
+// GothamCryptoOperations::sign
+fn application_public_key(application_key: &ApplicationKey) -> Vec<u8> {
+    let x_pos = BigInt::from(0);
+    let y_pos = BigInt::from(0);
+
+    let child_master_key = application_key
+        .key()
+        .master_key
+        .get_child(vec![x_pos, y_pos]);
+    child_master_key.public.q.pk_to_key_slice()
+}
+
 fn message_to_sign_for_register(
     application: &AppId,
     challenge: &Challenge,
@@ -654,7 +787,6 @@ fn message_to_sign_for_register(
 #[cfg(test)]
 mod tests {
     extern crate client_lib;
-    extern crate server_lib;
 
     use std::cell::RefCell;
     use std::collections::HashMap;
@@ -669,8 +801,18 @@ mod tests {
     use super::attestation::Attestation;
     use super::*;
 
-    use self::server_lib::server;
-    use std::{thread, time};
+    lazy_static! {
+        // One co-signer shared by every test
+        pub(crate) static ref COSIGNER: DevCosigner = DevCosigner::new().unwrap();
+    }
+
+    fn operations() -> Box<SecureCryptoOperations> {
+        Box::new(SecureCryptoOperations::with_transport(
+            get_test_attestation(),
+            COSIGNER.transport(),
+            None,
+        ))
+    }
 
     fn fake_app_id() -> AppId {
         AppId([0u8; 32])
@@ -714,6 +856,8 @@ mod tests {
         fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
             Box::new(future::ok(()))
         }
+        fn key_revoked(&self, _: &AppId) {}
+        fn waiting_for_approval(&self, _: &AppId, _: Duration) {}
     }
 
     struct InMemoryStorage(RefCell<InMemoryStorageInner>);
@@ -802,11 +946,81 @@ AwEHoUQDQgAEryDZdIOGjRKLLyG6Mkc4oSVUDBndagZDDbdwLcUdNLzFlHx/yqYl
         }
     }
 
+    #[test]
+    fn encoded_authenticate_request_decodes() {
+        let request = Request::Authenticate {
+            application: AppId([1u8; 32]),
+            challenge: Challenge([2u8; 32]),
+            control_code: AuthenticateControlCode::CheckOnly,
+            key_handle: fake_key_handle(),
+        };
+
+        let bytes = request.into_bytes().unwrap();
+
+        match Request::decode(&bytes) {
+            Ok(Request::Authenticate {
+                application,
+                challenge,
+                control_code: AuthenticateControlCode::CheckOnly,
+                key_handle,
+            }) => {
+                assert_eq!(application, AppId([1u8; 32]));
+                assert_eq!(challenge.as_ref(), &[2u8; 32]);
+                assert!(key_handle.eq_consttime(&fake_key_handle()));
+            }
+            other => panic!("Unexpected decoding {:?}", other),
+        }
+    }
+
+    #[test]
+    fn encoded_version_request_decodes() {
+        let bytes = Request::GetVersion.into_bytes().unwrap();
+
+        assert_matches!(Request::decode(&bytes), Ok(Request::GetVersion));
+    }
+
+    #[test]
+    fn challenge_must_be_32_bytes() {
+        assert_eq!(
+            Challenge::from_bytes(&[3u8; 32]).unwrap().as_ref(),
+            &[3u8; 32]
+        );
+        assert!(Challenge::from_bytes(&[3u8; 31]).is_none());
+        assert!(Challenge::from_bytes(&[3u8; 33]).is_none());
+    }
+
+    #[test]
+    fn request_data_over_length_field_is_not_encoded() {
+        let bytes = vendor_request(0x40, &[0u8; 65535]).into_bytes().unwrap();
+        assert_eq!(&bytes[5..7], &[0xff, 0xff]);
+
+        assert!(vendor_request(0x40, &[0u8; 65536]).into_bytes().is_none());
+    }
+
+    #[test]
+    fn key_handle_over_length_byte_is_not_encoded() {
+        let request = |key_handle_len| Request::Authenticate {
+            application: AppId([1u8; 32]),
+            challenge: Challenge([2u8; 32]),
+            control_code: AuthenticateControlCode::CheckOnly,
+            // Only stored key handles can be this long
+            key_handle: serde_json::from_str::<KeyHandle>(&format!(
+                "\"{}\"",
+                base64::encode(&vec![0u8; key_handle_len])
+            ))
+            .unwrap(),
+        };
+
+        let bytes = request(255).into_bytes().unwrap();
+        assert_eq!(bytes[7 + 64], 255);
+
+        assert!(request(256).into_bytes().is_none());
+    }
+
     #[test]
     fn is_valid_key_handle_with_invalid_handle_is_false() {
-        spawn_server();
         let approval = Box::new(FakeUserPresence::always_approve());
-        let operations = Box::new(SecureCryptoOperations::new(get_test_attestation()));
+        let operations = operations();
         let storage = Box::new(InMemoryStorage::new());
         let u2f = U2F::new(approval, operations, storage, None).unwrap();
 
@@ -821,9 +1035,8 @@ AwEHoUQDQgAEryDZdIOGjRKLLyG6Mkc4oSVUDBndagZDDbdwLcUdNLzFlHx/yqYl
 
     #[test]
     fn is_valid_key_handle_with_valid_handle_is_true() {
-        spawn_server();
         let approval = Box::new(FakeUserPresence::always_approve());
-        let operations = Box::new(SecureCryptoOperations::new(get_test_attestation()));
+        let operations = operations();
         let storage = Box::new(InMemoryStorage::new());
         let u2f = U2F::new(approval, operations, storage, None).unwrap();
 
@@ -839,9 +1052,8 @@ AwEHoUQDQgAEryDZdIOGjRKLLyG6Mkc4oSVUDBndagZDDbdwLcUdNLzFlHx/yqYl
 
     #[test]
     fn authenticate_with_invalid_handle_errors() {
-        spawn_server();
         let approval = Box::new(FakeUserPresence::always_approve());
-        let operations = Box::new(SecureCryptoOperations::new(get_test_attestation()));
+        let operations = operations();
         let storage = Box::new(InMemoryStorage::new());
         let u2f = U2F::new(approval, operations, storage, None).unwrap();
 
@@ -857,9 +1069,8 @@ AwEHoUQDQgAEryDZdIOGjRKLLyG6Mkc4oSVUDBndagZDDbdwLcUdNLzFlHx/yqYl
 
     #[test]
     fn authenticate_with_valid_handle_succeeds() {
-        spawn_server();
         let approval = Box::new(FakeUserPresence::always_approve());
-        let operations = Box::new(SecureCryptoOperations::new(get_test_attestation()));
+        let operations = operations();
         let storage = Box::new(InMemoryStorage::new());
         let u2f = U2F::new(approval, operations, storage, None).unwrap();
 
@@ -877,12 +1088,11 @@ AwEHoUQDQgAEryDZdIOGjRKLLyG6Mkc4oSVUDBndagZDDbdwLcUdNLzFlHx/yqYl
 
     #[test]
     fn authenticate_with_rejected_approval_errors() {
-        spawn_server();
         let approval = Box::new(FakeUserPresence {
             should_approve_authentication: false,
             should_approve_registration: true,
         });
-        let operations = Box::new(SecureCryptoOperations::new(get_test_attestation()));
+        let operations = operations();
         let storage = Box::new(InMemoryStorage::new());
         let u2f = U2F::new(approval, operations, storage, None).unwrap();
 
@@ -902,12 +1112,11 @@ AwEHoUQDQgAEryDZdIOGjRKLLyG6Mkc4oSVUDBndagZDDbdwLcUdNLzFlHx/yqYl
 
     #[test]
     fn register_with_rejected_approval_errors() {
-        spawn_server();
         let approval = Box::new(FakeUserPresence {
             should_approve_authentication: true,
             should_approve_registration: false,
         });
-        let operations = Box::new(SecureCryptoOperations::new(get_test_attestation()));
+        let operations = operations();
         let storage = Box::new(InMemoryStorage::new());
         let u2f = U2F::new(approval, operations, storage, None).unwrap();
 
@@ -928,10 +1137,9 @@ AwEHoUQDQgAEryDZdIOGjRKLLyG6Mkc4oSVUDBndagZDDbdwLcUdNLzFlHx/yqYl
 
     #[test]
     fn register_signature() {
-        spawn_server();
         // Initialization process
         let approval = Box::new(FakeUserPresence::always_approve());
-        let operations = Box::new(SecureCryptoOperations::new(get_test_attestation()));
+        let operations = operations();
         let storage = Box::new(InMemoryStorage::new());
         let u2f = U2F::new(approval, operations, storage, None).unwrap();
 
@@ -967,9 +1175,8 @@ AwEHoUQDQgAEryDZdIOGjRKLLyG6Mkc4oSVUDBndagZDDbdwLcUdNLzFlHx/yqYl
 
     #[test]
     fn authenticate_signature() {
-        spawn_server();
         let approval = Box::new(FakeUserPresence::always_approve());
-        let operations = Box::new(SecureCryptoOperations::new(get_test_attestation()));
+        let operations = operations();
         let storage = Box::new(InMemoryStorage::new());
         let u2f = U2F::new(approval, operations, storage, None).unwrap();
 
@@ -1026,13 +1233,93 @@ AwEHoUQDQgAEryDZdIOGjRKLLyG6Mkc4oSVUDBndagZDDbdwLcUdNLzFlHx/yqYl
         );
     }
 
-    fn spawn_server() {
-        // Rocket server is blocking, so we spawn a new thread.
-        thread::spawn(move || {
-            server::get_server().launch();
-        });
+    struct EchoVendorHandler;
+
+    impl VendorHandler for EchoVendorHandler {
+        fn handle(
+            &self,
+            command: VendorCommand,
+            data: &[u8],
+        ) -> Option<Box<dyn Future<Item = Vec<u8>, Error = io::Error>>> {
+            match command {
+                VendorCommand::Apdu {
+                    instruction: 0x40, ..
+                } => Some(Box::new(future::ok(data.to_vec()))),
+                _ => None,
+            }
+        }
+    }
 
-        let five_seconds = time::Duration::from_millis(5000);
-        thread::sleep(five_seconds);
+    fn vendor_request(instruction: u8, data: &[u8]) -> Request {
+        Request::Vendor {
+            instruction,
+            parameter1: 0,
+            parameter2: 0,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn vendor_request_is_answered_by_handler() {
+        let approval = Box::new(FakeUserPresence::always_approve());
+        let storage = Box::new(InMemoryStorage::new());
+        let handler = Rc::new(EchoVendorHandler);
+        let u2f = U2F::with_vendor_handler(approval, operations(), storage, handler, None).unwrap();
+
+        let response = u2f.call(vendor_request(0x40, &[1, 2, 3])).wait().unwrap();
+        assert_eq!(response.into_bytes(), vec![1, 2, 3, 0x90, 0x00]);
+
+        let response = u2f.call(vendor_request(0x41, &[])).wait().unwrap();
+        assert_eq!(response.into_bytes(), vec![0x6D, 0x00]);
+    }
+
+    #[test]
+    fn vendor_request_without_handler_is_not_supported() {
+        let approval = Box::new(FakeUserPresence::always_approve());
+        let storage = Box::new(InMemoryStorage::new());
+        let u2f = U2F::new(approval, operations(), storage, None).unwrap();
+
+        let response = u2f.call(vendor_request(0x40, &[1])).wait().unwrap();
+        assert_eq!(response.into_bytes(), vec![0x6D, 0x00]);
+    }
+
+    #[test]
+    fn decode_vendor_request() {
+        let data = [0x00, 0x50, 0x01, 0x02, 0x00, 0x00, 0x02, 0xaa, 0xbb];
+        match Request::decode(&data) {
+            Ok(Request::Vendor {
+                instruction,
+                parameter1,
+                parameter2,
+                data,
+            }) => {
+                assert_eq!((instruction, parameter1, parameter2), (0x50, 0x01, 0x02));
+                assert_eq!(data, vec![0xaa, 0xbb]);
+            }
+            res => panic!("expected vendor request, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn decode_malformed_requests() {
+        let bytes = Request::Register {
+            application: AppId([1u8; 32]),
+            challenge: Challenge([2u8; 32]),
+        }
+        .into_bytes()
+        .unwrap();
+        assert_matches!(
+            Request::decode(&bytes[..bytes.len() - 10]),
+            Err(RequestError::Length)
+        );
+        assert_matches!(Request::decode(&[0x00, 0x01]), Err(RequestError::Length));
+        assert_matches!(
+            Request::decode(&[0x00, 0x02, 0x05, 0x00, 0x00, 0x00, 0x00]),
+            Err(RequestError::Parameter)
+        );
+        assert_matches!(
+            Request::decode(&[0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00]),
+            Err(RequestError::Instruction(0x10))
+        );
     }
 }
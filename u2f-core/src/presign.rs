@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use client_lib::ecdsa::PrivateShare;
+use client_lib::BigInt;
+use kms::ecdsa::two_party::MasterKey2;
+use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::{party_one, party_two};
+
+use cosigner::CosignerClient;
+use SignError;
+
+// The message independent half of a signature, the ephemeral key exchange
+// of the first gotham signing round. The co-signer keeps its half until
+// the second round, and serves that round once per first round.
+pub(crate) struct Presignature {
+    party_one_first_message: party_one::EphKeyGenFirstMsg,
+    comm_witness: party_two::EphCommWitness,
+    ec_key_pair: party_two::EphEcKeyPair,
+}
+
+#[derive(Serialize)]
+struct SignSecondMsgRequest {
+    message: BigInt,
+    party_two_sign_message: party_two::SignMessage,
+    x_pos_child_key: BigInt,
+    y_pos_child_key: BigInt,
+}
+
+pub(crate) fn presign(cosigner: &CosignerClient, key_id: &str) -> Result<Presignature, SignError> {
+    let (party_two_first_message, comm_witness, ec_key_pair) = MasterKey2::sign_first_message();
+    let party_one_first_message = cosigner.post_round(
+        &format!("ecdsa/sign/{}/first", key_id),
+        &party_two_first_message,
+    )?;
+    Ok(Presignature {
+        party_one_first_message,
+        comm_witness,
+        ec_key_pair,
+    })
+}
+
+// The second, and only online, signing round. Taking the presignature by
+// value keeps it from being used for a second message.
+pub(crate) fn sign_presigned(
+    cosigner: &CosignerClient,
+    share: &PrivateShare,
+    presignature: Presignature,
+    message: BigInt,
+    x_pos: BigInt,
+    y_pos: BigInt,
+) -> Result<party_one::SignatureRecid, SignError> {
+    let child_master_key = share
+        .master_key
+        .get_child(vec![x_pos.clone(), y_pos.clone()]);
+    let party_two_sign_message = child_master_key.sign_second_message(
+        &presignature.ec_key_pair,
+        presignature.comm_witness,
+        &presignature.party_one_first_message,
+        &message,
+    );
+    let request = SignSecondMsgRequest {
+        message,
+        party_two_sign_message,
+        x_pos_child_key: x_pos,
+        y_pos_child_key: y_pos,
+    };
+    cosigner.post_round(&format!("ecdsa/sign/{}/second", share.id), &request)
+}
+
+// At most one presignature per key, the co-signer only keeps the latest
+// first round for each key
+#[derive(Default)]
+pub(crate) struct Presignatures(Mutex<HashMap<String, Presignature>>);
+
+impl Presignatures {
+    pub fn insert(&self, key_id: &str, presignature: Presignature) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(key_id.to_string(), presignature);
+    }
+
+    pub fn take(&self, key_id: &str) -> Option<Presignature> {
+        self.0.lock().unwrap().remove(key_id)
+    }
+}
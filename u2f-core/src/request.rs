@@ -1,9 +1,9 @@
-use std::io::Cursor;
+use std::io::{self, Cursor};
 use std::io::Read;
 use std::result::Result;
 
 use app_id::AppId;
-use byteorder::{BigEndian, ReadBytesExt};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use constants::*;
 use key_handle::KeyHandle;
 
@@ -30,29 +30,58 @@ pub enum Request {
     },
     GetVersion,
     Wink,
+    Vendor {
+        instruction: u8,
+        parameter1: u8,
+        parameter2: u8,
+        data: Vec<u8>,
+    },
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum RequestError {
+        Length {
+            display("request length is invalid")
+        }
+        Parameter {
+            display("request parameters are invalid")
+        }
+        Instruction(code: u8) {
+            display("instruction {:#04x} is not supported", code)
+        }
+    }
+}
+
+// A request cut short, or with trailing bytes
+impl From<io::Error> for RequestError {
+    fn from(_: io::Error) -> RequestError {
+        RequestError::Length
+    }
 }
 
 impl Request {
     /// Only supports Extended Length Encoding
-    pub fn decode(data: &[u8]) -> Result<Request, ()> {
+    pub fn decode(data: &[u8]) -> Result<Request, RequestError> {
         let mut reader = Cursor::new(data);
 
         // CLA: Reserved to be used by the underlying transport protocol
-        let _class_byte = reader.read_u8().unwrap();
+        let _class_byte = reader.read_u8()?;
         // TODO check or error with RequestClassNotSupported
 
         // INS: U2F command code
-        let command_code = reader.read_u8().unwrap();
-        // TODO check or error with RequestInstructionNotSuppored
+        let command_code = reader.read_u8()?;
 
         // P1, P2: Parameter 1 and 2, defined by each command.
-        let parameter1 = reader.read_u8().unwrap();
-        let parameter2 = reader.read_u8().unwrap();
+        let parameter1 = reader.read_u8()?;
+        let parameter2 = reader.read_u8()?;
 
         // Extended Length Encoding
         // Always begins with a byte of value 0
-        let zero_byte = reader.read_u8().unwrap();
-        assert_eq!(zero_byte, 0);
+        let zero_byte = reader.read_u8()?;
+        if zero_byte != 0 {
+            return Err(RequestError::Length);
+        }
 
         // Nc: Length of the request-data, range 0..65 535
         // Lc: Encoding of Nc as two bytes
@@ -65,13 +94,13 @@ impl Request {
             }
             _ => {
                 // Lc in big-endian order
-                reader.read_u16::<BigEndian>().unwrap() as usize
+                reader.read_u16::<BigEndian>()? as usize
             }
         };
 
         // Request-data
         let mut request_data = vec![0u8; request_data_len];
-        reader.read_exact(&mut request_data[..]).unwrap();
+        reader.read_exact(&mut request_data[..])?;
 
         // Ne: Maximum length of the response data, range 0..65 536
         // Le: Encoding of Ne as two bytes
@@ -84,7 +113,7 @@ impl Request {
             }
             2 => {
                 // Encoded as: Le1 Le2
-                let mut value = reader.read_u16::<BigEndian>().unwrap() as usize;
+                let mut value = reader.read_u16::<BigEndian>()? as usize;
                 // When Ne = 65 536, let Le1 = 0 and Le2 = 0.
                 if value == 0 {
                     // The MSB is lost when encoding to two bytes, but
@@ -94,29 +123,41 @@ impl Request {
                 }
                 value
             }
-            _ => return Err(()),
+            _ => return Err(RequestError::Length),
         };
 
         // TODO If the instruction is not expected to yield any response bytes, L e may be omitted. O
+        if command_code >= VENDOR_FIRST_COMMAND_CODE && command_code <= VENDOR_LAST_COMMAND_CODE {
+            return Ok(Request::Vendor {
+                instruction: command_code,
+                parameter1,
+                parameter2,
+                data: request_data,
+            });
+        }
         let mut reader = Cursor::new(request_data);
         let request = match command_code {
             REGISTER_COMMAND_CODE => {
                 // The challenge parameter [32 bytes].
                 let mut challenge_parameter = [0u8; 32];
-                reader.read_exact(&mut challenge_parameter[..]).unwrap();
+                reader.read_exact(&mut challenge_parameter[..])?;
 
                 // The application parameter [32 bytes].
                 let mut application_parameter = [0u8; 32];
-                reader.read_exact(&mut application_parameter[..]).unwrap();
+                reader.read_exact(&mut application_parameter[..])?;
 
-                assert_eq!(reader.position() as usize, request_data_len);
+                if reader.position() as usize != request_data_len {
+                    return Err(RequestError::Length);
+                }
                 Request::Register {
                     application: AppId(application_parameter),
                     challenge: Challenge(challenge_parameter),
                 }
             }
             AUTHENTICATE_COMMAND_CODE => {
-                assert_eq!(parameter2, 0);
+                if parameter2 != 0 {
+                    return Err(RequestError::Parameter);
+                }
 
                 // Control byte (P1).
                 let control_code = match parameter1 {
@@ -125,23 +166,23 @@ impl Request {
                     AUTH_DONT_ENFORCE => {
                         AuthenticateControlCode::DontEnforceUserPresenceAndSign
                     }
-                    _ => panic!("Unknown control code"),
+                    _ => return Err(RequestError::Parameter),
                 };
 
                 // The challenge parameter [32 bytes].
                 let mut challenge_parameter = [0u8; 32];
-                reader.read_exact(&mut challenge_parameter[..]).unwrap();
+                reader.read_exact(&mut challenge_parameter[..])?;
 
                 // The application parameter [32 bytes].
                 let mut application_parameter = [0u8; 32];
-                reader.read_exact(&mut application_parameter[..]).unwrap();
+                reader.read_exact(&mut application_parameter[..])?;
 
                 // key handle length byte [1 byte]
-                let key_handle_len = reader.read_u8().unwrap();
+                let key_handle_len = reader.read_u8()?;
 
                 // key handle [length specified in previous field]
                 let mut key_handle_bytes = vec![0u8; key_handle_len as usize];
-                reader.read_exact(&mut key_handle_bytes[..]).unwrap();
+                reader.read_exact(&mut key_handle_bytes[..])?;
 
                 Request::Authenticate {
                     application: AppId(application_parameter),
@@ -151,13 +192,80 @@ impl Request {
                 }
             }
             VERSION_COMMAND_CODE => {
-                assert_eq!(parameter1, 0);
-                assert_eq!(parameter2, 0);
-                assert_eq!(request_data_len, 0);
+                if parameter1 != 0 || parameter2 != 0 {
+                    return Err(RequestError::Parameter);
+                }
+                if request_data_len != 0 {
+                    return Err(RequestError::Length);
+                }
                 Request::GetVersion
             }
-            _ => panic!("Not implemented"),
+            _ => return Err(RequestError::Instruction(command_code)),
         };
         Ok(request)
     }
+
+    /// Encodes with Extended Length Encoding, as decode expects. Wink is a
+    /// U2FHID command rather than an APDU, so has no encoding, and requests
+    /// with fields too long for their length field cannot be encoded.
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        let (instruction, parameter1, parameter2, request_data) = match self {
+            Request::Register {
+                application,
+                challenge,
+            } => {
+                let mut data = Vec::with_capacity(64);
+                data.extend_from_slice(challenge.as_ref());
+                data.extend_from_slice(application.as_ref());
+                (REGISTER_COMMAND_CODE, 0, 0, data)
+            }
+            Request::Authenticate {
+                application,
+                challenge,
+                control_code,
+                key_handle,
+            } => {
+                let control_byte = match control_code {
+                    AuthenticateControlCode::CheckOnly => AUTH_CHECK_ONLY,
+                    AuthenticateControlCode::EnforceUserPresenceAndSign => AUTH_ENFORCE,
+                    AuthenticateControlCode::DontEnforceUserPresenceAndSign => AUTH_DONT_ENFORCE,
+                };
+                let key_handle_bytes = key_handle.as_ref();
+                // The length is a single byte
+                if key_handle_bytes.len() > MAX_KEY_HANDLE_LEN {
+                    return None;
+                }
+                let mut data = Vec::with_capacity(65 + key_handle_bytes.len());
+                data.extend_from_slice(challenge.as_ref());
+                data.extend_from_slice(application.as_ref());
+                data.push(key_handle_bytes.len() as u8);
+                data.extend_from_slice(key_handle_bytes);
+                (AUTHENTICATE_COMMAND_CODE, control_byte, 0, data)
+            }
+            Request::GetVersion => (VERSION_COMMAND_CODE, 0, 0, Vec::new()),
+            Request::Wink => return None,
+            Request::Vendor {
+                instruction,
+                parameter1,
+                parameter2,
+                data,
+            } => (instruction, parameter1, parameter2, data),
+        };
+
+        // CLA, INS, P1, P2, then the zero byte starting extended length
+        let mut bytes = vec![0x00, instruction, parameter1, parameter2, 0x00];
+        // Lc, omitted with no request data
+        if request_data.len() > u16::max_value() as usize {
+            return None;
+        }
+        if !request_data.is_empty() {
+            bytes
+                .write_u16::<BigEndian>(request_data.len() as u16)
+                .unwrap();
+            bytes.extend_from_slice(&request_data);
+        }
+        // Le of zero, for up to 65 536 bytes of response data
+        bytes.extend_from_slice(&[0x00, 0x00]);
+        Some(bytes)
+    }
 }
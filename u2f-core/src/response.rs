@@ -3,6 +3,7 @@ use std::io;
 use attestation::AttestationCertificate;
 use byteorder::{BigEndian, WriteBytesExt};
 use key_handle::KeyHandle;
+use request::RequestError;
 
 use super::user_presence_byte;
 use super::Counter;
@@ -26,8 +27,14 @@ pub enum Response {
         version_string: String,
     },
     DidWink,
+    Vendor {
+        data: Vec<u8>,
+    },
     TestOfUserPresenceNotSatisfied,
     InvalidKeyHandle,
+    InstructionNotSupported,
+    RequestLengthInvalid,
+    InvalidRequestData,
     UnknownError,
 }
 
@@ -96,6 +103,12 @@ impl Response {
                 // Status word [2 bytes]
                 StatusCode::NoError.write(&mut bytes);
             }
+            Response::Vendor { data } => {
+                bytes.extend_from_slice(&data);
+
+                // Status word [2 bytes]
+                StatusCode::NoError.write(&mut bytes);
+            }
             Response::TestOfUserPresenceNotSatisfied => {
                 // Status word [2 bytes]
                 StatusCode::TestOfUserPresenceNotSatisfied.write(&mut bytes);
@@ -104,6 +117,18 @@ impl Response {
                 // Status word [2 bytes]
                 StatusCode::InvalidKeyHandle.write(&mut bytes);
             }
+            Response::InstructionNotSupported => {
+                // Status word [2 bytes]
+                StatusCode::RequestInstructionNotSuppored.write(&mut bytes);
+            }
+            Response::RequestLengthInvalid => {
+                // Status word [2 bytes]
+                StatusCode::RequestLengthInvalid.write(&mut bytes);
+            }
+            Response::InvalidRequestData => {
+                // Status word [2 bytes]
+                StatusCode::InvalidRequestData.write(&mut bytes);
+            }
             Response::UnknownError => {
                 // Status word [2 bytes]
                 StatusCode::UnknownError.write(&mut bytes);
@@ -113,6 +138,17 @@ impl Response {
     }
 }
 
+// The answer to a request that could not be decoded
+impl From<RequestError> for Response {
+    fn from(err: RequestError) -> Response {
+        match err {
+            RequestError::Length => Response::RequestLengthInvalid,
+            RequestError::Parameter => Response::InvalidRequestData,
+            RequestError::Instruction(_) => Response::InstructionNotSupported,
+        }
+    }
+}
+
 quick_error! {
     #[derive(Debug)]
     pub enum ResponseError {
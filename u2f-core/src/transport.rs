@@ -0,0 +1,262 @@
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use httparse;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{self, Method, StatusCode};
+use rocket::http::{self, Header};
+use rocket::local::Client;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+// Endpoints of this form reach the co-signer over a Unix domain socket
+pub const UNIX_ENDPOINT_PREFIX: &str = "unix:";
+
+// Keygen rounds prove the Paillier key, which takes the co-signer a while
+const UNIX_TIMEOUT: Duration = Duration::from_secs(120);
+const MAX_HEADERS: usize = 32;
+
+// How the co-signer is reached, over HTTP, or over a Unix domain socket
+// when it runs on the same host. The socket speaks HTTP/1.1 with one
+// request per connection, as local-server serves it. A co-signer in this
+// process is called through Rocket's local client, with no socket at all.
+#[derive(Clone)]
+pub enum CosignerTransport {
+    Http {
+        endpoint: String,
+        client: reqwest::Client,
+    },
+    Unix(PathBuf),
+    Local(Arc<Client>),
+}
+
+pub struct TransportResponse {
+    pub status: StatusCode,
+    pub body: Vec<u8>,
+}
+
+impl TransportResponse {
+    pub fn json<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_slice(&self.body)
+    }
+}
+
+impl CosignerTransport {
+    // Takes an http(s) URL, or unix:PATH for a socket
+    pub fn new(endpoint: &str) -> CosignerTransport {
+        if endpoint.starts_with(UNIX_ENDPOINT_PREFIX) {
+            CosignerTransport::Unix(PathBuf::from(&endpoint[UNIX_ENDPOINT_PREFIX.len()..]))
+        } else {
+            CosignerTransport::Http {
+                endpoint: endpoint.trim_end_matches('/').to_string(),
+                client: reqwest::Client::new(),
+            }
+        }
+    }
+
+    // Sends a request with an optional bearer token and JSON body
+    pub fn send(
+        &self,
+        method: Method,
+        path: &str,
+        token: Option<&str>,
+        body: Option<Vec<u8>>,
+    ) -> io::Result<TransportResponse> {
+        let path = path.trim_start_matches('/');
+        match *self {
+            CosignerTransport::Http {
+                ref endpoint,
+                ref client,
+            } => {
+                let mut request = client.request(method, &format!("{}/{}", endpoint, path));
+                if let Some(token) = token {
+                    request = request.bearer_auth(token);
+                }
+                if let Some(body) = body {
+                    request = request.header(CONTENT_TYPE, "application/json").body(body);
+                }
+                let mut response = request.send().map_err(http_error)?;
+                let mut body = Vec::new();
+                response.copy_to(&mut body).map_err(http_error)?;
+                Ok(TransportResponse {
+                    status: response.status(),
+                    body,
+                })
+            }
+            CosignerTransport::Unix(ref socket) => send_unix(socket, &method, path, token, body),
+            CosignerTransport::Local(ref client) => send_local(client, &method, path, token, body),
+        }
+    }
+}
+
+// Dispatched as if from loopback, as local-server does for its socket
+fn send_local(
+    client: &Client,
+    method: &Method,
+    path: &str,
+    token: Option<&str>,
+    body: Option<Vec<u8>>,
+) -> io::Result<TransportResponse> {
+    let method = http::Method::from_str(method.as_str()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("method {} is not supported", method),
+        )
+    })?;
+    let mut request = client
+        .req(method, format!("/{}", path))
+        .remote(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+    if let Some(token) = token {
+        request.add_header(Header::new("Authorization", format!("Bearer {}", token)));
+    }
+    if let Some(body) = body {
+        request.add_header(Header::new("Content-Type", "application/json"));
+        request.set_body(body);
+    }
+    let mut response = request.dispatch();
+    let status = StatusCode::from_u16(response.status().code).map_err(|_| malformed_response())?;
+    Ok(TransportResponse {
+        status,
+        body: response.body_bytes().unwrap_or_default(),
+    })
+}
+
+fn send_unix(
+    socket: &Path,
+    method: &Method,
+    path: &str,
+    token: Option<&str>,
+    body: Option<Vec<u8>>,
+) -> io::Result<TransportResponse> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(UNIX_TIMEOUT))?;
+    stream.set_write_timeout(Some(UNIX_TIMEOUT))?;
+
+    let body = body.unwrap_or_default();
+    let mut head = format!(
+        "{} /{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        body.len()
+    );
+    if let Some(token) = token {
+        head.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    if !body.is_empty() {
+        head.push_str("Content-Type: application/json\r\n");
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&body)?;
+
+    // The server closes the connection after its response
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    parse_response(&response)
+}
+
+fn parse_response(bytes: &[u8]) -> io::Result<TransportResponse> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    let head_len = match response.parse(bytes) {
+        Ok(httparse::Status::Complete(head_len)) => head_len,
+        _ => return Err(malformed_response()),
+    };
+    let status = response
+        .code
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .ok_or_else(malformed_response)?;
+    Ok(TransportResponse {
+        status,
+        body: bytes[head_len..].to_vec(),
+    })
+}
+
+fn malformed_response() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "malformed response from co-signer",
+    )
+}
+
+fn http_error(err: reqwest::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use self::tempdir::TempDir;
+    use super::*;
+
+    #[test]
+    fn unix_endpoint_is_recognised() {
+        match CosignerTransport::new("unix:/run/cosigner/cosigner.sock") {
+            CosignerTransport::Unix(path) => {
+                assert_eq!(path, PathBuf::from("/run/cosigner/cosigner.sock"))
+            }
+            _ => panic!("expected a socket transport"),
+        }
+        match CosignerTransport::new("http://localhost:8000/") {
+            CosignerTransport::Http { endpoint, .. } => {
+                assert_eq!(endpoint, "http://localhost:8000")
+            }
+            _ => panic!("expected an HTTP transport"),
+        }
+    }
+
+    #[test]
+    fn request_round_trips_over_socket() {
+        let dir = TempDir::new("transport").unwrap();
+        let socket = dir.path().join("cosigner.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                head.push(line.trim_end().to_string());
+            }
+            let mut body = [0u8; 2];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 4\r\n\r\n{\"a\"")
+                .unwrap();
+            (head, body)
+        });
+
+        let transport = CosignerTransport::new(&format!("unix:{}", socket.display()));
+        let response = transport
+            .send(
+                Method::POST,
+                "ecdsa/keygen/first",
+                Some("token"),
+                Some(b"{}".to_vec()),
+            )
+            .unwrap();
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+        assert_eq!(response.body, b"{\"a\"".to_vec());
+
+        let (head, body) = server.join().unwrap();
+        assert_eq!(head[0], "POST /ecdsa/keygen/first HTTP/1.1");
+        assert!(head.contains(&String::from("Authorization: Bearer token")));
+        assert!(head.contains(&String::from("Content-Length: 2")));
+        assert_eq!(&body, b"{}");
+    }
+}
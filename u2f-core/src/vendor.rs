@@ -0,0 +1,28 @@
+use std::io;
+
+use futures::Future;
+
+// A command in one of the ranges the specs leave to vendors
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VendorCommand {
+    // U2FHID command byte, 0xc0 to 0xff
+    Hid(u8),
+    // U2F APDU instruction, 0x40 to 0xbf, and its parameters
+    Apdu {
+        instruction: u8,
+        parameter1: u8,
+        parameter2: u8,
+    },
+}
+
+// Answers vendor commands, e.g. for tools querying the device for its
+// version or state over HID
+pub trait VendorHandler {
+    // Returns None for commands it does not handle, which are refused as
+    // unsupported. An APDU response has the status word appended to it.
+    fn handle(
+        &self,
+        command: VendorCommand,
+        data: &[u8],
+    ) -> Option<Box<dyn Future<Item = Vec<u8>, Error = io::Error>>>;
+}
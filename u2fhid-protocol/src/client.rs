@@ -0,0 +1,605 @@
+use std::collections::vec_deque::VecDeque;
+use std::io;
+
+use futures::{future, Async, AsyncSink, Future, Poll, Sink, Stream};
+use rand::{OsRng, Rng};
+use u2f_core;
+
+use definitions::*;
+
+// Sent by CTAPHID authenticators while waiting on the user. U2FHID has no
+// such command, so it arrives as an unknown one.
+const CTAPHID_KEEPALIVE: u8 = 0xbb;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ClientError {
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            display("I/O error: {}", err)
+        }
+        Packet(err: PacketError) {
+            from()
+            cause(err)
+            display("Packet error: {}", err)
+        }
+        Closed {
+            display("Transport closed before the response arrived")
+        }
+        Busy {
+            display("Device is busy with a transaction on another channel")
+        }
+        Device(code: u8) {
+            display("Device responded with error {:#04x}", code)
+        }
+        UnexpectedCommand(command: Command) {
+            display("Device responded with command {:?}", command)
+        }
+        InvalidResponse(message: &'static str) {
+            display("Invalid response: {}", message)
+        }
+        Unencodable {
+            display("Request has no APDU encoding")
+        }
+    }
+}
+
+// What the device said about itself in response to INIT
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceInfo {
+    pub channel_id: ChannelId,
+    pub protocol_version: u8,
+    pub major_device_version: u8,
+    pub minor_device_version: u8,
+    pub build_device_version: u8,
+    pub capabilities: u8,
+}
+
+impl DeviceInfo {
+    pub fn can_wink(&self) -> bool {
+        self.capabilities & CapabilityFlags::CAPFLAG_WINK.bits() != 0
+    }
+}
+
+// The host side of U2FHID, over a transport of packets to and from a
+// device. Each transaction takes the client and gives it back with the
+// response. There are no timeouts, a caller that needs one can race the
+// transaction against a timer.
+pub struct Client<T> {
+    channel_id: ChannelId,
+    report_format: ReportFormat,
+    transport: T,
+}
+
+impl<T, E> Client<T>
+where
+    T: Sink<SinkItem = Packet, SinkError = E> + Stream<Item = Packet, Error = E> + 'static,
+    E: Into<io::Error>,
+{
+    // Allocates a channel of the client's own with INIT on the broadcast
+    // channel
+    pub fn init(
+        transport: T,
+        report_format: ReportFormat,
+    ) -> Box<dyn Future<Item = (Client<T>, DeviceInfo), Error = ClientError>> {
+        let mut nonce = [0u8; 8];
+        match OsRng::new() {
+            Ok(mut rng) => rng.fill_bytes(&mut nonce),
+            Err(err) => return Box::new(future::err(ClientError::Io(err))),
+        }
+        let client = Client {
+            channel_id: BROADCAST_CHANNEL_ID,
+            report_format,
+            transport,
+        };
+        Box::new(
+            Transaction::new(client, Command::Init, &nonce, Some(nonce)).and_then(
+                |(mut client, data)| {
+                    if data.len() < 17 {
+                        return Err(ClientError::InvalidResponse("INIT response too short"));
+                    }
+                    let info = DeviceInfo {
+                        channel_id: ChannelId(
+                            data[8..12]
+                                .iter()
+                                .fold(0, |id, &byte| (id << 8) | u32::from(byte)),
+                        ),
+                        protocol_version: data[12],
+                        major_device_version: data[13],
+                        minor_device_version: data[14],
+                        build_device_version: data[15],
+                        capabilities: data[16],
+                    };
+                    client.channel_id = info.channel_id;
+                    Ok((client, info))
+                },
+            ),
+        )
+    }
+
+    pub fn channel_id(&self) -> ChannelId {
+        self.channel_id
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    // Sends a request and waits for the response to the same command,
+    // skipping keepalives
+    pub fn transact(
+        self,
+        command: Command,
+        data: &[u8],
+    ) -> Box<dyn Future<Item = (Client<T>, Vec<u8>), Error = ClientError>> {
+        Box::new(Transaction::new(self, command, data, None))
+    }
+
+    // Sends an APDU, responding with the response APDU
+    pub fn msg(
+        self,
+        apdu: &[u8],
+    ) -> Box<dyn Future<Item = (Client<T>, Vec<u8>), Error = ClientError>> {
+        self.transact(Command::Msg, apdu)
+    }
+
+    pub fn request(
+        self,
+        request: u2f_core::Request,
+    ) -> Box<dyn Future<Item = (Client<T>, Vec<u8>), Error = ClientError>> {
+        match request.into_bytes() {
+            Some(apdu) => self.msg(&apdu),
+            None => Box::new(future::err(ClientError::Unencodable)),
+        }
+    }
+
+    pub fn ping(
+        self,
+        data: &[u8],
+    ) -> Box<dyn Future<Item = (Client<T>, Vec<u8>), Error = ClientError>> {
+        self.transact(Command::Ping, data)
+    }
+
+    pub fn wink(self) -> Box<dyn Future<Item = Client<T>, Error = ClientError>> {
+        Box::new(self.transact(Command::Wink, &[]).map(|(client, _)| client))
+    }
+}
+
+// A response being reassembled from its packets
+struct Reassembly {
+    data: Vec<u8>,
+    payload_len: usize,
+    next_sequence_number: u8,
+}
+
+struct Transaction<T> {
+    client: Option<Client<T>>,
+    command: Command,
+    // Only for INIT, which is answered on the broadcast channel where
+    // responses to other hosts' INITs can be seen too
+    nonce: Option<[u8; 8]>,
+    outgoing: VecDeque<Packet>,
+    response: Option<Reassembly>,
+    setup_error: Option<ClientError>,
+}
+
+impl<T> Transaction<T> {
+    fn new(
+        client: Client<T>,
+        command: Command,
+        data: &[u8],
+        nonce: Option<[u8; 8]>,
+    ) -> Transaction<T> {
+        let (outgoing, setup_error) =
+            match encode_message(&client.report_format, client.channel_id, command, data) {
+                Ok(packets) => (packets, None),
+                Err(err) => (VecDeque::new(), Some(ClientError::Packet(err))),
+            };
+        Transaction {
+            client: Some(client),
+            command,
+            nonce,
+            outgoing,
+            response: None,
+            setup_error,
+        }
+    }
+
+    // Returns the response once the packet completes it
+    fn accept_packet(
+        &mut self,
+        packet: Packet,
+        format: &ReportFormat,
+    ) -> Result<Option<Vec<u8>>, ClientError> {
+        if let Some(nonce) = self.nonce {
+            // Everything else on the broadcast channel, errors included,
+            // may be meant for another host. INIT responses fit in one
+            // packet, so the nonce can be checked on arrival.
+            match packet {
+                Packet::Initialization {
+                    command: Command::Init,
+                    ref data,
+                    ..
+                } if data.starts_with(&nonce) => {}
+                _ => return Ok(None),
+            }
+        }
+        match packet {
+            Packet::Initialization {
+                command:
+                    Command::Unknown {
+                        identifier: CTAPHID_KEEPALIVE,
+                    },
+                ..
+            } => Ok(None),
+            Packet::Initialization {
+                command: Command::Error,
+                data,
+                ..
+            } => {
+                let code = data.first().cloned().unwrap_or(0);
+                if code == ErrorCode::ChannelBusy.into_byte() {
+                    Err(ClientError::Busy)
+                } else {
+                    Err(ClientError::Device(code))
+                }
+            }
+            Packet::Initialization {
+                command,
+                data,
+                payload_len,
+                ..
+            } => {
+                if command != self.command {
+                    return Err(ClientError::UnexpectedCommand(command));
+                }
+                if payload_len > format.max_payload_len() {
+                    return Err(ClientError::InvalidResponse(
+                        "payload length over the maximum",
+                    ));
+                }
+                self.response = Some(Reassembly {
+                    data,
+                    payload_len,
+                    next_sequence_number: 0,
+                });
+                Ok(self.complete_response())
+            }
+            Packet::Continuation {
+                sequence_number,
+                data,
+                ..
+            } => {
+                match self.response {
+                    Some(ref mut response) if response.next_sequence_number == sequence_number => {
+                        response.data.extend_from_slice(&data);
+                        response.next_sequence_number += 1;
+                    }
+                    Some(_) => {
+                        return Err(ClientError::InvalidResponse("continuation out of sequence"))
+                    }
+                    None => {
+                        return Err(ClientError::InvalidResponse(
+                            "continuation without initialization",
+                        ))
+                    }
+                }
+                Ok(self.complete_response())
+            }
+        }
+    }
+
+    fn complete_response(&mut self) -> Option<Vec<u8>> {
+        let complete = match self.response {
+            Some(ref response) => response.data.len() >= response.payload_len,
+            None => false,
+        };
+        if !complete {
+            return None;
+        }
+        let response = self.response.take().unwrap();
+        let mut data = response.data;
+        // Reports are padded out to their full length
+        data.truncate(response.payload_len);
+        Some(data)
+    }
+}
+
+impl<T, E> Future for Transaction<T>
+where
+    T: Sink<SinkItem = Packet, SinkError = E> + Stream<Item = Packet, Error = E>,
+    E: Into<io::Error>,
+{
+    type Item = (Client<T>, Vec<u8>);
+    type Error = ClientError;
+
+    fn poll(&mut self) -> Poll<Self::Item, ClientError> {
+        if let Some(err) = self.setup_error.take() {
+            return Err(err);
+        }
+        loop {
+            let (channel_id, report_format) = {
+                let client = self
+                    .client
+                    .as_mut()
+                    .expect("poll after transaction completed");
+
+                while let Some(packet) = self.outgoing.pop_front() {
+                    if let AsyncSink::NotReady(packet) = client
+                        .transport
+                        .start_send(packet)
+                        .map_err(into_client_error)?
+                    {
+                        self.outgoing.push_front(packet);
+                        break;
+                    }
+                }
+                let flushed = client
+                    .transport
+                    .poll_complete()
+                    .map_err(into_client_error)?
+                    .is_ready();
+                if !flushed || !self.outgoing.is_empty() {
+                    return Ok(Async::NotReady);
+                }
+                (client.channel_id, client.report_format)
+            };
+
+            let polled = self
+                .client
+                .as_mut()
+                .unwrap()
+                .transport
+                .poll()
+                .map_err(into_client_error)?;
+            let packet = match polled {
+                Async::Ready(Some(packet)) => packet,
+                Async::Ready(None) => return Err(ClientError::Closed),
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+            // Traffic for other hosts' channels
+            if packet.channel_id() != channel_id {
+                continue;
+            }
+            if let Some(data) = self.accept_packet(packet, &report_format)? {
+                return Ok(Async::Ready((self.client.take().unwrap(), data)));
+            }
+        }
+    }
+}
+
+fn into_client_error<E: Into<io::Error>>(err: E) -> ClientError {
+    ClientError::Io(err.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StartSend;
+    use u2f_core::Service;
+
+    use super::*;
+    use loopback::Loopback;
+
+    struct VersionService;
+
+    impl Service for VersionService {
+        type Request = u2f_core::Request;
+        type Response = u2f_core::Response;
+        type Error = io::Error;
+        type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+        fn call(&self, _req: Self::Request) -> Self::Future {
+            Box::new(future::ok(u2f_core::Response::Version {
+                version_string: String::from("U2F_V2"),
+            }))
+        }
+    }
+
+    // Answers with canned packets, whatever is sent
+    struct Scripted {
+        incoming: VecDeque<Packet>,
+    }
+
+    impl Sink for Scripted {
+        type SinkItem = Packet;
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, _packet: Packet) -> StartSend<Packet, io::Error> {
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    impl Stream for Scripted {
+        type Item = Packet;
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Option<Packet>, io::Error> {
+            Ok(Async::Ready(self.incoming.pop_front()))
+        }
+    }
+
+    const CHANNEL_ID: ChannelId = ChannelId(0x0102_0304);
+
+    fn scripted(packets: Vec<Packet>) -> Client<Scripted> {
+        Client {
+            channel_id: CHANNEL_ID,
+            report_format: ReportFormat::default(),
+            transport: Scripted {
+                incoming: packets.into_iter().collect(),
+            },
+        }
+    }
+
+    fn initialization(channel_id: ChannelId, command: Command, data: Vec<u8>) -> Packet {
+        Packet::Initialization {
+            channel_id,
+            command,
+            payload_len: data.len(),
+            data,
+        }
+    }
+
+    #[test]
+    fn init_allocates_channel() {
+        let (client, info) =
+            Client::init(Loopback::new(VersionService, None), ReportFormat::default())
+                .wait()
+                .unwrap();
+
+        assert_ne!(info.channel_id, BROADCAST_CHANNEL_ID);
+        assert_eq!(client.channel_id(), info.channel_id);
+        assert_eq!(info.protocol_version, U2FHID_PROTOCOL_VERSION);
+        assert!(info.can_wink());
+    }
+
+    #[test]
+    fn ping_reassembles_segmented_response() {
+        let (client, _) =
+            Client::init(Loopback::new(VersionService, None), ReportFormat::default())
+                .wait()
+                .unwrap();
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+
+        let (_, pong) = client.ping(&data).wait().unwrap();
+
+        assert_eq!(pong, data);
+    }
+
+    #[test]
+    fn request_returns_response_apdu() {
+        let (client, _) =
+            Client::init(Loopback::new(VersionService, None), ReportFormat::default())
+                .wait()
+                .unwrap();
+
+        let (_, response) = client
+            .request(u2f_core::Request::GetVersion)
+            .wait()
+            .unwrap();
+
+        assert_eq!(response, b"U2F_V2\x90\x00".to_vec());
+    }
+
+    #[test]
+    fn unsupported_vendor_command_is_device_error() {
+        let (client, _) =
+            Client::init(Loopback::new(VersionService, None), ReportFormat::default())
+                .wait()
+                .unwrap();
+
+        let result = client
+            .transact(Command::Vendor { identifier: 0xc0 }, &[])
+            .wait();
+
+        match result {
+            Err(ClientError::Device(0x01)) => {}
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Vendor command answered"),
+        }
+    }
+
+    #[test]
+    fn keepalive_is_skipped() {
+        let client = scripted(vec![
+            initialization(
+                CHANNEL_ID,
+                Command::Unknown {
+                    identifier: CTAPHID_KEEPALIVE,
+                },
+                vec![1],
+            ),
+            initialization(CHANNEL_ID, Command::Ping, vec![7, 8]),
+        ]);
+
+        let (_, pong) = client.ping(&[7, 8]).wait().unwrap();
+
+        assert_eq!(pong, vec![7, 8]);
+    }
+
+    #[test]
+    fn other_channels_are_skipped() {
+        let client = scripted(vec![
+            initialization(ChannelId(9), Command::Ping, vec![1]),
+            initialization(CHANNEL_ID, Command::Ping, vec![7, 8]),
+        ]);
+
+        let (_, pong) = client.ping(&[7, 8]).wait().unwrap();
+
+        assert_eq!(pong, vec![7, 8]);
+    }
+
+    #[test]
+    fn busy_error_is_reported() {
+        let client = scripted(vec![initialization(
+            CHANNEL_ID,
+            Command::Error,
+            vec![ErrorCode::ChannelBusy.into_byte()],
+        )]);
+
+        match client.ping(&[1]).wait() {
+            Err(ClientError::Busy) => {}
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Busy device answered"),
+        }
+    }
+
+    #[test]
+    fn continuation_out_of_sequence_is_invalid() {
+        let client = scripted(vec![
+            Packet::Initialization {
+                channel_id: CHANNEL_ID,
+                command: Command::Ping,
+                data: vec![0; 57],
+                payload_len: 200,
+            },
+            Packet::Continuation {
+                channel_id: CHANNEL_ID,
+                sequence_number: 1,
+                data: vec![0; 59],
+            },
+        ]);
+
+        match client.ping(&[0; 200]).wait() {
+            Err(ClientError::InvalidResponse(_)) => {}
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Out of sequence response accepted"),
+        }
+    }
+
+    #[test]
+    fn init_skips_response_to_other_nonce() {
+        let mut other_response = vec![9u8; 8];
+        other_response.extend_from_slice(&[0, 0, 0, 5, 2, 0, 1, 0, 1]);
+        let client = scripted(vec![initialization(
+            BROADCAST_CHANNEL_ID,
+            Command::Init,
+            other_response,
+        )]);
+
+        match Client::init(client.into_inner(), ReportFormat::default()).wait() {
+            Err(ClientError::Closed) => {}
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Response to another nonce accepted"),
+        }
+    }
+
+    #[test]
+    fn init_skips_errors_on_broadcast_channel() {
+        let client = scripted(vec![initialization(
+            BROADCAST_CHANNEL_ID,
+            Command::Error,
+            vec![ErrorCode::ChannelBusy.into_byte()],
+        )]);
+
+        match Client::init(client.into_inner(), ReportFormat::default()).wait() {
+            Err(ClientError::Closed) => {}
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Error for another host accepted"),
+        }
+    }
+}
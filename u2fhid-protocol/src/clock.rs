@@ -0,0 +1,15 @@
+use std::time::Instant;
+
+// Source of the current time for protocol timeouts, so tests can move it
+// forward without waiting
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
@@ -1,6 +1,6 @@
 use std::cmp;
 use std::collections::vec_deque::VecDeque;
-use std::io::{Cursor, Read};
+use std::io::{self, Cursor, Read};
 use std::mem::size_of;
 use std::time::Duration;
 
@@ -14,9 +14,13 @@ pub const BUILD_DEVICE_VERSION_NUMBER: u8 = 0;
 
 pub const U2FHID_PROTOCOL_VERSION: u8 = 2;
 
-const HID_REPORT_LEN: usize = 64;
-const INITIAL_PACKET_DATA_LEN: usize = HID_REPORT_LEN - 7;
-const CONTINUATION_PACKET_DATA_LEN: usize = HID_REPORT_LEN - 5;
+const DEFAULT_REPORT_LEN: usize = 64;
+const INITIAL_PACKET_HEADER_LEN: usize = 7;
+const CONTINUATION_PACKET_HEADER_LEN: usize = 5;
+const MAX_SEQUENCE_NUMBER: u8 = 0x7f;
+
+// Room for at least one byte of payload in an initialization packet
+pub const MIN_REPORT_LEN: usize = INITIAL_PACKET_HEADER_LEN + 1;
 
 const FRAME_TYPE_INIT: u8 = 0b1000_0000;
 const FRAME_TYPE_CONT: u8 = 0b0000_0000;
@@ -34,7 +38,10 @@ const U2FHID_VENDOR_FIRST: u8 = FRAME_TYPE_INIT | 0x40; // First vendor defined
 const U2FHID_VENDOR_LAST: u8 = FRAME_TYPE_INIT | 0x7f; // Last vendor defined command
 
 const COMMAND_INIT_DATA_LEN: usize = 8;
-const COMMAND_WINK_DATA_LEN: usize = 1;
+const COMMAND_LOCK_DATA_LEN: usize = 1;
+const COMMAND_SYNC_DATA_LEN: usize = 1;
+
+const MAX_LOCK_TIME_SECS: u8 = 10;
 
 pub const BROADCAST_CHANNEL_ID: ChannelId = ChannelId(0xffff_ffff);
 
@@ -44,8 +51,14 @@ pub fn packet_timeout_duration() -> Duration {
 pub fn transaction_timeout_duration() -> Duration {
     Duration::from_millis(3000)
 }
+// Backstop for a service that never responds. A signature may wait on an
+// approver for as long as the client allows, then on the user presence
+// prompt and the co-signer.
+pub fn dispatch_timeout_duration() -> Duration {
+    u2f_core::MAX_APPROVAL_WAIT + Duration::from_secs(60)
+}
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct ChannelId(pub u32);
 
 impl ChannelId {
@@ -75,7 +88,7 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum ErrorCode {
     None,
     InvalidChannel,
@@ -91,7 +104,7 @@ pub enum ErrorCode {
 }
 
 impl ErrorCode {
-    fn into_byte(self) -> u8 {
+    pub(crate) fn into_byte(self) -> u8 {
         match self {
             ErrorCode::None => 0x00,
             ErrorCode::InvalidCommand => 0x01,
@@ -108,7 +121,7 @@ impl ErrorCode {
     }
 }
 
-#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
 pub enum Command {
     Msg,
     Ping,
@@ -142,7 +155,7 @@ impl slog::Value for Command {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Packet {
     Initialization {
         channel_id: ChannelId,
@@ -171,6 +184,118 @@ impl slog::Value for Packet {
     }
 }
 
+quick_error! {
+    #[derive(Debug)]
+    pub enum PacketError {
+        ReportFormat(report_len: usize) {
+            display("Reports of {} bytes are under the minimum of {}", report_len, MIN_REPORT_LEN)
+        }
+        ReportLength(expected_len: usize, actual_len: usize) {
+            display("Expected a report of {} bytes, got {}", expected_len, actual_len)
+        }
+        ReportId(expected_id: u8, actual_id: u8) {
+            display("Expected report ID {}, got {}", expected_id, actual_id)
+        }
+        ReservedReportId {
+            display("Report ID 0 is reserved for devices without report IDs")
+        }
+        PayloadLength(len: usize, max_len: usize) {
+            display("Payload of {} bytes is over the maximum of {}", len, max_len)
+        }
+        DataLength(len: usize, max_len: usize) {
+            display("Packet data of {} bytes is over the maximum of {}", len, max_len)
+        }
+        SequenceNumber(sequence_number: u8) {
+            display("Sequence number {} is over the maximum of {}", sequence_number, MAX_SEQUENCE_NUMBER)
+        }
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            display("{}", err)
+        }
+    }
+}
+
+impl slog::Value for PacketError {
+    fn serialize(
+        &self,
+        record: &slog::Record,
+        key: slog::Key,
+        serializer: &mut dyn slog::Serializer,
+    ) -> slog::Result {
+        format!("{}", self).serialize(record, key, serializer)
+    }
+}
+
+// How packets are framed in HID reports: the report size declared by the
+// device's report descriptor, and the report ID prefixed to every report
+// when the descriptor declares one
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReportFormat {
+    report_len: usize,
+    report_id: Option<u8>,
+}
+
+impl ReportFormat {
+    pub fn new(report_len: usize, report_id: Option<u8>) -> Result<ReportFormat, PacketError> {
+        let format = ReportFormat {
+            report_len,
+            report_id,
+        };
+        format.validate()?;
+        Ok(format)
+    }
+
+    pub fn report_len(&self) -> usize {
+        self.report_len
+    }
+
+    pub fn report_id(&self) -> Option<u8> {
+        self.report_id
+    }
+
+    // Length of a whole report, including any report ID
+    pub fn framed_len(&self) -> usize {
+        self.report_id.map_or(0, |_| 1) + self.report_len
+    }
+
+    // An initialization packet followed by every continuation sequence
+    // number, limited by the 16 bit payload length field
+    pub fn max_payload_len(&self) -> usize {
+        let len = self.initial_data_len()
+            + (MAX_SEQUENCE_NUMBER as usize + 1) * self.continuation_data_len();
+        cmp::min(len, u16::max_value() as usize)
+    }
+
+    fn initial_data_len(&self) -> usize {
+        self.report_len - INITIAL_PACKET_HEADER_LEN
+    }
+
+    fn continuation_data_len(&self) -> usize {
+        self.report_len - CONTINUATION_PACKET_HEADER_LEN
+    }
+
+    // Formats can also arrive deserialized, so are checked before use
+    fn validate(&self) -> Result<(), PacketError> {
+        if self.report_len < MIN_REPORT_LEN {
+            return Err(PacketError::ReportFormat(self.report_len));
+        }
+        if self.report_id == Some(0) {
+            return Err(PacketError::ReservedReportId);
+        }
+        Ok(())
+    }
+}
+
+impl Default for ReportFormat {
+    fn default() -> ReportFormat {
+        ReportFormat {
+            report_len: DEFAULT_REPORT_LEN,
+            report_id: None,
+        }
+    }
+}
+
 impl Packet {
     pub fn channel_id(&self) -> ChannelId {
         match self {
@@ -179,12 +304,20 @@ impl Packet {
         }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Packet, ()> {
-        assert_eq!(bytes.len(), HID_REPORT_LEN + 1);
-        let mut reader = Cursor::new(bytes);
-        reader.read_u8().unwrap(); // TODO why do we have this extra byte to skip here
-        let channel_id = ChannelId(reader.read_u32::<BigEndian>().unwrap());
-        let first_byte = reader.read_u8().unwrap();
+    pub fn from_report(report: &[u8], format: &ReportFormat) -> Result<Packet, PacketError> {
+        format.validate()?;
+        if report.len() != format.framed_len() {
+            return Err(PacketError::ReportLength(format.framed_len(), report.len()));
+        }
+        let mut reader = Cursor::new(report);
+        if let Some(expected_id) = format.report_id {
+            let report_id = reader.read_u8()?;
+            if report_id != expected_id {
+                return Err(PacketError::ReportId(expected_id, report_id));
+            }
+        }
+        let channel_id = ChannelId(reader.read_u32::<BigEndian>()?);
+        let first_byte = reader.read_u8()?;
         if first_byte & FRAME_TYPE_MASK == FRAME_TYPE_INIT {
             let command = match first_byte {
                 U2FHID_MSG => Command::Msg,
@@ -199,9 +332,9 @@ impl Packet {
                 }
                 id => Command::Unknown { identifier: id }
             };
-            let payload_len = reader.read_u16::<BigEndian>().unwrap();
-            let mut packet_data = vec![0u8; INITIAL_PACKET_DATA_LEN];
-            reader.read_exact(&mut packet_data[..]).unwrap();
+            let payload_len = reader.read_u16::<BigEndian>()?;
+            let mut packet_data = vec![0u8; format.initial_data_len()];
+            reader.read_exact(&mut packet_data[..])?;
             Ok(Packet::Initialization {
                 channel_id,
                 command,
@@ -210,8 +343,8 @@ impl Packet {
             })
         } else {
             let sequence_number = first_byte;
-            let mut packet_data = vec![0u8; CONTINUATION_PACKET_DATA_LEN];
-            reader.read_exact(&mut packet_data[..]).unwrap();
+            let mut packet_data = vec![0u8; format.continuation_data_len()];
+            reader.read_exact(&mut packet_data[..])?;
             Ok(Packet::Continuation {
                 channel_id,
                 sequence_number,
@@ -220,8 +353,12 @@ impl Packet {
         }
     }
 
-    pub fn into_bytes(self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(HID_REPORT_LEN);
+    pub fn into_report(self, format: &ReportFormat) -> Result<Vec<u8>, PacketError> {
+        format.validate()?;
+        let mut bytes = Vec::with_capacity(format.framed_len());
+        if let Some(report_id) = format.report_id {
+            bytes.push(report_id);
+        }
         match self {
             Packet::Initialization {
                 channel_id,
@@ -229,6 +366,13 @@ impl Packet {
                 data,
                 payload_len,
             } => {
+                if payload_len > format.max_payload_len() {
+                    return Err(PacketError::PayloadLength(payload_len, format.max_payload_len()));
+                }
+                if data.len() > format.initial_data_len() {
+                    return Err(PacketError::DataLength(data.len(), format.initial_data_len()));
+                }
+
                 // Offset Length Mnemonic Description
                 // 0      4      CID      Channel identifier
                 channel_id.write(&mut bytes);
@@ -249,11 +393,11 @@ impl Packet {
 
                 // 5      1      BCNTH    High part of payload length
                 // 6      1      BCNTL    Low part of payload length
-                bytes.write_u16::<BigEndian>(payload_len as u16).unwrap();
+                bytes.write_u16::<BigEndian>(payload_len as u16)?;
 
                 // 7      (s-7)  DATA     Payload data (s is equal to the fixed packet size)
                 bytes.extend_from_slice(&data);
-                for _ in data.len()..INITIAL_PACKET_DATA_LEN {
+                for _ in data.len()..format.initial_data_len() {
                     bytes.push(0u8);
                 }
             }
@@ -262,23 +406,28 @@ impl Packet {
                 sequence_number,
                 data,
             } => {
+                if sequence_number > MAX_SEQUENCE_NUMBER {
+                    return Err(PacketError::SequenceNumber(sequence_number));
+                }
+                if data.len() > format.continuation_data_len() {
+                    return Err(PacketError::DataLength(data.len(), format.continuation_data_len()));
+                }
+
                 // Offset Length Mnemonic Description
                 // 0      4      CID      Channel identifier
                 channel_id.write(&mut bytes);
 
                 // 4      1      SEQ      Packet sequence 0x00..0x7f (bit 7 always cleared)
-                assert_eq!(sequence_number & FRAME_TYPE_MASK, FRAME_TYPE_CONT);
                 bytes.push(sequence_number);
 
                 // 5      (s-5)  DATA     Payload data (s is equal to the fixed packet size)
                 bytes.extend_from_slice(&data);
-                for _ in data.len()..CONTINUATION_PACKET_DATA_LEN {
+                for _ in data.len()..format.continuation_data_len() {
                     bytes.push(0u8);
                 }
             }
         }
-        assert_eq!(bytes.len(), HID_REPORT_LEN);
-        bytes
+        Ok(bytes)
     }
 }
 
@@ -295,6 +444,9 @@ pub enum RequestMessage {
     // Lock time in seconds 0..10. A value of 0 immediately releases the lock
     Lock { lock_time: Duration },
     Ping { data: Vec<u8> },
+    // Aborts the channel's transactions, echoing the nonce once done
+    Sync { nonce: u8 },
+    Vendor { identifier: u8, data: Vec<u8> },
     Wink,
 }
 
@@ -318,8 +470,10 @@ impl RequestMessage {
             },
             &Command::Wink => Ok(RequestMessage::Wink),
             &Command::Lock => {
-                if data.len() != COMMAND_WINK_DATA_LEN {
-                    Err(RequestMessageDecodeError::PayloadLength(COMMAND_WINK_DATA_LEN, data.len()))
+                if data.len() != COMMAND_LOCK_DATA_LEN {
+                    Err(RequestMessageDecodeError::PayloadLength(COMMAND_LOCK_DATA_LEN, data.len()))
+                } else if data[0] > MAX_LOCK_TIME_SECS {
+                    Err(RequestMessageDecodeError::InvalidParameter)
                 } else {
                     Ok(RequestMessage::Lock {
                         lock_time: Duration::from_secs(data[0].into()),
@@ -327,10 +481,17 @@ impl RequestMessage {
                 }
             },
             &Command::Sync => {
-                Err(RequestMessageDecodeError::UnsupportedCommand(*command))
+                if data.len() != COMMAND_SYNC_DATA_LEN {
+                    Err(RequestMessageDecodeError::PayloadLength(COMMAND_SYNC_DATA_LEN, data.len()))
+                } else {
+                    Ok(RequestMessage::Sync { nonce: data[0] })
+                }
             },
             &Command::Error => Err(RequestMessageDecodeError::UnsupportedCommand(*command)),
-            &Command::Vendor { .. } => Err(RequestMessageDecodeError::UnsupportedCommand(*command)),
+            &Command::Vendor { identifier } => Ok(RequestMessage::Vendor {
+                identifier,
+                data: data.to_vec(),
+            }),
 
             &Command::Unknown { .. } => {
                 // The Fido v2.0 specification is backwards compatible with U2F
@@ -347,6 +508,7 @@ quick_error! {
     #[derive(Debug)]
     pub enum RequestMessageDecodeError {
         PayloadLength(expected_len: usize, actual_len: usize)
+        InvalidParameter
         UnsupportedCommand(command: Command)
     }
 }
@@ -369,11 +531,12 @@ pub struct Response {
 }
 
 impl Response {
-    pub fn into_packets(self) -> VecDeque<Packet> {
+    // Fails for a payload too long to send
+    pub fn into_packets(self, format: &ReportFormat) -> Result<VecDeque<Packet>, PacketError> {
         let channel_id = self.channel_id;
         match self.message {
             ResponseMessage::EncapsulatedResponse { data } => {
-                encode_response(channel_id, Command::Msg, &data)
+                encode_message(format, channel_id, Command::Msg, &data)
             }
             ResponseMessage::Init {
                 nonce,
@@ -393,15 +556,23 @@ impl Response {
                 data.push(build_device_version_number);
                 data.push(capabilities.bits);
                 assert_eq!(data.len(), 17);
-                encode_response(channel_id, Command::Init, &data)
+                encode_message(format, channel_id, Command::Init, &data)
+            }
+            ResponseMessage::Pong { data } => {
+                encode_message(format, channel_id, Command::Ping, &data)
             }
-            ResponseMessage::Pong { data } => encode_response(channel_id, Command::Ping, &data),
             ResponseMessage::Error { code } => {
                 let data = vec![code.into_byte()];
-                encode_response(channel_id, Command::Error, &data)
+                encode_message(format, channel_id, Command::Error, &data)
+            }
+            ResponseMessage::Wink => encode_message(format, channel_id, Command::Wink, &[]),
+            ResponseMessage::Lock => encode_message(format, channel_id, Command::Lock, &[]),
+            ResponseMessage::Sync { nonce } => {
+                encode_message(format, channel_id, Command::Sync, &[nonce])
+            }
+            ResponseMessage::Vendor { identifier, data } => {
+                encode_message(format, channel_id, Command::Vendor { identifier }, &data)
             }
-            ResponseMessage::Wink => encode_response(channel_id, Command::Wink, &[]),
-            ResponseMessage::Lock => encode_response(channel_id, Command::Lock, &[]),
         }
     }
 }
@@ -428,6 +599,13 @@ pub enum ResponseMessage {
     },
     Wink,
     Lock,
+    Sync {
+        nonce: u8,
+    },
+    Vendor {
+        identifier: u8,
+        data: Vec<u8>,
+    },
 }
 
 impl slog::Value for ResponseMessage {
@@ -444,6 +622,8 @@ impl slog::Value for ResponseMessage {
             ResponseMessage::Error { .. } => "Error",
             ResponseMessage::Wink => "Wink",
             ResponseMessage::Lock => "Lock",
+            ResponseMessage::Sync { .. } => "Sync",
+            ResponseMessage::Vendor { .. } => "Vendor",
         }.serialize(record, key, serializer)
     }
 }
@@ -456,10 +636,20 @@ impl From<u2f_core::Response> for ResponseMessage {
     }
 }
 
-fn encode_response(channel_id: ChannelId, command: Command, data: &[u8]) -> VecDeque<Packet> {
+// Segments a request or response into packets
+pub(crate) fn encode_message(
+    format: &ReportFormat,
+    channel_id: ChannelId,
+    command: Command,
+    data: &[u8],
+) -> Result<VecDeque<Packet>, PacketError> {
+    format.validate()?;
+    if data.len() > format.max_payload_len() {
+        return Err(PacketError::PayloadLength(data.len(), format.max_payload_len()));
+    }
     let mut packets = VecDeque::new();
     let payload_len = data.len();
-    let split_index = cmp::min(data.len(), INITIAL_PACKET_DATA_LEN);
+    let split_index = cmp::min(data.len(), format.initial_data_len());
     let (initial, remaining) = data.split_at(split_index);
     packets.push_back(Packet::Initialization {
         channel_id,
@@ -467,12 +657,152 @@ fn encode_response(channel_id: ChannelId, command: Command, data: &[u8]) -> VecD
         payload_len,
         data: initial.to_vec(),
     });
-    for (i, chunk) in remaining.chunks(CONTINUATION_PACKET_DATA_LEN).enumerate() {
+    for (i, chunk) in remaining.chunks(format.continuation_data_len()).enumerate() {
         packets.push_back(Packet::Continuation {
             channel_id,
             sequence_number: i as u8,
             data: chunk.to_vec(),
         });
     }
-    packets
+    Ok(packets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn continuation_round_trip(format: &ReportFormat) {
+        let data = vec![1u8; format.continuation_data_len()];
+        let packet = Packet::Continuation {
+            channel_id: ChannelId(7),
+            sequence_number: 3,
+            data: data.clone(),
+        };
+
+        let report = packet.into_report(format).unwrap();
+        assert_eq!(report.len(), format.framed_len());
+
+        match Packet::from_report(&report, format).unwrap() {
+            Packet::Continuation {
+                channel_id,
+                sequence_number,
+                data: parsed_data,
+            } => {
+                assert_eq!(channel_id, ChannelId(7));
+                assert_eq!(sequence_number, 3);
+                assert_eq!(parsed_data, data);
+            }
+            _ => panic!("Expected a continuation packet"),
+        }
+    }
+
+    #[test]
+    fn from_report_rejects_short_report() {
+        match Packet::from_report(&[0u8; 10], &ReportFormat::default()) {
+            Err(PacketError::ReportLength(64, 10)) => {}
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Short report parsed"),
+        }
+    }
+
+    #[test]
+    fn from_report_rejects_other_report_id() {
+        let format = ReportFormat::new(64, Some(1)).unwrap();
+        let mut report = vec![0u8; 65];
+        report[0] = 2;
+
+        match Packet::from_report(&report, &format) {
+            Err(PacketError::ReportId(1, 2)) => {}
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Report with another ID parsed"),
+        }
+    }
+
+    #[test]
+    fn packet_round_trips_through_report() {
+        continuation_round_trip(&ReportFormat::default());
+    }
+
+    #[test]
+    fn packet_round_trips_through_report_with_id() {
+        let format = ReportFormat::new(64, Some(3)).unwrap();
+        continuation_round_trip(&format);
+
+        let report = Packet::Continuation {
+            channel_id: ChannelId(7),
+            sequence_number: 0,
+            data: vec![],
+        }.into_report(&format)
+            .unwrap();
+        assert_eq!(report[0], 3);
+    }
+
+    #[test]
+    fn packet_round_trips_through_larger_report() {
+        continuation_round_trip(&ReportFormat::new(512, None).unwrap());
+    }
+
+    #[test]
+    fn report_format_too_short() {
+        match ReportFormat::new(MIN_REPORT_LEN - 1, None) {
+            Err(PacketError::ReportFormat(len)) => assert_eq!(len, MIN_REPORT_LEN - 1),
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Report format too short for a packet"),
+        }
+    }
+
+    #[test]
+    fn report_format_with_reserved_report_id() {
+        match ReportFormat::new(64, Some(0)) {
+            Err(PacketError::ReservedReportId) => {}
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Report ID 0 cannot be prefixed to reports"),
+        }
+    }
+
+    #[test]
+    fn max_payload_len_fits_length_field() {
+        assert_eq!(ReportFormat::default().max_payload_len(), 7609);
+        assert_eq!(
+            ReportFormat::new(1024, None).unwrap().max_payload_len(),
+            u16::max_value() as usize
+        );
+    }
+
+    #[test]
+    fn largest_response_fills_every_sequence_number() {
+        let format = ReportFormat::default();
+        let response = Response {
+            channel_id: ChannelId(1),
+            message: ResponseMessage::Pong {
+                data: vec![0u8; format.max_payload_len()],
+            },
+        };
+
+        let packets = response.into_packets(&format).unwrap();
+
+        assert_eq!(packets.len(), 129);
+        for packet in packets {
+            packet.into_report(&format).unwrap();
+        }
+    }
+
+    #[test]
+    fn oversized_response_not_segmented() {
+        let format = ReportFormat::default();
+        let response = Response {
+            channel_id: ChannelId(1),
+            message: ResponseMessage::Pong {
+                data: vec![0u8; format.max_payload_len() + 1],
+            },
+        };
+
+        match response.into_packets(&format) {
+            Err(PacketError::PayloadLength(len, _)) => {
+                assert_eq!(len, format.max_payload_len() + 1)
+            }
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Oversized response segmented"),
+        }
+    }
 }
@@ -0,0 +1,65 @@
+use std::io;
+use std::rc::Rc;
+
+use futures::{future, Future};
+use protocol_state_machine::{DispatchRequest, DispatchResponse};
+use u2f_core::{self, Service, VendorCommand, VendorHandler};
+
+// Calls the U2F service, or the vendor handler, for requests the state
+// machine dispatches
+pub struct Dispatcher<S> {
+    service: S,
+    vendor_handler: Option<Rc<dyn VendorHandler>>,
+}
+
+impl<S> Dispatcher<S>
+where
+    S: Service<
+        Request = u2f_core::Request,
+        Response = u2f_core::Response,
+        Error = io::Error,
+        Future = Box<dyn Future<Item = u2f_core::Response, Error = io::Error>>,
+    >,
+{
+    pub fn new(service: S) -> Dispatcher<S> {
+        Dispatcher {
+            service,
+            vendor_handler: None,
+        }
+    }
+
+    // Answers U2FHID vendor commands, which are otherwise refused as invalid
+    pub fn set_vendor_handler(&mut self, handler: Rc<dyn VendorHandler>) {
+        self.vendor_handler = Some(handler);
+    }
+
+    // The service or handler failing is a DispatchResponse::Failed rather
+    // than an error, so the device carries on
+    pub fn call(
+        &self,
+        request: DispatchRequest,
+    ) -> Box<dyn Future<Item = DispatchResponse, Error = io::Error>> {
+        match request {
+            DispatchRequest::U2F(request) => Box::new(
+                self.service
+                    .call(request)
+                    .map(DispatchResponse::U2F)
+                    .or_else(|err| Ok(DispatchResponse::Failed(err))),
+            ),
+            DispatchRequest::Vendor { identifier, data } => {
+                let handled = self
+                    .vendor_handler
+                    .as_ref()
+                    .and_then(|handler| handler.handle(VendorCommand::Hid(identifier), &data));
+                match handled {
+                    Some(response) => Box::new(
+                        response
+                            .map(DispatchResponse::Vendor)
+                            .or_else(|err| Ok(DispatchResponse::Failed(err))),
+                    ),
+                    None => Box::new(future::ok(DispatchResponse::Unsupported)),
+                }
+            }
+        }
+    }
+}
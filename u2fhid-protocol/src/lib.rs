@@ -6,6 +6,7 @@ extern crate futures;
 extern crate itertools;
 #[macro_use]
 extern crate quick_error;
+extern crate rand;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
@@ -16,35 +17,41 @@ extern crate u2f_core;
 
 use std::collections::vec_deque::VecDeque;
 use std::io;
+use std::rc::Rc;
 
-use definitions::*;
-pub use definitions::Packet;
+pub use client::{Client, ClientError, DeviceInfo};
+pub use clock::{Clock, SystemClock};
+pub use definitions::{
+    ChannelId, Command, Packet, PacketError, ReportFormat, BROADCAST_CHANNEL_ID, MIN_REPORT_LEN,
+};
+pub use dispatcher::Dispatcher;
+pub use loopback::Loopback;
 use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
-use protocol_state_machine::StateMachine;
-use segmenting_sink::{Segmenter, SegmentingSink};
+pub use protocol_state_machine::{
+    Dispatch, DispatchId, DispatchRequest, DispatchResponse, Output, StateMachine,
+};
 use slog::Drain;
-use tokio_core::reactor::Handle;
-use u2f_core::{Service, U2F};
+use tokio_core::reactor::{Handle, Timeout};
+use u2f_core::{Service, VendorHandler, U2F};
 
+mod client;
+mod clock;
 mod definitions;
+mod dispatcher;
+mod loopback;
 mod protocol_state_machine;
-mod segmenting_sink;
-
-struct PacketSegmenter;
-
-impl Segmenter for PacketSegmenter {
-    type Item = Response;
-    type SegmentedItem = Packet;
-
-    fn segment(&self, item: Self::Item) -> VecDeque<Self::SegmentedItem> {
-        item.into_packets()
-    }
-}
 
+// Drives the protocol state machine from a tokio reactor, over a transport
+// of packets to and from the host
 pub struct U2FHID<T: Sink + Stream, S> {
+    dispatch: Option<(DispatchId, Box<dyn Future<Item = DispatchResponse, Error = io::Error>>)>,
+    dispatcher: Dispatcher<S>,
+    handle: Handle,
     logger: slog::Logger,
-    state_machine: StateMachine<S>,
-    transport: SegmentingSink<T, PacketSegmenter>,
+    outgoing: VecDeque<Packet>,
+    state_machine: StateMachine,
+    timer: Option<Timeout>,
+    transport: T,
 }
 
 impl<T, E> U2FHID<T, U2F>
@@ -63,10 +70,58 @@ where
             .unwrap_or_else(|| slog::Logger::root(slog_stdlog::StdLog.fuse(), o!()));
         let state_machine_logger = logger.new(o!());
         U2FHID {
+            dispatch: None,
+            dispatcher: Dispatcher::new(service),
+            handle,
             logger,
-            state_machine: StateMachine::new(service, handle, state_machine_logger),
-            transport: SegmentingSink::new(transport, PacketSegmenter),
+            outgoing: VecDeque::new(),
+            state_machine: StateMachine::new(Box::new(SystemClock), state_machine_logger),
+            timer: None,
+            transport,
+        }
+    }
+}
+
+impl<T: Sink + Stream, S> U2FHID<T, S>
+where
+    S: Service<
+        Request = u2f_core::Request,
+        Response = u2f_core::Response,
+        Error = io::Error,
+        Future = Box<dyn Future<Item = u2f_core::Response, Error = io::Error>>,
+    >,
+{
+    // Answers U2FHID vendor commands, 0xc0 to 0xff, with the handler
+    pub fn with_vendor_handler(mut self, handler: Rc<dyn VendorHandler>) -> Self {
+        self.dispatcher.set_vendor_handler(handler);
+        self
+    }
+
+    // Sizes packets for reports of this format, rather than the default
+    // 64 byte reports without a report ID
+    pub fn with_report_format(mut self, format: ReportFormat) -> Self {
+        self.state_machine.set_report_format(format);
+        self
+    }
+
+    // Carries out what the state machine asked for since last time
+    fn take_outputs(&mut self) -> Result<(), io::Error> {
+        while let Some(output) = self.state_machine.poll_output() {
+            match output {
+                Output::Packet(packet) => self.outgoing.push_back(packet),
+                Output::Dispatch(dispatch) => {
+                    self.dispatch = Some((dispatch.id, self.dispatcher.call(dispatch.request)));
+                }
+                Output::CancelDispatch(id) => {
+                    if self.dispatch.as_ref().map(|&(dispatch_id, _)| dispatch_id) == Some(id) {
+                        self.dispatch = None;
+                    }
+                }
+                Output::Timer(Some(at)) => self.timer = Some(Timeout::new_at(at, &self.handle)?),
+                Output::Timer(None) => self.timer = None,
+            }
         }
+        Ok(())
     }
 }
 
@@ -88,24 +143,48 @@ where
         loop {
             trace!(self.logger, "Poll U2FHID");
 
-            // Always tick the transport first
-            // TODO self.transport.tick();
+            self.take_outputs()?;
 
-            try_ready!(self.transport.poll_complete());
+            while let Some(packet) = self.outgoing.pop_front() {
+                if let AsyncSink::NotReady(packet) = self.transport.start_send(packet)? {
+                    self.outgoing.push_front(packet);
+                    break;
+                }
+            }
+            let flushed = self.transport.poll_complete()?.is_ready();
 
-            if let Some(response) = self.state_machine.step()? {
-                debug!(self.logger, "Send response"; "channel_id" => &response.channel_id, "message" => &response.message);
-                send(&mut self.transport, response)?;
+            let completed = match self.dispatch {
+                Some((id, ref mut future)) => match future.poll()? {
+                    Async::Ready(response) => Some((id, response)),
+                    Async::NotReady => None,
+                },
+                None => None,
+            };
+            if let Some((id, response)) = completed {
+                self.dispatch = None;
+                self.state_machine.complete_dispatch(id, response);
                 continue;
             }
 
+            let expired = match self.timer {
+                Some(ref mut timer) => timer.poll()?.is_ready(),
+                None => false,
+            };
+            if expired {
+                self.timer = None;
+                self.state_machine.step();
+                continue;
+            }
+
+            // Responses are sent before more requests are read
+            if !flushed || !self.outgoing.is_empty() {
+                return Ok(Async::NotReady);
+            }
+
             match try_ready!(self.transport.poll()) {
                 Some(packet) => {
                     trace!(self.logger, "Got packet from transport"; "packet" => &packet);
-                    if let Some(response) = self.state_machine.accept_packet(packet)? {
-                        debug!(self.logger, "Send response"; "channel_id" => &response.channel_id, "message" => &response.message);
-                        send(&mut self.transport, response)?;
-                    }
+                    self.state_machine.accept_packet(packet);
                 }
                 None => {
                     // TODO close
@@ -115,13 +194,3 @@ where
         }
     }
 }
-
-fn send<S: Sink>(s: &mut S, item: S::SinkItem) -> Result<(), S::SinkError> {
-    match s.start_send(item)? {
-        AsyncSink::Ready => Ok(()),
-        AsyncSink::NotReady(_) => panic!(
-            "sink reported itself as ready after `poll_ready` but was \
-             then unable to accept a message"
-        ),
-    }
-}
@@ -0,0 +1,96 @@
+use std::collections::vec_deque::VecDeque;
+use std::io;
+use std::rc::Rc;
+
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use slog::{self, Drain};
+use slog_stdlog;
+use u2f_core::{self, Service, VendorHandler};
+
+use clock::SystemClock;
+use definitions::Packet;
+use dispatcher::Dispatcher;
+use protocol_state_machine::{Output, StateMachine};
+
+// The device side in memory, as a transport for the client: packets sent
+// are handed straight to the state machine, and dispatches are run to
+// completion before the send returns. Nothing waits on timers, so
+// transactions never time out.
+pub struct Loopback<S> {
+    dispatcher: Dispatcher<S>,
+    incoming: VecDeque<Packet>,
+    state_machine: StateMachine,
+}
+
+impl<S> Loopback<S>
+where
+    S: Service<
+        Request = u2f_core::Request,
+        Response = u2f_core::Response,
+        Error = io::Error,
+        Future = Box<dyn Future<Item = u2f_core::Response, Error = io::Error>>,
+    >,
+{
+    pub fn new<L: Into<Option<slog::Logger>>>(service: S, logger: L) -> Loopback<S> {
+        let logger = logger
+            .into()
+            .unwrap_or_else(|| slog::Logger::root(slog_stdlog::StdLog.fuse(), o!()));
+        Loopback {
+            dispatcher: Dispatcher::new(service),
+            incoming: VecDeque::new(),
+            state_machine: StateMachine::new(Box::new(SystemClock), logger),
+        }
+    }
+
+    pub fn set_vendor_handler(&mut self, handler: Rc<dyn VendorHandler>) {
+        self.dispatcher.set_vendor_handler(handler);
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        while let Some(output) = self.state_machine.poll_output() {
+            match output {
+                Output::Packet(packet) => self.incoming.push_back(packet),
+                Output::Dispatch(dispatch) => {
+                    let response = self.dispatcher.call(dispatch.request).wait()?;
+                    self.state_machine.complete_dispatch(dispatch.id, response);
+                }
+                Output::CancelDispatch(_) | Output::Timer(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S> Sink for Loopback<S>
+where
+    S: Service<
+        Request = u2f_core::Request,
+        Response = u2f_core::Response,
+        Error = io::Error,
+        Future = Box<dyn Future<Item = u2f_core::Response, Error = io::Error>>,
+    >,
+{
+    type SinkItem = Packet;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, packet: Packet) -> StartSend<Packet, io::Error> {
+        self.state_machine.accept_packet(packet);
+        self.run()?;
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<S> Stream for Loopback<S> {
+    type Item = Packet;
+    type Error = io::Error;
+
+    // Everything is answered synchronously, so nothing queued means nothing
+    // more is coming
+    fn poll(&mut self) -> Poll<Option<Packet>, io::Error> {
+        Ok(Async::Ready(self.incoming.pop_front()))
+    }
+}
@@ -1,82 +1,148 @@
+use std::collections::vec_deque::VecDeque;
+use std::collections::HashMap;
 use std::io;
-use std::mem;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use clock::Clock;
 use definitions::*;
-use futures::{Async, Future};
-use futures::future;
+use rand::{OsRng, Rng};
 use slog::Logger;
-use tokio_core::reactor::Handle;
-use tokio_core::reactor::Timeout;
-use u2f_core::{self, Service};
-
-macro_rules! try_some {
-    ($e:expr) => (match $e {
-        Ok(Some(t)) => return Ok(Some(t)),
-        Ok(None) => {},
-        Err(e) => return Err(From::from(e)),
-    })
-}
+use u2f_core;
 
 struct ReceiveState {
     buffer: Vec<u8>,
     command: Command,
     next_sequence_number: u8,
     payload_len: usize,
-    channel_id: ChannelId,
-    packet_timeout: Timeout,
-    transaction_timeout: Timeout,
+    packet_deadline: Instant,
+    transaction_deadline: Instant,
+}
+
+impl ReceiveState {
+    fn deadline(&self) -> Instant {
+        self.packet_deadline.min(self.transaction_deadline)
+    }
 }
 
 struct DispatchState {
+    id: DispatchId,
     channel_id: ChannelId,
-    future: Box<dyn Future<Item = ResponseMessage, Error = io::Error>>,
-    timeout: Timeout,
+    command: Command,
+    deadline: Instant,
+}
+
+// Identifies a dispatch, so a response that arrives after the dispatch was
+// cancelled is not mistaken for the response to a later one
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DispatchId(u64);
+
+pub enum DispatchRequest {
+    U2F(u2f_core::Request),
+    Vendor { identifier: u8, data: Vec<u8> },
 }
 
-enum State {
-    Idle,
-    Receive(ReceiveState),
-    Dispatch(DispatchState),
-    Unknown,
+pub enum DispatchResponse {
+    U2F(u2f_core::Response),
+    Vendor(Vec<u8>),
+    // No handler for the vendor command
+    Unsupported,
+    // The service or vendor handler failed, answered with an error
+    // rather than ending the device
+    Failed(io::Error),
 }
 
-impl State {
-    fn take(&mut self) -> State {
-        mem::replace(self, State::Unknown)
-    }
+pub struct Dispatch {
+    pub id: DispatchId,
+    pub request: DispatchRequest,
 }
 
-const MAX_CHANNEL_ID: ChannelId = ChannelId(BROADCAST_CHANNEL_ID.0 - 1);
-const MIN_CHANNEL_ID: ChannelId = ChannelId(1);
+// What the state machine needs of its driver
+pub enum Output {
+    // Send to the host
+    Packet(Packet),
+    // Call the service, then pass its response to complete_dispatch
+    Dispatch(Dispatch),
+    // The response is no longer wanted, drop the call if still running
+    CancelDispatch(DispatchId),
+    // Call step once this instant has passed, replacing any earlier timer.
+    // None cancels the timer.
+    Timer(Option<Instant>),
+}
 
+const RESERVED_CHANNEL_ID: ChannelId = ChannelId(0);
+
+// Most channels open at once, beyond which the least recently used idle
+// channel is closed to make room for a new one
+const MAX_CHANNELS: usize = 64;
+
+quick_error! {
+    #[derive(Debug)]
+    enum AllocateError {
+        Exhausted {
+            display("every channel is busy with a transaction")
+        }
+        Random(err: io::Error) {
+            from()
+            display("unable to generate a channel id: {}", err)
+        }
+    }
+}
+
+// Channels given out by INIT, with unpredictable ids so one client cannot
+// guess and interfere with another's channel
 #[derive(Debug)]
 struct Channels {
-    next_allocation: ChannelId,
+    capacity: usize,
+    // Least recently used first
+    live: VecDeque<ChannelId>,
 }
 
 impl Channels {
-    fn new() -> Channels {
+    fn new(capacity: usize) -> Channels {
         Channels {
-            next_allocation: MIN_CHANNEL_ID,
+            capacity: capacity,
+            live: VecDeque::with_capacity(capacity),
         }
     }
 
-    fn allocate(&mut self) -> Result<ChannelId, ()> {
-        if self.next_allocation > MAX_CHANNEL_ID {
-            Err(())
+    // Returns the new channel, and any channel closed to make room for it
+    fn allocate<F>(&mut self, is_busy: F) -> Result<(ChannelId, Option<ChannelId>), AllocateError>
+    where
+        F: Fn(ChannelId) -> bool,
+    {
+        let mut rng = OsRng::new()?;
+        let closed = if self.live.len() >= self.capacity {
+            let index = self
+                .live
+                .iter()
+                .position(|&channel_id| !is_busy(channel_id))
+                .ok_or(AllocateError::Exhausted)?;
+            self.live.remove(index)
         } else {
-            let allocation = self.next_allocation;
-            self.next_allocation = self.next_allocation.checked_add(1).unwrap();
-            Ok(allocation)
+            None
+        };
+        let allocation = loop {
+            let channel_id = ChannelId(rng.next_u32());
+            if channel_id != BROADCAST_CHANNEL_ID
+                && channel_id != RESERVED_CHANNEL_ID
+                && !self.live.contains(&channel_id)
+            {
+                break channel_id;
+            }
+        };
+        self.live.push_back(allocation);
+        Ok((allocation, closed))
+    }
+
+    fn touch(&mut self, channel_id: ChannelId) {
+        if let Some(index) = self.live.iter().position(|&live| live == channel_id) {
+            self.live.remove(index);
+            self.live.push_back(channel_id);
         }
     }
 
     fn is_valid(&self, channel_id: ChannelId) -> bool {
-        let is_broadcast = channel_id == BROADCAST_CHANNEL_ID;
-        let is_in_allocated_range =
-            channel_id >= MIN_CHANNEL_ID && channel_id < self.next_allocation;
-        is_broadcast || is_in_allocated_range
+        channel_id == BROADCAST_CHANNEL_ID || self.live.contains(&channel_id)
     }
 }
 
@@ -84,375 +150,378 @@ enum LockState {
     None,
     Locked {
         channel_id: ChannelId,
-        timeout: Timeout,
+        expires_at: Instant,
     },
 }
 
 impl LockState {
-    fn lock(
-        &mut self,
-        duration: Duration,
-        channel_id: ChannelId,
-        handle: &Handle,
-    ) -> io::Result<()> {
+    fn lock(&mut self, duration: Duration, channel_id: ChannelId, now: Instant) {
         *self = LockState::Locked {
             channel_id: channel_id,
-            timeout: Timeout::new(duration, handle)?,
+            expires_at: now + duration,
         };
-        Ok(())
     }
 
     fn release(&mut self) {
         *self = LockState::None;
     }
 
-    fn tick(&mut self) -> Result<(), io::Error> {
-        // Check lock timeout
-        let timed_out = match *self {
-            LockState::Locked {
-                ref mut timeout, ..
-            } => match timeout.poll()? {
-                Async::Ready(()) => true,
-                Async::NotReady => false,
-            },
-            _ => false,
-        };
+    fn holder(&self) -> Option<ChannelId> {
+        match *self {
+            LockState::Locked { channel_id, .. } => Some(channel_id),
+            LockState::None => None,
+        }
+    }
 
-        if timed_out {
+    fn expire(&mut self, now: Instant) {
+        let expired = match *self {
+            LockState::Locked { expires_at, .. } => now >= expires_at,
+            LockState::None => false,
+        };
+        if expired {
             *self = LockState::None;
         }
-
-        // TODO Check frame and transation timeouts
-
-        Ok(())
     }
-}
 
-struct StateTransition<O> {
-    new_state: State,
-    output: O,
+    fn deadline(&self) -> Option<Instant> {
+        match *self {
+            LockState::Locked { expires_at, .. } => Some(expires_at),
+            LockState::None => None,
+        }
+    }
 }
 
-pub struct StateMachine<S> {
+// Messages are received on any number of channels at once, but only one
+// request is dispatched to the service at a time, as the user can only
+// answer one presence prompt. INIT, PING and LOCK are answered straight
+// away, so browsers can open channels and check the device is alive while
+// a request waits on the user. A MSG or WINK received during a dispatch is
+// refused with ChannelBusy for the host to retry, rather than queued
+// behind a request that can take as long as the user does.
+//
+// Does no I/O of its own: packets from the host, timer expiry and service
+// responses are passed in, and what to do in turn is taken from
+// poll_output, so the protocol can be driven by any runtime.
+pub struct StateMachine {
     channels: Channels,
-    handle: Handle,
+    clock: Box<dyn Clock>,
+    dispatch: Option<DispatchState>,
     lock: LockState,
     logger: Logger,
-    service: S,
-    state: State,
+    next_dispatch_id: u64,
+    outputs: VecDeque<Output>,
+    receives: HashMap<ChannelId, ReceiveState>,
+    report_format: ReportFormat,
+    timer: Option<Instant>,
 }
 
-impl<S> StateMachine<S>
-where
-    S: Service<
-        Request = u2f_core::Request,
-        Response = u2f_core::Response,
-        Error = io::Error,
-        Future = Box<dyn Future<Item = u2f_core::Response, Error = io::Error>>,
-    >,
-    ResponseMessage: From<<S as u2f_core::Service>::Response>,
-{
-    pub fn new(service: S, handle: Handle, logger: Logger) -> StateMachine<S> {
+impl StateMachine {
+    pub fn new(clock: Box<dyn Clock>, logger: Logger) -> StateMachine {
         StateMachine {
-            channels: Channels::new(),
-            handle: handle,
+            channels: Channels::new(MAX_CHANNELS),
+            clock: clock,
+            dispatch: None,
             lock: LockState::None,
             logger: logger,
-            service: service,
-            state: State::Idle,
+            next_dispatch_id: 0,
+            outputs: VecDeque::new(),
+            receives: HashMap::new(),
+            report_format: ReportFormat::default(),
+            timer: None,
         }
     }
 
-    pub fn step(&mut self) -> Result<Option<Response>, io::Error> {
-        // Tick the lock for possible timeout
-        self.lock.tick()?;
+    // Sizes packets for reports of this format, and limits the length of
+    // messages received to what fits in them
+    pub fn set_report_format(&mut self, format: ReportFormat) {
+        self.report_format = format;
+    }
+
+    pub fn poll_output(&mut self) -> Option<Output> {
+        self.outputs.pop_front()
+    }
+
+    // Expires timed out transactions and locks. The last timer requested
+    // is spent, another is requested if a deadline remains.
+    pub fn step(&mut self) {
+        let now = self.clock.now();
+        self.lock.expire(now);
+
+        let dispatch_expired = self
+            .dispatch
+            .as_ref()
+            .map_or(false, |dispatch| now >= dispatch.deadline);
+        if dispatch_expired {
+            let dispatch = self.dispatch.take().unwrap();
+            debug!(self.logger, "Dispatch timed out"; "channel_id" => &dispatch.channel_id);
+            self.outputs.push_back(Output::CancelDispatch(dispatch.id));
+            self.respond(Self::error_output(
+                ErrorCode::MessageTimedOut,
+                dispatch.channel_id,
+            ));
+        }
+
+        let expired: Vec<ChannelId> = self
+            .receives
+            .iter()
+            .filter(|&(_, receive)| now >= receive.deadline())
+            .map(|(&channel_id, _)| channel_id)
+            .collect();
+        for channel_id in expired {
+            debug!(self.logger, "Receive timed out"; "channel_id" => &channel_id);
+            self.receives.remove(&channel_id);
+            self.respond(Self::error_output(ErrorCode::MessageTimedOut, channel_id));
+        }
+
+        self.timer = None;
+        self.request_timer();
+    }
 
-        let transition = match self.state.take() {
-            State::Receive(receive) => {
-                // TODO check timeouts
-                // receive.packet_timeout
-                StateTransition {
-                    new_state: State::Receive(receive),
-                    output: None,
+    pub fn accept_packet(&mut self, packet: Packet) {
+        let channel_id = packet.channel_id();
+        let response = match self
+            .check_channel_id(&packet)
+            .or_else(|| self.check_lock(&packet))
+        {
+            Some(response) => Some(response),
+            None => {
+                self.channels.touch(channel_id);
+                match self.receive_packet(packet) {
+                    Some(response) => Some(response),
+                    None => self.try_complete_receive(channel_id),
                 }
             }
-            State::Dispatch(mut dispatch) => {
-                // check if ready
-                match dispatch.future.poll()? {
-                    Async::Ready(result) => {
-                        let channel_id = dispatch.channel_id;
-                        StateTransition {
-                            new_state: State::Idle,
-                            output: Some(Response {
-                                channel_id: channel_id,
-                                message: result,
-                            }),
-                        }
-                    }
-                    Async::NotReady => StateTransition {
-                        new_state: State::Dispatch(dispatch),
-                        output: None,
-                    },
+        };
+        if let Some(response) = response {
+            self.respond(response);
+        }
+        self.request_timer();
+    }
+
+    // Answers the host with the response to a dispatch, unless the
+    // dispatch has since been cancelled
+    pub fn complete_dispatch(&mut self, id: DispatchId, response: DispatchResponse) {
+        if self.dispatch.as_ref().map(|dispatch| dispatch.id) != Some(id) {
+            debug!(self.logger, "Response to cancelled dispatch, ignoring");
+            return;
+        }
+        let dispatch = self.dispatch.take().unwrap();
+        let message = match (dispatch.command, response) {
+            (_, DispatchResponse::U2F(response)) => response.into(),
+            (Command::Vendor { identifier }, DispatchResponse::Vendor(data)) => {
+                ResponseMessage::Vendor {
+                    identifier: identifier,
+                    data: data,
                 }
             }
-            state => StateTransition {
-                new_state: state,
-                output: None,
+            (_, DispatchResponse::Vendor(_)) => {
+                warn!(self.logger, "Vendor response to a U2F request");
+                ResponseMessage::Error {
+                    code: ErrorCode::Other,
+                }
+            }
+            (_, DispatchResponse::Unsupported) => ResponseMessage::Error {
+                code: ErrorCode::InvalidCommand,
             },
+            (Command::Msg, DispatchResponse::Failed(err)) => {
+                warn!(self.logger, "U2F request failed"; "error" => %err);
+                u2f_core::Response::UnknownError.into()
+            }
+            (_, DispatchResponse::Failed(err)) => {
+                warn!(self.logger, "Dispatched request failed"; "error" => %err);
+                ResponseMessage::Error {
+                    code: ErrorCode::Other,
+                }
+            }
         };
-        self.state = transition.new_state;
-        Ok(transition.output)
+        self.respond(Response {
+            channel_id: dispatch.channel_id,
+            message: message,
+        });
+        self.request_timer();
     }
 
-    pub fn accept_packet(&mut self, packet: Packet) -> Result<Option<Response>, io::Error> {
-        debug!(self.logger, "check_channel_id");
-        try_some!(self.check_channel_id(&packet));
-
-        debug!(self.logger, "check_lock");
-        try_some!(self.check_lock(&packet));
-
-        debug!(self.logger, "step_with_packet");
-        try_some!(self.step_with_packet(packet));
-
-        debug!(self.logger, "try_complete_receive");
-        try_some!(self.try_complete_receive());
+    // Forgets the channel's transactions and releases its lock, for a host
+    // that has lost track of them
+    fn reset_channel(&mut self, channel_id: ChannelId) {
+        debug!(self.logger, "Reset channel"; "channel_id" => &channel_id);
+        self.receives.remove(&channel_id);
+        if self.dispatch.as_ref().map(|dispatch| dispatch.channel_id) == Some(channel_id) {
+            let dispatch = self.dispatch.take().unwrap();
+            self.outputs.push_back(Output::CancelDispatch(dispatch.id));
+        }
+        if self.lock.holder() == Some(channel_id) {
+            self.lock.release();
+        }
+    }
 
-        debug!(self.logger, "try_complete_dispatch");
-        try_some!(self.try_complete_dispatch());
+    // Asks for a timer at the earliest deadline, when it has changed
+    fn request_timer(&mut self) {
+        let deadline = self
+            .receives
+            .values()
+            .map(ReceiveState::deadline)
+            .chain(self.dispatch.as_ref().map(|dispatch| dispatch.deadline))
+            .chain(self.lock.deadline())
+            .min();
+        if deadline != self.timer {
+            self.timer = deadline;
+            self.outputs.push_back(Output::Timer(deadline));
+        }
+    }
 
-        Ok(None)
+    fn respond(&mut self, response: Response) {
+        debug!(self.logger, "Send response"; "channel_id" => &response.channel_id, "message" => &response.message);
+        let channel_id = response.channel_id;
+        let packets = match response.into_packets(&self.report_format) {
+            Ok(packets) => packets,
+            Err(err) => {
+                // Tell the host rather than send a truncated message
+                info!(self.logger, "Failed to segment response"; "channel_id" => &channel_id, "error" => err);
+                Self::error_output(ErrorCode::Other, channel_id)
+                    .into_packets(&self.report_format)
+                    .unwrap_or_default()
+            }
+        };
+        self.outputs
+            .extend(packets.into_iter().map(Output::Packet));
     }
 
-    fn check_channel_id(&self, packet: &Packet) -> Result<Option<Response>, io::Error> {
+    fn check_channel_id(&self, packet: &Packet) -> Option<Response> {
         let channel_id = packet.channel_id();
         if !self.channels.is_valid(channel_id) {
             debug!(self.logger, "Invalid channel"; "id" => channel_id);
-            Ok(Some(Self::error_output(
-                ErrorCode::InvalidChannel,
-                channel_id,
-            )))
+            Some(Self::error_output(ErrorCode::InvalidChannel, channel_id))
         } else {
-            Ok(None)
+            None
         }
     }
 
-    fn check_lock(&self, packet: &Packet) -> Result<Option<Response>, io::Error> {
+    fn check_lock(&self, packet: &Packet) -> Option<Response> {
         let packet_channel_id = packet.channel_id();
         match self.lock {
-            LockState::Locked { channel_id, .. } => {
-                if packet_channel_id != channel_id {
-                    Ok(Some(Self::error_output(
-                        ErrorCode::ChannelBusy,
-                        packet_channel_id,
-                    )))
-                } else {
-                    Ok(None)
-                }
-            }
-            LockState::None => Ok(None),
+            LockState::Locked { channel_id, .. } if packet_channel_id != channel_id => Some(
+                Self::error_output(ErrorCode::ChannelBusy, packet_channel_id),
+            ),
+            _ => None,
         }
     }
 
-    fn step_with_packet(&mut self, packet: Packet) -> Result<Option<Response>, io::Error> {
-        let transition = match (self.state.take(), packet) {
-            (
-                State::Idle,
-                Packet::Initialization {
-                    channel_id,
-                    data,
-                    payload_len,
-                    command,
-                },
-            ) => {
+    fn receive_packet(&mut self, packet: Packet) -> Option<Response> {
+        let now = self.clock.now();
+        match packet {
+            Packet::Initialization {
+                channel_id,
+                data,
+                payload_len,
+                command,
+            } => {
+                if let Command::Sync = command {
+                    self.reset_channel(channel_id);
+                }
+                if self.receives.remove(&channel_id).is_some() {
+                    debug!(self.logger, "Invalid message sequencing");
+                    return Some(Self::error_output(
+                        ErrorCode::InvalidMessageSequencing,
+                        channel_id,
+                    ));
+                }
+                if self.dispatch.as_ref().map(|dispatch| dispatch.channel_id) == Some(channel_id) {
+                    debug!(self.logger, "Channel busy with transaction");
+                    return Some(Self::error_output(ErrorCode::ChannelBusy, channel_id));
+                }
+                if payload_len > self.report_format.max_payload_len() {
+                    debug!(self.logger, "Payload too long"; "channel_id" => &channel_id, "payload_len" => payload_len);
+                    return Some(Self::error_output(
+                        ErrorCode::InvalidMessageLength,
+                        channel_id,
+                    ));
+                }
                 debug!(self.logger, "Begin transaction"; "channel_id" => &channel_id, "command" => &command, "payload_len" => payload_len);
-                StateTransition {
-                    new_state: State::Receive(ReceiveState {
-                        buffer: data.to_vec(),
-                        channel_id: channel_id,
+                self.receives.insert(
+                    channel_id,
+                    ReceiveState {
+                        buffer: data,
                         command: command,
                         next_sequence_number: 0,
                         payload_len: payload_len,
-                        packet_timeout: Timeout::new(packet_timeout_duration(), &self.handle)?,
-                        transaction_timeout: Timeout::new(
-                            transaction_timeout_duration(),
-                            &self.handle,
-                        )?,
-                    }),
-                    output: None,
-                }
-            }
-            (state @ State::Idle, Packet::Continuation { .. }) => {
-                debug!(self.logger, "Out of order continuation packet, ignoring");
-                StateTransition {
-                    new_state: state,
-                    output: None,
-                }
-            }
-            (State::Receive(receive), Packet::Initialization { channel_id, .. }) => {
-                if channel_id == receive.channel_id {
-                    debug!(self.logger, "Invalid message sequencing");
-                    StateTransition {
-                        new_state: State::Idle,
-                        output: Some(Response {
-                            channel_id: channel_id,
-                            message: ResponseMessage::Error {
-                                code: ErrorCode::InvalidMessageSequencing,
-                            },
-                        }),
-                    }
-                } else {
-                    debug!(self.logger, "Other channel busy with transaction");
-                    StateTransition {
-                        new_state: State::Receive(receive),
-                        output: Some(Response {
-                            channel_id: channel_id,
-                            message: ResponseMessage::Error {
-                                code: ErrorCode::ChannelBusy,
-                            },
-                        }),
-                    }
-                }
+                        packet_deadline: now + packet_timeout_duration(),
+                        transaction_deadline: now + transaction_timeout_duration(),
+                    },
+                );
+                None
             }
-            (
-                State::Receive(mut receive),
-                Packet::Continuation {
-                    channel_id,
-                    sequence_number,
-                    data,
-                },
-            ) => {
-                if channel_id != receive.channel_id {
-                    StateTransition {
-                        new_state: State::Receive(receive),
-                        output: Some(Response {
-                            channel_id: channel_id,
-                            message: ResponseMessage::Error {
-                                code: ErrorCode::ChannelBusy,
-                            },
-                        }),
+            Packet::Continuation {
+                channel_id,
+                sequence_number,
+                data,
+            } => {
+                let in_sequence = match self.receives.get_mut(&channel_id) {
+                    Some(receive) => {
+                        if sequence_number == receive.next_sequence_number {
+                            receive.next_sequence_number += 1;
+                            receive.buffer.extend_from_slice(&data);
+                            receive.packet_deadline = now + packet_timeout_duration();
+                            true
+                        } else {
+                            false
+                        }
                     }
-                } else if sequence_number != receive.next_sequence_number {
-                    StateTransition {
-                        new_state: State::Idle,
-                        output: Some(Response {
-                            channel_id: channel_id,
-                            message: ResponseMessage::Error {
-                                code: ErrorCode::InvalidMessageSequencing,
-                            },
-                        }),
+                    None => {
+                        debug!(self.logger, "Out of order continuation packet, ignoring");
+                        return None;
                     }
+                };
+                if in_sequence {
+                    None
                 } else {
-                    receive.next_sequence_number += 1;
-                    receive.buffer.extend_from_slice(&data);
-                    receive.packet_timeout = Timeout::new(packet_timeout_duration(), &self.handle)?;
-                    StateTransition {
-                        new_state: State::Receive(receive),
-                        output: None,
-                    }
+                    self.receives.remove(&channel_id);
+                    Some(Self::error_output(
+                        ErrorCode::InvalidMessageSequencing,
+                        channel_id,
+                    ))
                 }
             }
-            (state @ State::Dispatch(_), packet) => StateTransition {
-                new_state: state,
-                output: Some(Self::error_output(
-                    ErrorCode::ChannelBusy,
-                    packet.channel_id(),
-                )),
-            },
-            (State::Unknown, _) => panic!(),
-        };
+        }
+    }
 
-        self.state = transition.new_state;
-        Ok(transition.output)
-    }
-
-    fn try_complete_receive(&mut self) -> Result<Option<Response>, io::Error> {
-        let transition = match self.state.take() {
-            State::Receive(receive) => {
-                if receive.buffer.len() >= receive.payload_len {
-                    let bytes = &receive.buffer[0..receive.payload_len];
-                    debug!(self.logger, "Received payload"; "len" => receive.payload_len);
-                    match RequestMessage::decode(&receive.command, bytes) {
-                        Err(RequestMessageDecodeError::UnsupportedCommand(Command::Unknown { .. })) => {
-                            info!(self.logger, "Unknown command. Responding with InvalidCommand error to encourage fallback to U2F protocol");
-                            StateTransition {
-                                new_state: State::Idle,
-                                output: Some(Response {
-                                    channel_id: receive.channel_id,
-                                    message: ResponseMessage::Error {
-                                        code: ErrorCode::InvalidCommand,
-                                    },
-                                }),
-                            }
-                        },
-                        Err(error) => {
-                            debug!(self.logger, "Unable to decode request message"; "error" => error);
-                            StateTransition {
-                                new_state: State::Idle,
-                                output: Some(Response {
-                                    channel_id: receive.channel_id,
-                                    message: ResponseMessage::Error {
-                                        code: ErrorCode::Other,
-                                    },
-                                }),
-                            }
-                        },
-                        Ok(message) => {
-                            let response_future = self.handle_request(Request {
-                                channel_id: receive.channel_id,
-                                message: message,
-                            })?;
-                            let dispatch_state = DispatchState {
-                                channel_id: receive.channel_id,
-                                future: response_future,
-                                timeout: receive.transaction_timeout,
-                            };
-                            StateTransition {
-                                new_state: State::Dispatch(dispatch_state),
-                                output: None,
-                            }
-                        }
-                    }
-                } else {
+    fn try_complete_receive(&mut self, channel_id: ChannelId) -> Option<Response> {
+        let complete = match self.receives.get(&channel_id) {
+            Some(receive) => {
+                if receive.buffer.len() < receive.payload_len {
                     debug!(self.logger, "Payload incomplete"; "payload_len" => receive.payload_len, "receive_len" => receive.buffer.len());
-                    StateTransition {
-                        new_state: State::Receive(receive),
-                        output: None,
-                    }
                 }
+                receive.buffer.len() >= receive.payload_len
             }
-            state => StateTransition {
-                new_state: state,
-                output: None,
-            },
+            None => false,
         };
+        if !complete {
+            return None;
+        }
 
-        self.state = transition.new_state;
-        Ok(transition.output)
-    }
-
-    fn try_complete_dispatch(&mut self) -> Result<Option<Response>, io::Error> {
-        let transition = match self.state.take() {
-            State::Dispatch(mut dispatch) => match dispatch.future.poll()? {
-                Async::Ready(response) => StateTransition {
-                    new_state: State::Idle,
-                    output: Some(Response {
-                        channel_id: dispatch.channel_id,
-                        message: response,
-                    }),
-                },
-                Async::NotReady => StateTransition {
-                    new_state: State::Dispatch(dispatch),
-                    output: None,
-                },
+        let receive = self.receives.remove(&channel_id).unwrap();
+        let bytes = &receive.buffer[0..receive.payload_len];
+        debug!(self.logger, "Received payload"; "len" => receive.payload_len);
+        match RequestMessage::decode(&receive.command, bytes) {
+            Err(RequestMessageDecodeError::UnsupportedCommand(Command::Unknown { .. })) => {
+                info!(self.logger, "Unknown command. Responding with InvalidCommand error to encourage fallback to U2F protocol");
+                Some(Self::error_output(ErrorCode::InvalidCommand, channel_id))
             },
-            state => StateTransition {
-                new_state: state,
-                output: None,
+            Err(error) => {
+                debug!(self.logger, "Unable to decode request message"; "error" => &error);
+                let code = match (receive.command, error) {
+                    (Command::Sync, _) => ErrorCode::SyncCommandFailed,
+                    (_, RequestMessageDecodeError::PayloadLength(..)) => ErrorCode::InvalidMessageLength,
+                    (_, RequestMessageDecodeError::InvalidParameter) => ErrorCode::InvalidParameter,
+                    (_, RequestMessageDecodeError::UnsupportedCommand(_)) => ErrorCode::Other,
+                };
+                Some(Self::error_output(code, channel_id))
             },
-        };
-
-        self.state = transition.new_state;
-        Ok(transition.output)
+            Ok(message) => self.handle_request(Request {
+                channel_id: channel_id,
+                message: message,
+            }),
+        }
     }
 
     fn error_output(error_code: ErrorCode, channel_id: ChannelId) -> Response {
@@ -462,26 +531,50 @@ where
         }
     }
 
-    fn handle_request(
-        &mut self,
-        request: Request,
-    ) -> Result<Box<dyn Future<Item = ResponseMessage, Error = io::Error>>, io::Error> {
+    fn handle_request(&mut self, request: Request) -> Option<Response> {
         let channel_id = request.channel_id;
-        match request.message {
+        let message = match request.message {
             RequestMessage::EncapsulatedRequest { data } => {
-                // TODO no unwrap
                 debug!(self.logger, "RequestMessage::EncapsulatedRequest"; "data.len" => data.len());
-                let request = u2f_core::Request::decode(&data).unwrap();
-                Ok(self.dispatch(request))
+                match u2f_core::Request::decode(&data) {
+                    Ok(request) => {
+                        return self.dispatch(channel_id, Command::Msg, DispatchRequest::U2F(request));
+                    }
+                    // Answered with the status word for the error, as the
+                    // host expects of an APDU it got wrong
+                    Err(err) => {
+                        info!(self.logger, "Unable to decode APDU"; "error" => %err);
+                        u2f_core::Response::from(err).into()
+                    }
+                }
             }
             RequestMessage::Init { nonce } => {
                 // TODO Check what channnel message came in on
-                // TODO check unwrap
-                let new_channel_id = self.channels
-                    .allocate()
-                    .expect("Failed to allocate new channel");
+                let receives = &self.receives;
+                let dispatch_channel_id = self.dispatch.as_ref().map(|dispatch| dispatch.channel_id);
+                let lock_holder = self.lock.holder();
+                let allocation = self.channels.allocate(|channel_id| {
+                    receives.contains_key(&channel_id)
+                        || dispatch_channel_id == Some(channel_id)
+                        || lock_holder == Some(channel_id)
+                });
+                let (new_channel_id, closed_channel_id) = match allocation {
+                    Ok(allocation) => allocation,
+                    Err(err) => {
+                        warn!(self.logger, "Unable to allocate channel"; "error" => %err);
+                        let code = match err {
+                            AllocateError::Exhausted => ErrorCode::ChannelBusy,
+                            AllocateError::Random(_) => ErrorCode::Other,
+                        };
+                        return Some(Self::error_output(code, channel_id));
+                    }
+                };
+                if let Some(closed_channel_id) = closed_channel_id {
+                    debug!(self.logger, "Closed least recently used channel"; "channel_id" => closed_channel_id);
+                    self.reset_channel(closed_channel_id);
+                }
                 debug!(self.logger, "RequestMessage::Init"; "new_channel_id" => new_channel_id);
-                Ok(Box::new(future::ok(ResponseMessage::Init {
+                ResponseMessage::Init {
                     nonce,
                     new_channel_id: new_channel_id,
                     u2fhid_protocol_version: U2FHID_PROTOCOL_VERSION,
@@ -489,47 +582,95 @@ where
                     minor_device_version_number: MINOR_DEVICE_VERSION_NUMBER,
                     build_device_version_number: BUILD_DEVICE_VERSION_NUMBER,
                     capabilities: CapabilityFlags::CAPFLAG_WINK,
-                })))
+                }
             }
             RequestMessage::Ping { data } => {
                 debug!(self.logger, "RequestMessage::Ping"; "data.len" => data.len());
-                Ok(Box::new(future::ok(ResponseMessage::Pong { data: data })))
+                ResponseMessage::Pong { data: data }
+            }
+            RequestMessage::Wink => {
+                let request = DispatchRequest::U2F(u2f_core::Request::Wink);
+                return self.dispatch(channel_id, Command::Wink, request);
             }
-            RequestMessage::Wink => Ok(self.dispatch(u2f_core::Request::Wink)),
             RequestMessage::Lock { lock_time } => {
                 debug!(self.logger, "RequestMessage::Lock"; "lock_time" => lock_time.as_secs());
+                // Other channels are refused while the lock is held, so
+                // this channel either holds the lock or no channel does
                 if lock_time == Duration::from_secs(0) {
-                    // TODO Enforce correct channel
+                    if self.lock.holder() != Some(channel_id) {
+                        return Some(Self::error_output(
+                            ErrorCode::CommandRequiresChannelLock,
+                            channel_id,
+                        ));
+                    }
                     self.lock.release();
                 } else {
-                    // TODO enforce range of 1-10
-                    // TODO check channel_id matches current lock state
-                    self.lock.lock(lock_time, channel_id, &self.handle)?;
+                    // Locking again extends the lock
+                    let now = self.clock.now();
+                    self.lock.lock(lock_time, channel_id, now);
                 }
-                Ok(Box::new(future::ok(ResponseMessage::Lock)))
+                ResponseMessage::Lock
             }
-        }
+            RequestMessage::Sync { nonce } => {
+                debug!(self.logger, "RequestMessage::Sync"; "nonce" => nonce);
+                ResponseMessage::Sync { nonce: nonce }
+            }
+            RequestMessage::Vendor { identifier, data } => {
+                debug!(self.logger, "RequestMessage::Vendor"; "identifier" => identifier, "data.len" => data.len());
+                let request = DispatchRequest::Vendor {
+                    identifier: identifier,
+                    data: data,
+                };
+                return self.dispatch(channel_id, Command::Vendor { identifier }, request);
+            }
+        };
+        Some(Response {
+            channel_id: channel_id,
+            message: message,
+        })
     }
 
+    // Hands a request to the driver, unless one is already waiting on the
+    // user
     fn dispatch(
         &mut self,
-        request: u2f_core::Request,
-    ) -> Box<dyn Future<Item = ResponseMessage, Error = io::Error>> {
-        Box::new(self.service.call(request).map(|response| response.into()))
+        channel_id: ChannelId,
+        command: Command,
+        request: DispatchRequest,
+    ) -> Option<Response> {
+        if self.dispatch.is_some() {
+            debug!(self.logger, "Other channel busy with transaction"; "channel_id" => &channel_id);
+            return Some(Self::error_output(ErrorCode::ChannelBusy, channel_id));
+        }
+        let id = DispatchId(self.next_dispatch_id);
+        self.next_dispatch_id += 1;
+        self.dispatch = Some(DispatchState {
+            id: id,
+            channel_id: channel_id,
+            command: command,
+            deadline: self.clock.now() + dispatch_timeout_duration(),
+        });
+        self.outputs.push_back(Output::Dispatch(Dispatch {
+            id: id,
+            request: request,
+        }));
+        None
     }
 }
 
 #[cfg(test)]
 mod tests {
-    extern crate rand;
+    use std::cell::Cell;
+    use std::rc::Rc;
 
+    use byteorder::{BigEndian, ByteOrder};
+    use futures::{future, Async, Future};
     use slog::{self, Drain};
     use slog_stdlog;
-    use tokio_core::reactor::Core;
+    use u2f_core::{Service, VendorCommand, VendorHandler};
 
     use super::*;
-
-    use self::rand::{OsRng, Rng};
+    use dispatcher::Dispatcher;
 
     struct FakeU2FService;
 
@@ -544,28 +685,183 @@ mod tests {
         }
     }
 
-    #[test]
-    fn channels_broadcast_channel_is_valid() {
-        let channels = Channels::new();
-        assert!(channels.is_valid(BROADCAST_CHANNEL_ID));
+    // Never responds, like a user ignoring a presence prompt
+    struct PendingService;
+
+    impl Service for PendingService {
+        type Request = u2f_core::Request;
+        type Response = u2f_core::Response;
+        type Error = io::Error;
+        type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+        fn call(&self, _req: Self::Request) -> Self::Future {
+            Box::new(future::empty())
+        }
     }
 
-    #[test]
-    fn channels_allocated_channel_is_valid() {
-        let mut channels = Channels::new();
-        let channel_id = channels.allocate().unwrap();
-        assert!(channels.is_valid(channel_id));
+    #[derive(Clone)]
+    struct FakeClock(Rc<Cell<Instant>>);
+
+    impl FakeClock {
+        fn new() -> FakeClock {
+            FakeClock(Rc::new(Cell::new(Instant::now())))
+        }
+
+        fn advance(&self, duration: Duration) {
+            self.0.set(self.0.get() + duration);
+        }
     }
 
-    #[test]
-    fn init() {
-        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
-        let core = Core::new().unwrap();
-        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger);
-        init_channel(&mut state_machine);
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    // A response reassembled from the packets sent
+    #[derive(Debug)]
+    struct Reply {
+        channel_id: ChannelId,
+        command: Command,
+        data: Vec<u8>,
+    }
+
+    // Drives the state machine synchronously, on a fake clock. Dispatches
+    // are polled once, so a service that never responds leaves its
+    // dispatch running.
+    struct SyncDriver<S> {
+        clock: FakeClock,
+        dispatch: Option<(DispatchId, Box<dyn Future<Item = DispatchResponse, Error = io::Error>>)>,
+        dispatcher: Dispatcher<S>,
+        packets: VecDeque<Packet>,
+        state_machine: StateMachine,
+        timer: Option<Instant>,
+    }
+
+    impl<S> SyncDriver<S>
+    where
+        S: Service<
+            Request = u2f_core::Request,
+            Response = u2f_core::Response,
+            Error = io::Error,
+            Future = Box<dyn Future<Item = u2f_core::Response, Error = io::Error>>,
+        >,
+    {
+        fn new(service: S) -> SyncDriver<S> {
+            let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+            let clock = FakeClock::new();
+            SyncDriver {
+                clock: clock.clone(),
+                dispatch: None,
+                dispatcher: Dispatcher::new(service),
+                packets: VecDeque::new(),
+                state_machine: StateMachine::new(Box::new(clock), logger),
+                timer: None,
+            }
+        }
+
+        fn accept_packet(&mut self, packet: Packet) -> Option<Reply> {
+            self.state_machine.accept_packet(packet);
+            self.run();
+            self.reply()
+        }
+
+        // Moves the clock on, stepping if the requested timer has passed
+        fn advance(&mut self, duration: Duration) -> Option<Reply> {
+            self.clock.advance(duration);
+            if self.timer.map_or(false, |at| self.clock.now() >= at) {
+                self.timer = None;
+                self.state_machine.step();
+                self.run();
+            }
+            self.reply()
+        }
+
+        fn run(&mut self) {
+            loop {
+                while let Some(output) = self.state_machine.poll_output() {
+                    match output {
+                        Output::Packet(packet) => self.packets.push_back(packet),
+                        Output::Dispatch(dispatch) => {
+                            self.dispatch =
+                                Some((dispatch.id, self.dispatcher.call(dispatch.request)));
+                        }
+                        Output::CancelDispatch(id) => {
+                            if self.dispatch.as_ref().map(|&(dispatch_id, _)| dispatch_id) == Some(id) {
+                                self.dispatch = None;
+                            }
+                        }
+                        Output::Timer(at) => self.timer = at,
+                    }
+                }
+                let completed = match self.dispatch {
+                    Some((id, ref mut future)) => match future.poll().unwrap() {
+                        Async::Ready(response) => Some((id, response)),
+                        Async::NotReady => None,
+                    },
+                    None => None,
+                };
+                match completed {
+                    Some((id, response)) => {
+                        self.dispatch = None;
+                        self.state_machine.complete_dispatch(id, response);
+                    }
+                    None => return,
+                }
+            }
+        }
+
+        fn reply(&mut self) -> Option<Reply> {
+            let (channel_id, command, mut data, payload_len) = match self.packets.pop_front()? {
+                Packet::Initialization {
+                    channel_id,
+                    command,
+                    data,
+                    payload_len,
+                } => (channel_id, command, data, payload_len),
+                Packet::Continuation { .. } => panic!("expected an initialization packet"),
+            };
+            while data.len() < payload_len {
+                match self.packets.pop_front() {
+                    Some(Packet::Continuation {
+                        data: continuation, ..
+                    }) => data.extend_from_slice(&continuation),
+                    _ => panic!("expected a continuation packet"),
+                }
+            }
+            data.truncate(payload_len);
+            Some(Reply {
+                channel_id,
+                command,
+                data,
+            })
+        }
+    }
+
+    fn send_command<S>(
+        driver: &mut SyncDriver<S>,
+        channel_id: ChannelId,
+        command: Command,
+        data: Vec<u8>,
+    ) -> Option<Reply>
+    where
+        S: Service<
+            Request = u2f_core::Request,
+            Response = u2f_core::Response,
+            Error = io::Error,
+            Future = Box<dyn Future<Item = u2f_core::Response, Error = io::Error>>,
+        >,
+    {
+        let payload_len = data.len();
+        driver.accept_packet(Packet::Initialization {
+            channel_id: channel_id,
+            command: command,
+            data: data,
+            payload_len: payload_len,
+        })
     }
 
-    fn init_channel<S>(state_machine: &mut StateMachine<S>) -> ChannelId
+    fn init_channel<S>(driver: &mut SyncDriver<S>) -> ChannelId
     where
         S: Service<
             Request = u2f_core::Request,
@@ -576,70 +872,655 @@ mod tests {
     {
         let mut os_rng = OsRng::new().unwrap();
         let request_nonce: [u8; 8] = os_rng.gen();
-        let data = request_nonce.to_vec();
-        let data_len = data.len();
 
-        let res = state_machine
-            .accept_packet(Packet::Initialization {
-                channel_id: BROADCAST_CHANNEL_ID,
+        match send_command(driver, BROADCAST_CHANNEL_ID, Command::Init, request_nonce.to_vec()) {
+            Some(Reply {
+                channel_id,
                 command: Command::Init,
-                data: data,
-                payload_len: data_len,
-            })
-            .unwrap();
-
-        match res {
-            Some(Response {
-                channel_id: response_channel_id,
-                message:
-                    ResponseMessage::Init {
-                        new_channel_id,
-                        nonce,
-                        ..
-                    },
+                data,
             }) => {
-                assert_eq!(response_channel_id, BROADCAST_CHANNEL_ID);
-                assert_eq!(request_nonce, nonce);
-                assert!(state_machine.channels.is_valid(new_channel_id));
+                assert_eq!(channel_id, BROADCAST_CHANNEL_ID);
+                assert_eq!(data.len(), 17);
+                assert_eq!(data[..8], request_nonce);
+                let new_channel_id = ChannelId(BigEndian::read_u32(&data[8..12]));
+                assert!(driver.state_machine.channels.is_valid(new_channel_id));
                 new_channel_id
             }
-            _ => panic!(),
+            reply => panic!("expected init response, got {:?}", reply),
+        }
+    }
+
+    fn assert_error(reply: Option<Reply>, expected_channel_id: ChannelId, expected_code: ErrorCode) {
+        let expected = format!("{:?}", expected_code);
+        let expected_byte = expected_code.into_byte();
+        match reply {
+            Some(Reply {
+                channel_id,
+                command: Command::Error,
+                ref data,
+            }) if channel_id == expected_channel_id && data[..] == [expected_byte] => {}
+            _ => panic!("expected {}, got {:?}", expected, reply),
+        }
+    }
+
+    fn assert_timed_out(reply: Option<Reply>, expected_channel_id: ChannelId) {
+        assert_error(reply, expected_channel_id, ErrorCode::MessageTimedOut);
+    }
+
+    fn assert_pong(reply: Option<Reply>, expected_channel_id: ChannelId) {
+        match reply {
+            Some(Reply {
+                channel_id,
+                command: Command::Ping,
+                ..
+            }) => assert_eq!(channel_id, expected_channel_id),
+            _ => panic!("expected pong, got {:?}", reply),
         }
     }
 
+    #[test]
+    fn channels_broadcast_channel_is_valid() {
+        let channels = Channels::new(MAX_CHANNELS);
+        assert!(channels.is_valid(BROADCAST_CHANNEL_ID));
+    }
+
+    #[test]
+    fn channels_allocated_channel_is_valid() {
+        let mut channels = Channels::new(MAX_CHANNELS);
+        let (channel_id, _) = channels.allocate(|_| false).unwrap();
+        assert!(channels.is_valid(channel_id));
+        assert!(!channels.is_valid(ChannelId(channel_id.0.wrapping_add(1))));
+    }
+
+    #[test]
+    fn channels_closes_least_recently_used() {
+        let mut channels = Channels::new(2);
+        let (first, _) = channels.allocate(|_| false).unwrap();
+        let (second, _) = channels.allocate(|_| false).unwrap();
+        channels.touch(first);
+
+        let (third, closed) = channels.allocate(|_| false).unwrap();
+        assert_eq!(closed, Some(second));
+        assert!(channels.is_valid(first));
+        assert!(!channels.is_valid(second));
+        assert!(channels.is_valid(third));
+    }
+
+    #[test]
+    fn channels_keeps_busy_channels_open() {
+        let mut channels = Channels::new(2);
+        let (first, _) = channels.allocate(|_| false).unwrap();
+        let (second, _) = channels.allocate(|_| false).unwrap();
+
+        let (_, closed) = channels.allocate(|channel_id| channel_id == first).unwrap();
+        assert_eq!(closed, Some(second));
+        match channels.allocate(|_| true) {
+            Err(AllocateError::Exhausted) => {}
+            res => panic!("expected exhausted, got {:?}", res),
+        }
+        assert!(channels.is_valid(first));
+    }
+
+    #[test]
+    fn init() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        init_channel(&mut driver);
+    }
+
     #[test]
     fn ping() {
-        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
-        let core = Core::new().unwrap();
+        let mut driver = SyncDriver::new(FakeU2FService);
         let mut os_rng = OsRng::new().unwrap();
-        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger);
         let ping_data: [u8; 8] = os_rng.gen();
-        let packet_data = ping_data.to_vec();
-        let packet_data_len = packet_data.len();
 
-        let channel_id = init_channel(&mut state_machine);
+        let channel_id = init_channel(&mut driver);
 
-        let res = state_machine
-            .accept_packet(Packet::Initialization {
+        match send_command(&mut driver, channel_id, Command::Ping, ping_data.to_vec()) {
+            Some(Reply {
+                channel_id: response_channel_id,
+                command: Command::Ping,
+                data,
+            }) => {
+                assert_eq!(response_channel_id, channel_id);
+                assert_eq!(data[..], ping_data);
+            }
+            reply => panic!("expected pong, got {:?}", reply),
+        };
+    }
+
+    #[test]
+    fn long_response_is_segmented() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let channel_id = init_channel(&mut driver);
+        let ping_data: Vec<u8> = (0..200u8).collect();
+
+        driver.accept_packet(Packet::Initialization {
+            channel_id: channel_id,
+            command: Command::Ping,
+            data: ping_data[..57].to_vec(),
+            payload_len: ping_data.len(),
+        });
+        for (sequence_number, chunk) in ping_data[57..].chunks(59).enumerate() {
+            driver.state_machine.accept_packet(Packet::Continuation {
+                channel_id: channel_id,
+                sequence_number: sequence_number as u8,
+                data: chunk.to_vec(),
+            });
+        }
+        driver.run();
+
+        assert_eq!(driver.packets.len(), 4);
+        match driver.reply() {
+            Some(Reply { data, .. }) => assert_eq!(data, ping_data),
+            reply => panic!("expected pong, got {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn timer_requested_for_receive_and_cancelled_once_complete() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let channel_id = init_channel(&mut driver);
+        assert_eq!(driver.timer, None);
+
+        let res = driver.accept_packet(Packet::Initialization {
+            channel_id: channel_id,
+            command: Command::Ping,
+            data: vec![0u8; 57],
+            payload_len: 100,
+        });
+        assert!(res.is_none());
+        assert_eq!(driver.timer, Some(driver.clock.now() + packet_timeout_duration()));
+
+        assert_pong(
+            driver.accept_packet(Packet::Continuation {
+                channel_id: channel_id,
+                sequence_number: 0,
+                data: vec![0u8; 59],
+            }),
+            channel_id,
+        );
+        assert_eq!(driver.timer, None);
+    }
+
+    #[test]
+    fn receive_times_out_waiting_for_next_packet() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let channel_id = init_channel(&mut driver);
+
+        let res = driver.accept_packet(Packet::Initialization {
+            channel_id: channel_id,
+            command: Command::Ping,
+            data: vec![0u8; 57],
+            payload_len: 100,
+        });
+        assert!(res.is_none());
+
+        assert!(driver.advance(Duration::from_millis(400)).is_none());
+        assert_timed_out(driver.advance(Duration::from_millis(100)), channel_id);
+
+        // Back to idle, so another transaction can begin
+        init_channel(&mut driver);
+    }
+
+    #[test]
+    fn receive_times_out_after_transaction_timeout() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let channel_id = init_channel(&mut driver);
+
+        driver.accept_packet(Packet::Initialization {
+            channel_id: channel_id,
+            command: Command::Ping,
+            data: vec![0u8; 57],
+            payload_len: 1000,
+        });
+
+        // Each packet arrives in time, but the message as a whole is too slow
+        for sequence_number in 0..7 {
+            assert!(driver.advance(Duration::from_millis(400)).is_none());
+            let res = driver.accept_packet(Packet::Continuation {
                 channel_id: channel_id,
+                sequence_number: sequence_number,
+                data: vec![0u8; 59],
+            });
+            assert!(res.is_none());
+        }
+        assert_timed_out(driver.advance(Duration::from_millis(200)), channel_id);
+    }
+
+    #[test]
+    fn dispatch_times_out_without_response() {
+        let mut driver = SyncDriver::new(PendingService);
+        let channel_id = init_channel(&mut driver);
+
+        assert!(send_command(&mut driver, channel_id, Command::Wink, Vec::new()).is_none());
+        assert!(driver.dispatch.is_some());
+
+        assert!(
+            driver
+                .advance(dispatch_timeout_duration() - Duration::from_millis(1))
+                .is_none()
+        );
+        assert_timed_out(driver.advance(Duration::from_millis(1)), channel_id);
+        // The call is dropped along with the dispatch
+        assert!(driver.dispatch.is_none());
+    }
+
+    #[test]
+    fn dispatch_outlasts_slow_approval() {
+        let mut driver = SyncDriver::new(PendingService);
+        let channel_id = init_channel(&mut driver);
+        send_command(&mut driver, channel_id, Command::Wink, Vec::new());
+        let id = driver.dispatch.as_ref().map(|&(id, _)| id).unwrap();
+
+        // An approver accepting at the last moment the client waits for
+        assert!(driver.advance(u2f_core::MAX_APPROVAL_WAIT).is_none());
+        driver
+            .state_machine
+            .complete_dispatch(id, DispatchResponse::U2F(u2f_core::Response::DidWink));
+        driver.run();
+        let reply = driver.reply().unwrap();
+        assert_eq!(reply.channel_id, channel_id);
+        // The success status word, rather than a timeout
+        assert_eq!(reply.data, vec![0x90, 0x00]);
+    }
+
+    #[test]
+    fn response_to_cancelled_dispatch_is_ignored() {
+        let mut driver = SyncDriver::new(PendingService);
+        let channel_id = init_channel(&mut driver);
+        send_command(&mut driver, channel_id, Command::Wink, Vec::new());
+        let id = driver.dispatch.as_ref().map(|&(id, _)| id).unwrap();
+
+        send_command(&mut driver, channel_id, Command::Sync, vec![1]);
+        assert!(driver.dispatch.is_none());
+        driver.reply();
+
+        driver
+            .state_machine
+            .complete_dispatch(id, DispatchResponse::Unsupported);
+        driver.run();
+        assert!(driver.reply().is_none());
+    }
+
+    #[test]
+    fn init_and_ping_served_during_dispatch() {
+        let mut driver = SyncDriver::new(PendingService);
+        let waiting_channel_id = init_channel(&mut driver);
+        assert!(send_command(&mut driver, waiting_channel_id, Command::Wink, Vec::new()).is_none());
+
+        let channel_id = init_channel(&mut driver);
+        match send_command(&mut driver, channel_id, Command::Ping, vec![1, 2, 3]) {
+            Some(Reply {
+                channel_id: response_channel_id,
                 command: Command::Ping,
-                data: packet_data,
-                payload_len: packet_data_len,
-            })
-            .unwrap();
+                data,
+            }) => {
+                assert_eq!(response_channel_id, channel_id);
+                assert_eq!(data, vec![1, 2, 3]);
+            }
+            reply => panic!("expected pong, got {:?}", reply),
+        }
+    }
 
+    #[test]
+    fn second_request_is_busy_during_dispatch() {
+        let mut driver = SyncDriver::new(PendingService);
+        let waiting_channel_id = init_channel(&mut driver);
+        let channel_id = init_channel(&mut driver);
+        assert!(send_command(&mut driver, waiting_channel_id, Command::Wink, Vec::new()).is_none());
+
+        assert_error(
+            send_command(&mut driver, channel_id, Command::Wink, Vec::new()),
+            channel_id,
+            ErrorCode::ChannelBusy,
+        );
+    }
+
+    #[test]
+    fn messages_interleave_across_channels() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let first_channel_id = init_channel(&mut driver);
+        let second_channel_id = init_channel(&mut driver);
+        let ping_data: Vec<u8> = (0..100).collect();
+
+        let res = driver.accept_packet(Packet::Initialization {
+            channel_id: first_channel_id,
+            command: Command::Ping,
+            data: ping_data[..57].to_vec(),
+            payload_len: ping_data.len(),
+        });
+        assert!(res.is_none());
+
+        assert_pong(
+            send_command(&mut driver, second_channel_id, Command::Ping, vec![7]),
+            second_channel_id,
+        );
+
+        let res = driver.accept_packet(Packet::Continuation {
+            channel_id: first_channel_id,
+            sequence_number: 0,
+            data: ping_data[57..].to_vec(),
+        });
         match res {
-            Some(Response {
+            Some(Reply {
+                channel_id,
+                command: Command::Ping,
+                data,
+            }) => {
+                assert_eq!(channel_id, first_channel_id);
+                assert_eq!(data, ping_data);
+            }
+            reply => panic!("expected pong, got {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn conformance_invalid_channel() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let channel_id = ChannelId(0x1234);
+
+        assert_error(
+            send_command(&mut driver, channel_id, Command::Ping, vec![1]),
+            channel_id,
+            ErrorCode::InvalidChannel,
+        );
+    }
+
+    #[test]
+    fn conformance_invalid_command() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let channel_id = init_channel(&mut driver);
+        let command = Command::Unknown { identifier: 0x90 };
+
+        assert_error(
+            send_command(&mut driver, channel_id, command, Vec::new()),
+            channel_id,
+            ErrorCode::InvalidCommand,
+        );
+    }
+
+    #[test]
+    fn conformance_invalid_parameter() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let channel_id = init_channel(&mut driver);
+
+        assert_error(
+            send_command(&mut driver, channel_id, Command::Lock, vec![11]),
+            channel_id,
+            ErrorCode::InvalidParameter,
+        );
+    }
+
+    #[test]
+    fn conformance_invalid_message_length() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+
+        assert_error(
+            send_command(&mut driver, BROADCAST_CHANNEL_ID, Command::Init, vec![0u8; 7]),
+            BROADCAST_CHANNEL_ID,
+            ErrorCode::InvalidMessageLength,
+        );
+    }
+
+    #[test]
+    fn oversized_message_rejected_before_receive() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let channel_id = init_channel(&mut driver);
+
+        assert_error(
+            driver.accept_packet(Packet::Initialization {
+                channel_id: channel_id,
+                command: Command::Msg,
+                data: vec![0u8; 57],
+                payload_len: ReportFormat::default().max_payload_len() + 1,
+            }),
+            channel_id,
+            ErrorCode::InvalidMessageLength,
+        );
+        assert!(driver.state_machine.receives.is_empty());
+    }
+
+    #[test]
+    fn conformance_invalid_message_sequencing() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let channel_id = init_channel(&mut driver);
+
+        driver.accept_packet(Packet::Initialization {
+            channel_id: channel_id,
+            command: Command::Ping,
+            data: vec![0u8; 57],
+            payload_len: 200,
+        });
+        let res = driver.accept_packet(Packet::Continuation {
+            channel_id: channel_id,
+            sequence_number: 1,
+            data: vec![0u8; 59],
+        });
+        assert_error(res, channel_id, ErrorCode::InvalidMessageSequencing);
+    }
+
+    #[test]
+    fn conformance_channel_busy_while_locked() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let locked_channel_id = init_channel(&mut driver);
+        let channel_id = init_channel(&mut driver);
+
+        send_command(&mut driver, locked_channel_id, Command::Lock, vec![5]);
+        assert_error(
+            send_command(&mut driver, channel_id, Command::Ping, vec![1]),
+            channel_id,
+            ErrorCode::ChannelBusy,
+        );
+        assert_pong(
+            send_command(&mut driver, locked_channel_id, Command::Ping, vec![1]),
+            locked_channel_id,
+        );
+
+        assert!(driver.advance(Duration::from_secs(5)).is_none());
+        assert_pong(
+            send_command(&mut driver, channel_id, Command::Ping, vec![1]),
+            channel_id,
+        );
+    }
+
+    #[test]
+    fn conformance_release_requires_channel_lock() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let channel_id = init_channel(&mut driver);
+
+        assert_error(
+            send_command(&mut driver, channel_id, Command::Lock, vec![0]),
+            channel_id,
+            ErrorCode::CommandRequiresChannelLock,
+        );
+    }
+
+    #[test]
+    fn conformance_sync_command_failed() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let channel_id = init_channel(&mut driver);
+
+        assert_error(
+            send_command(&mut driver, channel_id, Command::Sync, vec![1, 2]),
+            channel_id,
+            ErrorCode::SyncCommandFailed,
+        );
+    }
+
+    #[test]
+    fn conformance_other() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let channel_id = init_channel(&mut driver);
+
+        assert_error(
+            send_command(&mut driver, channel_id, Command::Error, vec![1]),
+            channel_id,
+            ErrorCode::Other,
+        );
+    }
+
+    #[test]
+    fn lock_holder_releases_lock() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let locked_channel_id = init_channel(&mut driver);
+        let channel_id = init_channel(&mut driver);
+
+        send_command(&mut driver, locked_channel_id, Command::Lock, vec![10]);
+        match send_command(&mut driver, locked_channel_id, Command::Lock, vec![0]) {
+            Some(Reply {
+                command: Command::Lock,
+                ..
+            }) => {}
+            reply => panic!("expected lock response, got {:?}", reply),
+        }
+        assert_pong(
+            send_command(&mut driver, channel_id, Command::Ping, vec![1]),
+            channel_id,
+        );
+    }
+
+    #[test]
+    fn sync_aborts_transactions_and_echoes_nonce() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let channel_id = init_channel(&mut driver);
+        let other_channel_id = init_channel(&mut driver);
+
+        send_command(&mut driver, channel_id, Command::Lock, vec![10]);
+        driver.accept_packet(Packet::Initialization {
+            channel_id: channel_id,
+            command: Command::Ping,
+            data: vec![0u8; 57],
+            payload_len: 100,
+        });
+
+        match send_command(&mut driver, channel_id, Command::Sync, vec![0x42]) {
+            Some(Reply {
                 channel_id: response_channel_id,
-                message:
-                    ResponseMessage::Pong {
-                        data: response_data,
-                    },
+                command: Command::Sync,
+                data,
             }) => {
                 assert_eq!(response_channel_id, channel_id);
-                assert_eq!(response_data[..], ping_data);
+                assert_eq!(data, vec![0x42]);
             }
-            _ => panic!(),
-        };
+            reply => panic!("expected sync response, got {:?}", reply),
+        }
+
+        // The aborted message's remaining packets are ignored
+        let res = driver.accept_packet(Packet::Continuation {
+            channel_id: channel_id,
+            sequence_number: 0,
+            data: vec![0u8; 59],
+        });
+        assert!(res.is_none());
+        // And the lock has been released
+        assert_pong(
+            send_command(&mut driver, other_channel_id, Command::Ping, vec![1]),
+            other_channel_id,
+        );
+    }
+
+    struct VersionVendorHandler;
+
+    impl VendorHandler for VersionVendorHandler {
+        fn handle(
+            &self,
+            command: VendorCommand,
+            _data: &[u8],
+        ) -> Option<Box<dyn Future<Item = Vec<u8>, Error = io::Error>>> {
+            match command {
+                VendorCommand::Hid(0xc0) => Some(Box::new(future::ok(b"1.0".to_vec()))),
+                VendorCommand::Hid(0xc2) => Some(Box::new(future::err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "handler failed",
+                )))),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_apdu_is_answered_with_status_word() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let channel_id = init_channel(&mut driver);
+
+        // A register request cut off part way through its challenge
+        let mut apdu = vec![0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x40];
+        apdu.extend_from_slice(&[0u8; 20]);
+        match send_command(&mut driver, channel_id, Command::Msg, apdu) {
+            Some(Reply {
+                channel_id: response_channel_id,
+                command: Command::Msg,
+                data,
+            }) => {
+                assert_eq!(response_channel_id, channel_id);
+                assert_eq!(data, vec![0x67, 0x00]);
+            }
+            reply => panic!("expected wrong length status, got {:?}", reply),
+        }
+        assert!(driver.dispatch.is_none());
+    }
+
+    #[test]
+    fn vendor_command_is_answered_by_handler() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        driver
+            .dispatcher
+            .set_vendor_handler(Rc::new(VersionVendorHandler));
+        let channel_id = init_channel(&mut driver);
+
+        let command = Command::Vendor { identifier: 0xc0 };
+        match send_command(&mut driver, channel_id, command, Vec::new()) {
+            Some(Reply {
+                channel_id: response_channel_id,
+                command: Command::Vendor { identifier },
+                data,
+            }) => {
+                assert_eq!(response_channel_id, channel_id);
+                assert_eq!(identifier, 0xc0);
+                assert_eq!(data, b"1.0".to_vec());
+            }
+            reply => panic!("expected vendor response, got {:?}", reply),
+        }
+
+        let command = Command::Vendor { identifier: 0xc1 };
+        assert_error(
+            send_command(&mut driver, channel_id, command, Vec::new()),
+            channel_id,
+            ErrorCode::InvalidCommand,
+        );
+    }
+
+    #[test]
+    fn failed_vendor_command_is_answered_with_error() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        driver
+            .dispatcher
+            .set_vendor_handler(Rc::new(VersionVendorHandler));
+        let channel_id = init_channel(&mut driver);
+
+        let command = Command::Vendor { identifier: 0xc2 };
+        assert_error(
+            send_command(&mut driver, channel_id, command, Vec::new()),
+            channel_id,
+            ErrorCode::Other,
+        );
+        // The device carries on serving requests
+        assert_pong(
+            send_command(&mut driver, channel_id, Command::Ping, vec![1]),
+            channel_id,
+        );
+    }
+
+    #[test]
+    fn vendor_command_without_handler_is_invalid() {
+        let mut driver = SyncDriver::new(FakeU2FService);
+        let channel_id = init_channel(&mut driver);
+
+        let command = Command::Vendor { identifier: 0xc0 };
+        assert_error(
+            send_command(&mut driver, channel_id, command, Vec::new()),
+            channel_id,
+            ErrorCode::InvalidCommand,
+        );
     }
 }